@@ -173,7 +173,7 @@ def serve_default_list(name: str):
 
     # Get format
     format_type = request.args.get("format", "hosts")
-    if format_type not in ["hosts", "plain", "adblock"]:
+    if format_type not in ["hosts", "plain", "adblock", "umbrella"]:
         format_type = "hosts"
 
     # Build file path
@@ -238,7 +238,7 @@ def serve_user_list(username: str, name: str):
 
     # Get format
     format_type = request.args.get("format", "hosts")
-    if format_type not in ["hosts", "plain", "adblock"]:
+    if format_type not in ["hosts", "plain", "adblock", "umbrella"]:
         format_type = "hosts"
 
     # Build file path
@@ -299,7 +299,7 @@ def list_default_lists():
         parts = base_name.rsplit("_", 1)
         if len(parts) == 2:
             name, format_type = parts
-            if format_type not in ["hosts", "plain", "adblock"]:
+            if format_type not in ["hosts", "plain", "adblock", "umbrella"]:
                 name = base_name
                 format_type = "hosts"
         else:
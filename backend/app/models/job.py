@@ -27,6 +27,15 @@ class Job:
     TYPE_MANUAL = "manual"
     TYPE_SCHEDULED = "scheduled"
     TYPE_ADMIN = "admin"
+    TYPE_REBUILD_ALL = "rebuild_all"  # Worker enqueues a job per enabled user
+    TYPE_PREVIEW = "preview"  # Diff config_override against the stored config, no build
+
+    # Skip reasons (result.skip_reason; display text goes in result.skip_message)
+    SKIP_NO_CHANGES = "no_changes"
+    SKIP_COPIED_FROM_USER = "copied_from_user"
+    SKIP_RATE_LIMITED = "rate_limited"
+    SKIP_BELOW_THRESHOLD = "below_threshold"
+    SKIP_DUPLICATE = "duplicate"  # Same idempotency_key as a recently completed job
 
     # Priority levels (lower number = higher priority)
     PRIORITY_HIGH = 1  # Default/admin jobs
@@ -154,21 +163,25 @@ class Job:
         self._data["result"] = {"errors": errors}
         self._emit_completed()
 
-    def skip(self, reason: str) -> None:
-        """Mark job as skipped (e.g., when another job is already running)."""
+    def skip(self, reason: str, reason_code: str = SKIP_RATE_LIMITED) -> None:
+        """Mark job as skipped (e.g., when another job is already running).
+
+        reason is the display text; reason_code is one of the SKIP_* values.
+        """
+        result = {"skip_reason": reason_code, "skip_message": reason}
         mongo.db[self.COLLECTION].update_one(
             {"_id": self._id},
             {
                 "$set": {
                     "status": self.STATUS_SKIPPED,
                     "completed_at": datetime.utcnow(),
-                    "result": {"skip_reason": reason},
+                    "result": result,
                 }
             },
         )
         self._data["status"] = self.STATUS_SKIPPED
         self._data["completed_at"] = datetime.utcnow()
-        self._data["result"] = {"skip_reason": reason}
+        self._data["result"] = result
         self._emit_skipped(reason)
 
     def _emit_skipped(self, reason: str) -> None:
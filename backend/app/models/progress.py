@@ -46,7 +46,7 @@ class WhitelistPatternProgress:
     pattern: str
     pattern_type: str  # exact, wildcard, regex, subdomain
     match_count: int = 0
-    samples: List[str] = field(default_factory=list)  # First 5 matched domains
+    samples: List[str] = field(default_factory=list)  # First matched domains (worker WHITELIST_SAMPLE_COUNT)
 
     def to_dict(self) -> Dict[str, Any]:
         """Convert to dictionary."""
@@ -40,6 +40,10 @@ export default function CompletedStage({ job, sources, whitelist }: CompletedSta
   const failedSources = sources.filter((s) => s.status === 'failed').length;
   const cachedSources = sources.filter((s) => s.cache_hit === true).length;
 
+  // Domains only opt-in lists (e.g. nsfw) have, which the combined count leaves out
+  const optinOnlyDomains =
+    (result?.all_categories_unique_domains ?? 0) - (result?.combined_unique_domains ?? 0);
+
   // Skipped message
   if (isSkipped) {
     return (
@@ -60,7 +64,7 @@ export default function CompletedStage({ job, sources, whitelist }: CompletedSta
           </svg>
           <h3 className="text-lg font-semibold text-yellow-400 mb-2">Job Skipped</h3>
           <p className="text-pihole-text-muted">
-            {result?.skip_reason || 'Another job was already running'}
+            {result?.skip_message || 'Another job was already running'}
           </p>
         </div>
       </div>
@@ -205,6 +209,11 @@ export default function CompletedStage({ job, sources, whitelist }: CompletedSta
             <div className="text-xl font-semibold text-pihole-text">
               {result.unique_domains?.toLocaleString()}
             </div>
+            {optinOnlyDomains > 0 && (
+              <div className="text-xs text-pihole-text-muted">
+                +{optinOnlyDomains.toLocaleString()} in opt-in lists only
+              </div>
+            )}
           </div>
           <div className="bg-pihole-darkest rounded-lg p-3">
             <div className="text-xs text-pihole-text-muted mb-1">Whitelisted</div>
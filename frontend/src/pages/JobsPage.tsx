@@ -79,7 +79,7 @@ export default function JobsPage() {
         ? {
             ...prev,
             status: 'skipped',
-            result: { ...prev.result, skip_reason: data.reason } as Job['result'],
+            result: { ...prev.result, skip_message: data.reason } as Job['result'],
           }
         : prev,
     );
@@ -35,6 +35,8 @@ export interface UserList {
   name: string;
   formats: string[];
   domain_count: number;
+  // Per-format counts, only present when formats differ (e.g. adblock collapses subdomains)
+  format_counts?: Record<string, number>;
   last_updated: string;
 }
 
@@ -80,6 +82,9 @@ export interface FormatBreakdown {
   hosts: number;
   plain: number;
   adblock: number;
+  json?: number;
+  dnsmasq?: number;
+  pac?: number;
 }
 
 // Source progress for downloading stage
@@ -95,6 +100,8 @@ export interface SourceProgress {
   download_time_ms: number | null;
   domain_count: number | null;
   domain_change: number | null;
+  domain_history?: { domain_count: number; recorded_at: string }[]; // Recent counts, oldest first
+  unique_domain_count?: number; // Domains no other source in the job lists
   format_breakdown?: FormatBreakdown;
   detected_formats?: string[]; // e.g., ["hosts", "adblock"]
   error: string | null;
@@ -117,12 +124,13 @@ export interface WhitelistProgress {
   domains_after: number;
   total_removed: number;
   patterns: WhitelistPatternProgress[];
+  category_removed?: Record<string, number>;
   processing: boolean;
 }
 
 // Output format generation progress
 export interface FormatProgress {
-  format: 'hosts' | 'plain' | 'adblock';
+  format: 'hosts' | 'plain' | 'adblock' | 'umbrella';
   status: 'pending' | 'generating' | 'compressing' | 'completed';
   domains_written: number;
   total_domains: number;
@@ -173,17 +181,39 @@ export interface EnhancedJobProgress {
   current_source: string | null;
 }
 
+export type SkipReason = 'no_changes' | 'copied_from_user' | 'rate_limited' | 'below_threshold' | 'duplicate';
+
 export interface JobResult {
   sources_processed: number;
   sources_failed: number;
   total_domains: number;
   unique_domains: number;
+  combined_unique_domains?: number; // Combined list, opt-in categories excluded
+  all_categories_unique_domains?: number; // Every category, opt-in ones included
   whitelisted_removed: number;
   output_files: OutputFile[];
   categories?: Record<string, number>;
   errors: string[];
-  skip_reason?: string;
+  skip_reason?: SkipReason;
+  skip_message?: string; // Human-readable text for skip_reason
   copied_from?: string; // Username whose build was copied (fingerprint match)
+  copy_savings?: CopySavings; // Work avoided by copying (copy-on-match only)
+  config_warnings?: string[]; // Config/whitelist lines dropped while parsing
+  config_diff?: ConfigDiff; // Preview jobs only
+}
+
+export interface ConfigDiff {
+  added_count: number;
+  removed_count: number;
+  unchanged_count: number;
+  added_sample: string[];
+  removed_sample: string[];
+}
+
+export interface CopySavings {
+  sources: number;
+  bytes: number;
+  download_time_ms: number;
 }
 
 export interface OutputFile {
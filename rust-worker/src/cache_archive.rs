@@ -0,0 +1,228 @@
+//! Cache export/import for seeding a fresh deployment
+//!
+//! `--export-cache <file>` writes every cached source (metadata + GridFS
+//! content) to a gzipped tarball; `--import-cache <file>` loads one into this
+//! deployment's MongoDB, so a new worker's first builds hit a warm cache
+//! instead of downloading everything. Sources already cached are left alone.
+//!
+//! Each source is two archive members, `<url_hash>.bson` (the cache entry)
+//! followed by `<url_hash>.data` (its content). MongoDB and the archive are
+//! bridged by a bounded channel, so only a few sources are in memory at once.
+
+use anyhow::{bail, Context, Result};
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use tokio::sync::mpsc;
+
+use crate::db::cache::{CacheEntry, CacheRepository};
+
+/// Sources buffered between MongoDB and the archive
+const CHANNEL_CAPACITY: usize = 4;
+
+/// Writes cache entries and their content to a gzipped tarball
+pub struct ArchiveWriter<W: Write> {
+    builder: tar::Builder<GzEncoder<W>>,
+    entries: u64,
+}
+
+impl<W: Write> ArchiveWriter<W> {
+    pub fn new(writer: W) -> Self {
+        Self {
+            builder: tar::Builder::new(GzEncoder::new(writer, Compression::default())),
+            entries: 0,
+        }
+    }
+
+    /// Add one cached source
+    pub fn append(&mut self, entry: &CacheEntry, content: &[u8]) -> Result<()> {
+        let metadata = bson::to_vec(entry)?;
+        self.append_member(&format!("{}.bson", entry.url_hash), &metadata)?;
+        self.append_member(&format!("{}.data", entry.url_hash), content)?;
+        self.entries += 1;
+        Ok(())
+    }
+
+    fn append_member(&mut self, name: &str, data: &[u8]) -> Result<()> {
+        let mut header = tar::Header::new_gnu();
+        header.set_size(data.len() as u64);
+        header.set_mode(0o644);
+        header.set_cksum();
+        self.builder.append_data(&mut header, name, data)?;
+        Ok(())
+    }
+
+    /// Finish the archive; returns the number of sources written and the writer
+    pub fn finish(self) -> Result<(u64, W)> {
+        Ok((self.entries, self.builder.into_inner()?.finish()?))
+    }
+}
+
+/// Read every cached source from an archive, in the order written
+pub fn read_archive<R: Read>(reader: R, mut visit: impl FnMut(CacheEntry, Vec<u8>) -> Result<()>) -> Result<u64> {
+    let mut archive = tar::Archive::new(GzDecoder::new(reader));
+    let mut pending: Option<CacheEntry> = None;
+    let mut count = 0;
+
+    for member in archive.entries()? {
+        let mut member = member?;
+        let name = member.path()?.to_string_lossy().into_owned();
+        let mut data = Vec::with_capacity(member.size() as usize);
+        member.read_to_end(&mut data)?;
+
+        if let Some(url_hash) = name.strip_suffix(".bson") {
+            if let Some(entry) = &pending {
+                bail!("Cache archive has no content for {}", entry.url_hash);
+            }
+            let entry: CacheEntry = bson::from_slice(&data).with_context(|| format!("Invalid cache entry {}", name))?;
+            if entry.url_hash != url_hash {
+                bail!("Cache archive member {} holds entry {}", name, entry.url_hash);
+            }
+            pending = Some(entry);
+        } else if let Some(url_hash) = name.strip_suffix(".data") {
+            match pending.take() {
+                Some(entry) if entry.url_hash == url_hash => {
+                    visit(entry, data)?;
+                    count += 1;
+                }
+                _ => bail!("Cache archive content {} has no entry before it", name),
+            }
+        } else {
+            bail!("Unexpected member {} in cache archive", name);
+        }
+    }
+
+    if let Some(entry) = pending {
+        bail!("Cache archive has no content for {}", entry.url_hash);
+    }
+    Ok(count)
+}
+
+/// Write every cached source to `path`; returns the number exported
+pub async fn export(cache: &CacheRepository, path: &Path) -> Result<u64> {
+    let file = File::create(path).with_context(|| format!("Failed to create {}", path.display()))?;
+    let (tx, mut rx) = mpsc::channel::<(CacheEntry, Vec<u8>)>(CHANNEL_CAPACITY);
+
+    let writer = tokio::task::spawn_blocking(move || -> Result<u64> {
+        let mut archive = ArchiveWriter::new(BufWriter::new(file));
+        while let Some((entry, content)) = rx.blocking_recv() {
+            archive.append(&entry, &content)?;
+        }
+        let (count, mut file) = archive.finish()?;
+        file.flush()?;
+        Ok(count)
+    });
+
+    for entry in cache.entries_with_content().await? {
+        let Some(gridfs_id) = entry.gridfs_id else {
+            continue;
+        };
+        let content = match cache.read_blob(gridfs_id).await {
+            Ok(content) => content,
+            Err(e) => {
+                tracing::warn!("Skipping cache entry {}: {}", entry.url_hash, e);
+                continue;
+            }
+        };
+        // A closed channel means the writer failed; its error is reported below
+        if tx.send((entry, content)).await.is_err() {
+            break;
+        }
+    }
+    drop(tx);
+
+    writer.await?
+}
+
+/// Sources loaded by an import
+#[derive(Debug, Default, PartialEq)]
+pub struct ImportSummary {
+    pub imported: u64,
+    /// Already cached in this deployment, left as they were
+    pub skipped: u64,
+}
+
+/// Load every source in the archive at `path` into the cache
+pub async fn import(cache: &CacheRepository, path: &Path) -> Result<ImportSummary> {
+    let file = File::open(path).with_context(|| format!("Failed to open {}", path.display()))?;
+    let (tx, mut rx) = mpsc::channel::<(CacheEntry, Vec<u8>)>(CHANNEL_CAPACITY);
+
+    let reader = tokio::task::spawn_blocking(move || {
+        read_archive(BufReader::new(file), |entry, content| {
+            tx.blocking_send((entry, content))
+                .map_err(|_| anyhow::anyhow!("Import stopped"))
+        })
+    });
+
+    let mut summary = ImportSummary::default();
+    let mut import_error = None;
+    while let Some((entry, content)) = rx.recv().await {
+        match cache.import_entry(entry, &content).await {
+            Ok(true) => summary.imported += 1,
+            Ok(false) => summary.skipped += 1,
+            Err(e) => {
+                import_error = Some(e);
+                break;
+            }
+        }
+    }
+    drop(rx);
+
+    if let Some(e) = import_error {
+        return Err(e);
+    }
+    reader.await??;
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(url_hash: &str, domain_count: i64) -> CacheEntry {
+        let mut entry: CacheEntry = bson::from_document(bson::doc! {
+            "url_hash": url_hash,
+            "url": format!("https://example.com/{}.txt", url_hash),
+            "gridfs_id": bson::oid::ObjectId::new(),
+            "etag": "\"v1\"",
+        })
+        .unwrap();
+        entry.stats.domain_count = domain_count;
+        entry
+    }
+
+    #[test]
+    fn test_export_then_import_reproduces_entries_and_content() {
+        let sources = vec![
+            (entry("aaa", 2), b"ads.com\ntracker.net\n".to_vec()),
+            (entry("bbb", 0), Vec::new()),
+            (entry("ccc", 1), vec![0x1f, 0x8b, 0x00, 0xff]),
+        ];
+
+        let mut archive = ArchiveWriter::new(Vec::new());
+        for (entry, content) in &sources {
+            archive.append(entry, content).unwrap();
+        }
+        let (written, bytes) = archive.finish().unwrap();
+        assert_eq!(written, 3);
+
+        let mut imported = Vec::new();
+        let read = read_archive(&bytes[..], |entry, content| {
+            imported.push((entry, content));
+            Ok(())
+        })
+        .unwrap();
+
+        assert_eq!(read, 3);
+        for ((expected, expected_content), (entry, content)) in sources.iter().zip(&imported) {
+            assert_eq!(bson::to_document(entry).unwrap(), bson::to_document(expected).unwrap());
+            assert_eq!(content, expected_content);
+        }
+
+        // A truncated archive is rejected rather than half-imported silently
+        assert!(read_archive(&bytes[..bytes.len() / 2], |_, _| Ok(())).is_err());
+    }
+}
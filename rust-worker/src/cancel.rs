@@ -0,0 +1,38 @@
+//! Shutdown-driven cancellation for long-running job work
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// How often `cancelled()` re-checks the flag
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Cancellation token backed by the worker's shutdown flag
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken {
+    flag: Arc<AtomicBool>,
+}
+
+impl CancelToken {
+    /// Token that fires once `flag` is set (e.g. by the Ctrl-C handler)
+    pub fn from_flag(flag: Arc<AtomicBool>) -> Self {
+        Self { flag }
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.flag.load(Ordering::Relaxed)
+    }
+
+    /// Set the flag, cancelling everything holding a clone of this token
+    #[cfg(test)]
+    pub fn cancel(&self) {
+        self.flag.store(true, Ordering::Relaxed);
+    }
+
+    /// Resolves once the token is cancelled; race it with `tokio::select!`
+    pub async fn cancelled(&self) {
+        while !self.is_cancelled() {
+            tokio::time::sleep(POLL_INTERVAL).await;
+        }
+    }
+}
@@ -0,0 +1,68 @@
+//! Domain -> category lookup for domains from uncategorized sources
+//!
+//! With AUTO_CATEGORIZE, a domain from a source without a category is looked
+//! up here (itself, then each parent domain) before falling back to the
+//! default category, so e.g. `stats.doubleclick.net` from a catch-all list
+//! still lands in `ads`. The map is bundled, or loaded from CATEGORY_MAP_FILE.
+
+use anyhow::{Context, Result};
+use std::collections::HashMap;
+use std::path::Path;
+
+/// Map shipped with the worker (`data/category_map.txt`)
+const BUNDLED_MAP: &str = include_str!("../data/category_map.txt");
+
+/// Domain -> category classifier
+#[derive(Debug, Default)]
+pub struct CategoryMap {
+    categories: HashMap<String, String>,
+}
+
+impl CategoryMap {
+    /// The map bundled with the worker
+    pub fn bundled() -> Self {
+        Self::parse(BUNDLED_MAP)
+    }
+
+    /// Load a map file in the bundled format
+    pub fn load(path: &Path) -> Result<Self> {
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read category map {}", path.display()))?;
+        Ok(Self::parse(&content))
+    }
+
+    /// Parse `domain category` lines; `#` comments and malformed lines are skipped
+    pub fn parse(content: &str) -> Self {
+        let categories = content
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .filter_map(|line| {
+                let mut fields = line.split_whitespace();
+                let domain = fields.next()?.trim_end_matches('.').to_lowercase();
+                let category = fields.next()?.to_lowercase();
+                Some((domain, category))
+            })
+            .collect();
+        Self { categories }
+    }
+
+    pub fn len(&self) -> usize {
+        self.categories.len()
+    }
+
+    /// Category of `domain` or its closest listed parent
+    ///
+    /// Walking up the labels finds the registrable domain's entry without a
+    /// public suffix list; a bare TLD is never matched.
+    pub fn lookup(&self, domain: &str) -> Option<&str> {
+        let mut candidate = domain;
+        while candidate.contains('.') {
+            if let Some(category) = self.categories.get(candidate) {
+                return Some(category);
+            }
+            candidate = candidate.split_once('.').map_or("", |(_, parent)| parent);
+        }
+        None
+    }
+}
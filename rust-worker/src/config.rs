@@ -1,6 +1,38 @@
+use std::collections::{HashMap, HashSet};
 use std::env;
+use std::net::IpAddr;
 use std::path::PathBuf;
 
+use crate::db::cache::DEFAULT_DOMAIN_HISTORY_SIZE;
+use crate::extractor::DEFAULT_HOSTS_SINK_IPS;
+use crate::downloader::DEFAULT_SOURCE_SCHEMES;
+use crate::generator::{DEFAULT_FILENAME_TEMPLATE, DEFAULT_MAX_CONCURRENT_FILE_WRITES};
+
+/// Suffixes dropped by FILTER_RESERVED_DOMAINS unless RESERVED_TLDS is set
+const DEFAULT_RESERVED_TLDS: &str = "onion,i2p,bit,local,localhost,internal,lan,home.arpa";
+
+/// What to do with a source whose format and domain count both shift drastically
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FormatShiftPolicy {
+    /// Don't compare against the previous run
+    Off,
+    /// Add a warning to the source and keep its domains
+    Warn,
+    /// Fail the source, leaving its domains out of the build
+    Fail,
+}
+
+impl FormatShiftPolicy {
+    fn parse(value: &str) -> Option<Self> {
+        match value.trim().to_lowercase().as_str() {
+            "off" => Some(Self::Off),
+            "warn" => Some(Self::Warn),
+            "fail" => Some(Self::Fail),
+            _ => None,
+        }
+    }
+}
+
 /// Worker configuration loaded from environment variables
 #[derive(Debug, Clone)]
 pub struct Config {
@@ -14,48 +46,429 @@ pub struct Config {
     pub worker_id: String,
     /// Heartbeat interval in seconds
     pub heartbeat_interval_secs: u64,
+    /// Minimum time between progress writes within a stage (ms)
+    pub progress_update_interval_ms: u64,
     /// Maximum concurrent downloads
     pub max_concurrent_downloads: usize,
-    /// HTTP request timeout in seconds
+    /// Total HTTP request timeout in seconds, body included (0 = no limit)
     pub http_timeout_secs: u64,
+    /// Seconds allowed to establish a connection (0 = no limit)
+    pub connect_timeout_secs: u64,
+    /// Fail a download when no bytes arrive for this many seconds (0 = off)
+    pub read_idle_timeout_secs: u64,
     /// Cache TTL in days
     pub cache_ttl_days: u64,
+    /// Total cache size budget; least valuable entries are evicted past it (0 = unbounded)
+    pub max_cache_size_bytes: u64,
+    /// Also emit zstd-compressed (`.txt.zst`) output variants
+    pub emit_zstd: bool,
+    /// Cap on output files open for writing at once during generation
+    pub max_concurrent_file_writes: usize,
+    /// Write the combined list while the per-category files are still being written
+    pub concurrent_combined_generation: bool,
+    /// Output file naming, e.g. `{format}/{name}.txt.gz` (see `FilenameTemplate`)
+    pub output_filename_template: String,
+    /// Fixed "Generated:" time for output headers (unix seconds), for byte-identical rebuilds
+    pub source_date_epoch: Option<i64>,
+    /// Also emit uncompressed (`.txt`) output variants
+    pub emit_uncompressed: bool,
+    /// Write a `{category}_sources.csv.gz` per category mapping each domain to its sources
+    pub emit_source_attribution: bool,
+    /// Leave subdomains of listed domains out of adblock output (`||parent^` covers them)
+    pub collapse_covered_subdomains: bool,
+    /// Re-read generated files and check their domain counts (debugging aid)
+    pub verify_output: bool,
+    /// Resolve all distinct source hosts up front before downloading
+    pub prewarm_dns: bool,
+    /// Evaluate the `schedules` collection and enqueue scheduled jobs in-process
+    pub internal_scheduler: bool,
+    /// Interval for the cross-user top domains report in seconds (0 = disabled)
+    pub top_domains_interval_secs: u64,
+    /// Number of domains kept in the top domains report
+    pub top_domains_limit: usize,
+    /// Delete finished jobs older than this many days (0 = keep forever)
+    pub job_retention_days: u64,
+    /// Newest finished jobs kept per user regardless of age
+    pub job_retention_min_per_user: usize,
+    /// Sort output by reversed labels (groups shared suffixes for smaller gzip)
+    pub compression_optimized_sort: bool,
+    /// Cap on unique domains in one build, across all categories (0 = unlimited)
+    pub max_total_domains: u64,
+    /// Wall-clock limit for one job before it is failed (0 = unlimited)
+    pub max_job_duration_secs: u64,
+    /// How far back a completed job's idempotency key suppresses a repeat (0 = off)
+    pub idempotency_window_secs: u64,
+    /// Build empty lists for a config with no valid sources instead of failing
+    /// (users can override with `config.empty_config_produces_empty_list`)
+    pub empty_config_produces_empty_list: bool,
+    /// POST to users' `webhook_url` when their build completes
+    pub enable_webhooks: bool,
+    /// Store output files once by content hash and hard-link them into output dirs
+    pub content_addressed_output: bool,
+    /// Extract domains from the body while it downloads instead of from a
+    /// full copy afterwards (not for JSON sources or with SECTION_HEADER_PATTERN)
+    pub streaming_extraction: bool,
+    /// Log source URLs as host plus hash, never with their path or query
+    pub redact_source_urls: bool,
+    /// Maximum HTTP redirects followed per source download
+    pub max_redirects: usize,
+    /// Idle keep-alive connections kept per host (reqwest default: unlimited)
+    pub pool_max_idle_per_host: usize,
+    /// Seconds an idle pooled connection is kept open (reqwest default: 90)
+    pub pool_idle_timeout_secs: u64,
+    /// Speak HTTP/2 without negotiation; only for sources known to support it
+    pub http2_prior_knowledge: bool,
+    /// Transcode Latin-1 and UTF-16 sources to UTF-8 instead of replacing invalid bytes
+    pub transcode_sources: bool,
+    /// Drop extracted domains under `reserved_tlds` (and single-label names)
+    pub filter_reserved_domains: bool,
+    /// Reserved/internal suffixes for `filter_reserved_domains`, without the dot
+    pub reserved_tlds: Vec<String>,
+    /// Addresses that start a hosts-format line (`0.0.0.0 ads.com`)
+    pub hosts_sink_ips: Vec<String>,
+    /// Prefixes stripped from `prefix:domain` lines (e.g. `sni:ads.com`); empty = none
+    pub domain_prefixes: Vec<String>,
+    /// Example domains shown per matched whitelist pattern (0 = none)
+    pub whitelist_sample_count: usize,
+    /// Fail a job whose whitelist has patterns that don't parse or are over-broad
+    /// (otherwise they are skipped, or applied, and reported as warnings)
+    pub strict_whitelist: bool,
+    /// Category for sources without one (None = `uncategorized_*` files)
+    pub default_category: Option<String>,
+    /// Categorize domains from uncategorized sources with a domain -> category map
+    pub auto_categorize: bool,
+    /// Map file for `auto_categorize` (None = the bundled map)
+    pub category_map_file: Option<PathBuf>,
+    /// Domain-count samples kept per source in the cache (0 = no history)
+    pub domain_history_size: usize,
+    /// Cache metadata writes buffered before they're sent together (0 = write each one)
+    pub cache_write_batch_size: usize,
+    /// Hash cached content on every read and re-download it if it doesn't match
+    pub verify_cache_integrity: bool,
+    /// Sink IP per category: its domains are rewritten to the IP instead of blocked
+    pub category_sinks: HashMap<String, String>,
+    /// Fail empty downloads of sources that previously had many domains (keeps the old cache)
+    pub treat_empty_as_failure: bool,
+    /// Response content types rejected as download failures (`type/` = whole type)
+    pub blocked_content_types: Vec<String>,
+    /// URL schemes a source may use; others are rejected when the config is parsed
+    pub allowed_source_schemes: Vec<String>,
+    /// HTTP statuses that fall back to the cached copy (with a warning) instead of failing
+    pub stale_if_error: Vec<u16>,
+    /// Handling of sources whose primary format changed while most domains vanished
+    pub format_shift_policy: FormatShiftPolicy,
+    /// Opt-in categories: own files only, never part of the combined all_domains list
+    pub optin_categories: HashSet<String>,
+    /// Regex for section header comments that set the category of the lines
+    /// after them; capture group 1 is the category (None = disabled)
+    pub section_header_pattern: Option<String>,
+    /// Log filter, e.g. `info` or `blocklist_worker=debug` (reloaded on SIGHUP)
+    pub log_level: String,
 }
 
 impl Config {
     /// Load configuration from environment variables
+    #[cfg(test)]
     pub fn from_env() -> Self {
+        Self::from_env_with(&HashMap::new())
+    }
+
+    /// Load configuration from environment variables, falling back to
+    /// `file_vars` (parsed from .env) for anything the environment doesn't set
+    pub fn from_env_with(file_vars: &HashMap<String, String>) -> Self {
+        let var = |key: &str| env::var(key).or_else(|e| file_vars.get(key).cloned().ok_or(e));
         let worker_id = uuid::Uuid::new_v4().to_string();
 
         Self {
-            mongo_uri: env::var("MONGO_URI")
+            mongo_uri: var("MONGO_URI")
                 .unwrap_or_else(|_| "mongodb://localhost:27017".to_string()),
-            database_name: env::var("DATABASE_NAME")
+            database_name: var("DATABASE_NAME")
                 .unwrap_or_else(|_| "blocklist".to_string()),
             data_dir: PathBuf::from(
-                env::var("DATA_DIR")
+                var("DATA_DIR")
                     .unwrap_or_else(|_| "./data".to_string())
             ),
             worker_id,
-            heartbeat_interval_secs: env::var("HEARTBEAT_INTERVAL_SECS")
+            heartbeat_interval_secs: var("HEARTBEAT_INTERVAL_SECS")
                 .ok()
                 .and_then(|v| v.parse().ok())
                 .unwrap_or(10),
-            max_concurrent_downloads: env::var("MAX_CONCURRENT_DOWNLOADS")
+            progress_update_interval_ms: var("PROGRESS_UPDATE_INTERVAL_MS")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(500),
+            max_concurrent_downloads: var("MAX_CONCURRENT_DOWNLOADS")
                 .ok()
                 .and_then(|v| v.parse().ok())
                 .unwrap_or(10),
-            http_timeout_secs: env::var("HTTP_TIMEOUT_SECS")
+            http_timeout_secs: var("HTTP_TIMEOUT_SECS")
                 .ok()
                 .and_then(|v| v.parse().ok())
                 .unwrap_or(60),
-            cache_ttl_days: env::var("CACHE_TTL_DAYS")
+            connect_timeout_secs: var("CONNECT_TIMEOUT_SECS")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(10),
+            read_idle_timeout_secs: var("READ_IDLE_TIMEOUT_SECS")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(30),
+            cache_ttl_days: var("CACHE_TTL_DAYS")
                 .ok()
                 .and_then(|v| v.parse().ok())
                 .unwrap_or(7),
+            max_cache_size_bytes: var("MAX_CACHE_SIZE_BYTES")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(0),
+            emit_zstd: var("EMIT_ZSTD")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(false),
+            max_concurrent_file_writes: var("MAX_CONCURRENT_FILE_WRITES")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(DEFAULT_MAX_CONCURRENT_FILE_WRITES),
+            concurrent_combined_generation: var("CONCURRENT_COMBINED_GENERATION")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(true),
+            source_date_epoch: var("SOURCE_DATE_EPOCH")
+                .ok()
+                .and_then(|v| v.trim().parse().ok()),
+            output_filename_template: var("OUTPUT_FILENAME_TEMPLATE")
+                .ok()
+                .filter(|v| !v.trim().is_empty())
+                .unwrap_or_else(|| DEFAULT_FILENAME_TEMPLATE.to_string()),
+            emit_uncompressed: var("EMIT_UNCOMPRESSED")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(false),
+            emit_source_attribution: var("EMIT_SOURCE_ATTRIBUTION")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(false),
+            collapse_covered_subdomains: var("COLLAPSE_COVERED_SUBDOMAINS")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(false),
+            verify_output: var("VERIFY_OUTPUT")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(false),
+            prewarm_dns: var("PREWARM_DNS")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(false),
+            internal_scheduler: var("INTERNAL_SCHEDULER")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(false),
+            top_domains_interval_secs: var("TOP_DOMAINS_INTERVAL_SECS")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(0),
+            top_domains_limit: var("TOP_DOMAINS_LIMIT")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(1000),
+            job_retention_days: var("JOB_RETENTION_DAYS")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(0),
+            job_retention_min_per_user: var("JOB_RETENTION_MIN_PER_USER")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(10),
+            compression_optimized_sort: var("COMPRESSION_OPTIMIZED_SORT")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(false),
+            max_total_domains: var("MAX_TOTAL_DOMAINS")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(0),
+            max_job_duration_secs: var("MAX_JOB_DURATION_SECS")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(0),
+            idempotency_window_secs: var("IDEMPOTENCY_WINDOW_SECS")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(3600),
+            empty_config_produces_empty_list: var("EMPTY_CONFIG_PRODUCES_EMPTY_LIST")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(false),
+            enable_webhooks: var("ENABLE_WEBHOOKS")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(false),
+            content_addressed_output: var("CONTENT_ADDRESSED_OUTPUT")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(false),
+            streaming_extraction: var("STREAMING_EXTRACTION")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(false),
+            redact_source_urls: var("REDACT_SOURCE_URLS")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(false),
+            max_redirects: var("MAX_REDIRECTS")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(10),
+            pool_max_idle_per_host: var("POOL_MAX_IDLE_PER_HOST")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(usize::MAX),
+            pool_idle_timeout_secs: var("POOL_IDLE_TIMEOUT_SECS")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(90),
+            http2_prior_knowledge: var("HTTP2_PRIOR_KNOWLEDGE")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(false),
+            default_category: var("DEFAULT_CATEGORY")
+                .ok()
+                .map(|v| v.trim().to_string())
+                .filter(|v| !v.is_empty()),
+            transcode_sources: var("TRANSCODE_SOURCES")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(false),
+            filter_reserved_domains: var("FILTER_RESERVED_DOMAINS")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(false),
+            reserved_tlds: var("RESERVED_TLDS")
+                .unwrap_or_else(|_| DEFAULT_RESERVED_TLDS.to_string())
+                .split(',')
+                .map(|t| t.trim().trim_matches('.').to_lowercase())
+                .filter(|t| !t.is_empty())
+                .collect(),
+            hosts_sink_ips: var("HOSTS_SINK_IPS")
+                .ok()
+                .filter(|v| !v.trim().is_empty())
+                .map(|v| v.split(',').map(|ip| ip.trim().to_string()).filter(|ip| !ip.is_empty()).collect())
+                .unwrap_or_else(|| DEFAULT_HOSTS_SINK_IPS.iter().map(|ip| ip.to_string()).collect()),
+            domain_prefixes: var("DOMAIN_PREFIXES")
+                .map(|v| v.split(',').map(|p| p.trim().to_string()).filter(|p| !p.is_empty()).collect())
+                .unwrap_or_default(),
+            whitelist_sample_count: var("WHITELIST_SAMPLE_COUNT")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(5),
+            strict_whitelist: var("STRICT_WHITELIST")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(false),
+            auto_categorize: var("AUTO_CATEGORIZE")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(false),
+            category_map_file: var("CATEGORY_MAP_FILE")
+                .ok()
+                .filter(|v| !v.trim().is_empty())
+                .map(PathBuf::from),
+            domain_history_size: var("DOMAIN_HISTORY_SIZE")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(DEFAULT_DOMAIN_HISTORY_SIZE),
+            cache_write_batch_size: var("CACHE_WRITE_BATCH_SIZE")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(0),
+            verify_cache_integrity: var("VERIFY_CACHE_INTEGRITY")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(false),
+            category_sinks: var("CATEGORY_SINKS")
+                .map(|v| Self::parse_category_sinks(&v))
+                .unwrap_or_default(),
+            treat_empty_as_failure: var("TREAT_EMPTY_AS_FAILURE")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(true),
+            blocked_content_types: var("BLOCKED_CONTENT_TYPES")
+                .unwrap_or_else(|_| "image/,video/,audio/,font/".to_string())
+                .split(',')
+                .map(|t| t.trim().to_lowercase())
+                .filter(|t| !t.is_empty())
+                .collect(),
+            allowed_source_schemes: var("ALLOWED_SOURCE_SCHEMES")
+                .map(|v| {
+                    v.split(',')
+                        .map(|s| s.trim().to_lowercase())
+                        .filter(|s| !s.is_empty())
+                        .collect()
+                })
+                .unwrap_or_else(|_| DEFAULT_SOURCE_SCHEMES.iter().map(|s| s.to_string()).collect()),
+            stale_if_error: var("STALE_IF_ERROR")
+                .unwrap_or_default()
+                .split(',')
+                .filter_map(|s| s.trim().parse().ok())
+                .collect(),
+            format_shift_policy: var("FORMAT_SHIFT_POLICY")
+                .ok()
+                .and_then(|v| FormatShiftPolicy::parse(&v))
+                .unwrap_or(FormatShiftPolicy::Warn),
+            optin_categories: var("OPTIN_CATEGORIES")
+                .unwrap_or_else(|_| "nsfw".to_string())
+                .split(',')
+                .map(|c| c.trim().to_lowercase())
+                .filter(|c| !c.is_empty())
+                .collect(),
+            section_header_pattern: var("SECTION_HEADER_PATTERN")
+                .ok()
+                .filter(|p| !p.trim().is_empty()),
+            log_level: var("LOG_LEVEL")
+                .ok()
+                .filter(|v| !v.trim().is_empty())
+                .unwrap_or_else(|| "info".to_string()),
         }
     }
 
+    /// Take the hot-reloadable settings from a freshly loaded config
+    ///
+    /// Only concurrency, HTTP timeouts and the log level change live; returns
+    /// the env vars that changed but need a restart, so they can be reported.
+    pub fn apply_reload(&mut self, fresh: Config) -> Vec<&'static str> {
+        let restart_only = [
+            ("MONGO_URI", self.mongo_uri != fresh.mongo_uri),
+            ("DATABASE_NAME", self.database_name != fresh.database_name),
+            ("DATA_DIR", self.data_dir != fresh.data_dir),
+        ];
+
+        self.max_concurrent_downloads = fresh.max_concurrent_downloads;
+        self.http_timeout_secs = fresh.http_timeout_secs;
+        self.connect_timeout_secs = fresh.connect_timeout_secs;
+        self.read_idle_timeout_secs = fresh.read_idle_timeout_secs;
+        self.log_level = fresh.log_level;
+
+        restart_only
+            .into_iter()
+            .filter(|(_, changed)| *changed)
+            .map(|(name, _)| name)
+            .collect()
+    }
+
+    /// Parse `category=ip` pairs separated by commas, skipping invalid IPs
+    fn parse_category_sinks(value: &str) -> HashMap<String, String> {
+        value
+            .split(',')
+            .filter_map(|pair| {
+                let (category, ip) = pair.split_once('=')?;
+                let ip: IpAddr = ip.trim().parse().ok()?;
+                Some((category.trim().to_lowercase(), ip.to_string()))
+            })
+            .filter(|(category, _)| !category.is_empty())
+            .collect()
+    }
+
     /// Get path for default lists
     pub fn default_dir(&self) -> PathBuf {
         self.data_dir.join("default")
@@ -66,6 +479,20 @@ impl Config {
         self.data_dir.join("users").join(username)
     }
 
+    /// Get output directory for a user's one-off test builds (not served)
+    pub fn test_output_dir(&self, username: &str) -> PathBuf {
+        if username == "__default__" {
+            self.default_dir().join("test-output")
+        } else {
+            self.user_dir(username).join("test-output")
+        }
+    }
+
+    /// Shared content-addressed store that output files link into
+    pub fn output_store_dir(&self) -> PathBuf {
+        self.data_dir.join("output-store")
+    }
+
     /// Get output directory for a user (output files still on filesystem for nginx)
     pub fn output_dir(&self, username: &str) -> PathBuf {
         if username == "__default__" {
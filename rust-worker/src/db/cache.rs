@@ -1,4 +1,6 @@
 use anyhow::Result;
+use std::collections::HashSet;
+use std::sync::Mutex;
 use bson::{doc, oid::ObjectId, Bson, DateTime as BsonDateTime};
 use chrono::Utc;
 use futures::io::AsyncReadExt;
@@ -6,6 +8,8 @@ use mongodb::{gridfs::GridFsBucket, options::GridFsBucketOptions, Collection, Da
 use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
 
+use crate::extractor::FormatBreakdown;
+
 /// Cache entry stats
 #[derive(Debug, Clone, Serialize, Deserialize, Default)]
 pub struct CacheStats {
@@ -19,6 +23,80 @@ pub struct CacheStats {
     pub last_download_at: Option<BsonDateTime>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub last_accessed_at: Option<BsonDateTime>,
+    #[serde(default)]
+    pub access_count: i64,
+    /// Outcomes of the most recent downloads, oldest first (bounded)
+    #[serde(default)]
+    pub fetch_history: Vec<FetchOutcome>,
+    /// Reads whose content didn't match `content_hash` (VERIFY_CACHE_INTEGRITY)
+    #[serde(default)]
+    pub corrupt_reads: i64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_corrupt_at: Option<BsonDateTime>,
+}
+
+impl CacheStats {
+    /// How the source's recent downloads went (None before the first one)
+    pub fn reliability(&self) -> Option<Reliability> {
+        (!self.fetch_history.is_empty()).then(|| Reliability {
+            fetches: self.fetch_history.len(),
+            successes: self.fetch_history.iter().filter(|f| f.success).count(),
+        })
+    }
+}
+
+/// One download attempt of a source
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct FetchOutcome {
+    pub success: bool,
+    pub at: BsonDateTime,
+}
+
+/// Success counts over a source's recent downloads
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Reliability {
+    pub fetches: usize,
+    pub successes: usize,
+}
+
+impl Reliability {
+    /// Share of recent downloads that succeeded (0.0 - 1.0)
+    pub fn success_rate(&self) -> f64 {
+        self.successes as f64 / self.fetches as f64
+    }
+}
+
+/// One point in a source's domain-count history
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct DomainCountSample {
+    pub domain_count: i64,
+    pub recorded_at: BsonDateTime,
+}
+
+/// What the last extraction of a source found, for comparing the next run against
+///
+/// Unlike `stats.domain_count`, which a download overwrites with an estimate,
+/// this only changes when a build extracts the source.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct ExtractionSummary {
+    pub domain_count: i64,
+    pub format_breakdown: FormatBreakdown,
+}
+
+/// Default number of domain-count samples kept per source
+pub const DEFAULT_DOMAIN_HISTORY_SIZE: usize = 30;
+
+/// Failed downloads kept per source in `recent_errors`
+const ERROR_HISTORY_SIZE: i64 = 10;
+
+/// Download outcomes kept per source in `stats.fetch_history`
+const FETCH_HISTORY_SIZE: i64 = 20;
+
+/// One failed download of a source
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct CacheError {
+    pub error: String,
+    pub at: BsonDateTime,
 }
 
 /// Cache document in MongoDB (metadata only, content stored in GridFS)
@@ -37,14 +115,298 @@ pub struct CacheEntry {
     pub content_hash: Option<String>,
     #[serde(default)]
     pub stats: CacheStats,
+    /// Recent domain counts, oldest first (bounded ring buffer)
+    #[serde(default)]
+    pub domain_history: Vec<DomainCountSample>,
+    /// Error from the most recent failed download (cleared on success)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_error: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_error_at: Option<BsonDateTime>,
+    /// Failed downloads since the last success
+    #[serde(default)]
+    pub consecutive_failures: i64,
+    /// Recent failures, oldest first (kept across successes)
+    #[serde(default)]
+    pub recent_errors: Vec<CacheError>,
+    /// End of the freshness window the feed declared (`! Expires:`), if any
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<BsonDateTime>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_extraction: Option<ExtractionSummary>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub updated_at: Option<BsonDateTime>,
 }
 
+impl CacheEntry {
+    /// Whether the content may still be served as a cache hit
+    ///
+    /// Sources without an `Expires` directive stay fresh until the TTL cleanup.
+    /// A per-source `ttl_override` counts from the last download and can only
+    /// shorten a window the feed declared.
+    pub fn is_fresh(&self, now: BsonDateTime, ttl_override: Option<chrono::Duration>) -> bool {
+        let override_end = ttl_override.map(|ttl| {
+            self.stats
+                .last_download_at
+                .map_or(i64::MIN, |at| at.timestamp_millis().saturating_add(ttl.num_milliseconds()))
+        });
+        let expires_end = self.expires_at.map(|expires_at| expires_at.timestamp_millis());
+        let end = match (expires_end, override_end) {
+            (Some(expires_end), Some(override_end)) => Some(expires_end.min(override_end)),
+            (expires_end, override_end) => expires_end.or(override_end),
+        };
+        end.is_none_or(|end| now.timestamp_millis() < end)
+    }
+}
+
+/// Build the update that records a failed download
+///
+/// Upserts, so sources that have never downloaded successfully still get a
+/// failure history; `updated_at` is only set on insert so a failing source
+/// doesn't keep its stale content alive past the TTL.
+fn failure_update(error: &str, at: BsonDateTime) -> bson::Document {
+    doc! {
+        "$set": {
+            "last_error": error,
+            "last_error_at": at,
+        },
+        "$inc": { "consecutive_failures": 1_i64 },
+        "$push": {
+            "recent_errors": {
+                "$each": [{ "error": error, "at": at }],
+                "$slice": -ERROR_HISTORY_SIZE,
+            },
+            "stats.fetch_history": fetch_outcome_push(false, at),
+        },
+        "$setOnInsert": {
+            "created_at": at,
+            "updated_at": at,
+        }
+    }
+}
+
+/// `$push` of one download outcome, capped at `FETCH_HISTORY_SIZE`
+fn fetch_outcome_push(success: bool, at: BsonDateTime) -> bson::Document {
+    doc! {
+        "$each": [{ "success": success, "at": at }],
+        "$slice": -FETCH_HISTORY_SIZE,
+    }
+}
+
+/// SHA-256 of cached content, as stored in `content_hash`
+fn content_hash(content: &[u8]) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(content);
+    format!("{:x}", hasher.finalize())
+}
+
+/// Whether content read back still matches the hash stored with it
+///
+/// Entries stored before hashes were kept have nothing to check against.
+fn content_intact(expected_hash: Option<&str>, content: &[u8]) -> bool {
+    expected_hash.is_none_or(|expected| expected == content_hash(content))
+}
+
+/// Build the update that records a corrupted read of an entry
+fn corruption_update(at: BsonDateTime) -> bson::Document {
+    doc! {
+        "$set": { "stats.last_corrupt_at": at },
+        "$inc": { "stats.corrupt_reads": 1_i64 },
+    }
+}
+
+/// Add the failure reset to a successful download's update
+fn clear_failures(update: &mut bson::Document) {
+    if let Ok(set) = update.get_document_mut("$set") {
+        set.insert("consecutive_failures", 0_i64);
+    }
+    update.insert("$unset", doc! { "last_error": "", "last_error_at": "" });
+}
+
+/// Add the feed's freshness window to a successful download's update
+///
+/// Must run after `clear_failures`, which creates the `$unset` document.
+fn set_expiry(update: &mut bson::Document, now: BsonDateTime, expires_after: Option<chrono::Duration>) {
+    match expires_after {
+        Some(after) => {
+            let expires_at = BsonDateTime::from_millis(now.timestamp_millis() + after.num_milliseconds());
+            if let Ok(set) = update.get_document_mut("$set") {
+                set.insert("expires_at", expires_at);
+            }
+        }
+        None => {
+            if let Ok(unset) = update.get_document_mut("$unset") {
+                unset.insert("expires_at", "");
+            }
+        }
+    }
+}
+
+/// Build the update that records an extraction and appends a sample, keeping only the newest `history_size`
+fn domain_count_update(sample: &DomainCountSample, format_breakdown: &FormatBreakdown, history_size: usize) -> bson::Document {
+    let last_extraction = ExtractionSummary {
+        domain_count: sample.domain_count,
+        format_breakdown: format_breakdown.clone(),
+    };
+    let mut update = doc! {
+        "$set": {
+            "stats.domain_count": sample.domain_count,
+            "last_extraction": bson::to_bson(&last_extraction).unwrap_or(Bson::Null),
+            "updated_at": sample.recorded_at,
+        }
+    };
+
+    if history_size > 0 {
+        update.insert(
+            "$push",
+            doc! {
+                "domain_history": {
+                    "$each": [{
+                        "domain_count": sample.domain_count,
+                        "recorded_at": sample.recorded_at,
+                    }],
+                    // Negative slice keeps the last N, dropping the oldest
+                    "$slice": -(history_size as i64),
+                }
+            },
+        );
+    }
+
+    update
+}
+
+/// Build the metadata update recording a successful download
+#[allow(clippy::too_many_arguments)]
+fn store_update(
+    url: &str,
+    gridfs_id: &Bson,
+    content: &[u8],
+    etag: Option<&str>,
+    last_modified: Option<&str>,
+    domain_count: i64,
+    now: BsonDateTime,
+    expires_after: Option<chrono::Duration>,
+) -> bson::Document {
+    let content_hash = content_hash(content);
+
+    let mut update = doc! {
+        "$set": {
+            "url": url,
+            "gridfs_id": gridfs_id.clone(),
+            "etag": etag,
+            "last_modified": last_modified,
+            "content_hash": content_hash,
+            "stats.size_bytes": content.len() as i64,
+            "stats.domain_count": domain_count,
+            "stats.last_download_at": now,
+            "updated_at": now,
+        },
+        "$inc": {
+            "stats.download_count": 1_i64,
+        },
+        "$push": {
+            "stats.fetch_history": fetch_outcome_push(true, now),
+        },
+        "$setOnInsert": {
+            "created_at": now,
+        }
+    };
+    clear_failures(&mut update);
+    set_expiry(&mut update, now, expires_after);
+    update
+}
+
+/// A download's metadata upsert waiting for the next batch flush
+#[derive(Debug, Clone)]
+struct PendingStore {
+    url_hash: String,
+    update: bson::Document,
+    /// The GridFS file this download uploaded
+    gridfs_id: Bson,
+    /// The file it replaces, deleted only once the new metadata is written
+    replaced_gridfs_id: Option<ObjectId>,
+}
+
+/// One `update` command upserting every pending entry
+///
+/// A single round trip on any server version (the driver's `bulk_write`
+/// needs MongoDB 8). Unordered, so one bad entry doesn't stop the rest.
+fn batch_update_command(collection: &str, batch: &[PendingStore]) -> bson::Document {
+    let updates: Vec<Bson> = batch
+        .iter()
+        .map(|store| {
+            Bson::Document(doc! {
+                "q": { "url_hash": &store.url_hash },
+                "u": store.update.clone(),
+                "upsert": true,
+            })
+        })
+        .collect();
+    doc! { "update": collection, "updates": updates, "ordered": false }
+}
+
+/// Keep only the newest `history_size` samples (history is stored oldest first)
+fn cap_history(mut history: Vec<DomainCountSample>, history_size: usize) -> Vec<DomainCountSample> {
+    let excess = history.len().saturating_sub(history_size);
+    history.drain(..excess);
+    history
+}
+
+/// Hours after which an access counts half as much towards an entry's score
+const ACCESS_HALF_LIFE_HOURS: f64 = 24.0;
+
+/// How much an entry is worth keeping: access frequency decayed by idle time
+///
+/// Combines LFU and LRU: every access counts, but its weight halves each
+/// `ACCESS_HALF_LIFE_HOURS` since the entry was last used.
+fn retention_score(entry: &CacheEntry, now_ms: i64) -> f64 {
+    let last_used = entry
+        .stats
+        .last_accessed_at
+        .or(entry.stats.last_download_at)
+        .or(entry.updated_at)
+        .map(|t| t.timestamp_millis())
+        .unwrap_or(0);
+    let idle_hours = (now_ms - last_used).max(0) as f64 / 3_600_000.0;
+    let uses = (entry.stats.access_count + entry.stats.download_count).max(1) as f64;
+
+    uses * 0.5_f64.powf(idle_hours / ACCESS_HALF_LIFE_HOURS)
+}
+
+/// Pick the lowest-scoring entries to drop until the cache fits in `max_bytes`
+fn eviction_victims(entries: &[CacheEntry], max_bytes: u64, now_ms: i64) -> Vec<&CacheEntry> {
+    let mut total: u64 = entries.iter().map(|e| e.stats.size_bytes.max(0) as u64).sum();
+    if total <= max_bytes {
+        return Vec::new();
+    }
+
+    let mut ranked: Vec<(f64, &CacheEntry)> =
+        entries.iter().map(|e| (retention_score(e, now_ms), e)).collect();
+    ranked.sort_by(|a, b| a.0.total_cmp(&b.0));
+
+    let mut victims = Vec::new();
+    for (_, entry) in ranked {
+        if total <= max_bytes {
+            break;
+        }
+        total = total.saturating_sub(entry.stats.size_bytes.max(0) as u64);
+        victims.push(entry);
+    }
+    victims
+}
+
 /// Repository for cache operations in MongoDB using GridFS for content storage
 pub struct CacheRepository {
     db: Database,
     collection: Collection<CacheEntry>,
+    /// Domain-count samples kept per source (0 = history disabled)
+    history_size: usize,
+    /// Metadata upserts buffered before a flush (0 = write each store at once)
+    batch_size: usize,
+    /// Stores waiting for the next flush
+    pending: Mutex<Vec<PendingStore>>,
+    /// Check content against its stored hash on every read
+    verify_integrity: bool,
 }
 
 impl CacheRepository {
@@ -53,9 +415,31 @@ impl CacheRepository {
         Self {
             db: db.clone(),
             collection: db.collection("cache"),
+            history_size: DEFAULT_DOMAIN_HISTORY_SIZE,
+            batch_size: 0,
+            pending: Mutex::new(Vec::new()),
+            verify_integrity: false,
         }
     }
 
+    /// Buffer store metadata and write it `batch_size` entries at a time
+    pub fn with_batch_size(mut self, batch_size: usize) -> Self {
+        self.batch_size = batch_size;
+        self
+    }
+
+    /// Treat content that no longer matches its `content_hash` as a cache miss
+    pub fn with_integrity_check(mut self, verify: bool) -> Self {
+        self.verify_integrity = verify;
+        self
+    }
+
+    /// Set how many domain-count samples are kept per source
+    pub fn with_history_size(mut self, history_size: usize) -> Self {
+        self.history_size = history_size;
+        self
+    }
+
     /// Get GridFS bucket for cache files
     fn get_bucket(&self) -> GridFsBucket {
         self.db.gridfs_bucket(
@@ -65,19 +449,52 @@ impl CacheRepository {
         )
     }
 
-    /// Get cached content from GridFS
+    /// Get cached content from GridFS, however old
     pub async fn get_content(&self, url_hash: &str) -> Result<Option<Vec<u8>>> {
         let filter = doc! { "url_hash": url_hash };
 
         let entry = self.collection.find_one(filter).await?;
+        self.read_content(entry).await
+    }
+
+    /// Get cached content only while it's within the feed's freshness window
+    /// (tightened by the source's `ttl_override`, if any)
+    pub async fn get_fresh_content(&self, url_hash: &str, ttl_override: Option<chrono::Duration>) -> Result<Option<Vec<u8>>> {
+        let filter = doc! { "url_hash": url_hash };
+
+        let now = BsonDateTime::from_millis(Utc::now().timestamp_millis());
+        let entry = self.collection.find_one(filter).await?;
+        match entry {
+            Some(entry) if !entry.is_fresh(now, ttl_override) => {
+                tracing::debug!("Cache entry {} expired (feed Expires directive or source ttl)", url_hash);
+                Ok(None)
+            }
+            entry => self.read_content(entry).await,
+        }
+    }
 
+    /// Read an entry's content from GridFS
+    async fn read_content(&self, entry: Option<CacheEntry>) -> Result<Option<Vec<u8>>> {
         if let Some(entry) = entry {
+            let url_hash = entry.url_hash.as_str();
             if let Some(gridfs_id) = entry.gridfs_id {
                 let bucket = self.get_bucket();
                 match bucket.open_download_stream(Bson::ObjectId(gridfs_id)).await {
                     Ok(mut stream) => {
                         let mut content = Vec::new();
                         stream.read_to_end(&mut content).await?;
+                        if self.verify_integrity && !content_intact(entry.content_hash.as_deref(), &content) {
+                            tracing::warn!(
+                                "Cached content for {} doesn't match its content hash, re-downloading",
+                                url_hash
+                            );
+                            let now = BsonDateTime::from_millis(Utc::now().timestamp_millis());
+                            let filter = doc! { "url_hash": url_hash };
+                            if let Err(e) = self.collection.update_one(filter, corruption_update(now)).await {
+                                tracing::warn!("Failed to record cache corruption for {}: {}", url_hash, e);
+                            }
+                            return Ok(None);
+                        }
                         // Update access stats
                         self.touch(url_hash).await?;
                         return Ok(Some(content));
@@ -93,7 +510,49 @@ impl CacheRepository {
         Ok(None)
     }
 
+    /// Every entry with stored content, metadata only (for export)
+    pub async fn entries_with_content(&self) -> Result<Vec<CacheEntry>> {
+        use futures::TryStreamExt;
+
+        let filter = doc! { "gridfs_id": { "$exists": true, "$ne": null } };
+        Ok(self.collection.find(filter).sort(doc! { "url_hash": 1 }).await?.try_collect().await?)
+    }
+
+    /// Read a GridFS blob in full, without counting it as a cache access
+    pub async fn read_blob(&self, gridfs_id: ObjectId) -> Result<Vec<u8>> {
+        let mut stream = self.get_bucket().open_download_stream(Bson::ObjectId(gridfs_id)).await?;
+        let mut content = Vec::new();
+        stream.read_to_end(&mut content).await?;
+        Ok(content)
+    }
+
+    /// Add an entry exported from another deployment, with its content
+    ///
+    /// The content is uploaded as a new GridFS file. Returns false (and
+    /// changes nothing) when the source is already cached here.
+    pub async fn import_entry(&self, mut entry: CacheEntry, content: &[u8]) -> Result<bool> {
+        use futures::io::AsyncWriteExt;
+
+        if self.collection.find_one(doc! { "url_hash": &entry.url_hash }).await?.is_some() {
+            return Ok(false);
+        }
+
+        let mut upload_stream = self.get_bucket().open_upload_stream(&entry.url_hash).await?;
+        upload_stream.write_all(content).await?;
+        upload_stream.close().await?;
+        entry.gridfs_id = upload_stream.id().as_object_id();
+
+        let mut document = bson::to_document(&entry)?;
+        document.insert("created_at", BsonDateTime::from_millis(Utc::now().timestamp_millis()));
+        self.collection.clone_with_type::<bson::Document>().insert_one(document).await?;
+        Ok(true)
+    }
+
     /// Store content in GridFS cache
+    ///
+    /// With a batch size, the content is uploaded now but the metadata waits
+    /// for `flush`; until then lookups still find the previous entry.
+    #[allow(clippy::too_many_arguments)]
     pub async fn store(
         &self,
         url_hash: &str,
@@ -102,22 +561,30 @@ impl CacheRepository {
         etag: Option<&str>,
         last_modified: Option<&str>,
         domain_count: i64,
+        expires_after: Option<chrono::Duration>,
     ) -> Result<()> {
         use futures::io::AsyncWriteExt;
 
         let now = BsonDateTime::from_millis(Utc::now().timestamp_millis());
         let bucket = self.get_bucket();
+        let batched = self.batch_size > 0;
 
-        // Calculate content hash
-        let mut hasher = Sha256::new();
-        hasher.update(content);
-        let content_hash = format!("{:x}", hasher.finalize());
+        // A source stored again before its first store was flushed must see that
+        // store's file as the one it replaces
+        if batched && self.pending.lock().unwrap().iter().any(|p| p.url_hash == url_hash) {
+            self.flush().await?;
+        }
 
-        // Delete old GridFS file if exists
+        // Delete old GridFS file if exists (batched: once the new metadata is written)
         let filter = doc! { "url_hash": url_hash };
+        let mut replaced_gridfs_id = None;
         if let Ok(Some(existing)) = self.collection.find_one(filter.clone()).await {
             if let Some(old_gridfs_id) = existing.gridfs_id {
-                let _ = bucket.delete(Bson::ObjectId(old_gridfs_id)).await;
+                if batched {
+                    replaced_gridfs_id = Some(old_gridfs_id);
+                } else {
+                    let _ = bucket.delete(Bson::ObjectId(old_gridfs_id)).await;
+                }
             }
         }
 
@@ -125,37 +592,101 @@ impl CacheRepository {
         let mut upload_stream = bucket.open_upload_stream(url_hash).await?;
         upload_stream.write_all(content).await?;
         upload_stream.close().await?;
-        let gridfs_id = upload_stream.id();
+        let gridfs_id = upload_stream.id().clone();
 
         // Update metadata document
-        let update = doc! {
-            "$set": {
-                "url": url,
-                "gridfs_id": gridfs_id,
-                "etag": etag,
-                "last_modified": last_modified,
-                "content_hash": content_hash,
-                "stats.size_bytes": content.len() as i64,
-                "stats.domain_count": domain_count,
-                "stats.last_download_at": now,
-                "updated_at": now,
-            },
-            "$inc": {
-                "stats.download_count": 1_i64,
-            },
-            "$setOnInsert": {
-                "created_at": now,
+        let update = store_update(url, &gridfs_id, content, etag, last_modified, domain_count, now, expires_after);
+
+        if !batched {
+            self.collection
+                .update_one(filter, update)
+                .upsert(true)
+                .await?;
+            return Ok(());
+        }
+
+        let full = {
+            let mut pending = self.pending.lock().unwrap();
+            pending.push(PendingStore {
+                url_hash: url_hash.to_string(),
+                update,
+                gridfs_id,
+                replaced_gridfs_id,
+            });
+            pending.len() >= self.batch_size
+        };
+        if full {
+            self.flush().await?;
+        }
+        Ok(())
+    }
+
+    /// Write all buffered store metadata in one command; returns how many entries it covered
+    ///
+    /// Replaced GridFS files are deleted once their new metadata is in; a
+    /// failed entry's new upload is deleted instead, leaving the old entry as it was.
+    pub async fn flush(&self) -> Result<usize> {
+        let batch = std::mem::take(&mut *self.pending.lock().unwrap());
+        if batch.is_empty() {
+            return Ok(0);
+        }
+
+        let bucket = self.get_bucket();
+        let result = match self.db.run_command(batch_update_command(self.collection.name(), &batch)).await {
+            Ok(result) => result,
+            Err(e) => {
+                for store in &batch {
+                    let _ = bucket.delete(store.gridfs_id.clone()).await;
+                }
+                return Err(e.into());
             }
         };
 
+        let failed: HashSet<i64> = result
+            .get_array("writeErrors")
+            .map(|errors| {
+                errors
+                    .iter()
+                    .filter_map(|e| e.as_document())
+                    .filter_map(|e| e.get_i32("index").map(i64::from).or_else(|_| e.get_i64("index")).ok())
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        for (idx, store) in batch.iter().enumerate() {
+            if failed.contains(&(idx as i64)) {
+                let _ = bucket.delete(store.gridfs_id.clone()).await;
+            } else if let Some(old_gridfs_id) = store.replaced_gridfs_id {
+                let _ = bucket.delete(Bson::ObjectId(old_gridfs_id)).await;
+            }
+        }
+
+        if !failed.is_empty() {
+            anyhow::bail!("{} of {} batched cache writes failed", failed.len(), batch.len());
+        }
+        tracing::debug!("Flushed {} batched cache writes", batch.len());
+        Ok(batch.len())
+    }
+
+    /// Record a failed download of a source
+    pub async fn record_failure(&self, url_hash: &str, error: &str) -> Result<()> {
+        let now = BsonDateTime::from_millis(Utc::now().timestamp_millis());
+        let filter = doc! { "url_hash": url_hash };
+
         self.collection
-            .update_one(filter, update)
+            .update_one(filter, failure_update(error, now))
             .upsert(true)
             .await?;
-
         Ok(())
     }
 
+    /// Success rate of a source's recent downloads (None if never fetched)
+    pub async fn get_reliability(&self, url_hash: &str) -> Result<Option<Reliability>> {
+        let filter = doc! { "url_hash": url_hash };
+        let entry = self.collection.find_one(filter).await?;
+        Ok(entry.and_then(|e| e.stats.reliability()))
+    }
+
     /// Update access time (touch)
     async fn touch(&self, url_hash: &str) -> Result<()> {
         let now = BsonDateTime::from_millis(Utc::now().timestamp_millis());
@@ -169,21 +700,47 @@ impl CacheRepository {
         Ok(())
     }
 
-    /// Update domain count after extraction
-    pub async fn update_domain_count(&self, url_hash: &str, domain_count: i64) -> Result<()> {
-        let now = BsonDateTime::from_millis(Utc::now().timestamp_millis());
-        let filter = doc! { "url_hash": url_hash };
-        let update = doc! {
-            "$set": {
-                "stats.domain_count": domain_count,
-                "updated_at": now,
-            }
+    /// Update domain count after extraction, appending it to the source's history
+    pub async fn update_domain_count(&self, url_hash: &str, domain_count: i64, format_breakdown: &FormatBreakdown) -> Result<()> {
+        let sample = DomainCountSample {
+            domain_count,
+            recorded_at: BsonDateTime::from_millis(Utc::now().timestamp_millis()),
         };
+        let filter = doc! { "url_hash": url_hash };
+        let update = domain_count_update(&sample, format_breakdown, self.history_size);
 
         self.collection.update_one(filter, update).await?;
         Ok(())
     }
 
+    /// Get the last recorded domain count for a source
+    pub async fn get_domain_count(&self, url_hash: &str) -> Result<Option<i64>> {
+        let filter = doc! { "url_hash": url_hash };
+        let entry = self.collection.find_one(filter).await?;
+        Ok(entry.map(|e| e.stats.domain_count))
+    }
+
+    /// Get what the source's previous extraction found
+    pub async fn get_last_extraction(&self, url_hash: &str) -> Result<Option<ExtractionSummary>> {
+        let filter = doc! { "url_hash": url_hash };
+        let entry = self.collection.find_one(filter).await?;
+        Ok(entry.and_then(|e| e.last_extraction))
+    }
+
+    /// Get a source's domain-count history, oldest first
+    pub async fn get_domain_history(&self, url_hash: &str) -> Result<Vec<DomainCountSample>> {
+        let filter = doc! { "url_hash": url_hash };
+        let history = self
+            .collection
+            .find_one(filter)
+            .await?
+            .map(|entry| entry.domain_history)
+            .unwrap_or_default();
+
+        // Entries written under a larger cap are trimmed on read
+        Ok(cap_history(history, self.history_size))
+    }
+
     /// Cleanup stale cache entries and their GridFS files
     pub async fn cleanup_stale(&self, days: i64) -> Result<u64> {
         use chrono::Duration;
@@ -216,22 +773,288 @@ impl CacheRepository {
         Ok(result.deleted_count)
     }
 
+    /// Evict the least valuable entries until the cache fits in `max_bytes`
+    ///
+    /// Entries are ranked by `retention_score`, so rarely used, long idle
+    /// sources go first while hot ones are kept. Returns the number evicted.
+    pub async fn cleanup_by_size(&self, max_bytes: u64) -> Result<u64> {
+        use futures::TryStreamExt;
+
+        let entries: Vec<CacheEntry> = self.collection.find(doc! {}).await?.try_collect().await?;
+        let now_ms = Utc::now().timestamp_millis();
+        let victims = eviction_victims(&entries, max_bytes, now_ms);
+        if victims.is_empty() {
+            return Ok(0);
+        }
+
+        let bucket = self.get_bucket();
+        for gridfs_id in victims.iter().filter_map(|e| e.gridfs_id) {
+            let _ = bucket.delete(Bson::ObjectId(gridfs_id)).await;
+        }
+
+        let hashes: Vec<&str> = victims.iter().map(|e| e.url_hash.as_str()).collect();
+        let result = self
+            .collection
+            .delete_many(doc! { "url_hash": { "$in": hashes } })
+            .await?;
+
+        Ok(result.deleted_count)
+    }
+
     /// Check if a valid cache entry exists (for "no changes" detection)
-    /// Returns true if cache exists and is not older than 7 days
-    pub async fn has_valid_cache(&self, url_hash: &str) -> Result<bool> {
+    /// Returns true if cache exists, is not older than 7 days (or the source's
+    /// `ttl_override` since its last download) and hasn't expired
+    pub async fn has_valid_cache(&self, url_hash: &str, ttl_override: Option<chrono::Duration>) -> Result<bool> {
         use chrono::Duration;
 
-        let cutoff = Utc::now() - Duration::days(7);
-        let cutoff_bson = BsonDateTime::from_millis(cutoff.timestamp_millis());
+        let now = Utc::now();
+        let now_bson = BsonDateTime::from_millis(now.timestamp_millis());
 
-        // Check if cache entry exists with gridfs_id and is recent
-        let filter = doc! {
+        // Check if cache entry exists with gridfs_id, is recent, and is within its Expires window
+        let mut filter = doc! {
             "url_hash": url_hash,
             "gridfs_id": { "$exists": true, "$ne": null },
-            "updated_at": { "$gte": cutoff_bson }
+            "$or": [
+                { "expires_at": { "$exists": false } },
+                { "expires_at": { "$gt": now_bson } }
+            ]
         };
+        match ttl_override {
+            Some(ttl) => {
+                let cutoff = BsonDateTime::from_millis((now - ttl).timestamp_millis());
+                filter.insert("stats.last_download_at", doc! { "$gte": cutoff });
+            }
+            None => {
+                let cutoff = BsonDateTime::from_millis((now - Duration::days(7)).timestamp_millis());
+                filter.insert("updated_at", doc! { "$gte": cutoff });
+            }
+        }
 
         let count = self.collection.count_documents(filter).await?;
         Ok(count > 0)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample(domain_count: i64) -> DomainCountSample {
+        DomainCountSample {
+            domain_count,
+            recorded_at: BsonDateTime::from_millis(domain_count * 1000),
+        }
+    }
+
+    #[test]
+    fn test_history_past_cap_drops_oldest() {
+        let history: Vec<DomainCountSample> = (1..=5).map(sample).collect();
+        let capped = cap_history(history, 3);
+        assert_eq!(capped, vec![sample(3), sample(4), sample(5)]);
+
+        // Appends are bounded in the same update that records the count
+        let update = domain_count_update(&sample(6), &FormatBreakdown::default(), 3);
+        let push = update.get_document("$push").unwrap().get_document("domain_history").unwrap();
+        assert_eq!(push.get_i64("$slice").unwrap(), -3);
+        assert_eq!(push.get_array("$each").unwrap().len(), 1);
+
+        // A size of zero disables history entirely
+        assert!(domain_count_update(&sample(6), &FormatBreakdown::default(), 0).get("$push").is_none());
+        assert!(cap_history((1..=2).map(sample).collect(), 0).is_empty());
+    }
+
+    fn entry(url_hash: &str, size_bytes: i64, access_count: i64, idle_hours: i64, now_ms: i64) -> CacheEntry {
+        CacheEntry {
+            url_hash: url_hash.to_string(),
+            url: None,
+            gridfs_id: None,
+            etag: None,
+            last_modified: None,
+            content_hash: None,
+            stats: CacheStats {
+                size_bytes,
+                access_count,
+                last_accessed_at: Some(BsonDateTime::from_millis(now_ms - idle_hours * 3_600_000)),
+                ..Default::default()
+            },
+            domain_history: Vec::new(),
+            last_error: None,
+            last_error_at: None,
+            consecutive_failures: 0,
+            recent_errors: Vec::new(),
+            expires_at: None,
+            last_extraction: None,
+            updated_at: None,
+        }
+    }
+
+    #[test]
+    fn test_size_eviction_keeps_hot_entries() {
+        let now_ms = 1_000 * 3_600_000;
+        let entries = vec![
+            entry("hot", 400, 50, 1, now_ms),
+            entry("cold", 400, 50, 24 * 14, now_ms),
+            entry("rare", 400, 1, 2, now_ms),
+            entry("warm", 400, 10, 12, now_ms),
+        ];
+
+        // Under budget: nothing goes
+        assert!(eviction_victims(&entries, 1600, now_ms).is_empty());
+
+        // Over budget: lowest scores go first until the total fits
+        let victims: Vec<&str> = eviction_victims(&entries, 1000, now_ms)
+            .iter()
+            .map(|e| e.url_hash.as_str())
+            .collect();
+        assert_eq!(victims, vec!["cold", "rare"]);
+
+        let kept: i64 = entries
+            .iter()
+            .filter(|e| !victims.contains(&e.url_hash.as_str()))
+            .map(|e| e.stats.size_bytes)
+            .sum();
+        assert!(kept <= 1000);
+    }
+
+    #[test]
+    fn test_failures_increment_and_success_resets() {
+        let at = BsonDateTime::from_millis(1_000);
+        let update = failure_update("HTTP 503", at);
+        assert_eq!(update.get_document("$inc").unwrap().get_i64("consecutive_failures").unwrap(), 1);
+        assert_eq!(update.get_document("$set").unwrap().get_str("last_error").unwrap(), "HTTP 503");
+        let push = update.get_document("$push").unwrap().get_document("recent_errors").unwrap();
+        assert_eq!(push.get_i64("$slice").unwrap(), -ERROR_HISTORY_SIZE);
+
+        // A failure must not refresh the TTL of content already cached
+        assert!(update.get_document("$set").unwrap().get("updated_at").is_none());
+
+        let mut success = doc! { "$set": { "stats.size_bytes": 10_i64 } };
+        clear_failures(&mut success);
+        assert_eq!(success.get_document("$set").unwrap().get_i64("consecutive_failures").unwrap(), 0);
+        let unset = success.get_document("$unset").unwrap();
+        assert!(unset.contains_key("last_error") && unset.contains_key("last_error_at"));
+        // The history itself survives a success
+        assert!(!unset.contains_key("recent_errors"));
+
+        // Older entries without the fields read as no failures
+        let entry: CacheEntry = bson::from_document(doc! { "url_hash": "abc" }).unwrap();
+        assert_eq!(entry.consecutive_failures, 0);
+        assert!(entry.last_error.is_none() && entry.recent_errors.is_empty());
+    }
+
+    #[test]
+    fn test_store_sets_or_clears_expiry() {
+        let now = BsonDateTime::from_millis(1_000);
+
+        let mut update = doc! { "$set": {} };
+        clear_failures(&mut update);
+        set_expiry(&mut update, now, Some(chrono::Duration::hours(12)));
+        let expires_at = update.get_document("$set").unwrap().get_datetime("expires_at").unwrap();
+        assert_eq!(expires_at.timestamp_millis(), 1_000 + 12 * 3_600_000);
+
+        // A feed that dropped its directive goes back to the default window
+        let mut update = doc! { "$set": {} };
+        clear_failures(&mut update);
+        set_expiry(&mut update, now, None);
+        assert!(update.get_document("$unset").unwrap().contains_key("expires_at"));
+        assert!(update.get_document("$unset").unwrap().contains_key("last_error"));
+    }
+
+    #[tokio::test]
+    async fn test_batched_stores_send_the_individual_updates() {
+        let now = BsonDateTime::from_millis(1_700_000_000_000);
+        let stores: Vec<(&str, &[u8], Option<chrono::Duration>)> = vec![
+            ("hash-a", b"ads.example.com\n", None),
+            ("hash-b", b"! Expires: 4 days\ntracker.example.net\n", Some(chrono::Duration::days(4))),
+        ];
+
+        let mut individual = Vec::new();
+        let mut batch = Vec::new();
+        for (url_hash, content, expires_after) in &stores {
+            let gridfs_id = Bson::ObjectId(ObjectId::new());
+            let update = store_update("https://example.com/list.txt", &gridfs_id, content, Some("\"v1\""), None, 1, now, *expires_after);
+            individual.push((doc! { "url_hash": *url_hash }, update.clone()));
+            batch.push(PendingStore {
+                url_hash: url_hash.to_string(),
+                update,
+                gridfs_id,
+                replaced_gridfs_id: None,
+            });
+        }
+
+        // Same filter and update per entry, upserted like update_one(...).upsert(true)
+        let command = batch_update_command("cache", &batch);
+        assert_eq!(command.get_str("update").unwrap(), "cache");
+        assert!(!command.get_bool("ordered").unwrap());
+        let statements: Vec<&bson::Document> =
+            command.get_array("updates").unwrap().iter().map(|s| s.as_document().unwrap()).collect();
+        assert_eq!(statements.len(), individual.len());
+        for (statement, (filter, update)) in statements.iter().zip(&individual) {
+            assert_eq!(statement.get_document("q").unwrap(), filter);
+            assert_eq!(statement.get_document("u").unwrap(), update);
+            assert!(statement.get_bool("upsert").unwrap());
+        }
+        let set = statements[1].get_document("u").unwrap().get_document("$set").unwrap();
+        assert!(set.contains_key("expires_at"));
+
+        // Nothing buffered: flushing never reaches MongoDB
+        let client = mongodb::Client::with_uri_str("mongodb://127.0.0.1:1/?serverSelectionTimeoutMS=200")
+            .await
+            .unwrap();
+        let repo = CacheRepository::new(&client.database("batch_test")).with_batch_size(2);
+        assert_eq!(repo.flush().await.unwrap(), 0);
+    }
+
+    #[test]
+    fn test_alternating_fetches_give_half_success_rate() {
+        // Each download pushes its outcome, capped to the most recent ones
+        let at = BsonDateTime::from_millis(1_000);
+        let failure = failure_update("HTTP 503", at);
+        let success = store_update("https://example.com/list.txt", &Bson::Null, b"a.com\n", None, None, 1, at, None);
+        let pushed = |update: &bson::Document| {
+            let push = update.get_document("$push").unwrap().get_document("stats.fetch_history").unwrap().clone();
+            assert_eq!(push.get_i64("$slice").unwrap(), -FETCH_HISTORY_SIZE);
+            push.get_array("$each").unwrap()[0].as_document().unwrap().get_bool("success").unwrap()
+        };
+        assert!(!pushed(&failure));
+        assert!(pushed(&success));
+
+        let history: Vec<_> = (0..10)
+            .map(|i| FetchOutcome {
+                success: i % 2 == 0,
+                at: BsonDateTime::from_millis(i),
+            })
+            .collect();
+        let stats = CacheStats {
+            fetch_history: history,
+            ..Default::default()
+        };
+        let reliability = stats.reliability().unwrap();
+        assert_eq!((reliability.successes, reliability.fetches), (5, 10));
+        assert_eq!(reliability.success_rate(), 0.5);
+
+        // Entries from before the history existed have no rate yet
+        assert_eq!(CacheStats::default().reliability(), None);
+    }
+
+    #[test]
+    fn test_corrupted_content_detected() {
+        let stored = b"ads.example.com\ntracker.example.net\n";
+        let update = store_update("https://example.com/list.txt", &Bson::Null, stored, None, None, 2, BsonDateTime::from_millis(0), None);
+        let hash = update.get_document("$set").unwrap().get_str("content_hash").unwrap();
+
+        assert!(content_intact(Some(hash), stored));
+        // A flipped byte or a truncated blob no longer matches, so the read is a miss
+        let mut flipped = stored.to_vec();
+        flipped[3] ^= 0x20;
+        assert!(!content_intact(Some(hash), &flipped));
+        assert!(!content_intact(Some(hash), &stored[..10]));
+        // Entries without a stored hash can't be checked and are served as before
+        assert!(content_intact(None, &flipped));
+
+        let at = BsonDateTime::from_millis(5_000);
+        let recorded = corruption_update(at);
+        assert_eq!(recorded.get_document("$inc").unwrap().get_i64("stats.corrupt_reads").unwrap(), 1);
+        assert_eq!(recorded.get_document("$set").unwrap().get_datetime("stats.last_corrupt_at").unwrap(), &at);
+    }
+}
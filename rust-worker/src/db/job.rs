@@ -2,33 +2,62 @@ use anyhow::Result;
 use bson::{doc, oid::ObjectId, DateTime as BsonDateTime};
 use chrono::Utc;
 use mongodb::{
-    options::{FindOneAndUpdateOptions, FindOneOptions, ReturnDocument},
-    Collection, Database,
+    options::{FindOneAndUpdateOptions, FindOneOptions, IndexOptions, ReturnDocument},
+    Collection, Database, IndexModel,
 };
 use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::time::Duration;
 
-use super::progress::{JobProgress, JobResult};
+use super::progress::{JobProgress, JobResult, SkipReason};
+use super::retry::with_retry;
+use super::user_config::UserConfig;
 
-/// Job type enum
+/// Job type enum (wire names must match the API's `Job.TYPE_*` constants)
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
-#[serde(rename_all = "lowercase")]
 pub enum JobType {
+    #[serde(rename = "manual")]
     Manual,
+    #[serde(rename = "scheduled")]
     Scheduled,
+    #[serde(rename = "admin")]
     Admin,
+    /// Operator request to enqueue a rebuild for every enabled user
+    #[serde(rename = "rebuild_all")]
+    RebuildAll,
+    /// Compare `config_override` with the stored config without building
+    #[serde(rename = "preview")]
+    Preview,
 }
 
-/// Job status enum
+/// Job status enum (wire names must match the API's `Job.STATUS_*` constants)
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
-#[serde(rename_all = "lowercase")]
 pub enum JobStatus {
+    #[serde(rename = "queued")]
     Queued,
+    #[serde(rename = "processing")]
     Processing,
+    #[serde(rename = "completed")]
     Completed,
+    #[serde(rename = "failed")]
     Failed,
+    #[serde(rename = "skipped")]
     Skipped,
 }
 
+impl JobStatus {
+    /// Wire string stored in MongoDB (matches the serde representation)
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            JobStatus::Queued => "queued",
+            JobStatus::Processing => "processing",
+            JobStatus::Completed => "completed",
+            JobStatus::Failed => "failed",
+            JobStatus::Skipped => "skipped",
+        }
+    }
+}
+
 /// Job document from MongoDB
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Job {
@@ -60,6 +89,91 @@ pub struct Job {
     pub read: bool,
     #[serde(default)]
     pub force_rebuild: bool,
+    /// Inline config for a one-off test build, used instead of the stored one
+    ///
+    /// Never saved to the user, and the build doesn't touch their published
+    /// lists or config hash.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub config_override: Option<UserConfig>,
+    /// Client-supplied key for one logical build request (double-clicks, retries)
+    ///
+    /// A job whose key matches a recently completed job of the same user is
+    /// skipped instead of built again.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub idempotency_key: Option<String>,
+}
+
+/// Finished job fields read by the retention sweep
+#[derive(Debug, Deserialize)]
+struct FinishedJob {
+    #[serde(rename = "_id")]
+    id: ObjectId,
+    username: String,
+    #[serde(default)]
+    completed_at: Option<BsonDateTime>,
+    created_at: BsonDateTime,
+}
+
+impl FinishedJob {
+    fn finished_at(&self) -> BsonDateTime {
+        self.completed_at.unwrap_or(self.created_at)
+    }
+}
+
+/// Jobs the retention sweep deletes: finished before `cutoff`, beyond each user's newest `min_per_user`
+fn expired_jobs(mut jobs: Vec<FinishedJob>, cutoff: BsonDateTime, min_per_user: usize) -> Vec<ObjectId> {
+    jobs.sort_by_key(|job| std::cmp::Reverse(job.finished_at()));
+
+    let mut kept: HashMap<String, usize> = HashMap::new();
+    jobs.into_iter()
+        .filter(|job| {
+            let rank = kept.entry(job.username.clone()).or_insert(0);
+            *rank += 1;
+            *rank > min_per_user && job.finished_at() < cutoff
+        })
+        .map(|job| job.id)
+        .collect()
+}
+
+/// Job ids per retention `delete_many`, keeping the filter document small
+const CLEANUP_BATCH_SIZE: usize = 1000;
+
+/// Priority for default-list jobs (lower number = higher priority)
+pub const PRIORITY_HIGH: i32 = 1;
+/// Priority for user jobs
+pub const PRIORITY_NORMAL: i32 = 2;
+
+impl Job {
+    /// Build a new queued job document (mirrors the API's job creation)
+    pub fn queued(username: &str, user_id: Option<ObjectId>, job_type: JobType) -> Self {
+        let priority = if username == "__default__" {
+            PRIORITY_HIGH
+        } else {
+            PRIORITY_NORMAL
+        };
+
+        Self {
+            id: ObjectId::new(),
+            job_id: uuid::Uuid::new_v4().to_string(),
+            user_id,
+            username: username.to_string(),
+            job_type,
+            status: JobStatus::Queued,
+            priority,
+            progress: JobProgress::default(),
+            result: None,
+            started_at: None,
+            completed_at: None,
+            created_at: BsonDateTime::from_millis(Utc::now().timestamp_millis()),
+            worker_id: None,
+            claimed_at: None,
+            heartbeat_at: None,
+            read: false,
+            force_rebuild: false,
+            config_override: None,
+            idempotency_key: None,
+        }
+    }
 }
 
 /// Job repository for MongoDB operations
@@ -82,13 +196,13 @@ impl JobRepository {
         let now = BsonDateTime::from_millis(Utc::now().timestamp_millis());
 
         let filter = doc! {
-            "status": "queued",
+            "status": JobStatus::Queued.as_str(),
             "worker_id": null
         };
 
         let update = doc! {
             "$set": {
-                "status": "processing",
+                "status": JobStatus::Processing.as_str(),
                 "worker_id": &self.worker_id,
                 "claimed_at": now,
                 "heartbeat_at": now,
@@ -110,20 +224,103 @@ impl JobRepository {
         Ok(result)
     }
 
-    /// Update job progress
-    pub async fn update_progress(&self, job_id: &ObjectId, progress: &JobProgress) -> Result<()> {
-        let progress_doc = bson::to_document(progress)?;
-
-        self.collection
-            .update_one(
-                doc! { "_id": job_id },
-                doc! { "$set": { "progress": progress_doc } },
+    /// Create the indexes the worker's own queries rely on
+    pub async fn ensure_indexes(&self) -> Result<()> {
+        // Backs find_completed_duplicate; only keyed jobs are indexed
+        let idempotency = IndexModel::builder()
+            .keys(doc! { "username": 1, "idempotency_key": 1, "completed_at": -1 })
+            .options(
+                IndexOptions::builder()
+                    .name("username_idempotency_key".to_string())
+                    .partial_filter_expression(doc! { "idempotency_key": { "$exists": true } })
+                    .build(),
             )
-            .await?;
+            .build();
+
+        self.collection.create_index(idempotency).await?;
+        Ok(())
+    }
+
+    /// Filter for a completed job of the same user sharing `job`'s idempotency key
+    ///
+    /// None when the job has no key.
+    fn duplicate_filter(job: &Job, since: BsonDateTime) -> Option<bson::Document> {
+        let key = job.idempotency_key.as_deref()?;
+        Some(doc! {
+            "username": &job.username,
+            "idempotency_key": key,
+            "status": JobStatus::Completed.as_str(),
+            "completed_at": { "$gte": since },
+            "_id": { "$ne": job.id }
+        })
+    }
+
+    /// Most recent job completed within `window` with the same idempotency key
+    pub async fn find_completed_duplicate(&self, job: &Job, window: Duration) -> Result<Option<Job>> {
+        let since = BsonDateTime::from_millis(Utc::now().timestamp_millis() - window.as_millis() as i64);
+        let Some(filter) = Self::duplicate_filter(job, since) else {
+            return Ok(None);
+        };
 
+        let options = FindOneOptions::builder()
+            .sort(doc! { "completed_at": -1 })
+            .build();
+
+        Ok(self.collection.find_one(filter).with_options(options).await?)
+    }
+
+    /// Insert a new job into the queue
+    pub async fn enqueue(&self, job: &Job) -> Result<()> {
+        self.collection.insert_one(job).await?;
         Ok(())
     }
 
+    /// Queued or processing jobs other than `excluding`
+    fn active_filter(excluding: &ObjectId) -> bson::Document {
+        doc! {
+            "_id": { "$ne": excluding },
+            "status": { "$in": [JobStatus::Queued.as_str(), JobStatus::Processing.as_str()] }
+        }
+    }
+
+    /// Usernames that already have a queued or processing job, besides `excluding`
+    ///
+    /// The calling job is still processing itself, so it passes its own id.
+    pub async fn active_usernames(&self, excluding: &ObjectId) -> Result<HashSet<String>> {
+        let filter = Self::active_filter(excluding);
+
+        let usernames = self.collection.distinct("username", filter).await?;
+        Ok(usernames
+            .into_iter()
+            .filter_map(|u| u.as_str().map(String::from))
+            .collect())
+    }
+
+    /// Check whether a user already has a queued or processing job
+    pub async fn has_active_job(&self, username: &str) -> Result<bool> {
+        let filter = doc! {
+            "username": username,
+            "status": { "$in": [JobStatus::Queued.as_str(), JobStatus::Processing.as_str()] }
+        };
+
+        let count = self.collection.count_documents(filter).await?;
+        Ok(count > 0)
+    }
+
+    /// Update job progress
+    pub async fn update_progress(&self, job_id: &ObjectId, progress: &JobProgress) -> Result<()> {
+        let progress_doc = bson::to_document(progress)?;
+        let update = doc! { "$set": { "progress": progress_doc } };
+
+        with_retry("progress update", || async {
+            self.collection
+                .update_one(doc! { "_id": job_id }, update.clone())
+                .await?;
+            Ok(())
+        })
+        .await
+    }
+
     /// Update heartbeat timestamp
     pub async fn heartbeat(&self, job_id: &str) -> Result<bool> {
         let now = BsonDateTime::from_millis(Utc::now().timestamp_millis());
@@ -134,7 +331,7 @@ impl JobRepository {
                 doc! {
                     "job_id": job_id,
                     "worker_id": &self.worker_id,
-                    "status": "processing"
+                    "status": JobStatus::Processing.as_str()
                 },
                 doc! { "$set": { "heartbeat_at": now } },
             )
@@ -148,20 +345,21 @@ impl JobRepository {
         let now = BsonDateTime::from_millis(Utc::now().timestamp_millis());
         let result_doc = bson::to_document(&result)?;
 
-        self.collection
-            .update_one(
-                doc! { "_id": job_id },
-                doc! {
-                    "$set": {
-                        "status": "completed",
-                        "completed_at": now,
-                        "result": result_doc
-                    }
-                },
-            )
-            .await?;
+        let update = doc! {
+            "$set": {
+                "status": JobStatus::Completed.as_str(),
+                "completed_at": now,
+                "result": result_doc
+            }
+        };
 
-        Ok(())
+        with_retry("job completion", || async {
+            self.collection
+                .update_one(doc! { "_id": job_id }, update.clone())
+                .await?;
+            Ok(())
+        })
+        .await
     }
 
     /// Fail a job
@@ -170,52 +368,79 @@ impl JobRepository {
         let result = JobResult::failure(errors);
         let result_doc = bson::to_document(&result)?;
 
-        self.collection
-            .update_one(
-                doc! { "_id": job_id },
-                doc! {
-                    "$set": {
-                        "status": "failed",
-                        "completed_at": now,
-                        "result": result_doc
-                    }
-                },
-            )
+        let update = doc! {
+            "$set": {
+                "status": JobStatus::Failed.as_str(),
+                "completed_at": now,
+                "result": result_doc
+            }
+        };
+
+        with_retry("job failure", || async {
+            self.collection
+                .update_one(doc! { "_id": job_id }, update.clone())
+                .await?;
+            Ok(())
+        })
+        .await
+    }
+
+    /// Delete finished jobs older than `retention_days`, keeping each user's newest `min_per_user`
+    ///
+    /// Queued and processing jobs are never touched. Returns the number deleted.
+    pub async fn cleanup_old_jobs(&self, retention_days: u64, min_per_user: usize) -> Result<u64> {
+        use futures::TryStreamExt;
+
+        let cutoff = Utc::now() - chrono::Duration::days(retention_days as i64);
+        let cutoff = BsonDateTime::from_millis(cutoff.timestamp_millis());
+        let finished = [
+            JobStatus::Completed.as_str(),
+            JobStatus::Failed.as_str(),
+            JobStatus::Skipped.as_str(),
+        ];
+
+        let jobs: Vec<FinishedJob> = self
+            .collection
+            .clone_with_type::<FinishedJob>()
+            .find(doc! { "status": { "$in": finished.to_vec() } })
+            .projection(doc! { "_id": 1, "username": 1, "completed_at": 1, "created_at": 1 })
+            .await?
+            .try_collect()
             .await?;
 
-        Ok(())
+        let mut deleted = 0;
+        for batch in expired_jobs(jobs, cutoff, min_per_user).chunks(CLEANUP_BATCH_SIZE) {
+            let result = self
+                .collection
+                .delete_many(doc! { "_id": { "$in": batch.to_vec() } })
+                .await?;
+            deleted += result.deleted_count;
+        }
+        Ok(deleted)
     }
 
-    /// Skip a job (no changes detected)
-    pub async fn skip(&self, job_id: &ObjectId, reason: String) -> Result<()> {
+    /// Skip a job (e.g. no changes detected)
+    pub async fn skip(&self, job_id: &ObjectId, reason: SkipReason) -> Result<()> {
         let now = BsonDateTime::from_millis(Utc::now().timestamp_millis());
 
-        // Create a minimal result with skip_reason
-        let result_doc = doc! {
-            "sources_processed": 0_i64,
-            "sources_failed": 0_i64,
-            "total_domains": 0_i64,
-            "unique_domains": 0_i64,
-            "whitelisted_removed": 0_i64,
-            "output_files": [],
-            "errors": [],
-            "skip_reason": &reason,
-        };
+        // Minimal result carrying skip_reason and skip_message
+        let result_doc = bson::to_document(&JobResult::skipped(reason))?;
 
-        self.collection
-            .update_one(
-                doc! { "_id": job_id },
-                doc! {
-                    "$set": {
-                        "status": "skipped",
-                        "completed_at": now,
-                        "result": result_doc
-                    }
-                },
-            )
-            .await?;
+        let update = doc! {
+            "$set": {
+                "status": JobStatus::Skipped.as_str(),
+                "completed_at": now,
+                "result": result_doc
+            }
+        };
 
-        Ok(())
+        with_retry("job skip", || async {
+            self.collection
+                .update_one(doc! { "_id": job_id }, update.clone())
+                .await?;
+            Ok(())
+        })
+        .await
     }
 
     /// Release a job back to the queue (on shutdown)
@@ -226,11 +451,11 @@ impl JobRepository {
                 doc! {
                     "job_id": job_id,
                     "worker_id": &self.worker_id,
-                    "status": "processing"
+                    "status": JobStatus::Processing.as_str()
                 },
                 doc! {
                     "$set": {
-                        "status": "queued",
+                        "status": JobStatus::Queued.as_str(),
                         "worker_id": null,
                         "claimed_at": null,
                         "heartbeat_at": null,
@@ -250,11 +475,11 @@ impl JobRepository {
             .update_many(
                 doc! {
                     "worker_id": &self.worker_id,
-                    "status": "processing"
+                    "status": JobStatus::Processing.as_str()
                 },
                 doc! {
                     "$set": {
-                        "status": "queued",
+                        "status": JobStatus::Queued.as_str(),
                         "worker_id": null,
                         "claimed_at": null,
                         "heartbeat_at": null,
@@ -273,7 +498,7 @@ impl JobRepository {
     pub async fn get_last_completed_result(&self, username: &str) -> Result<Option<JobResult>> {
         let filter = doc! {
             "username": username,
-            "status": "completed",
+            "status": JobStatus::Completed.as_str(),
             "result": { "$exists": true }
         };
 
@@ -292,7 +517,7 @@ impl JobRepository {
     pub async fn get_last_completed_progress(&self, username: &str) -> Result<Option<JobProgress>> {
         let filter = doc! {
             "username": username,
-            "status": "completed",
+            "status": JobStatus::Completed.as_str(),
             "progress": { "$exists": true }
         };
 
@@ -304,3 +529,98 @@ impl JobRepository {
         Ok(job.map(|j| j.progress))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_job_status_wire_strings() {
+        for (status, wire) in [
+            (JobStatus::Queued, "queued"),
+            (JobStatus::Processing, "processing"),
+            (JobStatus::Completed, "completed"),
+            (JobStatus::Failed, "failed"),
+            (JobStatus::Skipped, "skipped"),
+        ] {
+            assert_eq!(status.as_str(), wire);
+            assert_eq!(serde_json::to_value(&status).unwrap(), serde_json::json!(wire));
+            assert_eq!(serde_json::from_value::<JobStatus>(serde_json::json!(wire)).unwrap(), status);
+        }
+    }
+
+    #[test]
+    fn test_job_type_wire_strings() {
+        for (job_type, wire) in [
+            (JobType::Manual, "manual"),
+            (JobType::Scheduled, "scheduled"),
+            (JobType::Admin, "admin"),
+            (JobType::RebuildAll, "rebuild_all"),
+            (JobType::Preview, "preview"),
+        ] {
+            assert_eq!(serde_json::to_value(&job_type).unwrap(), serde_json::json!(wire));
+            assert_eq!(serde_json::from_value::<JobType>(serde_json::json!(wire)).unwrap(), job_type);
+        }
+    }
+
+    #[test]
+    fn test_duplicate_key_matches_completed_job() {
+        let since = BsonDateTime::from_millis(1_000);
+        let mut job = Job::queued("alice", None, JobType::Manual);
+        assert!(JobRepository::duplicate_filter(&job, since).is_none());
+
+        job.idempotency_key = Some("rebuild-42".to_string());
+        let filter = JobRepository::duplicate_filter(&job, since).unwrap();
+        assert_eq!(filter.get_str("username").unwrap(), "alice");
+        assert_eq!(filter.get_str("idempotency_key").unwrap(), "rebuild-42");
+        assert_eq!(filter.get_str("status").unwrap(), "completed");
+        assert_eq!(filter.get_document("completed_at").unwrap().get_datetime("$gte").unwrap(), &since);
+        // The job never matches itself
+        assert_eq!(filter.get_document("_id").unwrap().get_object_id("$ne").unwrap(), job.id);
+
+        // The key is optional on existing job documents
+        job.idempotency_key = None;
+        let doc = bson::to_document(&job).unwrap();
+        assert!(!doc.contains_key("idempotency_key"));
+    }
+
+    #[test]
+    fn test_active_filter_leaves_out_calling_job() {
+        let job = Job::queued("alice", None, JobType::RebuildAll);
+        let filter = JobRepository::active_filter(&job.id);
+        assert_eq!(filter.get_document("_id").unwrap().get_object_id("$ne").unwrap(), job.id);
+        let statuses: Vec<&str> = filter
+            .get_document("status")
+            .unwrap()
+            .get_array("$in")
+            .unwrap()
+            .iter()
+            .filter_map(|s| s.as_str())
+            .collect();
+        assert_eq!(statuses, vec!["queued", "processing"]);
+    }
+
+    #[test]
+    fn test_old_jobs_expire_beyond_per_user_minimum() {
+        let day = 86_400_000;
+        let job = |username: &str, finished_days_ago: i64| FinishedJob {
+            id: ObjectId::new(),
+            username: username.to_string(),
+            completed_at: Some(BsonDateTime::from_millis(100 * day - finished_days_ago * day)),
+            created_at: BsonDateTime::from_millis(0),
+        };
+        let cutoff = BsonDateTime::from_millis(100 * day - 30 * day);
+
+        // alice: one recent and three old jobs; bob: only old jobs
+        let jobs = vec![job("alice", 40), job("alice", 1), job("alice", 50), job("alice", 60), job("bob", 45), job("bob", 90)];
+        let ids: Vec<ObjectId> = jobs.iter().map(|j| j.id).collect();
+
+        let expired: HashSet<ObjectId> = expired_jobs(jobs, cutoff, 2).into_iter().collect();
+
+        // alice keeps her recent job plus the newest old one; bob keeps both as his minimum
+        assert!(!expired.contains(&ids[1]) && !expired.contains(&ids[0]));
+        assert!(expired.contains(&ids[2]) && expired.contains(&ids[3]));
+        assert!(!expired.contains(&ids[4]) && !expired.contains(&ids[5]));
+        assert_eq!(expired.len(), 2);
+    }
+}
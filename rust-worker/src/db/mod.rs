@@ -1,6 +1,10 @@
 pub mod cache;
 pub mod job;
 pub mod progress;
+pub mod retry;
+pub mod schedule;
+pub mod stats;
 pub mod user;
 pub mod user_config;
+pub mod worker;
 
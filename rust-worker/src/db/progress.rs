@@ -1,56 +1,57 @@
+use crate::db::cache::DomainCountSample;
 use crate::extractor::FormatBreakdown;
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 
 /// Job stage enum
-#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
-#[serde(rename_all = "lowercase")]
+///
+/// Wire names are spelled out per variant: they are read by the API and
+/// frontend, so renaming a variant must never change what is stored.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Default)]
 pub enum JobStage {
+    #[default]
+    #[serde(rename = "queue")]
     Queue,
+    #[serde(rename = "downloading")]
     Downloading,
+    #[serde(rename = "whitelist")]
     Whitelist,
+    #[serde(rename = "generation")]
     Generation,
+    #[serde(rename = "completed")]
     Completed,
 }
 
-impl Default for JobStage {
-    fn default() -> Self {
-        Self::Queue
-    }
-}
-
 /// Source download status
-#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
-#[serde(rename_all = "lowercase")]
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Default)]
 pub enum SourceStatus {
+    #[default]
+    #[serde(rename = "pending")]
     Pending,
+    #[serde(rename = "downloading")]
     Downloading,
+    #[serde(rename = "processing")]
     Processing,
+    #[serde(rename = "completed")]
     Completed,
+    #[serde(rename = "failed")]
     Failed,
 }
 
-impl Default for SourceStatus {
-    fn default() -> Self {
-        Self::Pending
-    }
-}
-
 /// Format generation status
-#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
-#[serde(rename_all = "lowercase")]
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Default)]
 pub enum FormatStatus {
+    #[default]
+    #[serde(rename = "pending")]
     Pending,
+    #[serde(rename = "generating")]
     Generating,
+    #[serde(rename = "compressing")]
     Compressing,
+    #[serde(rename = "completed")]
     Completed,
 }
 
-impl Default for FormatStatus {
-    fn default() -> Self {
-        Self::Pending
-    }
-}
-
 /// Progress for a single source
 #[derive(Debug, Clone, Serialize, Deserialize, Default)]
 pub struct SourceProgress {
@@ -71,6 +72,12 @@ pub struct SourceProgress {
     pub domain_count: Option<u64>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub domain_change: Option<i64>,
+    /// Recent domain counts of the source, oldest first, ending with this build
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub domain_history: Vec<DomainCountSample>,
+    /// Domains found in this source and no other source of the job
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub unique_domain_count: Option<u64>,
     /// Format breakdown - counts per format type (hosts/plain/adblock)
     #[serde(skip_serializing_if = "Option::is_none")]
     pub format_breakdown: Option<FormatBreakdown>,
@@ -106,6 +113,9 @@ pub struct WhitelistProgress {
     pub processing: bool,
     #[serde(default)]
     pub patterns: Vec<WhitelistPatternMatch>,
+    /// Domains removed per category ("uncategorized" for no category)
+    #[serde(default)]
+    pub category_removed: HashMap<String, u64>,
 }
 
 /// Single format generation progress
@@ -216,6 +226,7 @@ impl JobProgress {
     }
 
     /// Update to whitelist stage
+    #[allow(clippy::wrong_self_convention)]
     pub fn to_whitelist(&mut self, domains_before: u64) {
         self.current_step = "whitelist".to_string();
         self.stage = JobStage::Whitelist;
@@ -225,50 +236,70 @@ impl JobProgress {
             total_removed: 0,
             processing: true,
             patterns: Vec::new(),
+            category_removed: HashMap::new(),
         });
         self.stage_started_at = Some(chrono::Utc::now().format("%Y-%m-%dT%H:%M:%S%.6f").to_string());
     }
 
+    /// Set one source's status, tracking it as the current source while it's processing
+    ///
+    /// Returns false if no source has that id.
+    pub fn set_source_status(&mut self, id: &str, status: SourceStatus) -> bool {
+        let Some(source) = self.sources.iter_mut().find(|s| s.id == id) else {
+            return false;
+        };
+        self.current_source = (status == SourceStatus::Processing).then(|| source.name.clone());
+        source.status = status;
+        true
+    }
+
+    /// Carry over sources a previous attempt at this job already downloaded
+    ///
+    /// A job reclaimed after a worker crash keeps its stored progress; copying
+    /// the completed entries means the UI doesn't drop back to zero while the
+    /// (cache-fresh) sources are fetched again. Failed sources are retried, so
+    /// only completed ones carry over. Returns how many were resumed.
+    pub fn resume_from(&mut self, prior: &JobProgress) -> u64 {
+        let mut resumed = 0;
+        for source in &mut self.sources {
+            if let Some(done) = prior
+                .sources
+                .iter()
+                .find(|s| s.id == source.id && s.status == SourceStatus::Completed)
+            {
+                *source = done.clone();
+                resumed += 1;
+            }
+        }
+        self.processed_sources = resumed;
+        resumed
+    }
+
     /// Update to generation stage
-    pub fn to_generation(&mut self, total_domains: u64) {
+    #[allow(clippy::wrong_self_convention)]
+    pub fn to_generation(&mut self, total_domains: u64, formats: &[&str]) {
         self.current_step = "generation".to_string();
         self.stage = JobStage::Generation;
         self.generation = Some(GenerationProgress {
             current_format: None,
-            formats: vec![
-                FormatProgress {
-                    format: "hosts".to_string(),
+            formats: formats
+                .iter()
+                .map(|format| FormatProgress {
+                    format: format.to_string(),
                     status: FormatStatus::Pending,
                     domains_written: 0,
                     total_domains,
                     percent: 0.0,
                     file_size: None,
                     gz_size: None,
-                },
-                FormatProgress {
-                    format: "plain".to_string(),
-                    status: FormatStatus::Pending,
-                    domains_written: 0,
-                    total_domains,
-                    percent: 0.0,
-                    file_size: None,
-                    gz_size: None,
-                },
-                FormatProgress {
-                    format: "adblock".to_string(),
-                    status: FormatStatus::Pending,
-                    domains_written: 0,
-                    total_domains,
-                    percent: 0.0,
-                    file_size: None,
-                    gz_size: None,
-                },
-            ],
+                })
+                .collect(),
         });
         self.stage_started_at = Some(chrono::Utc::now().format("%Y-%m-%dT%H:%M:%S%.6f").to_string());
     }
 
     /// Mark as completed
+    #[allow(clippy::wrong_self_convention)]
     pub fn to_completed(&mut self) {
         self.current_step = "completed".to_string();
         self.stage = JobStage::Completed;
@@ -276,6 +307,43 @@ impl JobProgress {
     }
 }
 
+/// Why a job didn't run a full build
+///
+/// Clients switch on this; `JobResult::skip_message` carries the display text.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+pub enum SkipReason {
+    /// Config unchanged and every source still cached
+    #[serde(rename = "no_changes")]
+    NoChanges,
+    /// Output copied from a user with the same config fingerprint
+    #[serde(rename = "copied_from_user")]
+    CopiedFromUser,
+    /// Another job for the user was already running or queued too recently
+    #[serde(rename = "rate_limited")]
+    RateLimited,
+    /// Changes were too small to be worth a rebuild
+    #[serde(rename = "below_threshold")]
+    BelowThreshold,
+    /// A recent job with the same idempotency key already completed
+    #[serde(rename = "duplicate")]
+    Duplicate,
+}
+
+impl SkipReason {
+    /// Default human-readable message for this reason
+    pub fn message(&self) -> &'static str {
+        match self {
+            SkipReason::NoChanges => {
+                "No changes detected since last build. All sources are cached and configuration unchanged."
+            }
+            SkipReason::CopiedFromUser => "Output copied from an identical configuration.",
+            SkipReason::RateLimited => "Another job was already running.",
+            SkipReason::BelowThreshold => "Changes since last build were below the rebuild threshold.",
+            SkipReason::Duplicate => "The same build request was already completed.",
+        }
+    }
+}
+
 /// Job result on completion
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct JobResult {
@@ -283,6 +351,13 @@ pub struct JobResult {
     pub sources_failed: u64,
     pub total_domains: u64,
     pub unique_domains: u64,
+    /// Unique domains in the combined list, opt-in categories (e.g. nsfw) left out
+    #[serde(default)]
+    pub combined_unique_domains: u64,
+    /// Unique domains across every category, opt-in ones included; can exceed
+    /// `combined_unique_domains` when opt-in lists hold domains no other list has
+    #[serde(default)]
+    pub all_categories_unique_domains: u64,
     pub whitelisted_removed: u64,
     #[serde(default)]
     pub output_files: Vec<OutputFile>,
@@ -290,11 +365,91 @@ pub struct JobResult {
     pub categories: std::collections::HashMap<String, u64>,
     #[serde(default)]
     pub errors: Vec<String>,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    pub skip_reason: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub skip_reason: Option<SkipReason>,
+    /// Human-readable explanation of `skip_reason`, for display
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub skip_message: Option<String>,
     /// Username whose output was copied (for fingerprint-matched builds)
     #[serde(skip_serializing_if = "Option::is_none")]
     pub copied_from: Option<String>,
+    /// Per-user jobs enqueued (bulk rebuild jobs only)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub jobs_enqueued: Option<u64>,
+    /// Work avoided by copying another user's output (copy-on-match only)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub copy_savings: Option<CopySavings>,
+    /// Config and whitelist lines dropped while parsing (e.g. a disallowed
+    /// URL scheme or an invalid whitelist regex)
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub config_warnings: Vec<String>,
+    /// Domain changes a proposed config would make (preview jobs only)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub config_diff: Option<ConfigDiff>,
+}
+
+/// How a proposed config's combined list differs from the current one
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+pub struct ConfigDiff {
+    /// Domains only the proposed config blocks
+    pub added_count: u64,
+    /// Domains only the current config blocks
+    pub removed_count: u64,
+    /// Domains both configs block
+    pub unchanged_count: u64,
+    /// First added domains, sorted
+    pub added_sample: Vec<String>,
+    /// First removed domains, sorted
+    pub removed_sample: Vec<String>,
+}
+
+impl ConfigDiff {
+    /// Compare two combined domain sets, keeping up to `sample_size` examples each way
+    pub fn between(
+        current: &std::collections::HashSet<String>,
+        proposed: &std::collections::HashSet<String>,
+        sample_size: usize,
+    ) -> Self {
+        let sample = |from: &std::collections::HashSet<String>, without: &std::collections::HashSet<String>| {
+            let mut domains: Vec<String> = from.difference(without).cloned().collect();
+            domains.sort_unstable();
+            let count = domains.len() as u64;
+            domains.truncate(sample_size);
+            (count, domains)
+        };
+        let (added_count, added_sample) = sample(proposed, current);
+        let (removed_count, removed_sample) = sample(current, proposed);
+
+        Self {
+            added_count,
+            removed_count,
+            unchanged_count: current.intersection(proposed).count() as u64,
+            added_sample,
+            removed_sample,
+        }
+    }
+}
+
+/// Estimated work a copy-on-match build didn't have to do
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+pub struct CopySavings {
+    /// Sources that would have been downloaded
+    pub sources: u64,
+    /// Bytes the copied build downloaded (cache hits count as 0)
+    pub bytes: u64,
+    /// Time the copied build spent downloading (ms)
+    pub download_time_ms: u64,
+}
+
+impl CopySavings {
+    /// Estimate from the copied user's last build progress
+    pub fn estimate(sources: u64, source_progress: &JobProgress) -> Self {
+        Self {
+            sources,
+            bytes: source_progress.sources.iter().map(|s| s.bytes_downloaded).sum(),
+            download_time_ms: source_progress.sources.iter().filter_map(|s| s.download_time_ms).sum(),
+        }
+    }
 }
 
 /// Output file info
@@ -304,6 +459,13 @@ pub struct OutputFile {
     pub format: String,
     pub size_bytes: u64,
     pub domain_count: u64,
+    /// Compression of the file ("gz", "zst", or "none")
+    #[serde(default = "default_compression")]
+    pub compression: String,
+}
+
+fn default_compression() -> String {
+    "gz".to_string()
 }
 
 impl JobResult {
@@ -321,12 +483,19 @@ impl JobResult {
             sources_failed,
             total_domains,
             unique_domains,
+            combined_unique_domains: 0,
+            all_categories_unique_domains: 0,
             whitelisted_removed,
             output_files,
             categories: std::collections::HashMap::new(),
             errors: Vec::new(),
             skip_reason: None,
+            skip_message: None,
             copied_from: None,
+            jobs_enqueued: None,
+            copy_savings: None,
+            config_warnings: Vec::new(),
+            config_diff: None,
         }
     }
 
@@ -337,18 +506,26 @@ impl JobResult {
             sources_failed: 0,
             total_domains: 0,
             unique_domains: 0,
+            combined_unique_domains: 0,
+            all_categories_unique_domains: 0,
             whitelisted_removed: 0,
             output_files: Vec::new(),
             categories: std::collections::HashMap::new(),
             errors,
             skip_reason: None,
+            skip_message: None,
             copied_from: None,
+            jobs_enqueued: None,
+            copy_savings: None,
+            config_warnings: Vec::new(),
+            config_diff: None,
         }
     }
 
     /// Create a result for a job that copied output from another user
     ///
     /// Copies all build stats from the source user's last job, except build time.
+    #[allow(clippy::too_many_arguments)]
     pub fn copied_from_user(
         source_username: String,
         total_domains: u64,
@@ -364,12 +541,165 @@ impl JobResult {
             sources_failed,
             total_domains,
             unique_domains,
+            combined_unique_domains: 0,
+            all_categories_unique_domains: 0,
             whitelisted_removed,
             output_files,
             categories,
             errors: Vec::new(),
-            skip_reason: None,
+            skip_reason: Some(SkipReason::CopiedFromUser),
+            skip_message: Some(format!("Output copied from {}'s identical configuration.", source_username)),
             copied_from: Some(source_username),
+            jobs_enqueued: None,
+            copy_savings: None,
+            config_warnings: Vec::new(),
+            config_diff: None,
+        }
+    }
+
+    /// Create a result for a skipped job, with the reason's default message
+    pub fn skipped(reason: SkipReason) -> Self {
+        Self {
+            skip_reason: Some(reason),
+            skip_message: Some(reason.message().to_string()),
+            ..Self::failure(Vec::new())
         }
     }
+
+    /// Create a result for a bulk rebuild job
+    pub fn bulk_rebuild(jobs_enqueued: u64) -> Self {
+        Self {
+            jobs_enqueued: Some(jobs_enqueued),
+            ..Self::failure(Vec::new())
+        }
+    }
+
+    /// Create a result for a config preview job (nothing was built)
+    pub fn preview(diff: ConfigDiff, sources_processed: u64, sources_failed: u64) -> Self {
+        Self {
+            sources_processed,
+            sources_failed,
+            config_diff: Some(diff),
+            ..Self::failure(Vec::new())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Assert a value serializes to exactly `wire` and deserializes back
+    fn assert_wire<T>(value: T, wire: &str)
+    where
+        T: Serialize + serde::de::DeserializeOwned + PartialEq + std::fmt::Debug,
+    {
+        assert_eq!(serde_json::to_value(&value).unwrap(), serde_json::json!(wire));
+        assert_eq!(serde_json::from_value::<T>(serde_json::json!(wire)).unwrap(), value);
+    }
+
+    #[test]
+    fn test_job_stage_wire_strings() {
+        assert_wire(JobStage::Queue, "queue");
+        assert_wire(JobStage::Downloading, "downloading");
+        assert_wire(JobStage::Whitelist, "whitelist");
+        assert_wire(JobStage::Generation, "generation");
+        assert_wire(JobStage::Completed, "completed");
+    }
+
+    #[test]
+    fn test_source_status_wire_strings() {
+        assert_wire(SourceStatus::Pending, "pending");
+        assert_wire(SourceStatus::Downloading, "downloading");
+        assert_wire(SourceStatus::Processing, "processing");
+        assert_wire(SourceStatus::Completed, "completed");
+        assert_wire(SourceStatus::Failed, "failed");
+    }
+
+    #[test]
+    fn test_skip_reason_wire_strings() {
+        assert_wire(SkipReason::NoChanges, "no_changes");
+        assert_wire(SkipReason::CopiedFromUser, "copied_from_user");
+        assert_wire(SkipReason::RateLimited, "rate_limited");
+        assert_wire(SkipReason::BelowThreshold, "below_threshold");
+        assert_wire(SkipReason::Duplicate, "duplicate");
+    }
+
+    #[test]
+    fn test_skip_paths_set_reason() {
+        let no_changes = JobResult::skipped(SkipReason::NoChanges);
+        assert_eq!(no_changes.skip_reason, Some(SkipReason::NoChanges));
+        assert_eq!(no_changes.skip_message.as_deref(), Some(SkipReason::NoChanges.message()));
+
+        let copied = JobResult::copied_from_user("alice".to_string(), 10, 10, Vec::new(), 1, 0, 0, Default::default());
+        assert_eq!(copied.skip_reason, Some(SkipReason::CopiedFromUser));
+        assert!(copied.skip_message.unwrap().contains("alice"));
+
+        let built = JobResult::success(1, 0, 10, 10, 0, Vec::new());
+        assert_eq!(built.skip_reason, None);
+        assert_eq!(built.skip_message, None);
+    }
+
+    #[test]
+    fn test_format_status_wire_strings() {
+        assert_wire(FormatStatus::Pending, "pending");
+        assert_wire(FormatStatus::Generating, "generating");
+        assert_wire(FormatStatus::Compressing, "compressing");
+        assert_wire(FormatStatus::Completed, "completed");
+    }
+
+    #[test]
+    fn test_copy_savings_from_source_stats() {
+        let mut progress = JobProgress::downloading(3);
+        progress.sources = vec![
+            SourceProgress { bytes_downloaded: 1_000, download_time_ms: Some(120), ..Default::default() },
+            SourceProgress { bytes_downloaded: 2_500, download_time_ms: Some(80), ..Default::default() },
+            // Cache hit: nothing downloaded, no timing recorded
+            SourceProgress { cache_hit: Some(true), ..Default::default() },
+        ];
+
+        let savings = CopySavings::estimate(3, &progress);
+        assert_eq!(savings, CopySavings { sources: 3, bytes: 3_500, download_time_ms: 200 });
+
+        // Results stored before the field existed still deserialize
+        let mut value = serde_json::to_value(JobResult::success(1, 0, 10, 10, 0, Vec::new())).unwrap();
+        assert!(value.get("copy_savings").is_none());
+        value.as_object_mut().unwrap().remove("copy_savings");
+        let old: JobResult = serde_json::from_value(value).unwrap();
+        assert_eq!(old.copy_savings, None);
+    }
+
+    #[test]
+    fn test_reclaimed_job_resumes_completed_sources() {
+        let source = |id: &str, status: SourceStatus| SourceProgress {
+            id: id.to_string(),
+            name: id.to_string(),
+            status,
+            ..Default::default()
+        };
+
+        // Stored by the worker that crashed: a downloaded, b failed, c never started
+        let mut prior = JobProgress::downloading(3);
+        prior.sources = vec![
+            SourceProgress { cache_hit: Some(false), bytes_downloaded: 4_096, ..source("a", SourceStatus::Completed) },
+            source("b", SourceStatus::Failed),
+            source("c", SourceStatus::Downloading),
+        ];
+        prior.processed_sources = 2;
+        let prior: JobProgress = serde_json::from_value(serde_json::to_value(&prior).unwrap()).unwrap();
+
+        let mut progress = JobProgress::downloading(3);
+        progress.sources = ["a", "b", "c"].iter().map(|id| source(id, SourceStatus::Pending)).collect();
+        assert_eq!(progress.resume_from(&prior), 1);
+
+        assert_eq!(progress.processed_sources, 1);
+        assert_eq!(progress.sources[0].status, SourceStatus::Completed);
+        assert_eq!(progress.sources[0].bytes_downloaded, 4_096);
+        assert_eq!(progress.sources[1].status, SourceStatus::Pending);
+        assert_eq!(progress.sources[2].status, SourceStatus::Pending);
+
+        // A fresh job has nothing to resume
+        let mut fresh = progress.clone();
+        assert_eq!(fresh.resume_from(&JobProgress::default()), 0);
+    }
 }
@@ -0,0 +1,105 @@
+use anyhow::Result;
+use mongodb::error::{ErrorKind, RETRYABLE_WRITE_ERROR, TRANSIENT_TRANSACTION_ERROR};
+use std::future::Future;
+use std::time::Duration;
+use tracing::warn;
+
+/// Retries after the first attempt before giving up
+const MAX_RETRIES: u32 = 3;
+
+/// Backoff before the first retry (doubled for each further retry)
+const BASE_DELAY_MS: u64 = 200;
+
+/// Whether a MongoDB error is a connectivity blip worth retrying
+///
+/// Network, pool and server-selection errors are transient; logical errors
+/// such as duplicate keys or validation failures are not.
+fn is_transient_mongo(error: &mongodb::error::Error) -> bool {
+    matches!(
+        error.kind.as_ref(),
+        ErrorKind::Io(_) | ErrorKind::ConnectionPoolCleared { .. } | ErrorKind::ServerSelection { .. }
+    ) || error.contains_label(RETRYABLE_WRITE_ERROR)
+        || error.contains_label(TRANSIENT_TRANSACTION_ERROR)
+}
+
+/// Whether any error in the chain is a transient MongoDB error
+pub fn is_transient(error: &anyhow::Error) -> bool {
+    error
+        .chain()
+        .filter_map(|cause| cause.downcast_ref::<mongodb::error::Error>())
+        .any(is_transient_mongo)
+}
+
+/// Run an idempotent database write, retrying transient errors with backoff
+///
+/// `operation` names the write for logging. Non-transient errors and the
+/// error from the final attempt are returned unchanged.
+pub async fn with_retry<T, F, Fut>(operation: &str, mut write: F) -> Result<T>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T>>,
+{
+    let mut attempt = 0;
+    loop {
+        match write().await {
+            Ok(value) => return Ok(value),
+            Err(e) if attempt < MAX_RETRIES && is_transient(&e) => {
+                let delay = Duration::from_millis(BASE_DELAY_MS << attempt);
+                attempt += 1;
+                warn!(
+                    "Transient database error during {} (attempt {}/{}), retrying in {:?}: {}",
+                    operation,
+                    attempt,
+                    MAX_RETRIES + 1,
+                    delay,
+                    e
+                );
+                tokio::time::sleep(delay).await;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    fn connection_reset() -> anyhow::Error {
+        let io = std::io::Error::new(std::io::ErrorKind::ConnectionReset, "connection reset");
+        mongodb::error::Error::from(io).into()
+    }
+
+    #[tokio::test]
+    async fn test_transient_write_failure_succeeds_on_retry() {
+        let attempts = AtomicU32::new(0);
+
+        let result = with_retry("test write", || async {
+            if attempts.fetch_add(1, Ordering::SeqCst) == 0 {
+                Err(connection_reset())
+            } else {
+                Ok(42)
+            }
+        })
+        .await;
+
+        assert_eq!(result.unwrap(), 42);
+        assert_eq!(attempts.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_logical_error_is_not_retried() {
+        let attempts = AtomicU32::new(0);
+
+        let result: Result<()> = with_retry("test write", || async {
+            attempts.fetch_add(1, Ordering::SeqCst);
+            Err(anyhow::anyhow!("duplicate key"))
+        })
+        .await;
+
+        assert!(result.is_err());
+        assert_eq!(attempts.load(Ordering::SeqCst), 1);
+        assert!(is_transient(&connection_reset().context("Failed to update progress")));
+    }
+}
@@ -0,0 +1,83 @@
+use anyhow::Result;
+use bson::{doc, oid::ObjectId, DateTime as BsonDateTime};
+use futures::TryStreamExt;
+use mongodb::{Collection, Database};
+use serde::{Deserialize, Serialize};
+
+/// Per-user build schedule document
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Schedule {
+    #[serde(rename = "_id")]
+    pub id: ObjectId,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user_id: Option<ObjectId>,
+    pub username: String,
+    /// Cron expression (5-field standard or 6/7-field with seconds)
+    pub cron: String,
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+    /// Next time this schedule is due (None = not yet computed)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub next_run_at: Option<BsonDateTime>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_run_at: Option<BsonDateTime>,
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+/// Repository for schedule documents
+pub struct ScheduleRepository {
+    collection: Collection<Schedule>,
+}
+
+impl ScheduleRepository {
+    /// Create a new schedule repository
+    pub fn new(db: &Database) -> Self {
+        Self {
+            collection: db.collection("schedules"),
+        }
+    }
+
+    /// Get enabled schedules that are due (or have never been initialized)
+    pub async fn find_due(&self, now: BsonDateTime) -> Result<Vec<Schedule>> {
+        let filter = doc! {
+            "enabled": { "$ne": false },
+            "$or": [
+                { "next_run_at": { "$lte": now } },
+                { "next_run_at": null },
+            ]
+        };
+
+        let cursor = self.collection.find(filter).await?;
+        Ok(cursor.try_collect().await?)
+    }
+
+    /// Atomically advance a schedule from `previous` to `next`
+    ///
+    /// Returns false if another worker already advanced it, in which case
+    /// the caller must not act on this tick.
+    pub async fn advance(
+        &self,
+        id: &ObjectId,
+        previous: Option<BsonDateTime>,
+        next: BsonDateTime,
+        ran_at: Option<BsonDateTime>,
+    ) -> Result<bool> {
+        let mut set = doc! { "next_run_at": next };
+        if let Some(ran_at) = ran_at {
+            set.insert("last_run_at", ran_at);
+        }
+
+        let result = self
+            .collection
+            .update_one(
+                doc! { "_id": id, "next_run_at": previous },
+                doc! { "$set": set },
+            )
+            .await?;
+
+        Ok(result.modified_count > 0)
+    }
+}
@@ -0,0 +1,52 @@
+use anyhow::Result;
+use bson::{doc, DateTime as BsonDateTime};
+use chrono::Utc;
+use mongodb::{Collection, Database};
+
+use crate::domain_stats::TopDomains;
+
+/// Repository for operator-facing aggregate statistics
+pub struct StatsRepository {
+    collection: Collection<bson::Document>,
+}
+
+impl StatsRepository {
+    /// Create a new stats repository
+    pub fn new(db: &Database) -> Self {
+        Self {
+            collection: db.collection("stats"),
+        }
+    }
+
+    /// Store the latest cross-user top domains report
+    pub async fn save_top_domains(&self, top: &TopDomains) -> Result<()> {
+        let now = BsonDateTime::from_millis(Utc::now().timestamp_millis());
+
+        let domains_bson: Vec<bson::Document> = top
+            .domains
+            .iter()
+            .map(|(domain, user_count)| {
+                doc! {
+                    "domain": domain,
+                    "user_count": *user_count as i64,
+                }
+            })
+            .collect();
+
+        self.collection
+            .update_one(
+                doc! { "_id": "top_domains" },
+                doc! {
+                    "$set": {
+                        "domains": domains_bson,
+                        "users_scanned": top.users_scanned as i64,
+                        "generated_at": now,
+                    }
+                },
+            )
+            .upsert(true)
+            .await?;
+
+        Ok(())
+    }
+}
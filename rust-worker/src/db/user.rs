@@ -1,6 +1,7 @@
 use anyhow::Result;
-use bson::{doc, DateTime as BsonDateTime};
+use bson::{doc, oid::ObjectId, DateTime as BsonDateTime};
 use chrono::Utc;
+use futures::TryStreamExt;
 use mongodb::options::FindOneOptions;
 use mongodb::{Collection, Database};
 use serde::{Deserialize, Serialize};
@@ -12,6 +13,10 @@ pub struct ListMetadata {
     pub is_public: bool,
     pub formats: Vec<String>,
     pub domain_count: u64,
+    /// Domains in each format's file, when the formats don't all hold the same
+    /// number (e.g. adblock with covered subdomains collapsed)
+    #[serde(default, skip_serializing_if = "std::collections::HashMap::is_empty")]
+    pub format_counts: std::collections::HashMap<String, u64>,
     pub last_updated: BsonDateTime,
 }
 
@@ -29,9 +34,11 @@ struct UserDoc {
 pub struct UserStats {
     pub total_domains: Option<u64>,
     pub total_output_size_bytes: Option<u64>,
+    #[allow(dead_code)]
     pub last_build_at: Option<BsonDateTime>,
     pub config_hash: Option<String>,
     /// Normalized config fingerprint for cross-user matching
+    #[allow(dead_code)]
     pub config_fingerprint: Option<String>,
 }
 
@@ -41,6 +48,7 @@ pub struct MatchedUser {
     pub username: String,
     pub lists: Vec<ListMetadata>,
     pub total_domains: u64,
+    #[allow(dead_code)]
     pub total_output_size: u64,
 }
 
@@ -103,18 +111,7 @@ impl UserRepository {
         }
 
         // Convert lists to BSON
-        let lists_bson: Vec<bson::Document> = lists
-            .iter()
-            .map(|l| {
-                doc! {
-                    "name": &l.name,
-                    "is_public": l.is_public,
-                    "formats": &l.formats,
-                    "domain_count": l.domain_count as i64,
-                    "last_updated": l.last_updated,
-                }
-            })
-            .collect();
+        let lists_bson = lists.iter().map(bson::to_document).collect::<Result<Vec<_>, _>>()?;
 
         self.collection
             .update_one(
@@ -137,6 +134,7 @@ impl UserRepository {
     }
 
     /// Get existing lists for a user (to preserve is_public settings)
+    #[allow(dead_code)]
     pub async fn get_existing_lists(&self, username: &str) -> Result<Vec<ListMetadata>> {
         if username == "__default__" {
             return Ok(Vec::new());
@@ -164,7 +162,8 @@ impl UserRepository {
     /// Find a user with matching config fingerprint who has output files
     ///
     /// Returns the most recently built user with a matching fingerprint.
-    /// Excludes the requesting user and users without output files.
+    /// Excludes the requesting user, users without output files and users
+    /// who set `allow_copy_source: false`.
     /// Also checks __default__ build in system_config.
     pub async fn find_user_by_fingerprint(
         &self,
@@ -204,6 +203,8 @@ impl UserRepository {
             "lists": { "$exists": true, "$not": { "$size": 0 } },
             "stats.last_build_at": { "$exists": true },
             "is_enabled": true,
+            // Users who opted out of being copied from
+            "config.allow_copy_source": { "$ne": false },
         };
 
         // Sort by last_build_at descending to get most recent
@@ -231,4 +232,65 @@ impl UserRepository {
 
         Ok(None)
     }
+
+    /// Enabled, non-banned users that have a blocklist config (id, username)
+    pub async fn list_buildable(&self) -> Result<Vec<(Option<ObjectId>, String)>> {
+        let now = BsonDateTime::from_millis(Utc::now().timestamp_millis());
+        let users: Collection<bson::Document> = self.db.collection("users");
+
+        let filter = doc! {
+            "is_enabled": true,
+            "config.blocklists": { "$exists": true, "$nin": [null, ""] },
+            "$or": [
+                { "banned_until": null },
+                { "banned_until": { "$lt": now } },
+            ],
+        };
+
+        let cursor = users
+            .find(filter)
+            .projection(doc! { "_id": 1, "username": 1 })
+            .await?;
+        let documents: Vec<bson::Document> = cursor.try_collect().await?;
+
+        Ok(documents
+            .into_iter()
+            .filter_map(|d| {
+                let username = d.get_str("username").ok()?.to_string();
+                Some((d.get_object_id("_id").ok(), username))
+            })
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stored_list_keeps_format_counts() {
+        let list = ListMetadata {
+            name: "ads".to_string(),
+            is_public: true,
+            formats: vec!["hosts".to_string(), "adblock".to_string()],
+            domain_count: 3,
+            format_counts: std::collections::HashMap::from([("hosts".to_string(), 3), ("adblock".to_string(), 2)]),
+            last_updated: BsonDateTime::from_millis(0),
+        };
+
+        // As written into the user document by update_after_build
+        let stored = bson::to_document(&list).unwrap();
+        assert_eq!(stored.get_i64("domain_count").unwrap(), 3);
+        assert_eq!(stored.get_document("format_counts").unwrap().get_i64("adblock").unwrap(), 2);
+
+        let read: ListMetadata = bson::from_document(stored).unwrap();
+        assert_eq!(read.format_counts, list.format_counts);
+
+        // Lists whose formats agree store no breakdown
+        let uniform = ListMetadata {
+            format_counts: Default::default(),
+            ..list
+        };
+        assert!(!bson::to_document(&uniform).unwrap().contains_key("format_counts"));
+    }
 }
@@ -1,13 +1,35 @@
 use anyhow::{anyhow, Result};
 use bson::doc;
 use mongodb::{Collection, Database};
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 
-/// User config embedded in user document
-#[derive(Debug, Clone, Deserialize)]
+/// User config embedded in user document (or inline on a job as an override)
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct UserConfig {
     pub blocklists: Option<String>,
     pub whitelist: Option<String>,
+    /// Domains that are always blocked, even when a whitelist pattern matches
+    #[serde(default)]
+    pub forced_blocklist: Option<String>,
+    /// Output formats to generate (None = all formats)
+    #[serde(default)]
+    pub enabled_formats: Option<Vec<String>>,
+    /// URL POSTed a JSON summary when a build completes (needs ENABLE_WEBHOOKS)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub webhook_url: Option<String>,
+    /// Take another user's output when the config fingerprint matches (None = true)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub allow_copy_on_match: Option<bool>,
+    /// Let other users copy this user's output (None = true)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub allow_copy_source: Option<bool>,
+    /// Build empty lists when no sources are configured (None = worker default)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub empty_config_produces_empty_list: Option<bool>,
+    /// Extra whitelist content per category, applied on top of `whitelist`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub category_whitelists: Option<HashMap<String, String>>,
 }
 
 /// User document projection for config retrieval
@@ -21,12 +43,29 @@ struct UserDoc {
 struct SystemConfigDoc {
     pub blocklists: Option<String>,
     pub whitelist: Option<String>,
+    #[serde(default)]
+    pub forced_blocklist: Option<String>,
+    #[serde(default)]
+    pub enabled_formats: Option<Vec<String>>,
+}
+
+/// Operator-level block/allow lists applied to every build
+///
+/// Stored in system_config as `{ _id: "operator_lists", blocklist, allowlist }`,
+/// one domain per line.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct OperatorLists {
+    #[serde(default)]
+    pub blocklist: Option<String>,
+    #[serde(default)]
+    pub allowlist: Option<String>,
 }
 
 /// Repository for fetching user and system configurations from MongoDB
 pub struct UserConfigRepository {
     users_collection: Collection<UserDoc>,
     system_config_collection: Collection<SystemConfigDoc>,
+    operator_lists_collection: Collection<OperatorLists>,
 }
 
 impl UserConfigRepository {
@@ -35,6 +74,7 @@ impl UserConfigRepository {
         Self {
             users_collection: db.collection("users"),
             system_config_collection: db.collection("system_config"),
+            operator_lists_collection: db.collection("system_config"),
         }
     }
 
@@ -77,20 +117,16 @@ impl UserConfigRepository {
         Ok(UserConfig {
             blocklists: config.blocklists,
             whitelist: config.whitelist,
+            forced_blocklist: config.forced_blocklist,
+            enabled_formats: config.enabled_formats,
+            ..Default::default()
         })
     }
 
-    /// Get blocklist config content for a user
-    pub async fn get_blocklists(&self, username: &str) -> Result<String> {
-        let config = self.get_config(username).await?;
-        config
-            .blocklists
-            .ok_or_else(|| anyhow!("No blocklist config found for: {}", username))
-    }
-
-    /// Get whitelist content for a user (returns empty string if none)
-    pub async fn get_whitelist(&self, username: &str) -> Result<String> {
-        let config = self.get_config(username).await?;
-        Ok(config.whitelist.unwrap_or_default())
+    /// Get the operator-level block/allow lists (empty when not configured)
+    pub async fn get_operator_lists(&self) -> Result<OperatorLists> {
+        let filter = doc! { "_id": "operator_lists" };
+        let lists = self.operator_lists_collection.find_one(filter).await?;
+        Ok(lists.unwrap_or_default())
     }
 }
@@ -0,0 +1,162 @@
+use anyhow::Result;
+use bson::{doc, DateTime as BsonDateTime};
+use chrono::Utc;
+use mongodb::{Collection, Database};
+use serde::{Deserialize, Serialize};
+
+/// Worker liveness status
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum WorkerStatus {
+    #[serde(rename = "running")]
+    Running,
+    #[serde(rename = "stopped")]
+    Stopped,
+}
+
+impl WorkerStatus {
+    /// Wire name used in MongoDB
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            WorkerStatus::Running => "running",
+            WorkerStatus::Stopped => "stopped",
+        }
+    }
+}
+
+/// Registration document for a worker process (keyed by worker_id)
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WorkerRecord {
+    #[serde(rename = "_id")]
+    pub worker_id: String,
+    pub hostname: String,
+    pub version: String,
+    pub status: WorkerStatus,
+    pub started_at: BsonDateTime,
+    pub last_seen: BsonDateTime,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stopped_at: Option<BsonDateTime>,
+}
+
+impl WorkerRecord {
+    /// Describe this process as a freshly started worker
+    pub fn current(worker_id: &str) -> Self {
+        let now = BsonDateTime::from_millis(Utc::now().timestamp_millis());
+        Self {
+            worker_id: worker_id.to_string(),
+            hostname: hostname(),
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            status: WorkerStatus::Running,
+            started_at: now,
+            last_seen: now,
+            stopped_at: None,
+        }
+    }
+}
+
+/// Best-effort hostname ($HOSTNAME, then /etc/hostname)
+fn hostname() -> String {
+    std::env::var("HOSTNAME")
+        .ok()
+        .or_else(|| std::fs::read_to_string("/etc/hostname").ok())
+        .map(|h| h.trim().to_string())
+        .filter(|h| !h.is_empty())
+        .unwrap_or_else(|| "unknown".to_string())
+}
+
+/// Update applied on every heartbeat
+fn heartbeat_update(now: BsonDateTime) -> bson::Document {
+    doc! {
+        "$set": {
+            "last_seen": now,
+            "status": WorkerStatus::Running.as_str(),
+        }
+    }
+}
+
+/// Update applied on graceful shutdown
+fn stopped_update(now: BsonDateTime) -> bson::Document {
+    doc! {
+        "$set": {
+            "last_seen": now,
+            "status": WorkerStatus::Stopped.as_str(),
+            "stopped_at": now,
+        }
+    }
+}
+
+/// Repository for worker registration and liveness
+pub struct WorkerRepository {
+    collection: Collection<WorkerRecord>,
+}
+
+impl WorkerRepository {
+    /// Create a new worker repository
+    pub fn new(db: &Database) -> Self {
+        Self {
+            collection: db.collection("workers"),
+        }
+    }
+
+    /// Register (or re-register) a worker on startup
+    pub async fn register(&self, record: &WorkerRecord) -> Result<()> {
+        self.collection
+            .replace_one(doc! { "_id": &record.worker_id }, record)
+            .upsert(true)
+            .await?;
+        Ok(())
+    }
+
+    /// Refresh a worker's last_seen timestamp
+    pub async fn heartbeat(&self, worker_id: &str) -> Result<()> {
+        let now = BsonDateTime::from_millis(Utc::now().timestamp_millis());
+        self.collection
+            .update_one(doc! { "_id": worker_id }, heartbeat_update(now))
+            .await?;
+        Ok(())
+    }
+
+    /// Mark a worker as stopped (graceful shutdown)
+    pub async fn mark_stopped(&self, worker_id: &str) -> Result<()> {
+        let now = BsonDateTime::from_millis(Utc::now().timestamp_millis());
+        self.collection
+            .update_one(doc! { "_id": worker_id }, stopped_update(now))
+            .await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Apply a `$set` update to a document the way MongoDB would
+    fn apply_set(mut document: bson::Document, update: &bson::Document) -> bson::Document {
+        for (key, value) in update.get_document("$set").unwrap() {
+            document.insert(key, value.clone());
+        }
+        document
+    }
+
+    #[test]
+    fn test_registration_and_heartbeat_update_document() {
+        let record = WorkerRecord::current("worker-1");
+        let registered = bson::to_document(&record).unwrap();
+
+        assert_eq!(registered.get_str("_id").unwrap(), "worker-1");
+        assert_eq!(registered.get_str("status").unwrap(), "running");
+        assert_eq!(registered.get_str("version").unwrap(), env!("CARGO_PKG_VERSION"));
+        assert!(!registered.get_str("hostname").unwrap().is_empty());
+        assert!(!registered.contains_key("stopped_at"));
+
+        let later = BsonDateTime::from_millis(record.last_seen.timestamp_millis() + 10_000);
+        let after_heartbeat = apply_set(registered, &heartbeat_update(later));
+        let parsed: WorkerRecord = bson::from_document(after_heartbeat.clone()).unwrap();
+        assert_eq!(parsed.last_seen, later);
+        assert_eq!(parsed.started_at, record.started_at);
+        assert_eq!(parsed.status, WorkerStatus::Running);
+
+        let stopped: WorkerRecord = bson::from_document(apply_set(after_heartbeat, &stopped_update(later))).unwrap();
+        assert_eq!(stopped.status, WorkerStatus::Stopped);
+        assert_eq!(stopped.stopped_at, Some(later));
+    }
+}
@@ -0,0 +1,163 @@
+use anyhow::Result;
+use flate2::read::GzDecoder;
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+use tracing::{debug, warn};
+
+use crate::generator::{FilenameTemplate, OutputFormat, COMBINED_LIST_NAME};
+
+/// Formats the combined list is read from, in order of preference
+///
+/// Users may restrict their formats, so the first one they have is used.
+/// Adblock is left out: its raw rules aren't always a bare domain.
+const COMBINED_LIST_FORMATS: [OutputFormat; 3] = [OutputFormat::Plain, OutputFormat::Umbrella, OutputFormat::Hosts];
+
+/// Domain frequency across all users' combined lists
+#[derive(Debug, Clone, PartialEq)]
+pub struct TopDomains {
+    /// Number of users whose combined list was scanned
+    pub users_scanned: u64,
+    /// Most commonly blocked domains with the number of users blocking each
+    pub domains: Vec<(String, u64)>,
+}
+
+/// Stream a gzipped list into the frequency map without loading it into memory
+fn count_file(path: &Path, format: OutputFormat, counts: &mut HashMap<String, u64>) -> Result<()> {
+    let reader = BufReader::new(GzDecoder::new(File::open(path)?));
+
+    for line in reader.lines() {
+        let line = line?;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        // Hosts lines are `{ip} {domain}`; plain and umbrella are just the domain
+        let domain = match format {
+            OutputFormat::Hosts => match line.split_whitespace().nth(1) {
+                Some(domain) => domain,
+                None => continue,
+            },
+            _ => line,
+        };
+        // Avoid allocating for domains we've already seen
+        if let Some(count) = counts.get_mut(domain) {
+            *count += 1;
+        } else {
+            counts.insert(domain.to_string(), 1);
+        }
+    }
+
+    Ok(())
+}
+
+/// Build the top-N most commonly blocked domains across every user's combined list
+///
+/// Reads each user's combined list as named by `template` (e.g.
+/// `{data_dir}/users/*/output/all_domains_plain.txt.gz`); unreadable files
+/// are logged and skipped.
+pub fn aggregate_top_domains(data_dir: &Path, template: &FilenameTemplate, limit: usize) -> Result<TopDomains> {
+    let users_dir = data_dir.join("users");
+    let mut counts: HashMap<String, u64> = HashMap::new();
+    let mut users_scanned = 0;
+
+    if users_dir.exists() {
+        for entry in fs::read_dir(&users_dir)?.flatten() {
+            let output_dir = entry.path().join("output");
+            let found = COMBINED_LIST_FORMATS.into_iter().find_map(|format| {
+                let path = output_dir.join(template.render(COMBINED_LIST_NAME, format));
+                path.exists().then_some((path, format))
+            });
+            let Some((path, format)) = found else {
+                continue;
+            };
+
+            match count_file(&path, format, &mut counts) {
+                Ok(()) => users_scanned += 1,
+                Err(e) => warn!("Failed to read {}: {}", path.display(), e),
+            }
+        }
+    }
+
+    debug!(
+        "Aggregated {} distinct domains across {} users",
+        counts.len(),
+        users_scanned
+    );
+
+    // Highest count first, ties broken alphabetically for stable output
+    let mut domains: Vec<(String, u64)> = counts.into_iter().collect();
+    domains.sort_unstable_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    domains.truncate(limit);
+
+    Ok(TopDomains {
+        users_scanned,
+        domains,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+    use std::io::Write;
+    use tempfile::TempDir;
+
+    fn write_user_list(data_dir: &Path, username: &str, domains: &[&str]) {
+        write_user_file(data_dir, username, "all_domains_plain.txt.gz", "", domains);
+    }
+
+    fn write_user_file(data_dir: &Path, username: &str, filename: &str, prefix: &str, domains: &[&str]) {
+        let path = data_dir.join("users").join(username).join("output").join(filename);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+
+        let file = File::create(path).unwrap();
+        let mut encoder = GzEncoder::new(file, Compression::fast());
+        writeln!(encoder, "# Blocklist generated by lists.zachlagden.uk\n").unwrap();
+        for domain in domains {
+            writeln!(encoder, "{}{}", prefix, domain).unwrap();
+        }
+        encoder.finish().unwrap();
+    }
+
+    #[test]
+    fn test_aggregate_top_domains() {
+        let temp_dir = TempDir::new().unwrap();
+        write_user_list(temp_dir.path(), "alice", &["ads.com", "track.com", "only-alice.com"]);
+        write_user_list(temp_dir.path(), "bob", &["ads.com", "track.com"]);
+        write_user_list(temp_dir.path(), "carol", &["ads.com", "only-carol.com"]);
+
+        let top = aggregate_top_domains(temp_dir.path(), &FilenameTemplate::default(), 3).unwrap();
+
+        assert_eq!(top.users_scanned, 3);
+        assert_eq!(
+            top.domains,
+            vec![
+                ("ads.com".to_string(), 3),
+                ("track.com".to_string(), 2),
+                ("only-alice.com".to_string(), 1),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_aggregate_follows_template_and_formats() {
+        let temp_dir = TempDir::new().unwrap();
+        let template = FilenameTemplate::parse("{format}/{category}.txt.gz").unwrap();
+        write_user_file(temp_dir.path(), "alice", "plain/all.txt.gz", "", &["ads.com", "track.com"]);
+        // Only hosts enabled: read the domain after the IP
+        write_user_file(temp_dir.path(), "bob", "hosts/all.txt.gz", "0.0.0.0 ", &["ads.com"]);
+        // Default names aren't what this template produces
+        write_user_list(temp_dir.path(), "carol", &["ads.com"]);
+
+        let top = aggregate_top_domains(temp_dir.path(), &template, 10).unwrap();
+
+        assert_eq!(top.users_scanned, 2);
+        assert_eq!(
+            top.domains,
+            vec![("ads.com".to_string(), 2), ("track.com".to_string(), 1)]
+        );
+    }
+}
@@ -1,40 +1,183 @@
 use anyhow::{Context, Result};
 use futures::stream::{self, StreamExt};
 use mongodb::Database;
-use reqwest::Client;
+use reqwest::{Client, Method, Response};
+use regex::Regex;
 use sha2::{Digest, Sha256};
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+use std::sync::LazyLock;
 use std::time::{Duration, Instant};
 use tracing::{debug, info, warn};
 
+use crate::cancel::CancelToken;
 use crate::config::Config;
-use crate::db::cache::CacheRepository;
+use crate::db::cache::{CacheRepository, DomainCountSample, ExtractionSummary, Reliability};
 use crate::db::progress::{SourceProgress, SourceStatus};
+use crate::error::{ErrorKind, WorkerError};
+use crate::extractor::{DomainExtractor, ExtractionOutput, FormatBreakdown, StreamingExtraction};
 
 /// Maximum allowed size for a single source file (100MB)
 const MAX_SOURCE_SIZE_BYTES: u64 = 100 * 1024 * 1024;
 
+/// Times a dropped download is resumed with a Range request before giving up
+const MAX_RESUME_ATTEMPTS: usize = 3;
+
+/// Minimum time between byte-count reports while one body downloads
+const BYTE_PROGRESS_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Receives `(bytes_received, bytes_total)` while a body downloads
+type ReportBytes<'a> = dyn Fn(u64, Option<u64>) + Send + Sync + 'a;
+
+/// Previous domain count above which an empty download is treated as an upstream error
+const EMPTY_FAILURE_MIN_PREVIOUS: i64 = 100;
+
+/// Recent success rate below which a failing source is flagged as unreliable
+const FLAKY_SOURCE_SUCCESS_RATE: f64 = 0.5;
+
+/// Downloads on record before a source's success rate is judged
+const FLAKY_SOURCE_MIN_FETCHES: usize = 5;
+
+/// Source URL schemes allowed when ALLOWED_SOURCE_SCHEMES is unset
+pub const DEFAULT_SOURCE_SCHEMES: &[&str] = &["http", "https"];
+
+/// Sources parsed from a config, plus why any lines were dropped
+#[derive(Debug, Default)]
+pub struct ParsedConfig {
+    pub sources: Vec<Source>,
+    /// One message per rejected source line
+    pub warnings: Vec<String>,
+}
+
 /// Source definition from config file
 #[derive(Debug, Clone)]
 pub struct Source {
     pub name: String,
     pub url: String,
     pub category: Option<String>,
+    /// Field selector for JSON-array sources (`json=indicator.domain` option)
+    pub json_selector: Option<String>,
+    /// Download priority (`priority=N` option); higher starts first, default 0
+    pub priority: i32,
+    /// HTTP method (`method=POST` option), for feeds served by an API
+    pub method: Method,
+    /// Request body sent as JSON (`body={...}` option)
+    pub body: Option<String>,
+    /// Mirror group (`mirror=name` option): sources sharing a group are one source
+    pub mirror_group: Option<String>,
+    /// Later members of this source's mirror group, tried in order if it fails
+    pub mirrors: Vec<Source>,
+    /// Cache freshness window (`ttl=6h` / `ttl=30d` option); a feed's
+    /// `! Expires:` header still applies when it's shorter
+    pub cache_ttl_override: Option<chrono::Duration>,
+    /// Source is a PAC script (`format=pac` option): domains come from its host matchers
+    pub pac: bool,
+}
+
+/// URLs inside log text, for REDACT_SOURCE_URLS
+static URL_PATTERN: LazyLock<Regex> = LazyLock::new(|| Regex::new(r#"[a-zA-Z][a-zA-Z0-9+.-]*://[^\s'"()]+"#).unwrap());
+
+/// Log text with every URL reduced to its host and a hash of the full URL
+///
+/// Paths and queries can reveal what a user subscribes to (or carry tokens),
+/// so privacy-sensitive deployments keep them out of logs.
+fn loggable(text: &str, redact: bool) -> Cow<'_, str> {
+    if !redact {
+        return Cow::Borrowed(text);
+    }
+    URL_PATTERN.replace_all(text, |caps: &regex::Captures| {
+        let url = &caps[0];
+        let host = url::Url::parse(url)
+            .ok()
+            .and_then(|u| u.host_str().map(String::from))
+            .unwrap_or_else(|| "unknown-host".to_string());
+        format!("{}/[{}]", host, &Downloader::hash_url(url)[..12])
+    })
+}
+
+/// What to request for a download
+#[derive(Debug, Clone, Copy)]
+struct FetchRequest<'a> {
+    url: &'a str,
+    method: &'a Method,
+    body: Option<&'a str>,
+    /// Keep the URL out of log lines (REDACT_SOURCE_URLS)
+    redact_logs: bool,
+    /// Longest wait for the next body chunk (READ_IDLE_TIMEOUT_SECS)
+    idle_timeout: Option<Duration>,
+}
+
+impl<'a> FetchRequest<'a> {
+    /// Plain GET of a static file
+    #[cfg(test)]
+    fn get(url: &'a str) -> Self {
+        Self {
+            url,
+            method: &Method::GET,
+            body: None,
+            redact_logs: false,
+            idle_timeout: None,
+        }
+    }
+
+    fn of(source: &'a Source) -> Self {
+        Self {
+            url: &source.url,
+            method: &source.method,
+            body: source.body.as_deref(),
+            redact_logs: false,
+            idle_timeout: None,
+        }
+    }
 }
 
 /// Result of downloading a source
 #[derive(Debug)]
 pub struct DownloadResult {
     pub source: Source,
+    /// Cache key of the URL that served the content (a mirror's, if one did)
     pub url_hash: String,
+    /// Cache key of the primary when a mirror served it; progress reports under it
+    pub primary_hash: Option<String>,
     /// Content bytes (loaded in memory)
     pub content: Option<Vec<u8>>,
     pub cache_hit: bool,
     pub bytes_downloaded: u64,
     pub download_time_ms: u64,
     pub error: Option<String>,
+    /// Structured kind of `error`, for callers that branch on the failure
+    pub error_kind: Option<ErrorKind>,
     pub warnings: Vec<String>,
     /// Previous domain count from cache (for calculating domain_change)
     pub previous_domain_count: Option<u64>,
+    /// Domains extracted while downloading (STREAMING_EXTRACTION); `content` is
+    /// then None, as the body was only needed for the cache
+    pub extracted: Option<ExtractionOutput>,
+}
+
+impl DownloadResult {
+    /// Id of the configured source (the primary of a mirror group), for progress and reports
+    pub fn source_id(&self) -> &str {
+        self.primary_hash.as_deref().unwrap_or(&self.url_hash)
+    }
+}
+
+/// Bytes received before a connection dropped, kept for a Range resume
+#[derive(Debug)]
+struct PartialDownload {
+    content: Vec<u8>,
+    /// ETag or Last-Modified of the partial response, sent as If-Range
+    validator: Option<String>,
+}
+
+/// A fully downloaded response body and the headers we keep
+#[derive(Debug)]
+struct FetchedBody {
+    content: Vec<u8>,
+    content_type: Option<String>,
+    etag: Option<String>,
+    last_modified: Option<String>,
+    warnings: Vec<String>,
 }
 
 /// Downloader for fetching blocklist sources
@@ -42,20 +185,130 @@ pub struct Downloader {
     client: Client,
     config: Config,
     cache_repo: CacheRepository,
+    /// Extractor for streaming extraction (None = extract after download)
+    extractor: Option<DomainExtractor>,
 }
 
 impl Downloader {
     /// Create a new downloader
     pub fn new(config: Config, db: &Database) -> Result<Self> {
-        let client = Client::builder()
-            .timeout(Duration::from_secs(config.http_timeout_secs))
+        let client = Self::build_client(&config)?;
+
+        let cache_repo = CacheRepository::new(db)
+            .with_history_size(config.domain_history_size)
+            .with_batch_size(config.cache_write_batch_size)
+            .with_integrity_check(config.verify_cache_integrity);
+
+        // Sections need the whole body, so they turn streaming off
+        let extractor = (config.streaming_extraction && config.section_header_pattern.is_none())
+            .then(|| {
+                DomainExtractor::new()
+                    .with_domain_prefixes(&config.domain_prefixes)
+                    .with_hosts_sink_ips(&config.hosts_sink_ips)
+            })
+            .transpose()
+            .map_err(|e| WorkerError::ConfigInvalid(format!("HOSTS_SINK_IPS: {:#}", e)))?;
+
+        Ok(Self {
+            client,
+            cache_repo,
+            extractor,
+            config,
+        })
+    }
+
+    /// Build the HTTP client used for source downloads
+    fn build_client(config: &Config) -> Result<Client> {
+        let mut builder = Client::builder()
+            .redirect(reqwest::redirect::Policy::limited(config.max_redirects))
+            .pool_max_idle_per_host(config.pool_max_idle_per_host)
+            .pool_idle_timeout(Duration::from_secs(config.pool_idle_timeout_secs))
             .gzip(true)
-            .user_agent("BlocklistWorker/1.0 (lists.zachlagden.uk)")
-            .build()?;
+            .user_agent("BlocklistWorker/1.0 (lists.zachlagden.uk)");
+        if config.http_timeout_secs > 0 {
+            builder = builder.timeout(Duration::from_secs(config.http_timeout_secs));
+        }
+        if config.connect_timeout_secs > 0 {
+            builder = builder.connect_timeout(Duration::from_secs(config.connect_timeout_secs));
+        }
+        if config.http2_prior_knowledge {
+            builder = builder.http2_prior_knowledge();
+        }
+        Ok(builder.build()?)
+    }
 
-        let cache_repo = CacheRepository::new(db);
+    /// Check where a source ended up after redirects
+    ///
+    /// Returns a warning if the feed moved, or an error if the redirect chain
+    /// ended on an HTML page (typically a login wall rather than a blocklist).
+    fn check_redirect(requested_url: &str, response: &Response) -> Result<Option<String>> {
+        let final_url = response.url().as_str();
+        if final_url == requested_url {
+            return Ok(None);
+        }
+
+        let is_html = response
+            .headers()
+            .get("content-type")
+            .and_then(|v| v.to_str().ok())
+            .is_some_and(|v| v.trim_start().to_lowercase().starts_with("text/html"));
 
-        Ok(Self { client, config, cache_repo })
+        if is_html {
+            anyhow::bail!(
+                "Redirected from {} to an HTML page at {} - the feed may require authentication or has moved",
+                requested_url,
+                final_url
+            );
+        }
+
+        Ok(Some(format!("Source redirected to {} - consider updating the URL", final_url)))
+    }
+
+    /// Reject responses whose Content-Type can't be a blocklist
+    ///
+    /// `blocked` entries ending in `/` match a whole type (`image/`), others an
+    /// exact media type. `application/octet-stream` is still accepted for gzip
+    /// bodies, and `application/json` for JSON-array sources.
+    fn check_content_type(
+        content_type: Option<&str>,
+        content: &[u8],
+        blocked: &[String],
+        expects_json: bool,
+    ) -> Result<()> {
+        let Some(content_type) = content_type else {
+            return Ok(());
+        };
+        let media_type = content_type
+            .split(';')
+            .next()
+            .unwrap_or("")
+            .trim()
+            .to_lowercase();
+
+        let is_blocked = blocked.iter().any(|entry| {
+            if entry.ends_with('/') {
+                media_type.starts_with(entry.as_str())
+            } else {
+                media_type == *entry
+            }
+        });
+        if !is_blocked {
+            return Ok(());
+        }
+
+        let allowed = match media_type.as_str() {
+            "application/octet-stream" => content.starts_with(&[0x1f, 0x8b]),
+            "application/json" => expects_json,
+            _ => false,
+        };
+        if allowed {
+            return Ok(());
+        }
+
+        anyhow::bail!(
+            "Unexpected content type '{}' - the source is not serving a text list",
+            media_type
+        )
     }
 
     /// Hash a URL to get cache key
@@ -65,27 +318,45 @@ impl Downloader {
         format!("{:x}", hasher.finalize())
     }
 
+    /// Cache key for a source: the URL hash, plus method and body for API requests
+    ///
+    /// Plain GETs keep the URL-only key so existing cache entries stay valid.
+    pub fn cache_key(source: &Source) -> String {
+        if source.method == Method::GET && source.body.is_none() {
+            return Self::hash_url(&source.url);
+        }
+        Self::hash_url(&format!(
+            "{} {}\n{}",
+            source.method,
+            source.url,
+            source.body.as_deref().unwrap_or("")
+        ))
+    }
+
     /// Download a single source
-    pub async fn download_source(&self, source: &Source, force: bool) -> DownloadResult {
-        let url_hash = Self::hash_url(&source.url);
+    pub async fn download_source(&self, source: &Source, force: bool, report: Option<&ReportBytes<'_>>) -> DownloadResult {
+        let url_hash = Self::cache_key(source);
         let start = Instant::now();
         let mut warnings = Vec::new();
 
         // Check cache first (skip when force rebuild is requested)
         if !force {
-            match self.cache_repo.get_content(&url_hash).await {
+            match self.cache_repo.get_fresh_content(&url_hash, source.cache_ttl_override).await {
                 Ok(Some(content)) => {
                     debug!("Cache hit for {} ({} bytes)", source.name, content.len());
                     return DownloadResult {
                         source: source.clone(),
                         url_hash,
+                        primary_hash: None,
                         content: Some(content),
                         cache_hit: true,
                         bytes_downloaded: 0,
                         download_time_ms: start.elapsed().as_millis() as u64,
                         error: None,
+                        error_kind: None,
                         warnings,
                         previous_domain_count: None, // TODO: Get from cache stats
+                        extracted: None,
                     };
                 }
                 Ok(None) => {
@@ -100,118 +371,205 @@ impl Downloader {
         }
 
         // Download fresh
-        debug!("Downloading {} from {}", source.name, source.url);
+        debug!(
+            "Downloading {} from {}",
+            source.name,
+            loggable(&source.url, self.config.redact_source_urls)
+        );
 
-        let result = self.fetch_and_cache(source, &url_hash).await;
+        let result = self.fetch_and_cache(source, &url_hash, report).await;
 
         match result {
-            Ok((content, new_warnings)) => {
+            Ok((content, new_warnings, extracted)) => {
                 warnings.extend(new_warnings);
                 let bytes_downloaded = content.len() as u64;
                 DownloadResult {
                     source: source.clone(),
                     url_hash,
-                    content: Some(content),
+                    primary_hash: None,
+                    // Already extracted: the body was only needed for the cache
+                    content: extracted.is_none().then_some(content),
                     cache_hit: false,
                     bytes_downloaded,
                     download_time_ms: start.elapsed().as_millis() as u64,
                     error: None,
+                    error_kind: None,
                     warnings,
                     previous_domain_count: None,
+                    extracted,
                 }
             }
             Err(e) => {
-                warn!("Failed to download {}: {}", source.name, e);
-                DownloadResult {
-                    source: source.clone(),
-                    url_hash,
-                    content: None,
-                    cache_hit: false,
-                    bytes_downloaded: 0,
-                    download_time_ms: start.elapsed().as_millis() as u64,
-                    error: Some(e.to_string()),
-                    warnings,
-                    previous_domain_count: None,
+                if let Err(record_err) = self.cache_repo.record_failure(&url_hash, &format!("{:#}", e)).await {
+                    warn!("Failed to record download failure for {}: {}", source.name, record_err);
                 }
+
+                // stale-if-error: serve the cached copy rather than failing the source
+                if let Some(status) = Self::stale_if_error_status(&e, &self.config.stale_if_error) {
+                    if let Ok(Some(content)) = self.cache_repo.get_content(&url_hash).await {
+                        return Self::stale_result(source, url_hash, content, status, start, warnings);
+                    }
+                }
+
+                warn!(
+                    "Failed to download {}: {}",
+                    source.name,
+                    loggable(&e.to_string(), self.config.redact_source_urls)
+                );
+                if let Ok(Some(reliability)) = self.cache_repo.get_reliability(&url_hash).await {
+                    warnings.extend(Self::flaky_source_warning(reliability));
+                }
+                Self::failed_result(source, url_hash, &e, start, warnings)
             }
         }
     }
 
-    /// Fetch URL and cache the result in MongoDB
-    async fn fetch_and_cache(&self, source: &Source, url_hash: &str) -> Result<(Vec<u8>, Vec<String>)> {
-        let mut warnings = Vec::new();
+    /// Result for a source whose download failed, keeping the error's kind
+    fn failed_result(
+        source: &Source,
+        url_hash: String,
+        error: &anyhow::Error,
+        start: Instant,
+        warnings: Vec<String>,
+    ) -> DownloadResult {
+        DownloadResult {
+            source: source.clone(),
+            url_hash,
+            primary_hash: None,
+            content: None,
+            cache_hit: false,
+            bytes_downloaded: 0,
+            download_time_ms: start.elapsed().as_millis() as u64,
+            error: Some(error.to_string()),
+            error_kind: Some(ErrorKind::of(error)),
+            warnings,
+            previous_domain_count: None,
+            extracted: None,
+        }
+    }
 
-        // Make request
-        let response = self
-            .client
-            .get(&source.url)
-            .send()
-            .await
-            .with_context(|| format!("Failed to fetch {}", source.url))?;
+    /// Warning for a source that fails most of its recent downloads
+    fn flaky_source_warning(reliability: Reliability) -> Option<String> {
+        (reliability.fetches >= FLAKY_SOURCE_MIN_FETCHES && reliability.success_rate() < FLAKY_SOURCE_SUCCESS_RATE).then(|| {
+            format!(
+                "Only {} of the last {} downloads succeeded ({:.0}%) - this source may be worth dropping",
+                reliability.successes,
+                reliability.fetches,
+                reliability.success_rate() * 100.0
+            )
+        })
+    }
 
-        // Check status
-        let status = response.status();
-        if !status.is_success() {
-            anyhow::bail!("HTTP {} for {}", status, source.url);
+    /// The HTTP status of a failed fetch, if it's one configured for stale-if-error
+    fn stale_if_error_status(error: &anyhow::Error, statuses: &[u16]) -> Option<u16> {
+        match ErrorKind::of(error) {
+            ErrorKind::HttpStatus(status) if statuses.contains(&status) => Some(status),
+            _ => None,
         }
+    }
 
-        // Get headers for metadata
-        let etag = response
-            .headers()
-            .get("etag")
-            .and_then(|v| v.to_str().ok())
-            .map(String::from);
-        let last_modified = response
-            .headers()
-            .get("last-modified")
-            .and_then(|v| v.to_str().ok())
-            .map(String::from);
+    /// Result serving a cached copy after the live fetch failed with `status`
+    fn stale_result(
+        source: &Source,
+        url_hash: String,
+        content: Vec<u8>,
+        status: u16,
+        start: Instant,
+        mut warnings: Vec<String>,
+    ) -> DownloadResult {
+        let warning = format!("HTTP {} from source, using cached copy instead", status);
+        warn!("{}: {}", source.name, warning);
+        warnings.push(warning);
 
-        // Check Content-Length if available
-        let content_length = response
-            .headers()
-            .get("content-length")
-            .and_then(|v| v.to_str().ok())
-            .and_then(|v| v.parse::<u64>().ok());
-
-        if let Some(len) = content_length {
-            if len > MAX_SOURCE_SIZE_BYTES {
-                anyhow::bail!(
-                    "Source file too large: {} bytes (max {} bytes)",
-                    len,
-                    MAX_SOURCE_SIZE_BYTES
-                );
-            }
+        DownloadResult {
+            source: source.clone(),
+            url_hash,
+            primary_hash: None,
+            content: Some(content),
+            cache_hit: true,
+            bytes_downloaded: 0,
+            download_time_ms: start.elapsed().as_millis() as u64,
+            error: None,
+            error_kind: None,
+            warnings,
+            previous_domain_count: None,
+            extracted: None,
         }
+    }
 
-        // Download content to memory with size limit enforcement
-        let mut content = Vec::new();
-        let mut stream = response.bytes_stream();
-
-        use futures::StreamExt;
-        while let Some(chunk) = stream.next().await {
-            let chunk = chunk.with_context(|| "Error reading response chunk")?;
-            content.extend_from_slice(&chunk);
-
-            // Check size limit during streaming
-            if content.len() as u64 > MAX_SOURCE_SIZE_BYTES {
-                anyhow::bail!(
-                    "Source file exceeds size limit during download (max {} bytes)",
-                    MAX_SOURCE_SIZE_BYTES
-                );
+    /// Fetch URL and cache the result in MongoDB
+    ///
+    /// With streaming extraction the domains extracted during the download
+    /// are returned too.
+    async fn fetch_and_cache(
+        &self,
+        source: &Source,
+        url_hash: &str,
+        report: Option<&ReportBytes<'_>>,
+    ) -> Result<(Vec<u8>, Vec<String>, Option<ExtractionOutput>)> {
+        let request = FetchRequest {
+            redact_logs: self.config.redact_source_urls,
+            idle_timeout: Some(self.config.read_idle_timeout_secs)
+                .filter(|&secs| secs > 0)
+                .map(Duration::from_secs),
+            ..FetchRequest::of(source)
+        };
+        // JSON and PAC sources are extracted from the whole document
+        let mut lines = match (&self.extractor, &source.json_selector) {
+            (Some(extractor), None) if !source.pac => {
+                Some(StreamingExtraction::new(extractor).with_transcoding(self.config.transcode_sources))
             }
+            _ => None,
+        };
+        let download =
+            Self::download_body(&self.client, request, self.config.max_redirects, &mut lines, report);
+        let body = download.await?;
+
+        let FetchedBody {
+            content,
+            content_type,
+            etag,
+            last_modified,
+            mut warnings,
+        } = body;
+        for warning in &warnings {
+            warn!("{}: {}", source.name, loggable(warning, self.config.redact_source_urls));
         }
 
-        // Validate content
-        if content.is_empty() {
+        // Validate content (rejecting here keeps the previous cache entry)
+        Self::check_content_type(
+            content_type.as_deref(),
+            &content,
+            &self.config.blocked_content_types,
+            source.json_selector.is_some(),
+        )?;
+
+        // `.gz` feeds served without Content-Encoding arrive still compressed;
+        // whatever was streamed from the compressed bytes is useless
+        if Self::is_gzip(&content) {
+            lines = None;
+        }
+        let content = Self::decompress_if_gzip(content)
+            .with_context(|| format!("Failed to decompress gzip body of {}", source.url))?;
+        if content.iter().all(u8::is_ascii_whitespace) {
+            let previous = self.cache_repo.get_domain_count(url_hash).await.unwrap_or(None);
+            Self::check_empty(previous, self.config.treat_empty_as_failure)?;
             warnings.push("Downloaded empty file".to_string());
         }
 
-        // Estimate domain count from newlines
-        let domain_count = content.iter().filter(|&&b| b == b'\n').count() as i64;
+        // Rough domain count until extraction records the real one
+        let domain_count = Self::estimate_domain_count(&content);
 
-        // Store in MongoDB cache
-        self.cache_repo
+        // Adblock subscriptions say how long they stay fresh (`! Expires: 4 days`)
+        let expires_after = Self::subscription_expiry(&content);
+        if let Some(after) = expires_after {
+            debug!("{} declares Expires: {} hours", source.name, after.num_hours());
+        }
+
+        // Store in MongoDB cache; caching is only an optimization, so a GridFS
+        // failure still hands back the content we already have
+        let stored = self
+            .cache_repo
             .store(
                 url_hash,
                 &source.url,
@@ -219,33 +577,433 @@ impl Downloader {
                 etag.as_deref(),
                 last_modified.as_deref(),
                 domain_count,
+                expires_after,
             )
-            .await?;
+            .await;
+
+        match stored {
+            Ok(()) => info!(
+                "Downloaded {} ({} bytes) and cached in MongoDB",
+                source.name, content.len()
+            ),
+            Err(e) => {
+                let e = WorkerError::CacheUnavailable(e);
+                warn!("Downloaded {} ({} bytes) but didn't cache it: {}", source.name, content.len(), e);
+                warnings.push(format!("Not cached: {}", e));
+            }
+        }
+
+        Ok((content, warnings, lines.map(StreamingExtraction::finish)))
+    }
+
+    /// Estimate a source's domain count without extracting it
+    ///
+    /// Counts non-blank lines that aren't `#`/`!` comments: one pass over the
+    /// bytes, no parsing. Hosts lines listing several domains count once.
+    fn estimate_domain_count(content: &[u8]) -> i64 {
+        content
+            .split(|&b| b == b'\n')
+            .filter(|line| {
+                match line.iter().position(|b| !b.is_ascii_whitespace()) {
+                    Some(start) => !matches!(line[start], b'#' | b'!'),
+                    None => false,
+                }
+            })
+            .count() as i64
+    }
+
+    /// Freshness window from an adblock subscription's `! Expires:` header line
+    ///
+    /// Accepts `N day(s)` / `N hour(s)` (optionally followed by a comment such
+    /// as "(update frequency)"); like Adblock Plus the result is clamped to
+    /// 1 hour..14 days. Only the header (leading comment lines) is searched.
+    fn subscription_expiry(content: &[u8]) -> Option<chrono::Duration> {
+        let header = content
+            .split(|&b| b == b'\n')
+            .map(|line| String::from_utf8_lossy(line).trim_start_matches('\u{feff}').trim().to_string())
+            .take_while(|line| line.is_empty() || line.starts_with('!') || line.starts_with('['));
+
+        for line in header {
+            let Some(value) = line
+                .trim_start_matches('!')
+                .trim()
+                .strip_prefix("Expires:")
+                .map(str::trim)
+            else {
+                continue;
+            };
+
+            let mut words = value.split_whitespace();
+            let amount: i64 = words.next()?.parse().ok()?;
+            let unit = words.next()?.to_lowercase();
+            let duration = match unit.trim_end_matches('s') {
+                "day" | "d" => chrono::Duration::days(amount),
+                "hour" | "h" => chrono::Duration::hours(amount),
+                _ => return None,
+            };
+            return Some(duration.clamp(chrono::Duration::hours(1), chrono::Duration::days(14)));
+        }
+        None
+    }
+
+    /// Parse `Content-Range: bytes start-end/total` into (start, total)
+    fn parse_content_range(value: &str) -> Option<(u64, Option<u64>)> {
+        let (range, total) = value.strip_prefix("bytes ")?.split_once('/')?;
+        let start = range.split_once('-')?.0.trim().parse().ok()?;
+        Some((start, total.trim().parse().ok()))
+    }
+
+    /// Download a response body, resuming with `Range` when the connection drops
+    ///
+    /// Resuming needs `Accept-Ranges: bytes` and a known length (which also means
+    /// the body wasn't transparently decompressed, so byte offsets line up).
+    /// Received bytes are only kept within this call; a failed download starts
+    /// over on the next build.
+    async fn download_body(
+        client: &Client,
+        fetch: FetchRequest<'_>,
+        max_redirects: usize,
+        lines: &mut Option<StreamingExtraction<'_>>,
+        report: Option<&ReportBytes<'_>>,
+    ) -> Result<FetchedBody> {
+        use futures::StreamExt;
+        use reqwest::header::{ACCEPT_RANGES, CONTENT_LENGTH, CONTENT_RANGE, CONTENT_TYPE, IF_RANGE, RANGE};
+        use reqwest::StatusCode;
+
+        let url = fetch.url;
+
+        let mut warnings = Vec::new();
+        let mut staged: Option<PartialDownload> = None;
+        let mut resumes = 0;
+        let mut last_report: Option<Instant> = None;
+
+        loop {
+            let resume_from = staged.as_ref().map_or(0, |p| p.content.len() as u64);
+            let mut request = client.request(fetch.method.clone(), url);
+            if let Some(body) = fetch.body {
+                request = request.header(CONTENT_TYPE, "application/json").body(body.to_string());
+            }
+            if let Some(partial) = staged.as_ref() {
+                request = request.header(RANGE, format!("bytes={}-", resume_from));
+                if let Some(validator) = &partial.validator {
+                    request = request.header(IF_RANGE, validator);
+                }
+            }
+
+            // Make request
+            let response = match request.send().await {
+                Ok(response) => response,
+                Err(e) if e.is_redirect() => anyhow::bail!(
+                    "Too many redirects fetching {} (max {}) - possible redirect loop",
+                    url,
+                    max_redirects
+                ),
+                Err(e) => return Err(e).with_context(|| format!("Failed to fetch {}", url)),
+            };
+
+            // Surface feeds that moved (or redirect to login pages)
+            if let Some(warning) = Self::check_redirect(url, &response)? {
+                if !warnings.contains(&warning) {
+                    warnings.push(warning);
+                }
+            }
+
+            // Check status
+            let status = response.status();
+            if !status.is_success() {
+                return Err(WorkerError::HttpStatus {
+                    status: status.as_u16(),
+                    url: url.to_string(),
+                }
+                .into());
+            }
+
+            // Get headers for metadata
+            let header = |name| {
+                response
+                    .headers()
+                    .get(name)
+                    .and_then(|v| v.to_str().ok())
+                    .map(String::from)
+            };
+            let content_type = header(reqwest::header::CONTENT_TYPE);
+            let etag = header(reqwest::header::ETAG);
+            let last_modified = header(reqwest::header::LAST_MODIFIED);
+            let content_length = header(CONTENT_LENGTH).and_then(|v| v.parse::<u64>().ok());
+            // Ranges only make sense for GET; an API response is fetched again in full
+            let resumable = content_length.is_some()
+                && *fetch.method == Method::GET
+                && (status == StatusCode::PARTIAL_CONTENT || header(ACCEPT_RANGES).is_some_and(|v| v == "bytes"));
+
+            // A 206 continues the staged bytes; anything else (e.g. If-Range mismatch) starts over
+            let (mut content, expected_total) = match (staged.take(), status) {
+                (Some(partial), StatusCode::PARTIAL_CONTENT) => {
+                    let range = header(CONTENT_RANGE).and_then(|v| Self::parse_content_range(&v));
+                    match range {
+                        Some((start, total)) if start == resume_from => {
+                            debug!("Resuming {} from byte {}", loggable(url, fetch.redact_logs), start);
+                            (partial.content, total)
+                        }
+                        _ => anyhow::bail!("Unexpected Content-Range resuming {} from byte {}", url, resume_from),
+                    }
+                }
+                _ => (Vec::new(), content_length),
+            };
+
+            if let Some(total) = expected_total {
+                Self::check_size(total)?;
+            }
+
+            // A resume continues the streamed lines; a restart means
+            // extracting from what we hold now
+            if let Some(lines) = lines.as_mut() {
+                if lines.consumed() != content.len() {
+                    lines.reset();
+                    lines.push(&content);
+                }
+            }
+
+            // Download content to memory with size limit enforcement
+            let mut stream = response.bytes_stream();
+            let mut dropped: Option<anyhow::Error> = None;
+            loop {
+                // A stalled connection is handled like a dropped one
+                let next = match fetch.idle_timeout {
+                    Some(idle) => match tokio::time::timeout(idle, stream.next()).await {
+                        Ok(next) => next,
+                        Err(_) => {
+                            dropped = Some(anyhow::anyhow!("No data received for {:?}, connection stalled", idle));
+                            break;
+                        }
+                    },
+                    None => stream.next().await,
+                };
+                let chunk = match next {
+                    Some(Ok(chunk)) => chunk,
+                    Some(Err(e)) => {
+                        dropped = Some(e.into());
+                        break;
+                    }
+                    None => break,
+                };
+                content.extend_from_slice(&chunk);
+
+                // Check size limit during streaming
+                Self::check_size(content.len() as u64)?;
+
+                if let Some(lines) = lines.as_mut() {
+                    lines.push(&chunk);
+                }
+
+                // Live progress, debounced (the first chunk always reports)
+                if let Some(report) = report {
+                    let now = Instant::now();
+                    if last_report.is_none_or(|last| now.duration_since(last) >= BYTE_PROGRESS_INTERVAL) {
+                        last_report = Some(now);
+                        report(content.len() as u64, expected_total);
+                    }
+                }
+            }
+
+            if let Some(e) = dropped {
+                if !resumable || content.is_empty() {
+                    return Err(e).with_context(|| "Error reading response chunk");
+                }
+
+                staged = Some(PartialDownload {
+                    content,
+                    validator: etag.or(last_modified),
+                });
+                if resumes >= MAX_RESUME_ATTEMPTS {
+                    return Err(e).with_context(|| format!("Error reading response chunk after {} resumes", resumes));
+                }
+                resumes += 1;
+                warn!(
+                    "Connection dropped downloading {} ({}), resuming",
+                    loggable(url, fetch.redact_logs),
+                    loggable(&e.to_string(), fetch.redact_logs)
+                );
+                continue;
+            }
+
+            // The combined body must match what the server said the file size is
+            if let Some(total) = expected_total {
+                if content.len() as u64 != total {
+                    anyhow::bail!(
+                        "Downloaded {} bytes of {} but expected {}",
+                        content.len(),
+                        url,
+                        total
+                    );
+                }
+            }
+
+            if resumes > 0 {
+                warnings.push(format!("Download resumed {} time(s) after dropped connections", resumes));
+            }
+
+            return Ok(FetchedBody {
+                content,
+                content_type,
+                etag,
+                last_modified,
+                warnings,
+            });
+        }
+    }
+
+    /// Percentage of `total` received, when the size is known
+    fn download_percent(bytes: u64, total: Option<u64>) -> Option<f64> {
+        total
+            .filter(|&total| total > 0)
+            .map(|total| (bytes as f64 / total as f64 * 100.0).min(100.0))
+    }
+
+    /// Reject a source bigger than `MAX_SOURCE_SIZE_BYTES`
+    fn check_size(size: u64) -> Result<(), WorkerError> {
+        if size > MAX_SOURCE_SIZE_BYTES {
+            return Err(WorkerError::SourceTooLarge {
+                size,
+                max: MAX_SOURCE_SIZE_BYTES,
+            });
+        }
+        Ok(())
+    }
+
+    /// Decompress a gzip body (detected by magic bytes), reading every member
+    ///
+    /// Concatenated gzip streams are valid gzip; a single-member decoder would
+    /// stop after the first one and silently truncate the list.
+    fn decompress_if_gzip(content: Vec<u8>) -> Result<Vec<u8>> {
+        use std::io::Read;
+
+        if !Self::is_gzip(&content) {
+            return Ok(content);
+        }
+
+        let mut decompressed = Vec::new();
+        flate2::bufread::MultiGzDecoder::new(content.as_slice())
+            .take(MAX_SOURCE_SIZE_BYTES + 1)
+            .read_to_end(&mut decompressed)?;
+
+        Self::check_size(decompressed.len() as u64).context("Decompressed source exceeds size limit")?;
+
+        debug!("Decompressed gzip body: {} -> {} bytes", content.len(), decompressed.len());
+        Ok(decompressed)
+    }
+
+    /// Whether a body starts with the gzip magic bytes
+    fn is_gzip(content: &[u8]) -> bool {
+        content.starts_with(&[0x1f, 0x8b])
+    }
+
+    /// Reject an empty download when the source previously had a substantial domain count
+    ///
+    /// That is almost certainly an upstream error; failing keeps the prior cache
+    /// instead of replacing it with nothing.
+    fn check_empty(previous_domain_count: Option<i64>, treat_empty_as_failure: bool) -> Result<()> {
+        match previous_domain_count {
+            Some(previous) if treat_empty_as_failure && previous >= EMPTY_FAILURE_MIN_PREVIOUS => anyhow::bail!(
+                "Downloaded empty file but source previously had {} domains, keeping cached copy",
+                previous
+            ),
+            _ => Ok(()),
+        }
+    }
+
+    /// Distinct (host, port) pairs across sources, in first-seen order
+    fn source_hosts(sources: &[Source]) -> Vec<(String, u16)> {
+        let mut seen = HashSet::new();
+        sources
+            .iter()
+            .filter_map(|s| {
+                let url = url::Url::parse(&s.url).ok()?;
+                let host = url.host_str()?.to_string();
+                Some((host, url.port_or_known_default()?))
+            })
+            .filter(|pair| seen.insert(pair.clone()))
+            .collect()
+    }
+
+    /// Resolve every distinct source host concurrently to warm the resolver cache
+    ///
+    /// Failures are only logged; the download itself reports unresolvable hosts.
+    /// Returns the number of hosts that resolved.
+    pub async fn prewarm_dns(sources: &[Source], max_concurrent: usize) -> usize {
+        let hosts = Self::source_hosts(sources);
+        let started = Instant::now();
+        let total = hosts.len();
+
+        let resolved = stream::iter(hosts)
+            .map(|(host, port)| async move {
+                match tokio::net::lookup_host((host.as_str(), port)).await {
+                    Ok(_) => true,
+                    Err(e) => {
+                        debug!("DNS prewarm failed for {}: {}", host, e);
+                        false
+                    }
+                }
+            })
+            .buffer_unordered(max_concurrent.max(1))
+            .filter(|ok| futures::future::ready(*ok))
+            .count()
+            .await;
 
         info!(
-            "Downloaded {} ({} bytes) and cached in MongoDB",
-            source.name, content.len()
+            "DNS prewarm resolved {}/{} hosts in {}ms",
+            resolved,
+            total,
+            started.elapsed().as_millis()
         );
+        resolved
+    }
 
-        Ok((content, warnings))
+    /// Order in which sources are dispatched: highest priority first, config order within a priority
+    fn dispatch_order(sources: &[Source]) -> Vec<usize> {
+        let mut order: Vec<usize> = (0..sources.len()).collect();
+        // Stable sort keeps config order for equal priorities
+        order.sort_by_key(|&idx| std::cmp::Reverse(sources[idx].priority));
+        order
     }
 
     /// Download multiple sources in parallel
+    ///
+    /// Higher-priority sources start first; results are returned in config order.
+    ///
+    /// Once `cancel` fires, in-flight downloads are aborted and pending ones
+    /// never start; only the sources that finished are returned.
     pub async fn download_sources(
         &self,
         sources: Vec<Source>,
         force: bool,
+        cancel: &CancelToken,
         progress_callback: impl Fn(usize, &SourceProgress) + Send + Sync,
     ) -> Vec<DownloadResult> {
         let max_concurrent = self.config.max_concurrent_downloads;
 
-        let results: Vec<DownloadResult> = stream::iter(sources.into_iter().enumerate())
+        if self.config.prewarm_dns {
+            Self::prewarm_dns(&sources, max_concurrent).await;
+        }
+
+        let order = Self::dispatch_order(&sources);
+        let mut slots: Vec<Option<Source>> = sources.into_iter().map(Some).collect();
+        let dispatched: Vec<(usize, Source)> = order
+            .into_iter()
+            .filter_map(|idx| slots[idx].take().map(|source| (idx, source)))
+            .collect();
+
+        let progress_callback = &progress_callback;
+        let mut results: Vec<(usize, DownloadResult)> = stream::iter(dispatched)
             .map(|(idx, source)| {
                 let downloader = self;
                 async move {
+                    if cancel.is_cancelled() {
+                        return None;
+                    }
+
                     // Notify starting
                     let mut progress = SourceProgress {
-                        id: Self::hash_url(&source.url),
+                        id: Self::cache_key(&source),
                         name: source.name.clone(),
                         url: source.url.clone(),
                         status: SourceStatus::Downloading,
@@ -256,6 +1014,8 @@ impl Downloader {
                         download_time_ms: None,
                         domain_count: None,
                         domain_change: None,
+                        domain_history: Vec::new(),
+                        unique_domain_count: None,
                         format_breakdown: None,
                         detected_formats: Vec::new(),
                         error: None,
@@ -264,8 +1024,23 @@ impl Downloader {
                         completed_at: None,
                     };
 
-                    // Download
-                    let result = downloader.download_source(&source, force).await;
+                    // Live byte counts while the body streams in
+                    let report = |bytes: u64, total: Option<u64>| {
+                        let mut live = progress.clone();
+                        live.bytes_downloaded = bytes;
+                        live.bytes_total = total;
+                        live.download_percent = Self::download_percent(bytes, total);
+                        progress_callback(idx, &live);
+                    };
+
+                    // Download, dropping the request if the worker is shutting down
+                    let result = tokio::select! {
+                        result = downloader.download_with_mirrors(&source, force, Some(&report)) => result,
+                        _ = cancel.cancelled() => {
+                            debug!("Download of {} cancelled", source.name);
+                            return None;
+                        }
+                    };
 
                     // Update progress with result
                     progress.status = if result.error.is_some() {
@@ -280,26 +1055,42 @@ impl Downloader {
                     progress.warnings = result.warnings.clone();
                     progress.completed_at = Some(chrono::Utc::now().format("%Y-%m-%dT%H:%M:%S%.6f").to_string());
 
-                    (idx, result, progress)
+                    Some((idx, result, progress))
                 }
             })
             .buffered(max_concurrent)
+            .filter_map(std::future::ready)
             .map(|(idx, result, progress)| {
                 progress_callback(idx, &progress);
-                result
+                (idx, result)
             })
             .collect()
             .await;
 
-        results
+        // Write out metadata still buffered from this stage's downloads
+        if let Err(e) = self.cache_repo.flush().await {
+            warn!("Failed to write batched cache metadata: {:#}", e);
+        }
+
+        results.sort_by_key(|(idx, _)| *idx);
+        results.into_iter().map(|(_, result)| result).collect()
+    }
+
+    /// Parse sources from config file content, allowing the default schemes
+    #[cfg(test)]
+    pub fn parse_config(content: &str) -> Vec<Source> {
+        Self::parse_config_with_schemes(content, DEFAULT_SOURCE_SCHEMES).sources
     }
 
     /// Parse sources from config file content
     /// Format: url|name|category or url|name or just url
-    /// Deduplicates by URL (first occurrence wins)
-    pub fn parse_config(content: &str) -> Vec<Source> {
+    /// Trailing `key=value` fields set per-source options: url|name|category|json=field.path
+    /// Deduplicates by URL, method and body (first occurrence wins)
+    /// Sources whose scheme isn't in `schemes` are dropped with a warning
+    pub fn parse_config_with_schemes<S: AsRef<str>>(content: &str, schemes: &[S]) -> ParsedConfig {
         let mut sources = Vec::new();
-        let mut seen_urls = std::collections::HashSet::new();
+        let mut warnings = Vec::new();
+        let mut seen = std::collections::HashSet::new();
 
         for line in content.lines() {
             let line = line.trim();
@@ -315,23 +1106,21 @@ impl Downloader {
             let url = parts[0].trim();
 
             // Validate URL first
-            if url::Url::parse(url).is_err() {
+            let Ok(parsed) = url::Url::parse(url) else {
                 continue;
-            }
-
-            // Skip duplicate URLs
-            if seen_urls.contains(url) {
+            };
+            if !schemes.iter().any(|s| s.as_ref() == parsed.scheme()) {
+                warnings.push(format!("Skipped {}: scheme '{}' is not allowed", url, parsed.scheme()));
                 continue;
             }
-            seen_urls.insert(url.to_string());
 
             let name = if parts.len() > 1 {
                 parts[1].trim().to_string()
             } else {
                 // Use URL domain as name
-                url::Url::parse(url)
-                    .ok()
-                    .and_then(|u| u.host_str().map(String::from))
+                parsed
+                    .host_str()
+                    .map(String::from)
                     .unwrap_or_else(|| "Unknown".to_string())
             };
 
@@ -341,35 +1130,180 @@ impl Downloader {
                 None
             };
 
+            // Per-source options
+            let mut json_selector = None;
+            let mut priority = 0;
+            let mut method = Method::GET;
+            let mut body = None;
+            let mut mirror_group = None;
+            let mut cache_ttl_override = None;
+            let mut pac = false;
+            for option in parts.iter().skip(3) {
+                match option.trim().split_once('=') {
+                    Some(("json", selector)) if !selector.trim().is_empty() => {
+                        json_selector = Some(selector.trim().to_string());
+                    }
+                    Some(("priority", value)) if value.trim().parse::<i32>().is_ok() => {
+                        priority = value.trim().parse().unwrap_or(0);
+                    }
+                    Some(("method", value)) if Method::from_bytes(value.trim().to_uppercase().as_bytes()).is_ok() => {
+                        method = Method::from_bytes(value.trim().to_uppercase().as_bytes()).unwrap_or(Method::GET);
+                    }
+                    Some(("body", value)) if !value.trim().is_empty() => {
+                        body = Some(value.trim().to_string());
+                    }
+                    Some(("mirror", group)) if !group.trim().is_empty() => {
+                        mirror_group = Some(group.trim().to_lowercase());
+                    }
+                    Some(("ttl", value)) if Self::parse_ttl(value).is_some() => {
+                        cache_ttl_override = Self::parse_ttl(value);
+                    }
+                    Some(("format", value)) if value.trim().eq_ignore_ascii_case("pac") => {
+                        pac = true;
+                    }
+                    _ => debug!("Ignoring unknown source option '{}' for {}", option.trim(), name),
+                }
+            }
+
+            // Skip duplicates (the same URL may be queried with different bodies)
+            let request_key = format!("{} {} {}", method, url, body.as_deref().unwrap_or(""));
+            if !seen.insert(request_key) {
+                continue;
+            }
+
             sources.push(Source {
                 name,
                 url: url.to_string(),
                 category,
+                json_selector,
+                priority,
+                method,
+                body,
+                mirror_group,
+                mirrors: Vec::new(),
+                cache_ttl_override,
+                pac,
             });
         }
 
-        sources
+        ParsedConfig {
+            sources: Self::group_mirrors(sources),
+            warnings,
+        }
+    }
+
+    /// Parse a `ttl=` option: a positive count of minutes, hours or days (`30m`, `6h`, `30d`)
+    pub fn parse_ttl(value: &str) -> Option<chrono::Duration> {
+        let value = value.trim();
+        let unit_at = value.find(|c: char| !c.is_ascii_digit())?;
+        let amount: i64 = value[..unit_at].parse().ok().filter(|&n| n > 0)?;
+        match &value[unit_at..] {
+            "m" => Some(chrono::Duration::minutes(amount)),
+            "h" => Some(chrono::Duration::hours(amount)),
+            "d" => Some(chrono::Duration::days(amount)),
+            _ => None,
+        }
     }
 
-    /// Update domain count in cache after extraction
-    pub async fn update_domain_count(&self, url_hash: &str, domain_count: u64) -> Result<()> {
+    /// Fold each mirror group into its first source, keeping the rest as its fallbacks
+    fn group_mirrors(sources: Vec<Source>) -> Vec<Source> {
+        let mut grouped: Vec<Source> = Vec::with_capacity(sources.len());
+        let mut primaries: HashMap<String, usize> = HashMap::new();
+        for source in sources {
+            let Some(group) = source.mirror_group.clone() else {
+                grouped.push(source);
+                continue;
+            };
+            match primaries.get(&group) {
+                Some(&idx) => grouped[idx].mirrors.push(source),
+                None => {
+                    primaries.insert(group, grouped.len());
+                    grouped.push(source);
+                }
+            }
+        }
+        grouped
+    }
+
+    /// Download a source, falling back to its mirrors in order until one succeeds
+    ///
+    /// A mirror's result is reported as the primary's (`source_id`), so the
+    /// group stays one source in progress and stats; a warning names the mirror
+    /// that served it. `url_hash` stays the mirror's, whose cache entry holds the content.
+    async fn download_with_mirrors(&self, source: &Source, force: bool, report: Option<&ReportBytes<'_>>) -> DownloadResult {
+        Self::first_success(source, self.config.redact_source_urls, |candidate| {
+            self.download_source(candidate, force, report)
+        })
+        .await
+    }
+
+    /// Run `download` on a source and then its mirrors, stopping at the first success
+    async fn first_success<'s, F, Fut>(source: &'s Source, redact: bool, mut download: F) -> DownloadResult
+    where
+        F: FnMut(&'s Source) -> Fut,
+        Fut: std::future::Future<Output = DownloadResult>,
+    {
+        let mut result = download(source).await;
+        for mirror in &source.mirrors {
+            let Some(error) = result.error.clone() else {
+                break;
+            };
+            warn!(
+                "{}",
+                loggable(
+                    &format!("{} failed ({}), trying mirror {}", source.name, error, mirror.url),
+                    redact
+                )
+            );
+            result = download(mirror).await;
+            if result.error.is_none() {
+                result.warnings.push(format!("Primary {} failed ({}); served by mirror {}", source.url, error, mirror.url));
+                result.source = source.clone();
+                result.primary_hash = Some(Self::cache_key(source));
+            }
+        }
+        result
+    }
+
+    /// Update domain count (and format breakdown) in cache after extraction
+    pub async fn update_domain_count(&self, url_hash: &str, domain_count: u64, format_breakdown: &FormatBreakdown) -> Result<()> {
         self.cache_repo
-            .update_domain_count(url_hash, domain_count as i64)
+            .update_domain_count(url_hash, domain_count as i64, format_breakdown)
             .await
     }
 
-    /// Clean up old cache entries
+    /// A source's recent domain counts, oldest first
+    pub async fn domain_history(&self, url_hash: &str) -> Result<Vec<DomainCountSample>> {
+        self.cache_repo.get_domain_history(url_hash).await
+    }
+
+    /// What the previous build extracted from a source
+    pub async fn last_extraction(&self, url_hash: &str) -> Result<Option<ExtractionSummary>> {
+        self.cache_repo.get_last_extraction(url_hash).await
+    }
+
+    /// Clean up old cache entries, then evict down to the size budget
     pub async fn cleanup_cache(&self) -> Result<u64> {
-        self.cache_repo
+        let mut cleaned = self
+            .cache_repo
             .cleanup_stale(self.config.cache_ttl_days as i64)
-            .await
+            .await?;
+
+        if self.config.max_cache_size_bytes > 0 {
+            cleaned += self
+                .cache_repo
+                .cleanup_by_size(self.config.max_cache_size_bytes)
+                .await?;
+        }
+
+        Ok(cleaned)
     }
 
     /// Check if all sources would be cache hits (for "no changes" detection)
     pub async fn check_all_cached(&self, sources: &[Source]) -> bool {
         for source in sources {
-            let url_hash = Self::hash_url(&source.url);
-            match self.cache_repo.has_valid_cache(&url_hash).await {
+            let url_hash = Self::cache_key(source);
+            match self.cache_repo.has_valid_cache(&url_hash, source.cache_ttl_override).await {
                 Ok(true) => continue,
                 Ok(false) => {
                     debug!("Source {} not cached or cache expired", source.name);
@@ -384,3 +1318,774 @@ impl Downloader {
         true
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex as StdMutex};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    /// Serve a fixed redirect map on localhost: path -> (status line, headers, body)
+    async fn spawn_server(routes: Vec<(&'static str, &'static str, &'static str)>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            loop {
+                let (mut socket, _) = match listener.accept().await {
+                    Ok(conn) => conn,
+                    Err(_) => break,
+                };
+                let mut buf = vec![0u8; 4096];
+                let n = socket.read(&mut buf).await.unwrap_or(0);
+                let request = String::from_utf8_lossy(&buf[..n]);
+                let path = request.split_whitespace().nth(1).unwrap_or("/").to_string();
+
+                let response = match routes.iter().find(|(p, _, _)| *p == path) {
+                    Some((_, head, body)) => format!(
+                        "{}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                        head,
+                        body.len(),
+                        body
+                    ),
+                    None => "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_string(),
+                };
+                let _ = socket.write_all(response.as_bytes()).await;
+            }
+        });
+
+        format!("http://{}", addr)
+    }
+
+    /// Serve `body` but drop the connection after `cut` bytes unless the request resumes with Range
+    async fn spawn_flaky_server(body: &'static str, cut: usize, accept_ranges: bool) -> (String, Arc<StdMutex<Vec<String>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let requests = Arc::new(StdMutex::new(Vec::new()));
+        let seen = Arc::clone(&requests);
+
+        tokio::spawn(async move {
+            while let Ok((mut socket, _)) = listener.accept().await {
+                let mut buf = vec![0u8; 4096];
+                let n = socket.read(&mut buf).await.unwrap_or(0);
+                let request = String::from_utf8_lossy(&buf[..n]).to_lowercase();
+                seen.lock().unwrap().push(request.clone());
+
+                let range_start = request
+                    .lines()
+                    .find_map(|l| l.strip_prefix("range: bytes="))
+                    .and_then(|r| r.trim_end_matches('-').parse::<usize>().ok());
+                let response = match range_start {
+                    Some(start) => format!(
+                        "HTTP/1.1 206 Partial Content\r\nContent-Range: bytes {}-{}/{}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                        start,
+                        body.len() - 1,
+                        body.len(),
+                        body.len() - start,
+                        &body[start..]
+                    ),
+                    None => format!(
+                        "HTTP/1.1 200 OK\r\nContent-Length: {}\r\n{}ETag: \"v1\"\r\nConnection: close\r\n\r\n{}",
+                        body.len(),
+                        if accept_ranges { "Accept-Ranges: bytes\r\n" } else { "" },
+                        &body[..cut]
+                    ),
+                };
+                let _ = socket.write_all(response.as_bytes()).await;
+            }
+        });
+
+        (format!("http://{}/list.txt", addr), requests)
+    }
+
+    #[tokio::test]
+    async fn test_dropped_download_resumes_with_range() {
+        const BODY: &str = "ads.example.com\ntracker.example.com\nmalware.example.net\n";
+        let client = Downloader::build_client(&test_config(5)).unwrap();
+
+        let (url, requests) = spawn_flaky_server(BODY, 20, true).await;
+        let body = Downloader::download_body(&client, FetchRequest::get(&url), 5, &mut None, None).await.unwrap();
+        assert_eq!(body.content, BODY.as_bytes());
+        assert!(body.warnings.iter().any(|w| w.contains("resumed 1 time")));
+
+        // The resume asked for the remainder, guarded by the first response's ETag
+        let requests = requests.lock().unwrap().clone();
+        assert_eq!(requests.len(), 2);
+        assert!(requests[1].contains("range: bytes=20-"));
+        assert!(requests[1].contains("if-range: \"v1\""));
+
+        // Without Accept-Ranges the drop is a plain failure
+        let (url, requests) = spawn_flaky_server(BODY, 20, false).await;
+        assert!(Downloader::download_body(&client, FetchRequest::get(&url), 5, &mut None, None).await.is_err());
+        assert_eq!(requests.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_parse_config_json_option() {
+        let sources = Downloader::parse_config(
+            "https://intel.example.com/feed.json|Intel|malware|json=indicator.domain\n\
+             https://lists.example.com/hosts.txt|Hosts|ads",
+        );
+        assert_eq!(sources.len(), 2);
+        assert_eq!(sources[0].json_selector.as_deref(), Some("indicator.domain"));
+        assert_eq!(sources[0].category.as_deref(), Some("malware"));
+        assert_eq!(sources[1].json_selector, None);
+    }
+
+    fn test_config(max_redirects: usize) -> Config {
+        let mut config = Config::from_env();
+        config.max_redirects = max_redirects;
+        config.http_timeout_secs = 5;
+        config
+    }
+
+    #[tokio::test]
+    async fn test_redirect_chain() {
+        let base = spawn_server(vec![
+            ("/old", "HTTP/1.1 301 Moved Permanently\r\nLocation: /middle", ""),
+            ("/middle", "HTTP/1.1 302 Found\r\nLocation: /new", ""),
+            ("/new", "HTTP/1.1 200 OK\r\nContent-Type: text/plain", "ads.example.com\n"),
+            ("/loop", "HTTP/1.1 302 Found\r\nLocation: /loop", ""),
+            ("/private", "HTTP/1.1 302 Found\r\nLocation: /login", ""),
+            ("/login", "HTTP/1.1 200 OK\r\nContent-Type: text/html; charset=utf-8", "<html>Sign in</html>"),
+        ])
+        .await;
+        let client = Downloader::build_client(&test_config(5)).unwrap();
+
+        // Followed chain reports the final URL as a warning
+        let requested = format!("{}/old", base);
+        let response = client.get(&requested).send().await.unwrap();
+        let warning = Downloader::check_redirect(&requested, &response).unwrap().unwrap();
+        assert!(warning.contains(&format!("{}/new", base)));
+
+        // Redirect loops stop at the configured limit
+        let err = client.get(format!("{}/loop", base)).send().await.unwrap_err();
+        assert!(err.is_redirect());
+
+        // Redirect to an HTML login page is an error
+        let requested = format!("{}/private", base);
+        let response = client.get(&requested).send().await.unwrap();
+        let err = Downloader::check_redirect(&requested, &response).unwrap_err();
+        assert!(err.to_string().contains("HTML page"));
+
+        // No redirect, no warning
+        let requested = format!("{}/new", base);
+        let response = client.get(&requested).send().await.unwrap();
+        assert!(Downloader::check_redirect(&requested, &response).unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_stale_if_error_serves_cached_copy() {
+        let base = spawn_server(vec![("/list.txt", "HTTP/1.1 403 Forbidden\r\nContent-Type: text/plain", "denied")]).await;
+        let client = Downloader::build_client(&test_config(5)).unwrap();
+        let url = format!("{}/list.txt", base);
+
+        let err = Downloader::download_body(&client, FetchRequest::get(&url), 5, &mut None, None).await.unwrap_err();
+        assert_eq!(Downloader::stale_if_error_status(&err, &[403, 503]), Some(403));
+        assert_eq!(Downloader::stale_if_error_status(&err, &[503]), None);
+        assert_eq!(Downloader::stale_if_error_status(&anyhow::anyhow!("timeout"), &[403]), None);
+
+        let source = Source {
+            name: "CDN".to_string(),
+            url,
+            category: None,
+            json_selector: None,
+            priority: 0,
+            method: Method::GET,
+            body: None,
+            mirror_group: None,
+            mirrors: Vec::new(),
+            cache_ttl_override: None,
+            pac: false,
+        };
+        let stale = b"ads.example.com\n".to_vec();
+        let result = Downloader::stale_result(&source, "hash".to_string(), stale.clone(), 403, Instant::now(), Vec::new());
+        assert_eq!(result.content, Some(stale));
+        assert!(result.cache_hit);
+        assert!(result.error.is_none());
+        assert!(result.warnings[0].contains("HTTP 403"));
+    }
+
+    #[test]
+    fn test_image_content_type_rejected() {
+        let blocked = test_config(10).blocked_content_types;
+
+        let err = Downloader::check_content_type(Some("image/png"), b"\x89PNG", &blocked, false).unwrap_err();
+        assert!(err.to_string().contains("image/png"));
+
+        // Text and default-lenient types pass
+        assert!(Downloader::check_content_type(Some("text/plain; charset=utf-8"), b"a.com", &blocked, false).is_ok());
+        assert!(Downloader::check_content_type(Some("application/octet-stream"), b"a.com", &blocked, false).is_ok());
+        assert!(Downloader::check_content_type(None, b"a.com", &blocked, false).is_ok());
+
+        // Opted-in octet-stream/JSON blocks still allow gzip bodies and JSON sources
+        let strict = vec!["application/octet-stream".to_string(), "application/json".to_string()];
+        assert!(Downloader::check_content_type(Some("application/octet-stream"), b"a.com", &strict, false).is_err());
+        assert!(Downloader::check_content_type(Some("application/octet-stream"), &[0x1f, 0x8b, 8], &strict, false).is_ok());
+        assert!(Downloader::check_content_type(Some("application/json"), b"{}", &strict, false).is_err());
+        assert!(Downloader::check_content_type(Some("application/json"), b"[]", &strict, true).is_ok());
+    }
+
+    #[test]
+    fn test_multi_member_gzip_fully_decompressed() {
+        use flate2::{write::GzEncoder, Compression};
+        use std::io::Write;
+
+        let member = |text: &str| {
+            let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+            encoder.write_all(text.as_bytes()).unwrap();
+            encoder.finish().unwrap()
+        };
+        let mut body = member("ads.example.com\ntracker.example.com\n");
+        body.extend(member("malware.example.net\n"));
+
+        let content = Downloader::decompress_if_gzip(body).unwrap();
+        assert_eq!(
+            String::from_utf8(content).unwrap(),
+            "ads.example.com\ntracker.example.com\nmalware.example.net\n"
+        );
+
+        // Plain bodies pass through untouched
+        assert_eq!(Downloader::decompress_if_gzip(b"a.com\n".to_vec()).unwrap(), b"a.com\n");
+    }
+
+    #[test]
+    fn test_empty_download_with_previous_count_rejected() {
+        let err = Downloader::check_empty(Some(5000), true).unwrap_err();
+        assert!(err.to_string().contains("5000"));
+
+        // Small or unknown history, or the check disabled: just a warning
+        assert!(Downloader::check_empty(Some(3), true).is_ok());
+        assert!(Downloader::check_empty(None, true).is_ok());
+        assert!(Downloader::check_empty(Some(5000), false).is_ok());
+    }
+
+    #[test]
+    fn test_higher_priority_sources_dispatch_first() {
+        let sources = Downloader::parse_config(
+            "https://example.com/ads1.txt|Ads 1|ads\n\
+             https://example.com/malware.txt|Malware|malware|priority=10\n\
+             https://example.com/ads2.txt|Ads 2|ads\n\
+             https://example.com/slow.txt|Slow|misc|priority=-5\n\
+             https://example.com/phish.txt|Phishing|phishing|priority=10\n\
+             https://example.com/bad.txt|Bad|ads|priority=high",
+        );
+        assert_eq!(sources[1].priority, 10);
+        assert_eq!(sources[5].priority, 0);
+
+        let names: Vec<&str> = Downloader::dispatch_order(&sources)
+            .into_iter()
+            .map(|idx| sources[idx].name.as_str())
+            .collect();
+        assert_eq!(names, vec!["Malware", "Phishing", "Ads 1", "Ads 2", "Bad", "Slow"]);
+    }
+
+    #[tokio::test]
+    async fn test_prewarm_resolves_distinct_hosts() {
+        let source = |url: &str| Source {
+            name: url.to_string(),
+            url: url.to_string(),
+            category: None,
+            json_selector: None,
+            priority: 0,
+            method: Method::GET,
+            body: None,
+            mirror_group: None,
+            mirrors: Vec::new(),
+            cache_ttl_override: None,
+            pac: false,
+        };
+        let sources = vec![
+            source("http://localhost/a.txt"),
+            source("http://localhost/b.txt"),
+            source("http://127.0.0.1:8080/c.txt"),
+            source("not a url"),
+        ];
+
+        assert_eq!(
+            Downloader::source_hosts(&sources),
+            vec![("localhost".to_string(), 80), ("127.0.0.1".to_string(), 8080)]
+        );
+        assert_eq!(Downloader::prewarm_dns(&sources, 4).await, 2);
+    }
+
+    #[tokio::test]
+    async fn test_error_kind_preserved_through_download() {
+        let base = spawn_server(vec![]).await;
+        let client = Downloader::build_client(&test_config(5)).unwrap();
+        let source = Source {
+            name: "Gone".to_string(),
+            url: format!("{}/missing.txt", base),
+            category: None,
+            json_selector: None,
+            priority: 0,
+            method: Method::GET,
+            body: None,
+            mirror_group: None,
+            mirrors: Vec::new(),
+            cache_ttl_override: None,
+            pac: false,
+        };
+
+        let err = Downloader::download_body(&client, FetchRequest::of(&source), 5, &mut None, None)
+            .await
+            .context("Failed to fetch Gone")
+            .unwrap_err();
+        let result = Downloader::failed_result(&source, "hash".to_string(), &err, Instant::now(), Vec::new());
+        assert_eq!(result.error_kind, Some(ErrorKind::HttpStatus(404)));
+        assert!(result.error.unwrap().contains("Failed to fetch Gone"));
+
+        let err = anyhow::Error::from(Downloader::check_size(MAX_SOURCE_SIZE_BYTES + 1).unwrap_err())
+            .context("Decompressed source exceeds size limit");
+        assert_eq!(ErrorKind::of(&err), ErrorKind::SourceTooLarge);
+        assert!(Downloader::check_size(MAX_SOURCE_SIZE_BYTES).is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_client_with_custom_pool_settings() {
+        let base = spawn_server(vec![("/list.txt", "HTTP/1.1 200 OK\r\nContent-Type: text/plain", "ads.example.com\n")]).await;
+        let mut config = test_config(5);
+        config.pool_max_idle_per_host = 2;
+        config.pool_idle_timeout_secs = 5;
+        let client = Downloader::build_client(&config).unwrap();
+
+        let url = format!("{}/list.txt", base);
+        let body = Downloader::download_body(&client, FetchRequest::get(&url), 5, &mut None, None).await.unwrap();
+        assert_eq!(body.content, b"ads.example.com\n");
+
+        // Prior knowledge skips negotiation, so an HTTP/1-only server can't be reached
+        config.http2_prior_knowledge = true;
+        let h2_client = Downloader::build_client(&config).unwrap();
+        assert!(Downloader::download_body(&h2_client, FetchRequest::get(&url), 5, &mut None, None).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_cancel_stops_further_downloads() {
+        // Every response takes a while; count how many requests actually arrive
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base = format!("http://{}", listener.local_addr().unwrap());
+        let requests = Arc::new(StdMutex::new(0usize));
+        let seen = Arc::clone(&requests);
+        tokio::spawn(async move {
+            while let Ok((mut socket, _)) = listener.accept().await {
+                *seen.lock().unwrap() += 1;
+                tokio::spawn(async move {
+                    let mut buf = vec![0u8; 4096];
+                    let _ = socket.read(&mut buf).await;
+                    tokio::time::sleep(Duration::from_secs(5)).await;
+                    let _ = socket
+                        .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\nConnection: close\r\n\r\na\n")
+                        .await;
+                });
+            }
+        });
+
+        let client = mongodb::Client::with_uri_str("mongodb://127.0.0.1:1/?serverSelectionTimeoutMS=200")
+            .await
+            .unwrap();
+        let mut config = test_config(5);
+        config.max_concurrent_downloads = 1;
+        config.prewarm_dns = false;
+        let downloader = Downloader::new(config, &client.database("cancel_test")).unwrap();
+
+        let sources: Vec<Source> = (0..5)
+            .map(|i| Source {
+                name: format!("Slow {}", i),
+                url: format!("{}/list{}.txt", base, i),
+                category: None,
+                json_selector: None,
+                priority: 0,
+                method: Method::GET,
+                body: None,
+                mirror_group: None,
+                mirrors: Vec::new(),
+                cache_ttl_override: None,
+                pac: false,
+            })
+            .collect();
+
+        let cancel = CancelToken::default();
+        let trigger = cancel.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(200)).await;
+            trigger.cancel();
+        });
+
+        let start = Instant::now();
+        let results = downloader.download_sources(sources, true, &cancel, |_, _| {}).await;
+
+        assert!(results.is_empty());
+        assert!(start.elapsed() < Duration::from_secs(2), "took {:?}", start.elapsed());
+        // Only the first download started; the queued ones never hit the network
+        assert_eq!(*requests.lock().unwrap(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_post_source_fetched_with_body_aware_key() {
+        let sources = Downloader::parse_config(
+            "https://api.example.com/feed|Feed A|malware|method=post|body={\"type\":\"domain\",\"days\":7}\n\
+             https://api.example.com/feed|Feed B|malware|method=POST|body={\"type\":\"domain\",\"days\":30}\n\
+             https://api.example.com/feed|Feed A again|malware|method=POST|body={\"type\":\"domain\",\"days\":7}\n\
+             https://api.example.com/feed|Static",
+        );
+        assert_eq!(sources.len(), 3);
+        assert_eq!(sources[0].method, Method::POST);
+        assert_eq!(sources[0].body.as_deref(), Some("{\"type\":\"domain\",\"days\":7}"));
+        assert_eq!(sources[2].method, Method::GET);
+
+        // Each body caches separately; plain GETs keep the URL-only key
+        let keys: HashSet<String> = sources.iter().map(Downloader::cache_key).collect();
+        assert_eq!(keys.len(), 3);
+        assert_eq!(Downloader::cache_key(&sources[2]), Downloader::hash_url("https://api.example.com/feed"));
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut request = Vec::new();
+            let mut buf = [0u8; 4096];
+            while !String::from_utf8_lossy(&request).ends_with('}') {
+                let n = socket.read(&mut buf).await.unwrap();
+                if n == 0 {
+                    break;
+                }
+                request.extend_from_slice(&buf[..n]);
+            }
+            let body = "ads.example.com\n";
+            let response = format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}", body.len(), body);
+            socket.write_all(response.as_bytes()).await.unwrap();
+            String::from_utf8(request).unwrap()
+        });
+
+        let mut source = sources[0].clone();
+        source.url = format!("http://{}/feed", addr);
+        let client = Downloader::build_client(&test_config(5)).unwrap();
+        let body = Downloader::download_body(&client, FetchRequest::of(&source), 5, &mut None, None).await.unwrap();
+        assert_eq!(body.content, b"ads.example.com\n");
+
+        let request = server.await.unwrap();
+        assert!(request.starts_with("POST /feed "));
+        assert!(request.to_lowercase().contains("content-type: application/json"));
+        assert!(request.ends_with("{\"type\":\"domain\",\"days\":7}"));
+    }
+
+    #[test]
+    fn test_expires_directive_sets_source_cache_window() {
+        let list = |expires: &str| format!("[Adblock Plus 2.0]\n! Version: 202401010000\n{}\n! Title: Ads\n||ads.com^\n", expires);
+        let expiry = |content: String| Downloader::subscription_expiry(content.as_bytes());
+
+        assert_eq!(expiry(list("! Expires: 1 day")), Some(chrono::Duration::days(1)));
+        assert_eq!(expiry(list("! Expires: 12 hours (update frequency)")), Some(chrono::Duration::hours(12)));
+        assert_eq!(expiry(list("! Expires: 4 days")), Some(chrono::Duration::days(4)));
+        // Clamped like Adblock Plus does
+        assert_eq!(expiry(list("! Expires: 90 days")), Some(chrono::Duration::days(14)));
+        assert_eq!(expiry(list("! Expires: soon")), None);
+        assert_eq!(expiry(list("")), None);
+        // Only the header counts, not a rule body that happens to mention it
+        assert_eq!(expiry("||ads.com^\n! Expires: 1 day\n".to_string()), None);
+
+        // The window decides whether the cached copy is still a hit
+        let now = bson::DateTime::from_millis(10 * 3_600_000);
+        let mut entry: crate::db::cache::CacheEntry = bson::from_document(bson::doc! { "url_hash": "abc" }).unwrap();
+        assert!(entry.is_fresh(now, None));
+        entry.expires_at = Some(bson::DateTime::from_millis(now.timestamp_millis() + 3_600_000));
+        assert!(entry.is_fresh(now, None));
+        entry.expires_at = Some(bson::DateTime::from_millis(now.timestamp_millis() - 1));
+        assert!(!entry.is_fresh(now, None));
+    }
+
+    #[test]
+    fn test_domain_count_estimate_skips_comments() {
+        let content = "# Title: Example hosts\r\n\
+                       # Updated: 2024-01-01\r\n\
+                       \r\n\
+                       0.0.0.0 ads.example.com\r\n\
+                       0.0.0.0 tracker.example.com # inline note\r\n\
+                       \t\r\n\
+                       ! adblock-style comment\n\
+                       ||metrics.example.net^\n\
+                       malware.example.org\n\
+                       \n";
+
+        let newlines = content.matches('\n').count() as i64;
+        let estimate = Downloader::estimate_domain_count(content.as_bytes());
+        let extracted = crate::extractor::DomainExtractor::new().extract_from_content(content).len() as i64;
+
+        assert_eq!(newlines, 10);
+        assert_eq!(estimate, 4);
+        assert_eq!(estimate, extracted);
+    }
+
+    #[test]
+    fn test_disallowed_scheme_rejected_with_reason() {
+        let content = "ftp://example.com/list.txt|FTP\nhttps://example.com/list.txt|HTTPS";
+
+        let parsed = Downloader::parse_config_with_schemes(content, DEFAULT_SOURCE_SCHEMES);
+        assert_eq!(parsed.sources.len(), 1);
+        assert_eq!(parsed.sources[0].name, "HTTPS");
+        assert_eq!(
+            parsed.warnings,
+            vec!["Skipped ftp://example.com/list.txt: scheme 'ftp' is not allowed".to_string()]
+        );
+
+        // Operators can widen the set
+        let parsed = Downloader::parse_config_with_schemes(content, &["ftp", "https"]);
+        assert_eq!(parsed.sources.len(), 2);
+        assert!(parsed.warnings.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_intermediate_download_progress_reported() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        use tokio::net::TcpListener;
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/big.txt", listener.local_addr().unwrap());
+
+        // Send the body in two parts with a pause longer than the report interval
+        tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut buf = [0u8; 1024];
+            let _ = socket.read(&mut buf).await;
+            socket
+                .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 40\r\nConnection: close\r\n\r\n")
+                .await
+                .unwrap();
+            socket.write_all(b"ads.example.com\n").await.unwrap();
+            socket.flush().await.unwrap();
+            tokio::time::sleep(BYTE_PROGRESS_INTERVAL * 2).await;
+            socket.write_all(b"tracker.example.net\nx.io\n").await.unwrap();
+        });
+
+        let reports = StdMutex::new(Vec::new());
+        let report = |bytes: u64, total: Option<u64>| reports.lock().unwrap().push((bytes, total));
+        let client = Downloader::build_client(&test_config(5)).unwrap();
+        let body = Downloader::download_body(&client, FetchRequest::get(&url), 5, &mut None, Some(&report))
+            .await
+            .unwrap();
+        assert_eq!(body.content.len(), 40);
+
+        // Reported while the download was still running, then again at the end
+        let reports = reports.into_inner().unwrap();
+        assert_eq!(reports, vec![(16, Some(40)), (40, Some(40))]);
+        assert_eq!(Downloader::download_percent(16, Some(40)), Some(40.0));
+        assert_eq!(Downloader::download_percent(16, None), None);
+    }
+
+    /// Captures formatted log output for assertions
+    #[derive(Clone, Default)]
+    struct LogCapture(Arc<StdMutex<Vec<u8>>>);
+
+    impl std::io::Write for LogCapture {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn test_redacted_source_urls_kept_out_of_logs() {
+        // Nothing listens on either port: the cache read and the fetch both fail
+        let client = mongodb::Client::with_uri_str("mongodb://127.0.0.1:1/?serverSelectionTimeoutMS=200")
+            .await
+            .unwrap();
+        let db = client.database("redact_test");
+        let port = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap().port();
+        let url = format!("http://127.0.0.1:{}/private/feed.txt?token=s3cret", port);
+        let source = Downloader::parse_config_with_schemes(&format!("{}|Private", url), DEFAULT_SOURCE_SCHEMES)
+            .sources
+            .remove(0);
+
+        let mut logs = Vec::new();
+        for redact in [false, true] {
+            let capture = LogCapture::default();
+            let writer = capture.clone();
+            let subscriber = tracing_subscriber::fmt()
+                .with_max_level(tracing::Level::DEBUG)
+                .with_ansi(false)
+                .with_writer(move || writer.clone())
+                .finish();
+            let _guard = tracing::subscriber::set_default(subscriber);
+
+            let mut config = test_config(5);
+            config.redact_source_urls = redact;
+            let result = Downloader::new(config, &db).unwrap().download_source(&source, false, None).await;
+            assert!(result.error.is_some());
+            logs.push(String::from_utf8(capture.0.lock().unwrap().clone()).unwrap());
+        }
+
+        let (plain, redacted) = (&logs[0], &logs[1]);
+        assert!(plain.contains(&format!("Downloading Private from {}", url)));
+        assert!(plain.contains("Failed to download Private"));
+
+        assert!(redacted.contains(&format!("Downloading Private from 127.0.0.1/[{}]", &Downloader::hash_url(&url)[..12])));
+        assert!(redacted.contains("Failed to download Private"));
+        assert!(!redacted.contains("/private/feed.txt"));
+        assert!(!redacted.contains("s3cret"));
+    }
+
+    #[tokio::test]
+    async fn test_failed_primary_falls_back_to_mirror() {
+        let config = "https://primary.example.com/hosts|Steven|ads|mirror=steven\n\
+                      https://other.example.com/list.txt|Other\n\
+                      https://mirror-a.example.com/hosts|Steven (A)|ads|mirror=Steven\n\
+                      https://mirror-b.example.com/hosts|Steven (B)|ads|mirror=steven\n\
+                      https://mirror-c.example.com/hosts|Steven (C)|ads|mirror=steven\n";
+        let sources = Downloader::parse_config(config);
+
+        // The group folds into its first source
+        assert_eq!(sources.len(), 2);
+        assert_eq!(sources[0].name, "Steven");
+        let mirrors: Vec<&str> = sources[0].mirrors.iter().map(|m| m.name.as_str()).collect();
+        assert_eq!(mirrors, vec!["Steven (A)", "Steven (B)", "Steven (C)"]);
+        assert!(sources[1].mirrors.is_empty());
+
+        // Primary and first mirror fail; the second mirror serves and the third is never tried
+        let tried = StdMutex::new(Vec::new());
+        let result = Downloader::first_success(&sources[0], false, |candidate| {
+            tried.lock().unwrap().push(candidate.name.clone());
+            let url_hash = Downloader::cache_key(candidate);
+            let result = if candidate.name == "Steven (B)" {
+                DownloadResult {
+                    source: candidate.clone(),
+                    url_hash,
+                    primary_hash: None,
+                    content: Some(b"ads.example.com\n".to_vec()),
+                    cache_hit: false,
+                    bytes_downloaded: 16,
+                    download_time_ms: 1,
+                    error: None,
+                    error_kind: None,
+                    warnings: Vec::new(),
+                    previous_domain_count: None,
+                    extracted: None,
+                }
+            } else {
+                let err = anyhow::anyhow!("HTTP 503 for {}", candidate.url);
+                Downloader::failed_result(candidate, url_hash, &err, Instant::now(), Vec::new())
+            };
+            std::future::ready(result)
+        })
+        .await;
+
+        assert_eq!(*tried.lock().unwrap(), vec!["Steven", "Steven (A)", "Steven (B)"]);
+        assert!(result.error.is_none());
+        assert_eq!(result.content.as_deref(), Some(&b"ads.example.com\n"[..]));
+        // Reported as the primary, with a warning naming the mirror
+        assert_eq!(result.source.name, "Steven");
+        assert_eq!(result.source_id(), Downloader::cache_key(&sources[0]));
+        // The cache entry stays the mirror's, as that is where the content came from
+        assert_eq!(result.url_hash, Downloader::cache_key(&sources[0].mirrors[1]));
+        assert!(result.warnings.iter().any(|w| w.contains("served by mirror https://mirror-b.example.com/hosts")));
+    }
+
+    #[test]
+    fn test_source_ttl_overrides_cache_freshness() {
+        let sources = Downloader::parse_config(
+            "https://a.example/hourly.txt|Hourly|ads|ttl=1h\n\
+             https://a.example/monthly.txt|Monthly|ads|ttl=30d\n\
+             https://a.example/plain.txt|Plain|ads|ttl=soon\n",
+        );
+        assert_eq!(sources[0].cache_ttl_override, Some(chrono::Duration::hours(1)));
+        assert_eq!(sources[1].cache_ttl_override, Some(chrono::Duration::days(30)));
+        assert_eq!(sources[2].cache_ttl_override, None);
+
+        let hour = 3_600_000;
+        let now = bson::DateTime::from_millis(100 * hour);
+        let mut entry: crate::db::cache::CacheEntry = bson::from_document(bson::doc! { "url_hash": "abc" }).unwrap();
+        entry.stats.last_download_at = Some(bson::DateTime::from_millis(now.timestamp_millis() - 2 * hour));
+
+        // Without a directive the source TTL alone decides
+        assert!(entry.is_fresh(now, None));
+        assert!(!entry.is_fresh(now, sources[0].cache_ttl_override));
+        assert!(entry.is_fresh(now, sources[1].cache_ttl_override));
+
+        // A feed's Expires window is the default; the override can only tighten it
+        entry.expires_at = Some(bson::DateTime::from_millis(now.timestamp_millis() + 2 * hour));
+        assert!(entry.is_fresh(now, None));
+        assert!(!entry.is_fresh(now, sources[0].cache_ttl_override));
+        entry.expires_at = Some(bson::DateTime::from_millis(now.timestamp_millis() - 1));
+        assert!(!entry.is_fresh(now, sources[1].cache_ttl_override));
+    }
+
+    #[tokio::test]
+    async fn test_gridfs_store_failure_keeps_downloaded_content() {
+        // Nothing listens for MongoDB: the cache read and the GridFS store both fail
+        let client = mongodb::Client::with_uri_str("mongodb://127.0.0.1:1/?serverSelectionTimeoutMS=200")
+            .await
+            .unwrap();
+        let base = spawn_server(vec![("/list.txt", "HTTP/1.1 200 OK\r\nContent-Type: text/plain", "ads.example.com\n")]).await;
+        let source = Downloader::parse_config(&format!("{}/list.txt|Example", base)).remove(0);
+
+        let mut config = test_config(5);
+        config.streaming_extraction = false;
+        let downloader = Downloader::new(config, &client.database("store_failure_test")).unwrap();
+        let result = downloader.download_source(&source, false, None).await;
+
+        assert!(result.error.is_none(), "{:?}", result.error);
+        assert_eq!(result.content.as_deref(), Some(&b"ads.example.com\n"[..]));
+        assert!(!result.cache_hit);
+        assert!(result.warnings.iter().any(|w| w.starts_with("Not cached: Cache unavailable")));
+    }
+
+    /// Send `chunks` one by one, pausing `pause` before each after the first
+    async fn spawn_trickle_server(chunks: &'static [&'static str], pause: Duration) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut buf = [0u8; 1024];
+            let _ = socket.read(&mut buf).await;
+            let length: usize = chunks.iter().map(|c| c.len()).sum();
+            let head = format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n", length);
+            socket.write_all(head.as_bytes()).await.unwrap();
+            for (i, chunk) in chunks.iter().enumerate() {
+                if i > 0 {
+                    tokio::time::sleep(pause).await;
+                }
+                if socket.write_all(chunk.as_bytes()).await.is_err() {
+                    break;
+                }
+                socket.flush().await.unwrap();
+            }
+        });
+
+        format!("http://{}/list.txt", addr)
+    }
+
+    #[tokio::test]
+    async fn test_idle_timeout_only_fails_stalled_downloads() {
+        let mut config = test_config(5);
+        config.http_timeout_secs = 0;
+        let client = Downloader::build_client(&config).unwrap();
+        let idle = Some(Duration::from_millis(300));
+
+        // Slow but steady: the whole download outlasts the idle timeout, no single gap does
+        const CHUNKS: &[&str] = &["a.example.com\n", "b.example.com\n", "c.example.com\n", "d.example.com\n", "e.example.com\n"];
+        let url = spawn_trickle_server(CHUNKS, Duration::from_millis(150)).await;
+        let request = FetchRequest {
+            idle_timeout: idle,
+            ..FetchRequest::get(&url)
+        };
+        let body = Downloader::download_body(&client, request, 5, &mut None, None).await.unwrap();
+        assert_eq!(body.content, CHUNKS.concat().as_bytes());
+
+        // Stalled after the first chunk
+        const STALLED: &[&str] = &["a.example.com\n", "b.example.com\n"];
+        let url = spawn_trickle_server(STALLED, Duration::from_secs(5)).await;
+        let request = FetchRequest {
+            idle_timeout: idle,
+            ..FetchRequest::get(&url)
+        };
+        let started = Instant::now();
+        let err = Downloader::download_body(&client, request, 5, &mut None, None).await.unwrap_err();
+        assert!(format!("{:#}", err).contains("connection stalled"), "{:#}", err);
+        assert!(started.elapsed() < Duration::from_secs(2));
+    }
+}
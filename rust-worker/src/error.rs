@@ -0,0 +1,84 @@
+//! Typed errors for the downloader and processor
+//!
+//! Internals still return `anyhow::Result`; these errors travel inside it and
+//! are recovered with `ErrorKind::of`, so callers can branch on what failed
+//! without parsing messages.
+
+use thiserror::Error;
+
+/// Failure raised by the download and processing paths
+#[derive(Debug, Error)]
+pub enum WorkerError {
+    /// The source is bigger than the worker will hold in memory
+    #[error("Source file too large: {size} bytes (max {max} bytes)")]
+    SourceTooLarge { size: u64, max: u64 },
+
+    /// The source answered with a non-success HTTP status
+    #[error("HTTP {status} for {url}")]
+    HttpStatus { status: u16, url: String },
+
+    /// The MongoDB cache couldn't be read or written
+    #[error("Cache unavailable: {0:#}")]
+    CacheUnavailable(anyhow::Error),
+
+    /// Worker configuration that can't be used
+    #[error("Invalid config: {0}")]
+    ConfigInvalid(String),
+
+    /// The job ran past MAX_JOB_DURATION_SECS
+    #[error("Job exceeded time limit of {limit_secs}s")]
+    JobTimedOut { limit_secs: u64 },
+}
+
+/// What kind of failure an error is, without its details
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind {
+    SourceTooLarge,
+    HttpStatus(u16),
+    CacheUnavailable,
+    ConfigInvalid,
+    JobTimedOut,
+    /// Anything not raised as a `WorkerError` (network, parsing, ...)
+    Other,
+}
+
+impl WorkerError {
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            WorkerError::SourceTooLarge { .. } => ErrorKind::SourceTooLarge,
+            WorkerError::HttpStatus { status, .. } => ErrorKind::HttpStatus(*status),
+            WorkerError::CacheUnavailable(_) => ErrorKind::CacheUnavailable,
+            WorkerError::ConfigInvalid(_) => ErrorKind::ConfigInvalid,
+            WorkerError::JobTimedOut { .. } => ErrorKind::JobTimedOut,
+        }
+    }
+}
+
+impl ErrorKind {
+    /// Kind of the first `WorkerError` in an error's chain (context layers are skipped)
+    pub fn of(error: &anyhow::Error) -> ErrorKind {
+        error
+            .chain()
+            .find_map(|cause| cause.downcast_ref::<WorkerError>())
+            .map_or(ErrorKind::Other, WorkerError::kind)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Context;
+
+    #[test]
+    fn test_kind_survives_context() {
+        let err: anyhow::Result<()> = Err(WorkerError::HttpStatus {
+            status: 503,
+            url: "https://example.com/list.txt".to_string(),
+        }
+        .into());
+        let err = err.context("Failed to download Example").unwrap_err();
+
+        assert_eq!(ErrorKind::of(&err), ErrorKind::HttpStatus(503));
+        assert_eq!(ErrorKind::of(&anyhow::anyhow!("connection reset")), ErrorKind::Other);
+    }
+}
@@ -1,7 +1,13 @@
+use anyhow::{Context, Result};
+use encoding_rs::{Encoding, UTF_8, WINDOWS_1252};
 use rayon::prelude::*;
 use regex::Regex;
+use serde::de::{DeserializeSeed, SeqAccess, Visitor};
 use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
 use std::collections::HashSet;
+use std::net::IpAddr;
+use std::sync::{Arc, LazyLock};
 
 /// Result of extracting from a line
 #[derive(Debug, Clone, PartialEq)]
@@ -18,6 +24,15 @@ pub struct FormatBreakdown {
     pub hosts: u64,
     pub plain: u64,
     pub adblock: u64,
+    /// Domains selected from JSON-array sources
+    #[serde(default)]
+    pub json: u64,
+    /// Domains from dnsmasq `address=/d/` and `local=/d/` lines
+    #[serde(default)]
+    pub dnsmasq: u64,
+    /// Domains from `shExpMatch` / `dnsDomainIs` calls in PAC sources
+    #[serde(default)]
+    pub pac: u64,
 }
 
 impl FormatBreakdown {
@@ -33,12 +48,21 @@ impl FormatBreakdown {
         if self.adblock > 0 {
             formats.push("adblock".to_string());
         }
+        if self.json > 0 {
+            formats.push("json".to_string());
+        }
+        if self.dnsmasq > 0 {
+            formats.push("dnsmasq".to_string());
+        }
+        if self.pac > 0 {
+            formats.push("pac".to_string());
+        }
         formats
     }
 
     /// Get the primary (most common) format
     pub fn primary_format(&self) -> Option<&'static str> {
-        let max = self.hosts.max(self.plain).max(self.adblock);
+        let max = self.hosts.max(self.plain).max(self.adblock).max(self.json).max(self.dnsmasq).max(self.pac);
         if max == 0 {
             return None;
         }
@@ -46,10 +70,26 @@ impl FormatBreakdown {
             Some("hosts")
         } else if self.adblock == max {
             Some("adblock")
-        } else {
+        } else if self.plain == max {
             Some("plain")
+        } else if self.json == max {
+            Some("json")
+        } else if self.dnsmasq == max {
+            Some("dnsmasq")
+        } else {
+            Some("pac")
         }
     }
+
+    /// Add another breakdown's counts to this one
+    pub fn merge(&mut self, other: &FormatBreakdown) {
+        self.hosts += other.hosts;
+        self.plain += other.plain;
+        self.adblock += other.adblock;
+        self.json += other.json;
+        self.dnsmasq += other.dnsmasq;
+        self.pac += other.pac;
+    }
 }
 
 /// Result of extraction with format breakdown
@@ -59,86 +99,280 @@ pub struct ExtractionOutput {
     pub format_breakdown: FormatBreakdown,
 }
 
+/// Streams a top-level JSON array, keeping only the selected field of each element
+struct JsonSelectorSeq<'a> {
+    path: &'a [&'a str],
+    results: &'a mut Vec<ExtractionResult>,
+}
+
+impl<'de> DeserializeSeed<'de> for JsonSelectorSeq<'_> {
+    type Value = ();
+
+    fn deserialize<D: serde::Deserializer<'de>>(self, deserializer: D) -> Result<(), D::Error> {
+        deserializer.deserialize_seq(self)
+    }
+}
+
+impl<'de> Visitor<'de> for JsonSelectorSeq<'_> {
+    type Value = ();
+
+    fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "a JSON array of objects")
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<(), A::Error> {
+        // Only one element is held in memory at a time
+        while let Some(element) = seq.next_element::<serde_json::Value>()? {
+            let selected = self
+                .path
+                .iter()
+                .try_fold(&element, |value, key| value.get(*key));
+
+            if let Some(domain) = selected.and_then(|v| v.as_str()) {
+                let domain = domain.trim().to_lowercase();
+                if PLAIN_PATTERN.is_match(&domain) {
+                    self.results.push(ExtractionResult {
+                        domain,
+                        raw_adblock_rule: None,
+                    });
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
 /// Detected format of a single line
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub enum DetectedFormat {
     Hosts,
     Plain,
     Adblock,
+    Dnsmasq,
+}
+
+// Patterns are compiled once per process and shared by every extractor
+
+/// Sink IPs that start a hosts line unless HOSTS_SINK_IPS lists others
+pub const DEFAULT_HOSTS_SINK_IPS: &[&str] = &["0.0.0.0", "127.0.0.1"];
+
+/// Hosts file format: IP domain, e.g. 0.0.0.0 domain.com or 127.0.0.1 domain.com
+static HOSTS_PATTERN: LazyLock<Regex> =
+    LazyLock::new(|| hosts_pattern(DEFAULT_HOSTS_SINK_IPS).expect("default sink IPs are valid"));
+
+/// Build the hosts-line pattern for a set of sink IPs (each must be an IP address)
+fn hosts_pattern<S: AsRef<str>>(sink_ips: &[S]) -> Result<Regex> {
+    let mut alternatives = Vec::with_capacity(sink_ips.len());
+    for ip in sink_ips {
+        let ip = ip.as_ref().trim();
+        ip.parse::<IpAddr>()
+            .with_context(|| format!("'{}' is not an IP address", ip))?;
+        alternatives.push(regex::escape(ip));
+    }
+    if alternatives.is_empty() {
+        anyhow::bail!("no sink IPs given");
+    }
+    Ok(Regex::new(&format!(
+        r"^(?:{})\s+([a-zA-Z0-9][-a-zA-Z0-9]*(?:\.[a-zA-Z0-9][-a-zA-Z0-9]*)+)",
+        alternatives.join("|")
+    ))?)
 }
 
+/// Just a domain on its own line
+static PLAIN_PATTERN: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"^([a-zA-Z0-9][-a-zA-Z0-9]*(?:\.[a-zA-Z0-9][-a-zA-Z0-9]*)+)$").unwrap());
+
+/// Adblock format: ||domain.com^, ||domain.com^|, ||domain.com| or bare ||domain.com, each with
+/// optional $modifiers directly after (captures domain and modifiers)
+static ADBLOCK_PATTERN: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"^\|\|([a-zA-Z0-9][-a-zA-Z0-9]*(?:\.[a-zA-Z0-9][-a-zA-Z0-9]*)+)\.?(?:\^\|?|\|)?(\$.+)?$").unwrap()
+});
+
+/// Dnsmasq format: address=/domain/0.0.0.0, local=/domain/ or server=/domain/
+/// (captures directive, domain, target)
+static DNSMASQ_PATTERN: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"^(address|local|server)=/([a-zA-Z0-9][-a-zA-Z0-9]*(?:\.[a-zA-Z0-9][-a-zA-Z0-9]*)+)/(.*)$").unwrap()
+});
+
+/// PAC host matchers: shExpMatch(host, "*.ads.com") or dnsDomainIs(host, ".ads.com")
+/// (captures function name and the string literal)
+static PAC_MATCHER_PATTERN: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r#"\b(shExpMatch|dnsDomainIs)\s*\(\s*[A-Za-z_$][\w$]*\s*,\s*(?:"([^"]*)"|'([^']*)')\s*\)"#).unwrap()
+});
+
+/// Comment lines
+static COMMENT_PATTERN: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"^[#!]").unwrap());
+
+/// CSS/cosmetic filter rules (element hiding, scriptlets - not DNS level)
+/// The separator (##, #@#, #?#, #$#, #%#, #+js(, ...) must directly follow the
+/// rule's domain list, so a "##" after whitespace is a comment, not a filter
+static CSS_FILTER_PATTERN: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"^[^\s#]*#(?:@?(?:#|\?#|\$\??#|%#)|\+js\()").unwrap());
+
+/// Modifiers that indicate the rule doesn't block at DNS level
+/// $third-party/$3p, $first-party/$1p (and ~negations) = context-aware blocking (can't do at DNS level)
+/// $badfilter = exception rule that DISABLES a blocking rule
+/// $removeparam, $redirect, $csp, $replace, $cookie = browser-level features
+/// The name must end at a comma, '=' or the end so e.g. $3p doesn't match $3pany
+static SKIP_MODIFIERS_PATTERN: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"(?i)\$(.*,)?~?(third-party|3p|strict3p|first-party|1p|strict1p|badfilter|removeparam|redirect-rule|redirect|csp|replace|cookie)(?:[,=]|$)").unwrap()
+});
+
 /// Domain extractor with high-performance regex parsing
+///
+/// A cheap handle: the patterns are process-wide statics, so creating one
+/// per job costs nothing. Only a custom hosts sink-IP set compiles its own
+/// pattern, shared by the handle's clones.
+#[derive(Debug, Clone, Default)]
 pub struct DomainExtractor {
-    /// Pattern for hosts file format: IP domain
-    hosts_pattern: Regex,
-    /// Pattern for plain domain
-    plain_pattern: Regex,
-    /// Pattern for adblock format: ||domain^ with optional modifiers
-    adblock_pattern: Regex,
-    /// Pattern for comments
-    comment_pattern: Regex,
-    /// Pattern for CSS/cosmetic filter rules (to skip)
-    css_filter_pattern: Regex,
-    /// Pattern to detect modifiers that mean this isn't a DNS-level block
-    skip_modifiers_pattern: Regex,
+    /// Hosts-line pattern for custom sink IPs (None = the default `HOSTS_PATTERN`)
+    hosts_pattern: Option<Arc<Regex>>,
+    /// Lowercase prefixes (without the colon) stripped from `prefix:domain` lines
+    domain_prefixes: Option<Arc<[String]>>,
 }
 
 impl DomainExtractor {
     /// Create a new domain extractor
     pub fn new() -> Self {
-        Self {
-            // Matches: 0.0.0.0 domain.com or 127.0.0.1 domain.com
-            hosts_pattern: Regex::new(r"^(?:0\.0\.0\.0|127\.0\.0\.1)\s+([a-zA-Z0-9][-a-zA-Z0-9]*(?:\.[a-zA-Z0-9][-a-zA-Z0-9]*)+)").unwrap(),
-            // Matches: just a domain on its own line
-            plain_pattern: Regex::new(r"^([a-zA-Z0-9][-a-zA-Z0-9]*(?:\.[a-zA-Z0-9][-a-zA-Z0-9]*)+)$").unwrap(),
-            // Matches: ||domain.com^ or ||domain.com^$... (captures domain and optional modifiers)
-            adblock_pattern: Regex::new(r"^\|\|([a-zA-Z0-9][-a-zA-Z0-9]*(?:\.[a-zA-Z0-9][-a-zA-Z0-9]*)+)\^?(\$.+)?$").unwrap(),
-            // Matches comment lines
-            comment_pattern: Regex::new(r"^[#!]").unwrap(),
-            // Matches CSS/cosmetic filter rules (element hiding - not DNS level)
-            css_filter_pattern: Regex::new(r"##|#@#|#\?#|#\$#|#\+js\(").unwrap(),
-            // Matches modifiers that indicate the rule doesn't block at DNS level
-            // $third-party = context-aware blocking (can't do at DNS level)
-            // $badfilter = exception rule that DISABLES a blocking rule
-            // $removeparam, $redirect, $csp, $replace, $cookie = browser-level features
-            skip_modifiers_pattern: Regex::new(r"(?i)\$(.*,)?(third-party|badfilter|removeparam|redirect|csp|replace|cookie)").unwrap(),
+        Self::default()
+    }
+
+    /// Recognize hosts lines starting with any of these sink IPs (HOSTS_SINK_IPS)
+    ///
+    /// The default set keeps sharing the static pattern.
+    pub fn with_hosts_sink_ips<S: AsRef<str>>(mut self, sink_ips: &[S]) -> Result<Self> {
+        let is_default = sink_ips.len() == DEFAULT_HOSTS_SINK_IPS.len()
+            && sink_ips.iter().zip(DEFAULT_HOSTS_SINK_IPS).all(|(ip, default)| ip.as_ref().trim() == *default);
+        self.hosts_pattern = if is_default {
+            None
+        } else {
+            Some(Arc::new(hosts_pattern(sink_ips)?))
+        };
+        Ok(self)
+    }
+
+    /// Accept `prefix:domain` lines for these prefixes (DOMAIN_PREFIXES), e.g. `sni`, `tls`
+    ///
+    /// Only the listed prefixes are stripped, matched case-insensitively with or
+    /// without a trailing colon, and the rest of the line must be a bare domain.
+    pub fn with_domain_prefixes<S: AsRef<str>>(mut self, prefixes: &[S]) -> Self {
+        let prefixes: Vec<String> = prefixes
+            .iter()
+            .map(|p| p.as_ref().trim().trim_end_matches(':').to_lowercase())
+            .filter(|p| !p.is_empty())
+            .collect();
+        self.domain_prefixes = (!prefixes.is_empty()).then(|| prefixes.into());
+        self
+    }
+
+    /// The domain of a `prefix:domain` line, if the prefix is one we strip
+    fn strip_domain_prefix<'l>(&self, line: &'l str) -> Option<&'l str> {
+        let prefixes = self.domain_prefixes.as_deref()?;
+        let (prefix, rest) = line.split_once(':')?;
+        prefixes
+            .iter()
+            .any(|p| p.eq_ignore_ascii_case(prefix.trim()))
+            .then(|| rest.trim())
+    }
+
+    /// The hosts-line pattern in use
+    fn hosts(&self) -> &Regex {
+        self.hosts_pattern.as_deref().unwrap_or(&HOSTS_PATTERN)
+    }
+
+    /// Parse every domain on a hosts line, or None if the line isn't hosts format
+    ///
+    /// Handles inline comments (`0.0.0.0 ads.com # note`), ports (`ads.com:80`),
+    /// trailing dots and several domains per line (`0.0.0.0 ads.com www.ads.com`).
+    /// Tokens that aren't valid domains are skipped.
+    fn extract_hosts_domains(&self, line: &str) -> Option<Vec<String>> {
+        if !self.hosts().is_match(line) {
+            return None;
         }
+
+        let entry = match line.find('#') {
+            Some(idx) => &line[..idx],
+            None => line,
+        };
+
+        let domains = entry
+            .split_whitespace()
+            .skip(1) // sink IP
+            .filter_map(|token| {
+                let host = token.split(':').next().unwrap_or(token).trim_end_matches('.');
+                PLAIN_PATTERN.is_match(host).then(|| host.to_lowercase())
+            })
+            .collect();
+
+        Some(domains)
+    }
+
+    /// Extract every domain from a line (hosts lines may list several)
+    ///
+    /// A stray `\r` (CR-only line endings) is treated as a line break.
+    fn extract_line(&self, line: &str) -> Vec<(ExtractionResult, DetectedFormat)> {
+        line.split('\r')
+            .flat_map(|part| {
+                let part = part.trim();
+                match self.extract_hosts_domains(part) {
+                    Some(domains) => domains
+                        .into_iter()
+                        .map(|domain| {
+                            (
+                                ExtractionResult {
+                                    domain,
+                                    raw_adblock_rule: None, // Not adblock format
+                                },
+                                DetectedFormat::Hosts,
+                            )
+                        })
+                        .collect(),
+                    None => self.extract_domain(part).into_iter().collect::<Vec<_>>(),
+                }
+            })
+            .collect()
     }
 
     /// Extract domain from a single line, returns result and detected format
+    ///
+    /// Hosts lines yield their first domain; use `extract_line` for all of them.
     fn extract_domain(&self, line: &str) -> Option<(ExtractionResult, DetectedFormat)> {
         let line = line.trim();
 
         // Skip empty lines and comments
-        if line.is_empty() || self.comment_pattern.is_match(line) {
+        if line.is_empty() || COMMENT_PATTERN.is_match(line) {
             return None;
         }
 
-        // Skip CSS/cosmetic filter rules (element hiding, not DNS level)
-        if self.css_filter_pattern.is_match(line) {
-            return None;
-        }
-
-        // Try hosts format first (most common)
-        if let Some(caps) = self.hosts_pattern.captures(line) {
-            if let Some(domain) = caps.get(1) {
-                return Some((
+        // Try hosts format first (most common; checked before CSS rules since
+        // an inline "##" comment is not a cosmetic filter here)
+        if let Some(domains) = self.extract_hosts_domains(line) {
+            return domains.into_iter().next().map(|domain| {
+                (
                     ExtractionResult {
-                        domain: domain.as_str().to_lowercase(),
+                        domain,
                         raw_adblock_rule: None, // Not adblock format
                     },
                     DetectedFormat::Hosts,
-                ));
-            }
+                )
+            });
         }
 
+        // Skip CSS/cosmetic filter rules (element hiding, not DNS level)
+        if CSS_FILTER_PATTERN.is_match(line) {
+            return None;
+        }
+
+        // Anything else may carry a trailing "# comment"
+        let line = strip_inline_comment(line);
+
         // Try adblock format
-        if let Some(caps) = self.adblock_pattern.captures(line) {
+        if let Some(caps) = ADBLOCK_PATTERN.captures(line) {
             if let Some(domain) = caps.get(1) {
                 // Check for modifiers that mean this isn't a DNS-level block
                 if let Some(modifiers) = caps.get(2) {
                     let mod_str = modifiers.as_str();
-                    if self.skip_modifiers_pattern.is_match(mod_str) {
+                    if SKIP_MODIFIERS_PATTERN.is_match(mod_str) {
                         return None;
                     }
                 }
@@ -152,8 +386,25 @@ impl DomainExtractor {
             }
         }
 
-        // Try plain domain
-        if let Some(caps) = self.plain_pattern.captures(line) {
+        // Try dnsmasq format
+        if let Some(caps) = DNSMASQ_PATTERN.captures(line) {
+            // server=/domain/upstream forwards queries rather than blocking them
+            let forwards = &caps[1] == "server" && !caps[3].is_empty();
+            if forwards {
+                return None;
+            }
+            return Some((
+                ExtractionResult {
+                    domain: caps[2].to_lowercase(),
+                    raw_adblock_rule: None, // Not adblock format
+                },
+                DetectedFormat::Dnsmasq,
+            ));
+        }
+
+        // Try plain domain, after a recognized prefix like sni: if there is one
+        let line = self.strip_domain_prefix(line).unwrap_or(line);
+        if let Some(caps) = PLAIN_PATTERN.captures(line) {
             if let Some(domain) = caps.get(1) {
                 return Some((
                     ExtractionResult {
@@ -170,10 +421,11 @@ impl DomainExtractor {
 
     /// Extract domains from file content (parallel processing)
     /// Returns just the results for backward compatibility
+    #[cfg(test)]
     pub fn extract_from_content(&self, content: &str) -> Vec<ExtractionResult> {
         content
             .par_lines()
-            .filter_map(|line| self.extract_domain(line).map(|(result, _)| result))
+            .flat_map_iter(|line| self.extract_line(line).into_iter().map(|(result, _)| result))
             .collect()
     }
 
@@ -184,15 +436,17 @@ impl DomainExtractor {
         let hosts_count = AtomicU64::new(0);
         let plain_count = AtomicU64::new(0);
         let adblock_count = AtomicU64::new(0);
+        let dnsmasq_count = AtomicU64::new(0);
 
         let results: Vec<ExtractionResult> = content
             .par_lines()
-            .filter_map(|line| {
-                self.extract_domain(line).map(|(result, format)| {
+            .flat_map_iter(|line| {
+                self.extract_line(line).into_iter().map(|(result, format)| {
                     match format {
                         DetectedFormat::Hosts => hosts_count.fetch_add(1, Ordering::Relaxed),
                         DetectedFormat::Plain => plain_count.fetch_add(1, Ordering::Relaxed),
                         DetectedFormat::Adblock => adblock_count.fetch_add(1, Ordering::Relaxed),
+                        DetectedFormat::Dnsmasq => dnsmasq_count.fetch_add(1, Ordering::Relaxed),
                     };
                     result
                 })
@@ -205,6 +459,118 @@ impl DomainExtractor {
                 hosts: hosts_count.load(Ordering::Relaxed),
                 plain: plain_count.load(Ordering::Relaxed),
                 adblock: adblock_count.load(Ordering::Relaxed),
+                json: 0,
+                dnsmasq: dnsmasq_count.load(Ordering::Relaxed),
+                pac: 0,
+            },
+        }
+    }
+
+    /// Extract domains section by section, splitting on header comments
+    ///
+    /// Lines matching `header` start a new section named by the pattern's first
+    /// capture group (e.g. `# Start gambling` in StevenBlack's consolidated
+    /// hosts). Lines before the first header come back under `None`, so the
+    /// caller can fall back to the source's own category.
+    pub fn extract_sections(&self, content: &str, header: &Regex) -> Vec<(Option<String>, ExtractionOutput)> {
+        let mut sections: Vec<(Option<String>, ExtractionOutput)> = Vec::new();
+        let mut name: Option<String> = None;
+        let mut start = 0;
+
+        let mut offset = 0;
+        for line in content.split_inclusive('\n') {
+            if let Some(caps) = header.captures(line.trim()) {
+                if start < offset {
+                    sections.push((name.take(), self.extract_from_content_with_breakdown(&content[start..offset])));
+                }
+                name = caps.get(1).map(|m| section_category(m.as_str())).filter(|c| !c.is_empty());
+                start = offset + line.len();
+            }
+            offset += line.len();
+        }
+        if start < content.len() {
+            sections.push((name, self.extract_from_content_with_breakdown(&content[start..])));
+        }
+
+        sections
+    }
+
+    /// Extract domains from a JSON-array source using a field selector
+    ///
+    /// The selector is a dotted path into each array element, e.g. `domain` or
+    /// `indicator.domain`; a JSONPath-style `$[*].` prefix is accepted. Elements
+    /// without the field, or whose value isn't a valid domain, are skipped.
+    pub fn extract_from_json(&self, content: &[u8], selector: &str) -> Result<ExtractionOutput> {
+        let selector = selector.trim();
+        let selector = selector.strip_prefix('$').unwrap_or(selector);
+        let selector = selector
+            .strip_prefix("[*]")
+            .or_else(|| selector.strip_prefix("[]"))
+            .unwrap_or(selector);
+        let path: Vec<&str> = selector
+            .trim_start_matches('.')
+            .split('.')
+            .filter(|key| !key.is_empty())
+            .collect();
+
+        if path.is_empty() {
+            anyhow::bail!("Empty JSON field selector");
+        }
+
+        let mut results = Vec::new();
+        let mut deserializer = serde_json::Deserializer::from_slice(content);
+        JsonSelectorSeq {
+            path: &path,
+            results: &mut results,
+        }
+        .deserialize(&mut deserializer)
+        .context("Malformed JSON source")?;
+        deserializer.end().context("Malformed JSON source")?;
+
+        let json = results.len() as u64;
+        Ok(ExtractionOutput {
+            results,
+            format_breakdown: FormatBreakdown {
+                json,
+                ..FormatBreakdown::default()
+            },
+        })
+    }
+
+    /// Extract domains from a PAC (proxy auto-config) script (`format=pac` sources)
+    ///
+    /// Only the string literals of `shExpMatch(host, "...")` and
+    /// `dnsDomainIs(host, "...")` calls are read; the rest of the JavaScript is
+    /// ignored. A leading `*.` or `.` is dropped (the list covers subdomains
+    /// anyway), and patterns with a wildcard anywhere else are skipped.
+    pub fn extract_from_pac(&self, content: &str) -> ExtractionOutput {
+        let mut results = Vec::new();
+        for line in content.lines() {
+            if line.trim_start().starts_with("//") {
+                continue;
+            }
+            for captures in PAC_MATCHER_PATTERN.captures_iter(line) {
+                let literal = captures.get(2).or_else(|| captures.get(3)).map_or("", |m| m.as_str());
+                let literal = match &captures[1] {
+                    "shExpMatch" => literal.strip_prefix('*').unwrap_or(literal),
+                    _ => literal,
+                };
+                let domain = literal.trim().trim_start_matches('.').trim_end_matches('.').to_lowercase();
+                if PLAIN_PATTERN.is_match(&domain) {
+                    results.push(ExtractionResult {
+                        domain,
+                        raw_adblock_rule: None,
+                    });
+                }
+            }
+        }
+
+        let pac = results.len() as u64;
+        ExtractionOutput {
+            results,
+            format_breakdown: FormatBreakdown {
+                pac,
+                ..FormatBreakdown::default()
             },
         }
     }
@@ -215,12 +581,222 @@ impl DomainExtractor {
         sorted.par_sort_unstable();
         sorted
     }
+
+    /// Sort domains by reversed labels (parallel sort)
+    ///
+    /// `a.doubleclick.net` and `b.doubleclick.net` end up adjacent, which gives
+    /// gzip long repeated suffixes to match against. On the synthetic dataset in
+    /// `test_suffix_sort_compresses_better` this shrinks the gzip output by ~10%.
+    pub fn sort_domains_by_suffix(domains: HashSet<String>) -> Vec<String> {
+        let mut sorted: Vec<String> = domains.into_iter().collect();
+        sorted.par_sort_unstable_by(|a, b| a.rsplit('.').cmp(b.rsplit('.')));
+        sorted
+    }
+}
+
+/// Drops domains under reserved or internal TLDs (`.onion`, `.i2p`, `.lan`, ...)
+///
+/// These can't be blocked meaningfully by public DNS and mostly show up in
+/// feeds by accident. Single-label names are dropped too, though the
+/// extraction patterns already require a dot.
+#[derive(Debug, Clone)]
+pub struct ReservedDomainFilter {
+    /// Suffixes without the leading dot, e.g. `onion` or `home.arpa`
+    tlds: HashSet<String>,
+}
+
+impl ReservedDomainFilter {
+    pub fn new<S: AsRef<str>>(tlds: &[S]) -> Self {
+        Self {
+            tlds: tlds
+                .iter()
+                .map(|t| t.as_ref().trim().trim_matches('.').to_lowercase())
+                .filter(|t| !t.is_empty())
+                .collect(),
+        }
+    }
+
+    /// Whether `domain` is single-label or under one of the reserved suffixes
+    pub fn is_reserved(&self, domain: &str) -> bool {
+        if !domain.contains('.') {
+            return true;
+        }
+        // Every suffix: "a.b.onion" checks "b.onion" and "onion"
+        domain
+            .match_indices('.')
+            .any(|(i, _)| self.tlds.contains(&domain[i + 1..]))
+    }
+
+    /// Remove reserved domains from `output`, returning how many were dropped
+    pub fn retain(&self, output: &mut ExtractionOutput) -> usize {
+        let before = output.results.len();
+        output.results.retain(|r| !self.is_reserved(&r.domain));
+        before - output.results.len()
+    }
+}
+
+/// Decode a downloaded body to text, dropping any byte order mark
+///
+/// A UTF-8 BOM is always stripped. With `transcode`, UTF-16 bodies (which
+/// must start with a BOM) are converted, and bodies that aren't valid UTF-8
+/// are read as Latin-1 (windows-1252) rather than having their bytes
+/// replaced. Without it, invalid bytes become U+FFFD as before.
+pub fn decode_source(bytes: &[u8], transcode: bool) -> Cow<'_, str> {
+    let (encoding, body) = match Encoding::for_bom(bytes) {
+        Some((encoding, bom_len)) => (encoding, &bytes[bom_len..]),
+        None => (UTF_8, bytes),
+    };
+    if encoding != UTF_8 && transcode {
+        return encoding.decode_without_bom_handling(body).0;
+    }
+    match std::str::from_utf8(body) {
+        Ok(text) => Cow::Borrowed(text),
+        Err(_) if transcode => WINDOWS_1252.decode_without_bom_handling(body).0,
+        Err(_) => String::from_utf8_lossy(body),
+    }
+}
+
+/// Extracts domains line by line from a body as its chunks arrive
+///
+/// Only the partial line at the end of a chunk is carried over, so the whole
+/// source is never held as one `String`. The output matches
+/// `extract_from_content_with_breakdown` on the joined chunks.
+pub struct StreamingExtraction<'a> {
+    extractor: &'a DomainExtractor,
+    /// Bytes of a line not yet terminated by `\n`
+    pending: Vec<u8>,
+    /// Body bytes pushed so far
+    consumed: usize,
+    /// Read non-UTF-8 lines as Latin-1 and UTF-16 bodies as UTF-16 (see `decode_source`)
+    transcode: bool,
+    /// Body opened with a UTF-16 BOM: lines can't be split on bytes, so the
+    /// whole body is buffered in `pending` and decoded by `finish`
+    utf16: bool,
+    output: ExtractionOutput,
+}
+
+impl<'a> StreamingExtraction<'a> {
+    pub fn new(extractor: &'a DomainExtractor) -> Self {
+        Self {
+            extractor,
+            pending: Vec::new(),
+            consumed: 0,
+            transcode: false,
+            utf16: false,
+            output: ExtractionOutput {
+                results: Vec::new(),
+                format_breakdown: FormatBreakdown::default(),
+            },
+        }
+    }
+
+    /// Transcode non-UTF-8 bodies instead of replacing their invalid bytes
+    pub fn with_transcoding(mut self, transcode: bool) -> Self {
+        self.transcode = transcode;
+        self
+    }
+
+    /// Body bytes pushed since creation or the last reset
+    pub fn consumed(&self) -> usize {
+        self.consumed
+    }
+
+    /// Drop everything extracted so far (the body is starting over)
+    pub fn reset(&mut self) {
+        *self = Self::new(self.extractor).with_transcoding(self.transcode);
+    }
+
+    /// Extract every line completed by `chunk`
+    pub fn push(&mut self, chunk: &[u8]) {
+        let first = self.consumed == 0;
+        self.consumed += chunk.len();
+
+        let mut rest = chunk;
+        if first {
+            match Encoding::for_bom(chunk) {
+                Some((encoding, _)) if encoding != UTF_8 && self.transcode => self.utf16 = true,
+                Some((_, bom_len)) => rest = &chunk[bom_len..],
+                None => {}
+            }
+        }
+        if self.utf16 {
+            self.pending.extend_from_slice(rest);
+            return;
+        }
+
+        while let Some(pos) = rest.iter().position(|&b| b == b'\n') {
+            if self.pending.is_empty() {
+                self.extract_line(&rest[..pos]);
+            } else {
+                // Line split across chunks: finish it, keeping the buffer's capacity
+                let mut line = std::mem::take(&mut self.pending);
+                line.extend_from_slice(&rest[..pos]);
+                self.extract_line(&line);
+                line.clear();
+                self.pending = line;
+            }
+            rest = &rest[pos + 1..];
+        }
+        self.pending.extend_from_slice(rest);
+    }
+
+    /// Extract the unterminated last line and return the results
+    pub fn finish(mut self) -> ExtractionOutput {
+        if self.utf16 {
+            let body = std::mem::take(&mut self.pending);
+            for line in decode_source(&body, true).lines() {
+                self.extract_text(line);
+            }
+            return self.output;
+        }
+        if !self.pending.is_empty() {
+            let line = std::mem::take(&mut self.pending);
+            self.extract_line(&line);
+        }
+        self.output
+    }
+
+    fn extract_line(&mut self, line: &[u8]) {
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        let line = decode_source(line, self.transcode);
+        self.extract_text(&line);
+    }
+
+    fn extract_text(&mut self, line: &str) {
+        let breakdown = &mut self.output.format_breakdown;
+        for (result, format) in self.extractor.extract_line(line) {
+            match format {
+                DetectedFormat::Hosts => breakdown.hosts += 1,
+                DetectedFormat::Plain => breakdown.plain += 1,
+                DetectedFormat::Adblock => breakdown.adblock += 1,
+                DetectedFormat::Dnsmasq => breakdown.dnsmasq += 1,
+            }
+            self.output.results.push(result);
+        }
+    }
+}
+
+/// Drop a trailing `# comment` (a `#` after whitespace); domains contain neither
+fn strip_inline_comment(line: &str) -> &str {
+    line.char_indices()
+        .find(|&(i, c)| c == '#' && line[..i].ends_with(char::is_whitespace))
+        .map_or(line, |(i, _)| line[..i].trim_end())
 }
 
-impl Default for DomainExtractor {
-    fn default() -> Self {
-        Self::new()
+/// Turn a section header label into a category name
+///
+/// Lowercased, with anything outside `[a-z0-9-]` collapsed to `-`; underscores
+/// are never produced because output file names use them as separators.
+fn section_category(label: &str) -> String {
+    let mut out = String::with_capacity(label.len());
+    for c in label.trim().to_lowercase().chars() {
+        if c.is_ascii_alphanumeric() {
+            out.push(c);
+        } else if !out.ends_with('-') {
+            out.push('-');
+        }
     }
+    out.trim_matches('-').to_string()
 }
 
 #[cfg(test)]
@@ -303,6 +879,32 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_modifiers_without_caret() {
+        let extractor = DomainExtractor::new();
+
+        // Modifier directly after the domain is still recognized and skipped
+        assert_eq!(extractor.extract_domain("||ads.com$third-party"), None);
+        assert_eq!(extractor.extract_domain("||ads.com$3p"), None);
+        assert_eq!(extractor.extract_domain("||ads.com$script,~third-party"), None);
+        assert_eq!(extractor.extract_domain("||ads.com$removeparam=utm"), None);
+
+        // Blocking modifiers without ^ are kept, with the original rule preserved
+        let (result, format) = extractor.extract_domain("||ads.com$important").unwrap();
+        assert_eq!(result.domain, "ads.com");
+        assert_eq!(result.raw_adblock_rule, Some("||ads.com$important".to_string()));
+        assert_eq!(format, DetectedFormat::Adblock);
+
+        // Bare and |-anchored forms
+        for rule in ["||ads.com", "||ads.com|", "||ads.com^|", "||ads.com.^"] {
+            let (result, _) = extractor.extract_domain(rule).unwrap();
+            assert_eq!(result.domain, "ads.com", "{}", rule);
+        }
+
+        // Modifier names only match whole tokens
+        assert!(extractor.extract_domain("||ads.com^$domain=3pany.com").is_some());
+    }
+
     #[test]
     fn test_badfilter_exception() {
         let extractor = DomainExtractor::new();
@@ -372,6 +974,70 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_hosts_crlf_line_endings() {
+        let extractor = DomainExtractor::new();
+
+        let output = extractor.extract_from_content_with_breakdown("0.0.0.0\tads.com\r\n127.0.0.1 tracker.com\r\n");
+        let domains: Vec<&str> = output.results.iter().map(|r| r.domain.as_str()).collect();
+        assert_eq!(domains, vec!["ads.com", "tracker.com"]);
+
+        // CR-only line endings
+        let domains: Vec<String> = extractor
+            .extract_line("0.0.0.0 a.com\r0.0.0.0 b.com\r")
+            .into_iter()
+            .map(|(r, _)| r.domain)
+            .collect();
+        assert_eq!(domains, vec!["a.com", "b.com"]);
+    }
+
+    #[test]
+    fn test_hosts_inline_comments_and_ports() {
+        let extractor = DomainExtractor::new();
+
+        let domain = |line: &str| extractor.extract_domain(line).map(|(r, _)| r.domain);
+        assert_eq!(domain("0.0.0.0 ads.com #comment"), Some("ads.com".to_string()));
+        assert_eq!(domain("0.0.0.0 ads.com#comment"), Some("ads.com".to_string()));
+        assert_eq!(domain("0.0.0.0 ads.com ## see issue 12"), Some("ads.com".to_string()));
+        assert_eq!(domain("0.0.0.0 ads.com:80"), Some("ads.com".to_string()));
+        assert_eq!(domain("0.0.0.0 Ads.Com.   \t "), Some("ads.com".to_string()));
+    }
+
+    #[test]
+    fn test_hosts_multiple_domains() {
+        let extractor = DomainExtractor::new();
+
+        let results = extractor.extract_line("0.0.0.0 ads.com www.ads.com # both");
+        let domains: Vec<&str> = results.iter().map(|(r, _)| r.domain.as_str()).collect();
+        assert_eq!(domains, vec!["ads.com", "www.ads.com"]);
+        assert!(results.iter().all(|(_, f)| *f == DetectedFormat::Hosts));
+
+        let output = extractor.extract_from_content_with_breakdown("0.0.0.0 a.com b.com\n0.0.0.0 c.com");
+        assert_eq!(output.format_breakdown.hosts, 3);
+    }
+
+    #[test]
+    fn test_dnsmasq_format() {
+        let extractor = DomainExtractor::new();
+
+        let (result, format) = extractor.extract_domain("address=/ads.example.com/0.0.0.0").unwrap();
+        assert_eq!(result.domain, "ads.example.com");
+        assert_eq!(result.raw_adblock_rule, None);
+        assert_eq!(format, DetectedFormat::Dnsmasq);
+
+        let (result, format) = extractor.extract_domain("local=/Tracker.com/").unwrap();
+        assert_eq!(result.domain, "tracker.com");
+        assert_eq!(format, DetectedFormat::Dnsmasq);
+
+        // server=/domain/ with no upstream blocks; with an upstream it only forwards
+        assert!(extractor.extract_domain("server=/ads.com/").is_some());
+        assert!(extractor.extract_domain("server=/corp.example.com/10.0.0.1").is_none());
+
+        let output = extractor.extract_from_content_with_breakdown("address=/a.com/0.0.0.0\nlocal=/b.com/\nc.com");
+        assert_eq!(output.format_breakdown.dnsmasq, 2);
+        assert_eq!(output.format_breakdown.plain, 1);
+    }
+
     #[test]
     fn test_comments() {
         let extractor = DomainExtractor::new();
@@ -436,6 +1102,9 @@ mod tests {
             hosts: 100,
             plain: 50,
             adblock: 25,
+            json: 0,
+            dnsmasq: 0,
+            pac: 0,
         };
         assert_eq!(breakdown.primary_format(), Some("hosts"));
 
@@ -445,4 +1114,335 @@ mod tests {
         breakdown.plain = 300;
         assert_eq!(breakdown.primary_format(), Some("plain"));
     }
+
+    #[test]
+    fn test_sort_domains_by_suffix() {
+        let domains: HashSet<String> = ["b.ads.com", "a.tracker.net", "ads.com", "a.ads.com", "z.com"]
+            .iter()
+            .map(|d| d.to_string())
+            .collect();
+
+        assert_eq!(
+            DomainExtractor::sort_domains_by_suffix(domains),
+            vec!["ads.com", "a.ads.com", "b.ads.com", "z.com", "a.tracker.net"]
+        );
+    }
+
+    #[test]
+    fn test_suffix_sort_compresses_better() {
+        use flate2::write::GzEncoder;
+        use flate2::Compression;
+        use std::io::Write;
+
+        // Real-ish dataset: many hosts spread over a few ad/tracking apexes, with
+        // leading labels that scatter them under plain lexicographic order
+        let apexes = [
+            "doubleclick.net",
+            "googlesyndication.com",
+            "adnxs.com",
+            "scorecardresearch.com",
+            "taboola.com",
+            "criteo.com",
+        ];
+        let prefixes = ["ad", "cdn", "pixel", "static", "track", "img", "sync", "stats"];
+        let mut domains = HashSet::new();
+        for (i, apex) in apexes.iter().enumerate() {
+            for n in 0..400 {
+                let prefix = prefixes[(n + i) % prefixes.len()];
+                domains.insert(format!("{}{}.{}", prefix, n, apex));
+            }
+        }
+
+        let gzip_size = |sorted: &[String]| -> usize {
+            let mut encoder = GzEncoder::new(Vec::new(), Compression::fast());
+            for domain in sorted {
+                writeln!(encoder, "0.0.0.0 {}", domain).unwrap();
+            }
+            encoder.finish().unwrap().len()
+        };
+
+        let lexicographic = gzip_size(&DomainExtractor::sort_domains(domains.clone()));
+        let by_suffix = gzip_size(&DomainExtractor::sort_domains_by_suffix(domains));
+
+        assert!(
+            by_suffix < lexicographic,
+            "suffix order should gzip smaller: suffix={} lexicographic={}",
+            by_suffix,
+            lexicographic
+        );
+    }
+
+    #[test]
+    fn test_json_nested_selector() {
+        let extractor = DomainExtractor::new();
+        let content = br#"[
+            {"indicator": {"domain": "C2.Example.com", "type": "c2"}},
+            {"indicator": {"domain": "phish.example.net"}},
+            {"indicator": {"ip": "10.0.0.1"}},
+            {"indicator": {"domain": "not a domain"}},
+            {"other": true}
+        ]"#;
+
+        let output = extractor.extract_from_json(content, "$[*].indicator.domain").unwrap();
+        let domains: Vec<&str> = output.results.iter().map(|r| r.domain.as_str()).collect();
+        assert_eq!(domains, vec!["c2.example.com", "phish.example.net"]);
+        assert_eq!(output.format_breakdown.json, 2);
+        assert_eq!(output.format_breakdown.detected_formats(), vec!["json".to_string()]);
+
+        // Plain top-level field selector
+        let output = extractor
+            .extract_from_json(br#"[{"domain":"bad.com","type":"c2"}]"#, "domain")
+            .unwrap();
+        assert_eq!(output.results[0].domain, "bad.com");
+    }
+
+    #[test]
+    fn test_json_malformed() {
+        let extractor = DomainExtractor::new();
+
+        let err = extractor
+            .extract_from_json(br#"[{"domain": "bad.com"}, {"domain": "#, "domain")
+            .unwrap_err();
+        assert!(err.to_string().contains("Malformed JSON"));
+
+        // Not an array
+        assert!(extractor.extract_from_json(br#"{"domain": "bad.com"}"#, "domain").is_err());
+    }
+
+    #[test]
+    fn test_sections_split_into_categories() {
+        let extractor = DomainExtractor::new();
+        let header = Regex::new(r"^#\s*Start\s+(\S+)").unwrap();
+        let content = "# Title: StevenBlack/hosts\n\
+            0.0.0.0 ads.example.com\n\
+            # Start Gambling\n\
+            0.0.0.0 casino.example.com\n\
+            0.0.0.0 poker.example.com\n\
+            # Start Fake_News\n\
+            0.0.0.0 hoax.example.com\n";
+
+        let sections = extractor.extract_sections(content, &header);
+        let buckets: Vec<(Option<&str>, Vec<&str>)> = sections
+            .iter()
+            .map(|(name, output)| {
+                (name.as_deref(), output.results.iter().map(|r| r.domain.as_str()).collect())
+            })
+            .collect();
+        assert_eq!(
+            buckets,
+            vec![
+                (None, vec!["ads.example.com"]),
+                (Some("gambling"), vec!["casino.example.com", "poker.example.com"]),
+                (Some("fake-news"), vec!["hoax.example.com"]),
+            ]
+        );
+        assert_eq!(sections[1].1.format_breakdown.hosts, 2);
+    }
+
+    #[test]
+    fn test_streaming_extraction_across_chunk_boundaries() {
+        let extractor = DomainExtractor::new();
+        let content = "# comment\r\n0.0.0.0 ads.example.com\r\n||tracker.example.net^\nplain.example.org";
+        let whole = extractor.extract_from_content_with_breakdown(content);
+
+        // Split at every byte, so some boundary falls mid-line, mid-CRLF, ...
+        for split in 0..=content.len() {
+            let mut lines = StreamingExtraction::new(&extractor);
+            lines.push(&content.as_bytes()[..split]);
+            lines.push(&content.as_bytes()[split..]);
+            assert_eq!(lines.consumed(), content.len());
+
+            let streamed = lines.finish();
+            assert_eq!(streamed.results, whole.results, "split at {}", split);
+            assert_eq!(streamed.format_breakdown, whole.format_breakdown);
+        }
+
+        // A reset forgets the lines pushed before it
+        let mut lines = StreamingExtraction::new(&extractor);
+        lines.push(b"stale.example.com\npartial.exa");
+        lines.reset();
+        lines.push(b"fresh.example.com");
+        let domains: Vec<String> = lines.finish().results.into_iter().map(|r| r.domain).collect();
+        assert_eq!(domains, vec!["fresh.example.com"]);
+    }
+
+    #[test]
+    fn test_extractors_share_compiled_patterns() {
+        // Construction allocates nothing: default handles use the static patterns
+        assert!(DomainExtractor::new().hosts_pattern.is_none());
+        assert!(std::ptr::eq(DomainExtractor::new().hosts(), &*HOSTS_PATTERN));
+
+        let content = "0.0.0.0 ads.example.com www.ads.example.com\n||tracker.example.net^\n\
+                       ||third.example.org^$third-party\nexample.com##.banner\n\
+                       address=/dns.example.com/0.0.0.0\nplain.example.io\n# comment";
+        let expected = vec![
+            "ads.example.com",
+            "www.ads.example.com",
+            "tracker.example.net",
+            "dns.example.com",
+            "plain.example.io",
+        ];
+
+        // Extractors made on other threads see the same patterns and results
+        let handles: Vec<_> = (0..4)
+            .map(|_| std::thread::spawn(move || DomainExtractor::new().extract_from_content_with_breakdown(content)))
+            .collect();
+        for handle in handles {
+            let output = handle.join().unwrap();
+            let domains: Vec<String> = output.results.into_iter().map(|r| r.domain).collect();
+            assert_eq!(domains, expected);
+            assert_eq!(output.format_breakdown.hosts, 2);
+            assert_eq!(output.format_breakdown.adblock, 1);
+            assert_eq!(output.format_breakdown.dnsmasq, 1);
+            assert_eq!(output.format_breakdown.plain, 1);
+        }
+
+        // An extractor built on another thread compiles no pattern of its own
+        let local = DomainExtractor::new();
+        let remote = std::thread::spawn(|| DomainExtractor::new().hosts() as *const Regex as usize)
+            .join()
+            .unwrap();
+        assert_eq!(local.hosts() as *const Regex as usize, remote);
+    }
+
+    #[test]
+    fn test_cosmetic_separator_anchored_after_domains() {
+        let extractor = DomainExtractor::new();
+        let domain = |line: &str| extractor.extract_domain(line).map(|(r, _)| r.domain);
+
+        // A plain domain next to a comment is still a domain
+        assert_eq!(domain("ads.example.com ## added 2024-01"), Some("ads.example.com".to_string()));
+        assert_eq!(domain("ads.example.com\t# tracker"), Some("ads.example.com".to_string()));
+        assert_eq!(domain("||ads.example.com^ #@# legacy"), Some("ads.example.com".to_string()));
+
+        // Genuine cosmetic and scriptlet rules, with or without a domain list
+        assert_eq!(domain("example.com,example.org##.ad-banner"), None);
+        assert_eq!(domain("##.sponsored"), None);
+        assert_eq!(domain("example.com##+js(set-constant, ads, false)"), None);
+        assert_eq!(domain("example.com##^script:has-text(adsbygoogle)"), None);
+        assert_eq!(domain("example.com#@%#window.ads = true"), None);
+    }
+
+    #[test]
+    fn test_reserved_tlds_filtered_only_when_enabled() {
+        let content = "ads.example.com\nhiddenservice.onion\n||tracker.i2p^\nrouter.home.arpa\n";
+        let extractor = DomainExtractor::new();
+
+        // Filter off: everything extracted is kept
+        let kept = extractor.extract_from_content_with_breakdown(content);
+        assert!(kept.results.iter().any(|r| r.domain == "hiddenservice.onion"));
+        assert_eq!(kept.results.len(), 4);
+
+        // Filter on: reserved suffixes (including multi-label ones) are dropped
+        let filter = ReservedDomainFilter::new(&["onion", ".i2p", "home.arpa"]);
+        let mut filtered = extractor.extract_from_content_with_breakdown(content);
+        assert_eq!(filter.retain(&mut filtered), 3);
+        let domains: Vec<&str> = filtered.results.iter().map(|r| r.domain.as_str()).collect();
+        assert_eq!(domains, vec!["ads.example.com"]);
+
+        assert!(filter.is_reserved("intranet"));
+        assert!(!filter.is_reserved("onion.example.com"));
+    }
+
+    #[test]
+    fn test_bom_and_utf16_sources_decoded() {
+        let extractor = DomainExtractor::new();
+        let domains = |output: ExtractionOutput| -> Vec<String> { output.results.into_iter().map(|r| r.domain).collect() };
+
+        // UTF-8 BOM: the first line would otherwise start with U+FEFF
+        let bom = b"\xef\xbb\xbfads.example.com\n0.0.0.0 tracker.example.net\n";
+        let text = decode_source(bom, false);
+        assert_eq!(
+            domains(extractor.extract_from_content_with_breakdown(&text)),
+            vec!["ads.example.com", "tracker.example.net"]
+        );
+        let mut lines = StreamingExtraction::new(&extractor);
+        lines.push(bom);
+        assert_eq!(domains(lines.finish()), vec!["ads.example.com", "tracker.example.net"]);
+
+        // UTF-16LE with a BOM, CRLF line endings
+        let mut utf16 = vec![0xff, 0xfe];
+        for unit in "||ads.example.com^\r\nmalware.example.org\r\n".encode_utf16() {
+            utf16.extend_from_slice(&unit.to_le_bytes());
+        }
+        let text = decode_source(&utf16, true);
+        assert_eq!(
+            domains(extractor.extract_from_content_with_breakdown(&text)),
+            vec!["ads.example.com", "malware.example.org"]
+        );
+        let mut lines = StreamingExtraction::new(&extractor).with_transcoding(true);
+        for chunk in utf16.chunks(5) {
+            lines.push(chunk);
+        }
+        assert_eq!(domains(lines.finish()), vec!["ads.example.com", "malware.example.org"]);
+
+        // Latin-1 comment bytes don't disturb the domains either way
+        let latin1 = b"# Liste f\xfcr Werbung\nads.example.com\n";
+        assert_eq!(decode_source(latin1, true).lines().next(), Some("# Liste f\u{fc}r Werbung"));
+        assert!(decode_source(latin1, false).contains('\u{fffd}'));
+    }
+
+    #[test]
+    fn test_pac_matchers_extracted() {
+        let extractor = DomainExtractor::new();
+        let pac = r#"function FindProxyForURL(url, host) {
+    // shExpMatch(host, "*.commented.example")
+    if (shExpMatch(host, "*.ads.com") || dnsDomainIs(host, ".tracker.net"))
+        return "PROXY 0.0.0.0:3128";
+    if (dnsDomainIs(host, 'Metrics.Example.org') || shExpMatch(host, "*doubleclick.net"))
+        return "PROXY 0.0.0.0:3128";
+    if (shExpMatch(host, "ads.*.com") || shExpMatch(url, "*/banner/*") || isPlainHostName(host))
+        return "DIRECT";
+    return "DIRECT";
+}"#;
+
+        let output = extractor.extract_from_pac(pac);
+        let domains: Vec<&str> = output.results.iter().map(|r| r.domain.as_str()).collect();
+        assert_eq!(domains, vec!["ads.com", "tracker.net", "metrics.example.org", "doubleclick.net"]);
+        assert_eq!(output.format_breakdown.pac, 4);
+        assert_eq!(output.format_breakdown.primary_format(), Some("pac"));
+    }
+
+    #[test]
+    fn test_custom_hosts_sink_ips() {
+        let content = "255.255.255.255 broadcast.example.com\n0.0.0.1 sink.example.net www.sink.example.net\n\
+                       0.0.0.0 default.example.org\n10.1.2.3 intranet.example.com\n";
+        let domains = |extractor: &DomainExtractor| -> Vec<String> {
+            extractor.extract_from_content(content).into_iter().map(|r| r.domain).collect()
+        };
+
+        // Defaults only know 0.0.0.0 and 127.0.0.1
+        assert_eq!(domains(&DomainExtractor::new()), vec!["default.example.org"]);
+
+        let custom = DomainExtractor::new()
+            .with_hosts_sink_ips(&["0.0.0.0", "255.255.255.255", " 0.0.0.1 "])
+            .unwrap();
+        assert_eq!(
+            domains(&custom),
+            vec!["broadcast.example.com", "sink.example.net", "www.sink.example.net", "default.example.org"]
+        );
+        assert_eq!(custom.extract_from_content_with_breakdown(content).format_breakdown.hosts, 4);
+
+        // The default set keeps the shared pattern; anything that isn't an IP is rejected
+        assert!(DomainExtractor::new().with_hosts_sink_ips(DEFAULT_HOSTS_SINK_IPS).unwrap().hosts_pattern.is_none());
+        assert!(DomainExtractor::new().with_hosts_sink_ips(&["0.0.0.0", "sinkhole"]).is_err());
+    }
+
+    #[test]
+    fn test_domain_prefixes_stripped_when_configured() {
+        let extractor = DomainExtractor::new().with_domain_prefixes(&["sni", "esni:", "TLS"]);
+        let domain = |line: &str| extractor.extract_domain(line).map(|(r, format)| (r.domain, format));
+
+        assert_eq!(domain("sni:ads.com"), Some(("ads.com".to_string(), DetectedFormat::Plain)));
+        assert_eq!(domain("ESNI:Ads.Example.com"), Some(("ads.example.com".to_string(), DetectedFormat::Plain)));
+        assert_eq!(domain("tls: tracker.net # seen 2024"), Some(("tracker.net".to_string(), DetectedFormat::Plain)));
+
+        // Unlisted prefixes, and anything but a bare domain after the prefix, are left alone
+        assert_eq!(domain("ja3:ads.com"), None);
+        assert_eq!(domain("sni:ads.com:443"), None);
+        assert_eq!(domain("sni:"), None);
+
+        // Off by default
+        assert_eq!(DomainExtractor::new().extract_domain("sni:ads.com"), None);
+    }
 }
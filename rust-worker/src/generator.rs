@@ -1,14 +1,20 @@
-use anyhow::Result;
-use chrono::Utc;
+use anyhow::{bail, Context, Result};
+use chrono::{DateTime, Utc};
 use flate2::write::GzEncoder;
 use flate2::Compression;
 use rayon::prelude::*;
-use std::collections::HashMap;
-use std::fs::{self, File};
-use std::io::{BufWriter, Write};
-use tracing::info;
+use regex::Regex;
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use tracing::{info, warn};
 
 use crate::db::progress::{FormatProgress, FormatStatus, GenerationProgress, OutputFile};
+use crate::output_sink::{FilesystemSink, OutputSink, StagedOutput, TMP_SUFFIX};
 
 /// Output format types
 #[derive(Debug, Clone, Copy, PartialEq)]
@@ -16,6 +22,8 @@ pub enum OutputFormat {
     Hosts,
     Plain,
     Adblock,
+    /// Cisco Umbrella / OpenDNS destination list bulk import
+    Umbrella,
 }
 
 impl OutputFormat {
@@ -24,53 +32,469 @@ impl OutputFormat {
             OutputFormat::Hosts => "hosts",
             OutputFormat::Plain => "plain",
             OutputFormat::Adblock => "adblock",
-        }
-    }
-
-    pub fn file_suffix(&self) -> &'static str {
-        match self {
-            OutputFormat::Hosts => "_hosts.txt.gz",
-            OutputFormat::Plain => "_plain.txt.gz",
-            OutputFormat::Adblock => "_adblock.txt.gz",
+            OutputFormat::Umbrella => "umbrella",
         }
     }
 
     pub fn comment_prefix(&self) -> &'static str {
         match self {
-            OutputFormat::Hosts | OutputFormat::Plain => "#",
+            OutputFormat::Hosts | OutputFormat::Plain | OutputFormat::Umbrella => "#",
             OutputFormat::Adblock => "!",
         }
     }
 
+    /// Whether a blocked domain also blocks its subdomains in this format
+    pub fn covers_subdomains(&self) -> bool {
+        matches!(self, OutputFormat::Adblock | OutputFormat::Umbrella)
+    }
+
+    /// Formats generated when a user hasn't picked any
     pub fn all() -> Vec<OutputFormat> {
         vec![OutputFormat::Hosts, OutputFormat::Plain, OutputFormat::Adblock]
     }
+
+    /// Every format, in canonical order; the ones beyond `all()` are opt-in
+    pub fn supported() -> Vec<OutputFormat> {
+        vec![OutputFormat::Hosts, OutputFormat::Plain, OutputFormat::Adblock, OutputFormat::Umbrella]
+    }
+
+    /// Parse a format name as stored in user config ("hosts", "plain", "adblock", "umbrella")
+    pub fn from_name(name: &str) -> Option<OutputFormat> {
+        match name.trim().to_lowercase().as_str() {
+            "hosts" => Some(OutputFormat::Hosts),
+            "plain" => Some(OutputFormat::Plain),
+            "adblock" => Some(OutputFormat::Adblock),
+            "umbrella" => Some(OutputFormat::Umbrella),
+            _ => None,
+        }
+    }
+}
+
+/// zstd compression level for `.txt.zst` variants
+const ZSTD_LEVEL: i32 = 3;
+
+/// Buffer size for output file writers
+const WRITE_BUFFER_SIZE: usize = 4 * 1024 * 1024;
+
+/// Move `from` over `to`, replacing any existing file
+///
+/// The rename is atomic on POSIX, so readers see either the old or the new
+/// file. Windows refuses to replace a file that is open elsewhere; there we
+/// fall back to removing the old file first.
+pub fn replace_file(from: &Path, to: &Path) -> io::Result<()> {
+    match fs::rename(from, to) {
+        Ok(()) => Ok(()),
+        Err(_) if cfg!(windows) && to.exists() => {
+            fs::remove_file(to)?;
+            fs::rename(from, to)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// Default cap on output files open for writing at once
+pub const DEFAULT_MAX_CONCURRENT_FILE_WRITES: usize = 64;
+
+/// Output file layout unless OUTPUT_FILENAME_TEMPLATE is set (`ads_hosts.txt.gz`)
+pub const DEFAULT_FILENAME_TEMPLATE: &str = "{name}_{format}.txt.gz";
+
+/// List name of the combined (all categories) files
+pub const COMBINED_LIST_NAME: &str = "all_domains";
+
+/// Suffix of the per-category source attribution files (`ads_sources.csv.gz`)
+pub const SOURCE_ATTRIBUTION_SUFFIX: &str = "_sources.csv.gz";
+
+/// How output files are named (OUTPUT_FILENAME_TEMPLATE)
+///
+/// `{name}` is the list (a category, `uncategorized` or `all_domains`),
+/// `{category}` the same except `all` for the combined list, and `{format}`
+/// the format. Templates end in `.gz`; the zstd and uncompressed variants
+/// swap that for `.zst` or drop it. A `/` puts files in subdirectories.
+#[derive(Debug, Clone)]
+pub struct FilenameTemplate {
+    template: String,
+    /// Matches every name the template produces, capturing the list and format
+    pattern: Regex,
+}
+
+impl FilenameTemplate {
+    pub fn parse(template: &str) -> Result<Self> {
+        let Some(stem) = template.strip_suffix(".gz") else {
+            bail!("'{}' must end in .gz", template);
+        };
+        if !template.contains("{format}") {
+            bail!("'{}' has no {{format}} placeholder", template);
+        }
+        if !template.contains("{name}") && !template.contains("{category}") {
+            bail!("'{}' has neither a {{name}} nor a {{category}} placeholder", template);
+        }
+        if template.split('/').any(|segment| matches!(segment, "" | "." | "..")) {
+            bail!("'{}' must be a relative path without empty, . or .. segments", template);
+        }
+
+        // Only the first {name}/{category}/{format} captures; repeats just have to match
+        let formats: Vec<&str> = OutputFormat::supported().iter().map(|f| f.as_str()).collect();
+        let formats = formats.join("|");
+        let mut pattern = String::from("^");
+        let mut captured = HashSet::new();
+        let mut rest = stem;
+        while let Some(start) = rest.find('{') {
+            pattern.push_str(&regex::escape(&rest[..start]));
+            let end = rest[start..]
+                .find('}')
+                .map(|i| start + i)
+                .with_context(|| format!("'{}' has an unclosed placeholder", template))?;
+            let placeholder = &rest[start + 1..end];
+            let matcher = match placeholder {
+                "name" | "category" => "[^/]+",
+                "format" => formats.as_str(),
+                other => bail!("'{}' has unknown placeholder {{{}}}", template, other),
+            };
+            if captured.insert(placeholder) {
+                pattern.push_str(&format!("(?P<{}>{})", placeholder, matcher));
+            } else {
+                pattern.push_str(&format!("(?:{})", matcher));
+            }
+            rest = &rest[end + 1..];
+        }
+        pattern.push_str(&regex::escape(rest));
+        pattern.push_str(r"(?:\.gz|\.zst)?$");
+
+        Ok(Self {
+            template: template.to_string(),
+            pattern: Regex::new(&pattern)?,
+        })
+    }
+
+    /// The `.gz` file name for one list in one format
+    pub fn render(&self, list: &str, format: OutputFormat) -> String {
+        let category = if list == COMBINED_LIST_NAME { "all" } else { list };
+        self.template
+            .replace("{name}", list)
+            .replace("{category}", category)
+            .replace("{format}", format.as_str())
+    }
+
+    /// List name and format of a file this template produced (any variant)
+    pub fn parse_name(&self, filename: &str) -> Option<(String, String)> {
+        let captures = self.pattern.captures(filename)?;
+        let list = match (captures.name("name"), captures.name("category")) {
+            (Some(name), _) => name.as_str().to_string(),
+            (None, Some(category)) if category.as_str() == "all" => COMBINED_LIST_NAME.to_string(),
+            (None, Some(category)) => category.as_str().to_string(),
+            (None, None) => return None,
+        };
+        Some((list, captures["format"].to_string()))
+    }
+
+    /// Whether `filename` is one of this template's files
+    pub fn matches(&self, filename: &str) -> bool {
+        self.pattern.is_match(filename)
+    }
+
+    /// Whether `filename` belongs to the combined list
+    pub fn is_combined(&self, filename: &str) -> bool {
+        self.parse_name(filename)
+            .is_some_and(|(list, _)| list == COMBINED_LIST_NAME)
+    }
+}
+
+impl Default for FilenameTemplate {
+    fn default() -> Self {
+        Self::parse(DEFAULT_FILENAME_TEMPLATE).expect("default filename template is valid")
+    }
+}
+
+/// Counting semaphore bounding how many output files are open at once
+///
+/// Category generation fans out to (categories × formats) rayon tasks; this
+/// keeps the number of open file handles at `max` regardless.
+struct WriteLimiter {
+    max: usize,
+    open: Mutex<usize>,
+    available: Condvar,
+    /// Highest number of files open at once so far
+    peak: AtomicUsize,
+}
+
+/// Held while a file is open; releases its slot on drop
+struct WritePermit<'a>(&'a WriteLimiter);
+
+impl WriteLimiter {
+    fn new(max: usize) -> Self {
+        Self {
+            max: max.max(1),
+            open: Mutex::new(0),
+            available: Condvar::new(),
+            peak: AtomicUsize::new(0),
+        }
+    }
+
+    fn acquire(&self) -> WritePermit<'_> {
+        let mut open = self.open.lock().unwrap_or_else(|e| e.into_inner());
+        while *open >= self.max {
+            open = self.available.wait(open).unwrap_or_else(|e| e.into_inner());
+        }
+        *open += 1;
+        self.peak.fetch_max(*open, Ordering::Relaxed);
+        WritePermit(self)
+    }
+}
+
+impl Drop for WritePermit<'_> {
+    fn drop(&mut self) {
+        let mut open = self.0.open.lock().unwrap_or_else(|e| e.into_inner());
+        *open -= 1;
+        self.0.available.notify_one();
+    }
 }
 
+/// Buffered writer over a staged output file
+type StagedWriter = BufWriter<Box<dyn StagedOutput>>;
+
 /// Output file generator
 pub struct OutputGenerator {
-    output_dir: std::path::PathBuf,
+    /// Where files are written (the user's output directory by default)
+    output: Arc<dyn OutputSink>,
+    /// Also emit `.txt.zst` variants alongside the `.txt.gz` files
+    emit_zstd: bool,
+    /// Also emit plain `.txt` variants for clients that can't handle gzip
+    emit_uncompressed: bool,
+    /// Formats to generate (defaults to all)
+    formats: Vec<OutputFormat>,
+    /// Sink IP per domain: these are written as rewrites instead of blocks
+    sinks: HashMap<String, String>,
+    /// Bounds simultaneously open output files
+    write_limiter: WriteLimiter,
+    /// Drop subdomains of listed domains in formats that block subdomains anyway
+    collapse_covered_subdomains: bool,
+    /// File naming and layout
+    filename_template: FilenameTemplate,
+    /// "Generated:" time in file headers, shared by every file of a build
+    generated_at: DateTime<Utc>,
+    /// Include the "Generated:" line (off when unchanged lists must stay byte-identical)
+    generated_line: bool,
+    /// Sources that listed each domain; set to also write `{category}_sources.csv.gz`
+    source_attribution: Option<HashMap<String, Vec<String>>>,
 }
 
 impl OutputGenerator {
     /// Create a new output generator
     pub fn new(output_dir: impl Into<std::path::PathBuf>) -> Self {
         Self {
-            output_dir: output_dir.into(),
+            output: Arc::new(FilesystemSink::new(output_dir.into())),
+            emit_zstd: false,
+            emit_uncompressed: false,
+            formats: OutputFormat::all(),
+            sinks: HashMap::new(),
+            write_limiter: WriteLimiter::new(DEFAULT_MAX_CONCURRENT_FILE_WRITES),
+            collapse_covered_subdomains: false,
+            filename_template: FilenameTemplate::default(),
+            generated_at: Utc::now(),
+            generated_line: true,
+            source_attribution: None,
+        }
+    }
+
+    /// Stamp headers with a fixed time (SOURCE_DATE_EPOCH) for reproducible output
+    pub fn with_generated_at(mut self, generated_at: DateTime<Utc>) -> Self {
+        self.generated_at = generated_at;
+        self
+    }
+
+    /// Enable or disable the "Generated:" header line
+    pub fn with_generated_line(mut self, generated_line: bool) -> Self {
+        self.generated_line = generated_line;
+        self
+    }
+
+    /// Name output files after `template` instead of `{name}_{format}.txt.gz`
+    pub fn with_filename_template(mut self, template: FilenameTemplate) -> Self {
+        self.filename_template = template;
+        self
+    }
+
+    /// Enable or disable collapsing subdomains covered by a listed parent
+    pub fn with_collapse_covered_subdomains(mut self, collapse: bool) -> Self {
+        self.collapse_covered_subdomains = collapse;
+        self
+    }
+
+    /// Write through a different destination, e.g. object storage
+    pub fn with_output_sink(mut self, output: Arc<dyn OutputSink>) -> Self {
+        self.output = output;
+        self
+    }
+
+    /// Cap how many output files may be open for writing at once
+    pub fn with_max_concurrent_writes(mut self, max: usize) -> Self {
+        self.write_limiter = WriteLimiter::new(max);
+        self
+    }
+
+    /// Restrict generation to the given formats
+    pub fn with_formats(mut self, formats: Vec<OutputFormat>) -> Self {
+        self.formats = formats;
+        self
+    }
+
+    /// Enable or disable zstd variants of every generated file
+    pub fn with_zstd(mut self, emit_zstd: bool) -> Self {
+        self.emit_zstd = emit_zstd;
+        self
+    }
+
+    /// Enable or disable uncompressed `.txt` variants of every generated file
+    pub fn with_uncompressed(mut self, emit_uncompressed: bool) -> Self {
+        self.emit_uncompressed = emit_uncompressed;
+        self
+    }
+
+    /// Write a `domain,source_name` attribution file per category from these sources
+    pub fn with_source_attribution(mut self, sources: Option<HashMap<String, Vec<String>>>) -> Self {
+        self.source_attribution = sources;
+        self
+    }
+
+    /// Map every domain in a category with a configured sink IP to that IP
+    pub fn domain_sinks(
+        category_domains: &HashMap<Option<String>, Vec<String>>,
+        category_sinks: &HashMap<String, String>,
+    ) -> HashMap<String, String> {
+        if category_sinks.is_empty() {
+            return HashMap::new();
+        }
+
+        let mut sinks = HashMap::new();
+        for (category, domains) in category_domains {
+            let sink_ip = category
+                .as_deref()
+                .and_then(|c| category_sinks.get(&c.to_lowercase()));
+            if let Some(sink_ip) = sink_ip {
+                for domain in domains {
+                    sinks.insert(domain.clone(), sink_ip.clone());
+                }
+            }
+        }
+        sinks
+    }
+
+    /// Rewrite the given domains to a sink IP instead of blocking them
+    pub fn with_sinks(mut self, sinks: HashMap<String, String>) -> Self {
+        self.sinks = sinks;
+        self
+    }
+
+    /// Domains to write for a format, with covered subdomains collapsed if enabled
+    ///
+    /// Only applies to formats where `||parent^` already blocks every
+    /// subdomain; hosts and plain lists always get the full set. Umbrella
+    /// is always collapsed: its import wants the highest listed domain only,
+    /// so its entries are normalized first and spellings of one domain merge.
+    /// Case-only duplicates are dropped for every format.
+    fn domains_for<'a>(
+        &self,
+        format: OutputFormat,
+        domains: &'a [String],
+        adblock_rules: &HashMap<String, String>,
+    ) -> Cow<'a, [String]> {
+        let domains = if format == OutputFormat::Umbrella {
+            Self::umbrella_domains(domains)
+        } else {
+            match Self::fold_case_duplicates(domains) {
+                Some(folded) => Cow::Owned(folded),
+                None => Cow::Borrowed(domains),
+            }
+        };
+        let collapse = match format {
+            OutputFormat::Umbrella => true,
+            _ => self.collapse_covered_subdomains && format.covers_subdomains(),
+        };
+        if !collapse {
+            return domains;
+        }
+        Cow::Owned(self.collapse_covered(&domains, adblock_rules))
+    }
+
+    /// Domains deduplicated ignoring ASCII case, or None if none has uppercase
+    ///
+    /// Extraction lowercases domains, so this is a safety net for paths that
+    /// keep a source's spelling. Each entry stays at its first position; the
+    /// lowercase spelling is kept when listed, otherwise the first one seen.
+    fn fold_case_duplicates(domains: &[String]) -> Option<Vec<String>> {
+        if !domains.iter().any(|d| d.bytes().any(|b| b.is_ascii_uppercase())) {
+            return None;
+        }
+
+        let mut positions: HashMap<String, usize> = HashMap::with_capacity(domains.len());
+        let mut folded: Vec<String> = Vec::with_capacity(domains.len());
+        for domain in domains {
+            let key = domain.to_ascii_lowercase();
+            match positions.get(&key) {
+                Some(&idx) if *domain == key => folded[idx] = key,
+                Some(_) => {}
+                None => {
+                    positions.insert(key, folded.len());
+                    folded.push(domain.clone());
+                }
+            }
         }
+        Some(folded)
+    }
+
+    /// Remove domains whose parent is also listed with the same effect
+    ///
+    /// A rule carrying modifiers (`$third-party`, `$important`, ...) doesn't
+    /// behave like a plain block, so such domains are neither collapsed nor
+    /// used to collapse others; neither are subdomains sunk to a different IP.
+    fn collapse_covered(&self, domains: &[String], adblock_rules: &HashMap<String, String>) -> Vec<String> {
+        let is_plain_block = |domain: &str| {
+            adblock_rules
+                .get(domain)
+                .is_none_or(|rule| rule.strip_prefix("||").and_then(|r| r.strip_suffix('^')) == Some(domain))
+        };
+        let parents: HashSet<&str> = domains
+            .iter()
+            .map(String::as_str)
+            .filter(|d| is_plain_block(d))
+            .collect();
+
+        domains
+            .iter()
+            .filter(|domain| {
+                if !is_plain_block(domain) {
+                    return true;
+                }
+                let sink = self.sinks.get(domain.as_str());
+                let covered = domain
+                    .match_indices('.')
+                    .map(|(i, _)| &domain[i + 1..])
+                    .any(|parent| parents.contains(parent) && self.sinks.get(parent) == sink);
+                !covered
+            })
+            .cloned()
+            .collect()
     }
 
     /// Generate header lines for output file
     fn generate_header(&self, format: OutputFormat, domain_count: u64) -> String {
-        let prefix = format.comment_prefix();
-        let now = Utc::now().format("%Y-%m-%dT%H:%M:%SZ");
+        // Umbrella's bulk import treats every line as a destination
+        if format == OutputFormat::Umbrella {
+            return String::new();
+        }
 
-        format!(
+        let prefix = format.comment_prefix();
+        let mut header = format!(
             "{} Blocklist generated by lists.zachlagden.uk\n\
-             {} Total domains: {}\n\
-             {} Generated: {}\n\n",
-            prefix, prefix, domain_count, prefix, now
-        )
+             {} Total domains: {}\n",
+            prefix, prefix, domain_count
+        );
+        if self.generated_line {
+            let now = self.generated_at.format("%Y-%m-%dT%H:%M:%SZ");
+            header.push_str(&format!("{} Generated: {}\n", prefix, now));
+        }
+        header.push('\n');
+        header
     }
 
     /// Write a domain directly to encoder without intermediate String allocation
@@ -107,11 +531,115 @@ impl OutputGenerator {
                 encoder.write_all(domain.as_bytes())?;
                 encoder.write_all(b"^\n")?;
             }
+            OutputFormat::Umbrella => {
+                encoder.write_all(Self::umbrella_domain(domain).as_bytes())?;
+                encoder.write_all(b"\n")?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Umbrella-normalized domains, each kept once at its first position
+    ///
+    /// Borrowed when nothing needs normalizing, the usual case since
+    /// extraction already lowercases and strips trailing dots.
+    fn umbrella_domains(domains: &[String]) -> Cow<'_, [String]> {
+        if domains.iter().all(|d| Self::umbrella_domain(d) == d.as_str()) {
+            return Cow::Borrowed(domains);
+        }
+
+        let mut seen: HashSet<String> = HashSet::with_capacity(domains.len());
+        let mut normalized: Vec<String> = Vec::with_capacity(domains.len());
+        for domain in domains {
+            let domain = Self::umbrella_domain(domain).into_owned();
+            if seen.insert(domain.clone()) {
+                normalized.push(domain);
+            }
+        }
+        Cow::Owned(normalized)
+    }
+
+    /// Normalize a domain for Umbrella: lowercase, no wildcard label, no trailing dot
+    fn umbrella_domain(domain: &str) -> Cow<'_, str> {
+        let domain = domain.strip_prefix("*.").unwrap_or(domain).trim_end_matches('.');
+        if domain.bytes().any(|b| b.is_ascii_uppercase()) {
+            Cow::Owned(domain.to_ascii_lowercase())
+        } else {
+            Cow::Borrowed(domain)
+        }
+    }
+
+    /// Write a rewrite-to-sink rule for a domain
+    /// For hosts: `{ip} domain`; for adblock: `||domain^$dnsrewrite={ip}`;
+    /// plain and umbrella lists can't express a target, so the domain is written as-is
+    #[inline]
+    fn write_sink_rewrite<W: Write>(
+        encoder: &mut W,
+        format: OutputFormat,
+        domain: &str,
+        sink_ip: &str,
+    ) -> std::io::Result<()> {
+        match format {
+            OutputFormat::Hosts => {
+                encoder.write_all(sink_ip.as_bytes())?;
+                encoder.write_all(b" ")?;
+                encoder.write_all(domain.as_bytes())?;
+                encoder.write_all(b"\n")?;
+            }
+            OutputFormat::Plain => {
+                encoder.write_all(domain.as_bytes())?;
+                encoder.write_all(b"\n")?;
+            }
+            OutputFormat::Adblock => {
+                encoder.write_all(b"||")?;
+                encoder.write_all(domain.as_bytes())?;
+                encoder.write_all(b"^$dnsrewrite=")?;
+                encoder.write_all(sink_ip.as_bytes())?;
+                encoder.write_all(b"\n")?;
+            }
+            // Destination lists only block; the domain is listed as-is
+            OutputFormat::Umbrella => Self::write_domain(encoder, format, domain, None)?,
         }
         Ok(())
     }
 
+    /// Write a domain as a sink rewrite if one is configured, otherwise as a block
+    #[inline]
+    fn write_entry<W: Write>(
+        &self,
+        encoder: &mut W,
+        format: OutputFormat,
+        domain: &str,
+        adblock_rules: Option<&HashMap<String, String>>,
+    ) -> std::io::Result<()> {
+        if !self.sinks.is_empty() {
+            if let Some(sink_ip) = self.sinks.get(domain) {
+                return Self::write_sink_rewrite(encoder, format, domain, sink_ip);
+            }
+        }
+        Self::write_domain(encoder, format, domain, adblock_rules)
+    }
+
+    /// Write an output file atomically, returning its final size
+    ///
+    /// `write` streams into `{filename}.tmp` and returns the writer once the
+    /// compressor is finished; the temp file is then synced and renamed over
+    /// `filename`. On failure the temp file is removed and any existing file
+    /// is left untouched, so a crash never leaves a partial list being served.
+    fn write_atomic(
+        &self,
+        filename: &str,
+        write: impl FnOnce(StagedWriter) -> Result<StagedWriter>,
+    ) -> Result<u64> {
+        let _permit = self.write_limiter.acquire();
+        let staged = self.output.create(filename)?;
+        let buf_writer = write(BufWriter::with_capacity(WRITE_BUFFER_SIZE, staged))?;
+        let staged = buf_writer.into_inner().map_err(|e| e.into_error())?;
+        Ok(staged.commit()?)
+    }
+
     /// Generate a single output file (optimized: zero string allocations per domain)
+    #[cfg(test)]
     pub fn generate_file(
         &self,
         format: OutputFormat,
@@ -120,39 +648,34 @@ impl OutputGenerator {
         mut progress_callback: impl FnMut(u64, u64),
     ) -> Result<OutputFile> {
         let total_domains = domains.len() as u64;
-        let filename = format!("all_domains{}", format.file_suffix());
-        let output_path = self.output_dir.join(&filename);
+        let filename = self.filename_template.render(COMBINED_LIST_NAME, format);
 
         // Ensure output directory exists
-        fs::create_dir_all(&self.output_dir)?;
-
-        // Create gzip encoder writing to file with fast compression
-        let file = File::create(&output_path)?;
-        let buf_writer = BufWriter::with_capacity(4 * 1024 * 1024, file); // 4MB buffer
-        let mut encoder = GzEncoder::new(buf_writer, Compression::fast()); // Level 1 for speed
-
-        // Write header
-        let header = self.generate_header(format, total_domains);
-        encoder.write_all(header.as_bytes())?;
-
-        // Write domains directly without String allocation
-        let update_interval = (total_domains / 100).max(1000);
-        let rules_ref = if format == OutputFormat::Adblock { Some(adblock_rules) } else { None };
-        for (i, domain) in domains.iter().enumerate() {
-            Self::write_domain(&mut encoder, format, domain, rules_ref)?;
-
-            // Progress callback (sparse)
-            if i as u64 % update_interval == 0 {
-                progress_callback(i as u64, total_domains);
-            }
-        }
+        self.output.prepare()?;
 
-        // Finish compression
-        let buf_writer = encoder.finish()?;
-        buf_writer.into_inner()?.sync_all()?;
+        let gz_size = self.write_atomic(&filename, |buf_writer| {
+            // Gzip with fast compression (level 1 for speed)
+            let mut encoder = GzEncoder::new(buf_writer, Compression::fast());
 
-        // Get file size
-        let gz_size = fs::metadata(&output_path)?.len();
+            // Write header
+            let header = self.generate_header(format, total_domains);
+            encoder.write_all(header.as_bytes())?;
+
+            // Write domains directly without String allocation
+            let update_interval = (total_domains / 100).max(1000);
+            let rules_ref = if format == OutputFormat::Adblock { Some(adblock_rules) } else { None };
+            for (i, domain) in domains.iter().enumerate() {
+                self.write_entry(&mut encoder, format, domain, rules_ref)?;
+
+                // Progress callback (sparse)
+                if (i as u64).is_multiple_of(update_interval) {
+                    progress_callback(i as u64, total_domains);
+                }
+            }
+
+            // Finish compression
+            Ok(encoder.finish()?)
+        })?;
 
         info!(
             "Generated {} ({} domains, {} bytes compressed)",
@@ -164,6 +687,7 @@ impl OutputGenerator {
             format: format.as_str().to_string(),
             size_bytes: gz_size,
             domain_count: total_domains,
+            compression: "gz".to_string(),
         })
     }
 
@@ -175,29 +699,25 @@ impl OutputGenerator {
         adblock_rules: &HashMap<String, String>,
     ) -> Result<OutputFile> {
         let total_domains = domains.len() as u64;
-        let filename = format!("all_domains{}", format.file_suffix());
-        let output_path = self.output_dir.join(&filename);
-
-        // Create gzip encoder with fast compression
-        let file = File::create(&output_path)?;
-        let buf_writer = BufWriter::with_capacity(4 * 1024 * 1024, file);
-        let mut encoder = GzEncoder::new(buf_writer, Compression::fast());
+        let filename = self.filename_template.render(COMBINED_LIST_NAME, format);
 
-        // Write header
-        let header = self.generate_header(format, total_domains);
-        encoder.write_all(header.as_bytes())?;
+        let gz_size = self.write_atomic(&filename, |buf_writer| {
+            // Gzip with fast compression
+            let mut encoder = GzEncoder::new(buf_writer, Compression::fast());
 
-        // Write all domains directly
-        let rules_ref = if format == OutputFormat::Adblock { Some(adblock_rules) } else { None };
-        for domain in domains {
-            Self::write_domain(&mut encoder, format, domain, rules_ref)?;
-        }
+            // Write header
+            let header = self.generate_header(format, total_domains);
+            encoder.write_all(header.as_bytes())?;
 
-        // Finish compression
-        let buf_writer = encoder.finish()?;
-        buf_writer.into_inner()?.sync_all()?;
+            // Write all domains directly
+            let rules_ref = if format == OutputFormat::Adblock { Some(adblock_rules) } else { None };
+            for domain in domains {
+                self.write_entry(&mut encoder, format, domain, rules_ref)?;
+            }
 
-        let gz_size = fs::metadata(&output_path)?.len();
+            // Finish compression
+            Ok(encoder.finish()?)
+        })?;
 
         info!(
             "Generated {} ({} domains, {} bytes compressed)",
@@ -209,9 +729,104 @@ impl OutputGenerator {
             format: format.as_str().to_string(),
             size_bytes: gz_size,
             domain_count: total_domains,
+            compression: "gz".to_string(),
+        })
+    }
+
+    /// Generate the zstd variant of a gzip output file (`name.txt.gz` -> `name.txt.zst`)
+    fn generate_zstd_variant(
+        &self,
+        gz_filename: &str,
+        format: OutputFormat,
+        domains: &[String],
+        adblock_rules: &HashMap<String, String>,
+    ) -> Result<OutputFile> {
+        let total_domains = domains.len() as u64;
+        let filename = format!("{}.zst", gz_filename.trim_end_matches(".gz"));
+
+        let zst_size = self.write_atomic(&filename, |buf_writer| {
+            let mut encoder = zstd::stream::write::Encoder::new(buf_writer, ZSTD_LEVEL)?;
+
+            // Write header
+            let header = self.generate_header(format, total_domains);
+            encoder.write_all(header.as_bytes())?;
+
+            // Write all domains
+            let rules_ref = if format == OutputFormat::Adblock { Some(adblock_rules) } else { None };
+            for domain in domains {
+                self.write_entry(&mut encoder, format, domain, rules_ref)?;
+            }
+
+            // Finish compression
+            Ok(encoder.finish()?)
+        })?;
+
+        info!(
+            "Generated {} ({} domains, {} bytes compressed)",
+            filename, total_domains, zst_size
+        );
+
+        Ok(OutputFile {
+            name: filename,
+            format: format.as_str().to_string(),
+            size_bytes: zst_size,
+            domain_count: total_domains,
+            compression: "zst".to_string(),
+        })
+    }
+
+    /// Generate the uncompressed variant of a gzip output file (`name.txt.gz` -> `name.txt`)
+    fn generate_uncompressed_variant(
+        &self,
+        gz_filename: &str,
+        format: OutputFormat,
+        domains: &[String],
+        adblock_rules: &HashMap<String, String>,
+    ) -> Result<OutputFile> {
+        let total_domains = domains.len() as u64;
+        let filename = gz_filename.trim_end_matches(".gz").to_string();
+
+        let size = self.write_atomic(&filename, |mut buf_writer| {
+            let header = self.generate_header(format, total_domains);
+            buf_writer.write_all(header.as_bytes())?;
+
+            let rules_ref = if format == OutputFormat::Adblock { Some(adblock_rules) } else { None };
+            for domain in domains {
+                self.write_entry(&mut buf_writer, format, domain, rules_ref)?;
+            }
+
+            Ok(buf_writer)
+        })?;
+
+        info!("Generated {} ({} domains, {} bytes)", filename, total_domains, size);
+
+        Ok(OutputFile {
+            name: filename,
+            format: format.as_str().to_string(),
+            size_bytes: size,
+            domain_count: total_domains,
+            compression: "none".to_string(),
         })
     }
 
+    /// Generate the enabled zstd/uncompressed variants of a gzip output file
+    fn generate_variants(
+        &self,
+        gz_file: &OutputFile,
+        format: OutputFormat,
+        domains: &[String],
+        adblock_rules: &HashMap<String, String>,
+    ) -> Result<Vec<OutputFile>> {
+        let mut variants = Vec::new();
+        if self.emit_zstd {
+            variants.push(self.generate_zstd_variant(&gz_file.name, format, domains, adblock_rules)?);
+        }
+        if self.emit_uncompressed {
+            variants.push(self.generate_uncompressed_variant(&gz_file.name, format, domains, adblock_rules)?);
+        }
+        Ok(variants)
+    }
+
     /// Generate all output formats in parallel (optimized)
     pub fn generate_all(
         &self,
@@ -220,14 +835,17 @@ impl OutputGenerator {
         mut progress_callback: impl FnMut(&GenerationProgress),
     ) -> Result<Vec<OutputFile>> {
         let total_domains = domains.len() as u64;
-        let formats = OutputFormat::all();
+        let formats = &self.formats;
 
         // Ensure output directory exists before parallel execution
-        fs::create_dir_all(&self.output_dir)?;
+        self.output.prepare()?;
 
         // Initialize progress - show all as generating since they run in parallel
         let mut progress = GenerationProgress {
-            current_format: Some("hosts, plain, adblock (parallel)".to_string()),
+            current_format: Some(format!(
+                "{} (parallel)",
+                formats.iter().map(|f| f.as_str()).collect::<Vec<_>>().join(", ")
+            )),
             formats: formats
                 .iter()
                 .map(|f| FormatProgress {
@@ -245,20 +863,26 @@ impl OutputGenerator {
         progress_callback(&progress);
 
         // Generate all formats in parallel using rayon
-        let results: Vec<Result<OutputFile>> = formats
+        let results: Vec<Result<(OutputFile, Vec<OutputFile>)>> = formats
             .par_iter()
-            .map(|format| self.generate_file_parallel(*format, domains, adblock_rules))
+            .map(|format| {
+                let domains = self.domains_for(*format, domains, adblock_rules);
+                let gz_file = self.generate_file_parallel(*format, &domains, adblock_rules)?;
+                let variants = self.generate_variants(&gz_file, *format, &domains, adblock_rules)?;
+                Ok((gz_file, variants))
+            })
             .collect();
 
         // Collect results and update progress
         let mut output_files = Vec::with_capacity(formats.len());
         for (idx, result) in results.into_iter().enumerate() {
-            let output_file = result?;
+            let (output_file, variants) = result?;
             progress.formats[idx].status = FormatStatus::Completed;
-            progress.formats[idx].domains_written = total_domains;
+            progress.formats[idx].domains_written = output_file.domain_count;
             progress.formats[idx].percent = 100.0;
             progress.formats[idx].gz_size = Some(output_file.size_bytes);
             output_files.push(output_file);
+            output_files.extend(variants);
         }
 
         progress.current_format = None;
@@ -277,32 +901,26 @@ impl OutputGenerator {
     ) -> Result<OutputFile> {
         let total_domains = domains.len() as u64;
 
-        // Filename: category_format.txt.gz or uncategorized_format.txt.gz
-        let filename = match category {
-            Some(cat) => format!("{}_{}.txt.gz", cat, format.as_str()),
-            None => format!("uncategorized_{}.txt.gz", format.as_str()),
-        };
-        let output_path = self.output_dir.join(&filename);
+        // Filename from the template; sources without a category go to "uncategorized"
+        let filename = self.filename_template.render(category.unwrap_or("uncategorized"), format);
 
-        // Create gzip encoder with fast compression
-        let file = File::create(&output_path)?;
-        let buf_writer = BufWriter::with_capacity(4 * 1024 * 1024, file);
-        let mut encoder = GzEncoder::new(buf_writer, Compression::fast());
+        let gz_size = self.write_atomic(&filename, |buf_writer| {
+            // Gzip with fast compression
+            let mut encoder = GzEncoder::new(buf_writer, Compression::fast());
 
-        // Write header
-        let header = self.generate_header(format, total_domains);
-        encoder.write_all(header.as_bytes())?;
+            // Write header
+            let header = self.generate_header(format, total_domains);
+            encoder.write_all(header.as_bytes())?;
 
-        // Write all domains
-        let rules_ref = if format == OutputFormat::Adblock { Some(adblock_rules) } else { None };
-        for domain in domains {
-            Self::write_domain(&mut encoder, format, domain, rules_ref)?;
-        }
+            // Write all domains
+            let rules_ref = if format == OutputFormat::Adblock { Some(adblock_rules) } else { None };
+            for domain in domains {
+                self.write_entry(&mut encoder, format, domain, rules_ref)?;
+            }
 
-        // Finish compression
-        let buf_writer = encoder.finish()?;
-        buf_writer.into_inner()?.sync_all()?;
-        let gz_size = fs::metadata(&output_path)?.len();
+            // Finish compression
+            Ok(encoder.finish()?)
+        })?;
 
         info!(
             "Generated {} ({} domains, {} bytes compressed)",
@@ -314,6 +932,7 @@ impl OutputGenerator {
             format: format.as_str().to_string(),
             size_bytes: gz_size,
             domain_count: total_domains,
+            compression: "gz".to_string(),
         })
     }
 
@@ -324,53 +943,179 @@ impl OutputGenerator {
         adblock_rules: &HashMap<String, String>,
     ) -> Result<Vec<OutputFile>> {
         // Ensure output directory exists before parallel execution
-        fs::create_dir_all(&self.output_dir)?;
-
-        // Build task list: (category, format) pairs with domain reference
-        let tasks: Vec<(Option<&str>, OutputFormat, &[String])> = category_domains
-            .iter()
+        self.output.prepare()?;
+
+        // Build task list: (category, format) pairs with domain reference, in
+        // category order so the returned files don't follow hash order
+        let mut categories: Vec<(&Option<String>, &Vec<String>)> = category_domains.iter().collect();
+        categories.sort_by(|a, b| a.0.cmp(b.0));
+        let tasks: Vec<(Option<&str>, OutputFormat, &[String])> = categories
+            .into_iter()
             .flat_map(|(cat, domains)| {
-                OutputFormat::all().into_iter()
-                    .map(move |fmt| (cat.as_deref(), fmt, domains.as_slice()))
+                self.formats.iter()
+                    .map(move |fmt| (cat.as_deref(), *fmt, domains.as_slice()))
             })
             .collect();
 
         info!(
-            "Generating {} category files ({} categories × 3 formats)",
+            "Generating {} category files ({} categories × {} formats)",
             tasks.len(),
-            category_domains.len()
+            category_domains.len(),
+            self.formats.len()
         );
 
         // Generate all files in parallel using rayon
-        let results: Vec<Result<OutputFile>> = tasks
+        let results: Vec<Result<Vec<OutputFile>>> = tasks
             .par_iter()
             .map(|(category, format, domains)| {
-                self.generate_category_file(*category, *format, domains, adblock_rules)
+                let domains = self.domains_for(*format, domains, adblock_rules);
+                let gz_file = self.generate_category_file(*category, *format, &domains, adblock_rules)?;
+                let variants = self.generate_variants(&gz_file, *format, &domains, adblock_rules)?;
+                Ok(std::iter::once(gz_file).chain(variants).collect())
             })
             .collect();
 
         // Collect results
-        let output_files: Result<Vec<OutputFile>> = results.into_iter().collect();
-        output_files
-    }
-
-    /// Clean up old output files
-    pub fn cleanup_old_files(&self) -> Result<()> {
-        if !self.output_dir.exists() {
-            return Ok(());
-        }
-
-        // Remove any legacy uncompressed files
-        for entry in fs::read_dir(&self.output_dir)? {
-            let entry = entry?;
-            let path = entry.path();
-            if path.extension().map(|e| e == "txt").unwrap_or(false) {
-                // Check if it's not a .gz file (legacy uncompressed)
-                let filename = path.file_name().and_then(|n| n.to_str()).unwrap_or("");
-                if !filename.ends_with(".gz") {
-                    info!("Removing legacy uncompressed file: {:?}", path);
-                    fs::remove_file(path)?;
+        let mut output_files = Vec::with_capacity(results.len());
+        for result in results {
+            output_files.extend(result?);
+        }
+        Ok(output_files)
+    }
+
+    /// Quote a CSV field if it holds a comma, quote or line break
+    fn csv_field(value: &str) -> Cow<'_, str> {
+        if value.contains([',', '"', '\n', '\r']) {
+            Cow::Owned(format!("\"{}\"", value.replace('"', "\"\"")))
+        } else {
+            Cow::Borrowed(value)
+        }
+    }
+
+    /// Write `{category}_sources.csv.gz` for every category (attribution mode only)
+    ///
+    /// One `domain,source_name` row per source that listed the domain, in the
+    /// category's domain order. Domains without a recorded source (e.g. added
+    /// by the operator layer) are left out. These aren't lists, so they're kept
+    /// apart from the list files; pass them to `cleanup_old_files` with them.
+    pub fn generate_source_attribution(
+        &self,
+        category_domains: &HashMap<Option<String>, Vec<String>>,
+    ) -> Result<Vec<OutputFile>> {
+        let Some(sources) = &self.source_attribution else {
+            return Ok(Vec::new());
+        };
+        self.output.prepare()?;
+
+        let mut categories: Vec<(&Option<String>, &Vec<String>)> = category_domains.iter().collect();
+        categories.sort_by(|a, b| a.0.cmp(b.0));
+
+        let mut files = Vec::with_capacity(categories.len());
+        for (category, domains) in categories {
+            let filename = format!(
+                "{}{}",
+                category.as_deref().unwrap_or("uncategorized"),
+                SOURCE_ATTRIBUTION_SUFFIX
+            );
+
+            let mut attributed = 0u64;
+            let gz_size = self.write_atomic(&filename, |buf_writer| {
+                let mut encoder = GzEncoder::new(buf_writer, Compression::fast());
+                encoder.write_all(b"domain,source_name\n")?;
+                for domain in domains {
+                    let Some(names) = sources.get(domain) else {
+                        continue;
+                    };
+                    attributed += 1;
+                    for name in names {
+                        writeln!(encoder, "{},{}", domain, Self::csv_field(name))?;
+                    }
                 }
+                Ok(encoder.finish()?)
+            })?;
+
+            info!("Generated {} ({} domains, {} bytes compressed)", filename, attributed, gz_size);
+            files.push(OutputFile {
+                name: filename,
+                format: "sources".to_string(),
+                size_bytes: gz_size,
+                domain_count: attributed,
+                compression: "gz".to_string(),
+            });
+        }
+
+        Ok(files)
+    }
+
+    /// Count domain entries in a generated file (non-empty, non-comment lines)
+    fn count_entries(&self, file: &OutputFile) -> Result<u64> {
+        let file_reader = self.output.open(&file.name)?;
+        let reader: Box<dyn std::io::Read> = match file.compression.as_str() {
+            "zst" => Box::new(zstd::stream::read::Decoder::new(file_reader)?),
+            "none" => file_reader,
+            _ => Box::new(flate2::read::GzDecoder::new(file_reader)),
+        };
+
+        let mut count = 0;
+        for line in BufReader::new(reader).lines() {
+            let line = line?;
+            if line.is_empty() || line.starts_with('#') || line.starts_with('!') {
+                continue;
+            }
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    /// Verify each generated file contains exactly its recorded `domain_count` entries
+    pub fn verify_output_files(&self, files: &[OutputFile]) -> Result<()> {
+        let mismatches: Vec<String> = files
+            .par_iter()
+            .filter_map(|file| match self.count_entries(file) {
+                Ok(actual) if actual == file.domain_count => None,
+                Ok(actual) => Some(format!(
+                    "{}: recorded {} domains but file contains {}",
+                    file.name, file.domain_count, actual
+                )),
+                Err(e) => Some(format!("{}: failed to read back: {}", file.name, e)),
+            })
+            .collect();
+
+        if !mismatches.is_empty() {
+            anyhow::bail!("Output verification failed: {}", mismatches.join("; "));
+        }
+
+        info!("Verified domain counts of {} output files", files.len());
+        Ok(())
+    }
+
+    /// Clean up old output files once the new set is in place
+    ///
+    /// Removes uncompressed files not in `current`, leftover `.tmp` files from an
+    /// interrupted run, and lists not in `current` (e.g. a dropped category
+    /// or a format that is no longer enabled). Only names the filename
+    /// template could have produced, and source attribution files, are touched.
+    pub fn cleanup_old_files(&self, current: &[OutputFile]) -> Result<()> {
+        let keep: HashSet<&str> = current.iter().map(|f| f.name.as_str()).collect();
+
+        for name in self.output.list()? {
+            let filename = name.as_str();
+
+            let reason = if filename.ends_with(TMP_SUFFIX) {
+                "leftover temporary"
+            } else if keep.contains(filename)
+                || !(self.filename_template.matches(filename) || filename.ends_with(SOURCE_ATTRIBUTION_SUFFIX))
+            {
+                continue;
+            } else if filename.ends_with(".gz") || filename.ends_with(".zst") {
+                "stale"
+            } else {
+                "uncompressed"
+            };
+
+            info!("Removing {} file: {}", reason, filename);
+            if let Err(e) = self.output.remove(filename) {
+                warn!("Failed to remove {}: {}", filename, e);
             }
         }
 
@@ -381,6 +1126,8 @@ impl OutputGenerator {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::output_sink::memory::MemorySink;
+    use std::fs::File;
     use tempfile::TempDir;
 
     #[test]
@@ -463,4 +1210,467 @@ mod tests {
         assert_eq!(output.domain_count, 2);
         // File should contain ||ads.example.com^$important and ||plain.example.com^
     }
+
+    #[test]
+    fn test_zstd_variant_matches_gzip() {
+        use flate2::read::GzDecoder;
+        use std::io::Read;
+
+        let temp_dir = TempDir::new().unwrap();
+        let generator = OutputGenerator::new(temp_dir.path()).with_zstd(true);
+
+        let domains = vec![
+            "ads.example.com".to_string(),
+            "plain.example.com".to_string(),
+            "tracker.example.com".to_string(),
+        ];
+        let mut adblock_rules = HashMap::new();
+        adblock_rules.insert("ads.example.com".to_string(), "||ads.example.com^$important".to_string());
+
+        let outputs = generator.generate_all(&domains, &adblock_rules, |_| {}).unwrap();
+        assert_eq!(outputs.len(), 6);
+
+        // Strip header comments so the generation timestamps can't differ
+        let body = |content: String| -> Vec<String> {
+            content
+                .lines()
+                .filter(|l| !l.is_empty() && !l.starts_with('#') && !l.starts_with('!'))
+                .map(String::from)
+                .collect()
+        };
+
+        for format in OutputFormat::all() {
+            let gz = outputs
+                .iter()
+                .find(|f| f.format == format.as_str() && f.compression == "gz")
+                .unwrap();
+            let zst = outputs
+                .iter()
+                .find(|f| f.format == format.as_str() && f.compression == "zst")
+                .unwrap();
+            assert_eq!(zst.name, gz.name.replace(".txt.gz", ".txt.zst"));
+            assert_eq!(zst.domain_count, gz.domain_count);
+
+            let mut gz_content = String::new();
+            GzDecoder::new(File::open(temp_dir.path().join(&gz.name)).unwrap())
+                .read_to_string(&mut gz_content)
+                .unwrap();
+            let zst_content =
+                zstd::stream::decode_all(File::open(temp_dir.path().join(&zst.name)).unwrap()).unwrap();
+
+            let gz_lines = body(gz_content);
+            assert_eq!(gz_lines.len(), domains.len());
+            assert_eq!(body(String::from_utf8(zst_content).unwrap()), gz_lines);
+        }
+    }
+
+    #[test]
+    fn test_verify_output_files() {
+        let temp_dir = TempDir::new().unwrap();
+        let generator = OutputGenerator::new(temp_dir.path()).with_zstd(true);
+
+        let domains = vec!["ads.example.com".to_string(), "tracker.example.com".to_string()];
+        let mut outputs = generator.generate_all(&domains, &HashMap::new(), |_| {}).unwrap();
+        generator.verify_output_files(&outputs).unwrap();
+
+        // A miscounted file must fail verification
+        outputs[0].domain_count += 1;
+        let err = generator.verify_output_files(&outputs).unwrap_err();
+        assert!(err.to_string().contains(&outputs[0].name));
+    }
+
+    #[test]
+    fn test_failed_write_leaves_old_file_intact() {
+        let temp_dir = TempDir::new().unwrap();
+        let generator = OutputGenerator::new(temp_dir.path());
+
+        let domains = vec!["ads.example.com".to_string()];
+        let old = generator
+            .generate_file(OutputFormat::Plain, &domains, &HashMap::new(), |_, _| {})
+            .unwrap();
+        let old_content = fs::read(temp_dir.path().join(&old.name)).unwrap();
+
+        // Simulate a crash partway through writing the replacement
+        let result = generator.write_atomic(&old.name, |mut buf_writer| {
+            buf_writer.write_all(b"partial")?;
+            anyhow::bail!("simulated failure")
+        });
+        assert!(result.is_err());
+
+        assert_eq!(fs::read(temp_dir.path().join(&old.name)).unwrap(), old_content);
+        assert!(!temp_dir.path().join(format!("{}{}", old.name, TMP_SUFFIX)).exists());
+
+        // Stale and temporary files are only removed by cleanup
+        fs::write(temp_dir.path().join("dropped_plain.txt.gz"), b"").unwrap();
+        fs::write(temp_dir.path().join("x_plain.txt.gz.tmp"), b"").unwrap();
+        generator.cleanup_old_files(std::slice::from_ref(&old)).unwrap();
+
+        let mut remaining: Vec<String> = fs::read_dir(temp_dir.path())
+            .unwrap()
+            .map(|e| e.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        remaining.sort();
+        assert_eq!(remaining, vec![old.name]);
+    }
+
+    #[test]
+    fn test_category_sink_writes_rewrites() {
+        use std::io::Read;
+
+        let temp_dir = TempDir::new().unwrap();
+
+        let mut category_sinks = HashMap::new();
+        category_sinks.insert("malware".to_string(), "10.0.0.53".to_string());
+
+        let mut by_category = HashMap::new();
+        by_category.insert(Some("malware".to_string()), vec!["evil.com".to_string()]);
+        by_category.insert(Some("ads".to_string()), vec!["ads.com".to_string()]);
+
+        let sinks = OutputGenerator::domain_sinks(&by_category, &category_sinks);
+        let generator = OutputGenerator::new(temp_dir.path()).with_sinks(sinks);
+        generator.generate_all_categories(&by_category, &HashMap::new()).unwrap();
+
+        let read = |name: &str| -> String {
+            let mut content = String::new();
+            flate2::read::GzDecoder::new(File::open(temp_dir.path().join(name)).unwrap())
+                .read_to_string(&mut content)
+                .unwrap();
+            content
+        };
+
+        assert!(read("malware_hosts.txt.gz").contains("\n10.0.0.53 evil.com\n"));
+        assert!(read("malware_adblock.txt.gz").contains("\n||evil.com^$dnsrewrite=10.0.0.53\n"));
+        assert!(read("ads_hosts.txt.gz").contains("\n0.0.0.0 ads.com\n"));
+        assert!(read("ads_adblock.txt.gz").contains("\n||ads.com^\n"));
+    }
+
+    #[test]
+    fn test_uncompressed_variant_matches_gzip() {
+        use flate2::read::GzDecoder;
+        use std::io::Read;
+
+        let temp_dir = TempDir::new().unwrap();
+        let generator = OutputGenerator::new(temp_dir.path())
+            .with_formats(vec![OutputFormat::Hosts, OutputFormat::Adblock])
+            .with_uncompressed(true);
+
+        let domains = vec!["ads.example.com".to_string(), "tracker.example.com".to_string()];
+        let outputs = generator.generate_all(&domains, &HashMap::new(), |_| {}).unwrap();
+        assert_eq!(outputs.len(), 4);
+
+        for gz in outputs.iter().filter(|f| f.compression == "gz") {
+            let txt = outputs
+                .iter()
+                .find(|f| f.format == gz.format && f.compression == "none")
+                .unwrap();
+            assert_eq!(txt.name, gz.name.trim_end_matches(".gz"));
+            assert_eq!(txt.domain_count, gz.domain_count);
+
+            let mut gz_content = String::new();
+            GzDecoder::new(File::open(temp_dir.path().join(&gz.name)).unwrap())
+                .read_to_string(&mut gz_content)
+                .unwrap();
+            let txt_content = fs::read_to_string(temp_dir.path().join(&txt.name)).unwrap();
+            assert_eq!(txt.size_bytes, txt_content.len() as u64);
+
+            // Compare past the header so the generation timestamps can't differ
+            let body = |content: &str| -> Vec<String> {
+                content.lines().filter(|l| !l.starts_with('#') && !l.starts_with('!')).map(String::from).collect()
+            };
+            assert_eq!(body(&txt_content), body(&gz_content));
+        }
+
+        // Current .txt variants survive cleanup and read back with the right counts
+        generator.cleanup_old_files(&outputs).unwrap();
+        generator.verify_output_files(&outputs).unwrap();
+    }
+
+    #[test]
+    fn test_concurrent_writes_capped() {
+        let temp_dir = TempDir::new().unwrap();
+        let generator = OutputGenerator::new(temp_dir.path())
+            .with_zstd(true)
+            .with_max_concurrent_writes(3);
+
+        let by_category: HashMap<Option<String>, Vec<String>> = (0..60)
+            .map(|i| (Some(format!("cat{}", i)), vec![format!("d{}.example.com", i)]))
+            .collect();
+        let files = generator.generate_all_categories(&by_category, &HashMap::new()).unwrap();
+
+        assert_eq!(files.len(), 60 * 3 * 2);
+        let peak = generator.write_limiter.peak.load(Ordering::Relaxed);
+        assert!((1..=3).contains(&peak), "peak open files {}", peak);
+        assert_eq!(*generator.write_limiter.open.lock().unwrap(), 0);
+    }
+
+    #[test]
+    fn test_collapse_only_in_subdomain_covering_formats() {
+        let temp_dir = TempDir::new().unwrap();
+        let generator = OutputGenerator::new(temp_dir.path()).with_collapse_covered_subdomains(true);
+
+        let domains: Vec<String> = ["ads.com", "a.ads.com", "b.x.ads.com", "notads.com", "tracker.net"]
+            .iter()
+            .map(|d| d.to_string())
+            .collect();
+        let files = generator.generate_all(&domains, &HashMap::new(), |_| {}).unwrap();
+
+        let count = |format: &str| files.iter().find(|f| f.format == format).unwrap().domain_count;
+        assert_eq!(count("hosts"), 5);
+        assert_eq!(count("plain"), 5);
+        assert_eq!(count("adblock"), 3);
+
+        // Rules with modifiers are not equivalent to a plain block of the parent
+        let mut rules = HashMap::new();
+        rules.insert("a.ads.com".to_string(), "||a.ads.com^$important".to_string());
+        let collapsed = generator.collapse_covered(&domains, &rules);
+        assert_eq!(collapsed, vec!["ads.com", "a.ads.com", "notads.com", "tracker.net"]);
+    }
+
+    #[test]
+    fn test_generation_through_memory_sink() {
+        let sink = Arc::new(MemorySink::default());
+        let objects = Arc::clone(&sink.objects);
+        let generator = OutputGenerator::new("unused")
+            .with_output_sink(sink)
+            .with_zstd(true);
+
+        let domains = vec!["ads.example.com".to_string(), "track.example.net".to_string()];
+        let files = generator.generate_all(&domains, &HashMap::new(), |_| {}).unwrap();
+        assert_eq!(objects.lock().unwrap().len(), files.len());
+        assert_eq!(files[0].size_bytes, objects.lock().unwrap()[&files[0].name].len() as u64);
+        generator.verify_output_files(&files).unwrap();
+
+        // A failed write never becomes visible; the committed object is kept
+        let before = objects.lock().unwrap()[&files[0].name].clone();
+        let result = generator.write_atomic(&files[0].name, |mut buf_writer| {
+            buf_writer.write_all(b"partial")?;
+            anyhow::bail!("simulated failure")
+        });
+        assert!(result.is_err());
+        assert_eq!(objects.lock().unwrap()[&files[0].name], before);
+
+        // Cleanup lists and removes through the sink too
+        objects.lock().unwrap().insert("dropped_plain.txt.gz".to_string(), Vec::new());
+        generator.cleanup_old_files(&files).unwrap();
+        assert!(!objects.lock().unwrap().contains_key("dropped_plain.txt.gz"));
+        assert_eq!(objects.lock().unwrap().len(), files.len());
+    }
+
+    #[test]
+    fn test_content_addressed_builds_share_blobs() {
+        use crate::output_sink::ContentAddressedSink;
+        use flate2::read::GzDecoder;
+        use std::io::Read;
+
+        let temp_dir = TempDir::new().unwrap();
+        let store = temp_dir.path().join("store");
+        let domains = vec!["ads.example.com".to_string(), "track.example.net".to_string()];
+        let build = |user: &str, epoch: i64| {
+            let dir = temp_dir.path().join(user);
+            let generator = OutputGenerator::new(&dir)
+                .with_output_sink(Arc::new(ContentAddressedSink::new(&dir, &store)))
+                .with_generated_at(DateTime::from_timestamp(epoch, 0).unwrap())
+                .with_generated_line(false);
+            generator.generate_all(&domains, &HashMap::new(), |_| {}).unwrap()
+        };
+
+        // Same lists built at different times by two users, then rebuilt later
+        let files = build("alice", 1_700_000_000);
+        build("bob", 1_700_003_600);
+        build("alice", 1_700_007_200);
+
+        let blobs = fs::read_dir(&store).unwrap().count();
+        assert_eq!(blobs, files.len());
+
+        let mut content = String::new();
+        GzDecoder::new(File::open(temp_dir.path().join("bob").join(&files[0].name)).unwrap())
+            .read_to_string(&mut content)
+            .unwrap();
+        assert!(content.contains("Total domains: 2"));
+        assert!(!content.contains("Generated:"));
+    }
+
+    #[test]
+    fn test_umbrella_normalization() {
+        use std::io::Read;
+
+        let sink = Arc::new(MemorySink::default());
+        let objects = Arc::clone(&sink.objects);
+        let generator = OutputGenerator::new("unused")
+            .with_output_sink(sink)
+            .with_formats(vec![OutputFormat::Umbrella]);
+
+        // Collapsing is on for umbrella even though the generator leaves it off
+        let domains: Vec<String> = ["*.Tracker.NET", "ads.com", "a.ads.com", "cdn.example.org."]
+            .iter()
+            .map(|d| d.to_string())
+            .collect();
+        let files = generator.generate_all(&domains, &HashMap::new(), |_| {}).unwrap();
+        assert_eq!(files[0].name, "all_domains_umbrella.txt.gz");
+        assert_eq!(files[0].domain_count, 3);
+
+        let gz = objects.lock().unwrap()[&files[0].name].clone();
+        let mut content = String::new();
+        flate2::read::GzDecoder::new(gz.as_slice()).read_to_string(&mut content).unwrap();
+        // No header: every line is an importable destination
+        assert_eq!(content, "tracker.net\nads.com\ncdn.example.org\n");
+        generator.verify_output_files(&files).unwrap();
+
+        assert_eq!(OutputFormat::from_name("Umbrella"), Some(OutputFormat::Umbrella));
+        assert!(!OutputFormat::all().contains(&OutputFormat::Umbrella));
+
+        // Spellings that normalize to the same domain are written once
+        for spellings in [["ads.com.", "ads.com"], ["*.Ads.com", "ads.com"]] {
+            objects.lock().unwrap().clear();
+            let domains: Vec<String> = spellings.iter().map(|d| d.to_string()).collect();
+            let files = generator.generate_all(&domains, &HashMap::new(), |_| {}).unwrap();
+            assert_eq!(files[0].domain_count, 1, "{:?}", spellings);
+
+            let gz = objects.lock().unwrap()[&files[0].name].clone();
+            let mut content = String::new();
+            flate2::read::GzDecoder::new(gz.as_slice()).read_to_string(&mut content).unwrap();
+            assert_eq!(content, "ads.com
+", "{:?}", spellings);
+        }
+    }
+
+    #[test]
+    fn test_filename_templates_produce_expected_paths() {
+        let mut by_category = HashMap::new();
+        by_category.insert(Some("ads".to_string()), vec!["ads.com".to_string()]);
+        let adblock_rules = HashMap::new();
+
+        let cases = [
+            ("{format}/{name}.txt.gz", vec!["hosts/ads.txt.gz", "hosts/all_domains.txt.gz", "hosts/all_domains.txt.zst"]),
+            ("{name}.{format}.gz", vec!["ads.hosts.gz", "all_domains.hosts.gz", "all_domains.hosts.zst"]),
+            ("lists/{category}-{format}.txt.gz", vec!["lists/ads-hosts.txt.gz", "lists/all-hosts.txt.gz", "lists/all-hosts.txt.zst"]),
+        ];
+        for (template, expected) in cases {
+            let temp_dir = TempDir::new().unwrap();
+            let template = FilenameTemplate::parse(template).unwrap();
+            let generator = OutputGenerator::new(temp_dir.path())
+                .with_filename_template(template.clone())
+                .with_formats(vec![OutputFormat::Hosts]);
+
+            let mut files = generator.generate_all_categories(&by_category, &adblock_rules).unwrap();
+            files.extend(
+                generator
+                    .with_zstd(true)
+                    .generate_all(&["ads.com".to_string()], &adblock_rules, |_| {})
+                    .unwrap(),
+            );
+            let names: Vec<&str> = files.iter().map(|f| f.name.as_str()).collect();
+            assert_eq!(names, expected);
+            for name in &names {
+                assert!(temp_dir.path().join(name).is_file(), "{} missing", name);
+            }
+
+            // Downstream code reads the list and format back from the name
+            assert_eq!(template.parse_name(names[0]), Some(("ads".to_string(), "hosts".to_string())));
+            assert!(template.is_combined(names[1]) && template.is_combined(names[2]));
+
+            // Cleanup finds stale files in subdirectories and leaves unrelated ones alone
+            let stale = template.render("dropped", OutputFormat::Hosts);
+            fs::write(temp_dir.path().join(&stale), b"").unwrap();
+            fs::write(temp_dir.path().join("README"), b"").unwrap();
+            OutputGenerator::new(temp_dir.path())
+                .with_filename_template(template)
+                .cleanup_old_files(&files)
+                .unwrap();
+            assert!(!temp_dir.path().join(&stale).exists());
+            assert!(temp_dir.path().join("README").exists());
+        }
+
+        // The default keeps today's names
+        let default = FilenameTemplate::default();
+        assert_eq!(default.render("ads", OutputFormat::Adblock), "ads_adblock.txt.gz");
+        assert_eq!(
+            default.parse_name("all_domains_umbrella.txt"),
+            Some(("all_domains".to_string(), "umbrella".to_string()))
+        );
+
+        for bad in ["{name}_{format}.txt", "{name}.txt.gz", "/{name}_{format}.gz", "../{name}_{format}.gz", "{user}/{name}_{format}.gz"] {
+            assert!(FilenameTemplate::parse(bad).is_err(), "{} accepted", bad);
+        }
+    }
+
+    #[test]
+    fn test_case_variants_in_raw_rules_dedup_to_one_entry() {
+        use std::io::Read;
+
+        let sink = Arc::new(MemorySink::default());
+        let objects = Arc::clone(&sink.objects);
+        let generator = OutputGenerator::new("unused")
+            .with_output_sink(sink)
+            .with_formats(vec![OutputFormat::Adblock, OutputFormat::Hosts]);
+
+        let domains: Vec<String> = ["ADS.example.com", "Tracker.net", "ads.example.com", "tracker.NET"]
+            .iter()
+            .map(|d| d.to_string())
+            .collect();
+        let mut rules = HashMap::new();
+        rules.insert("ADS.example.com".to_string(), "||ADS.example.com^$important".to_string());
+        rules.insert("ads.example.com".to_string(), "||ads.example.com^$important".to_string());
+
+        let files = generator.generate_all(&domains, &rules, |_| {}).unwrap();
+        let entries = |format: &str| {
+            let file = files.iter().find(|f| f.format == format).unwrap();
+            let mut body = String::new();
+            flate2::read::GzDecoder::new(&objects.lock().unwrap()[&file.name][..])
+                .read_to_string(&mut body)
+                .unwrap();
+            let lines: Vec<String> = body
+                .lines()
+                .filter(|l| !l.is_empty() && !l.starts_with('!') && !l.starts_with('#'))
+                .map(String::from)
+                .collect();
+            (file.domain_count, lines)
+        };
+
+        // The lowercase spelling (and its rule) wins; otherwise the first one seen
+        assert_eq!(entries("adblock"), (2, vec!["||ads.example.com^$important".to_string(), "||Tracker.net^".to_string()]));
+        assert_eq!(entries("hosts"), (2, vec!["0.0.0.0 ads.example.com".to_string(), "0.0.0.0 Tracker.net".to_string()]));
+        assert!(OutputGenerator::fold_case_duplicates(&["a.com".to_string()]).is_none());
+    }
+
+    #[test]
+    fn test_source_attribution_maps_domains_to_sources() {
+        use std::io::Read;
+
+        let sink = Arc::new(MemorySink::default());
+        let objects = Arc::clone(&sink.objects);
+        let mut sources = HashMap::new();
+        sources.insert("ads.example.com".to_string(), vec!["EasyList".to_string(), "Peter Lowe, ads".to_string()]);
+        sources.insert("tracker.net".to_string(), vec!["EasyPrivacy".to_string()]);
+        let generator = OutputGenerator::new("unused")
+            .with_output_sink(sink)
+            .with_source_attribution(Some(sources));
+
+        let mut by_category = HashMap::new();
+        by_category.insert(Some("ads".to_string()), vec!["ads.example.com".to_string(), "operator.example".to_string()]);
+        by_category.insert(None, vec!["tracker.net".to_string()]);
+
+        let files = generator.generate_source_attribution(&by_category).unwrap();
+        let names: Vec<&str> = files.iter().map(|f| f.name.as_str()).collect();
+        assert_eq!(names, vec!["uncategorized_sources.csv.gz", "ads_sources.csv.gz"]);
+
+        let read = |name: &str| {
+            let mut body = String::new();
+            flate2::read::GzDecoder::new(&objects.lock().unwrap()[name][..])
+                .read_to_string(&mut body)
+                .unwrap();
+            body
+        };
+        // One row per contributing source; a domain with none recorded is left out
+        assert_eq!(
+            read("ads_sources.csv.gz"),
+            "domain,source_name\nads.example.com,EasyList\nads.example.com,\"Peter Lowe, ads\"\n"
+        );
+        assert_eq!(read("uncategorized_sources.csv.gz"), "domain,source_name\ntracker.net,EasyPrivacy\n");
+        assert_eq!(files[1].domain_count, 1);
+
+        // Without attribution mode nothing is written
+        let plain = OutputGenerator::new("unused").with_output_sink(Arc::new(MemorySink::default()));
+        assert!(plain.generate_source_attribution(&by_category).unwrap().is_empty());
+    }
 }
@@ -0,0 +1,244 @@
+//! Standalone config linter (`--lint <file>`), usable without MongoDB
+//!
+//! Mirrors the rules `Downloader::parse_config` applies silently, but reports
+//! each problem with its line number so CI and pre-commit hooks can fail early.
+
+use anyhow::{Context, Result};
+use std::collections::HashMap;
+use std::fmt;
+use std::path::Path;
+
+use crate::downloader::Downloader;
+
+/// Per-source option keys understood by `parse_config`
+const KNOWN_OPTIONS: &[&str] = &["json", "priority", "method", "body", "mirror", "ttl", "format"];
+
+/// How serious a lint finding is
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    /// The line would be dropped or cannot be downloaded
+    Error,
+    /// The line works but is probably not what was intended
+    Warning,
+}
+
+/// A single finding, tied to a 1-based line number
+#[derive(Debug, Clone)]
+pub struct LintIssue {
+    pub line: usize,
+    pub severity: Severity,
+    pub message: String,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Severity::Error => write!(f, "error"),
+            Severity::Warning => write!(f, "warning"),
+        }
+    }
+}
+
+/// Result of linting one config file
+#[derive(Debug, Default)]
+pub struct LintReport {
+    /// Number of sources that would be downloaded
+    pub sources: usize,
+    pub issues: Vec<LintIssue>,
+}
+
+impl LintReport {
+    pub fn error_count(&self) -> usize {
+        self.issues.iter().filter(|i| i.severity == Severity::Error).count()
+    }
+
+    pub fn warning_count(&self) -> usize {
+        self.issues.len() - self.error_count()
+    }
+
+    fn push(&mut self, line: usize, severity: Severity, message: impl Into<String>) {
+        self.issues.push(LintIssue {
+            line,
+            severity,
+            message: message.into(),
+        });
+    }
+}
+
+/// Lint blocklist config content (the `url|name|category|options` format)
+/// against the configured `ALLOWED_SOURCE_SCHEMES`
+pub fn lint_config<S: AsRef<str>>(content: &str, schemes: &[S]) -> LintReport {
+    let mut report = LintReport::default();
+    let mut seen_urls: HashMap<String, usize> = HashMap::new();
+
+    for (idx, raw) in content.lines().enumerate() {
+        let line_no = idx + 1;
+        let line = raw.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let parts: Vec<&str> = line.split('|').collect();
+        let url = parts[0].trim();
+
+        let parsed = match url::Url::parse(url) {
+            Ok(parsed) => parsed,
+            Err(e) => {
+                report.push(line_no, Severity::Error, format!("invalid URL '{}': {}", url, e));
+                continue;
+            }
+        };
+
+        if !schemes.iter().any(|s| s.as_ref() == parsed.scheme()) {
+            report.push(
+                line_no,
+                Severity::Error,
+                format!("unsupported scheme '{}' in {}", parsed.scheme(), url),
+            );
+            continue;
+        }
+
+        // The same URL may be queried with different methods/bodies
+        let option = |key: &str| {
+            parts
+                .iter()
+                .skip(3)
+                .find_map(|o| o.trim().split_once('=').filter(|(k, _)| *k == key).map(|(_, v)| v.trim()))
+        };
+        let request_key = format!(
+            "{} {} {}",
+            option("method").unwrap_or("GET").to_uppercase(),
+            url,
+            option("body").unwrap_or("")
+        );
+        if let Some(first) = seen_urls.get(&request_key) {
+            report.push(
+                line_no,
+                Severity::Warning,
+                format!("duplicate of line {}, will be ignored: {}", first, url),
+            );
+            continue;
+        }
+        seen_urls.insert(request_key, line_no);
+
+        if parsed.scheme() == "http" {
+            report.push(
+                line_no,
+                Severity::Warning,
+                format!("plain http source may be blocked or redirected: {}", url),
+            );
+        }
+
+        if parts.len() > 1 && parts[1].trim().is_empty() {
+            report.push(line_no, Severity::Warning, "empty name, the source will have no display name");
+        }
+
+        for option in parts.iter().skip(3) {
+            match option.trim().split_once('=') {
+                Some(("priority", value)) if value.trim().parse::<i32>().is_err() => report.push(
+                    line_no,
+                    Severity::Warning,
+                    format!("priority '{}' is not a whole number, using 0", value.trim()),
+                ),
+                Some(("method", value)) if reqwest::Method::from_bytes(value.trim().to_uppercase().as_bytes()).is_err() => {
+                    report.push(
+                        line_no,
+                        Severity::Warning,
+                        format!("method '{}' is not an HTTP method, using GET", value.trim()),
+                    )
+                }
+                Some(("ttl", value)) if Downloader::parse_ttl(value).is_none() => report.push(
+                    line_no,
+                    Severity::Warning,
+                    format!("ttl '{}' is not a duration like 30m, 6h or 7d, ignored", value.trim()),
+                ),
+                Some(("format", value)) if !value.trim().eq_ignore_ascii_case("pac") => report.push(
+                    line_no,
+                    Severity::Warning,
+                    format!("format '{}' is not a known source format (pac), ignored", value.trim()),
+                ),
+                Some((key, value)) if KNOWN_OPTIONS.contains(&key) && !value.trim().is_empty() => {}
+                _ => report.push(
+                    line_no,
+                    Severity::Warning,
+                    format!("unknown source option '{}' is ignored", option.trim()),
+                ),
+            }
+        }
+
+        report.sources += 1;
+    }
+
+    report
+}
+
+/// Lint a config file from disk, print the report, and return the exit code
+pub fn run<S: AsRef<str>>(path: &Path, schemes: &[S]) -> Result<i32> {
+    let content = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read config file {:?}", path))?;
+    let report = lint_config(&content, schemes);
+
+    for issue in &report.issues {
+        // file:line: level: message, the format editors and CI annotators pick up
+        println!("{}:{}: {}: {}", path.display(), issue.line, issue.severity, issue.message);
+    }
+    println!(
+        "{}: {} sources, {} errors, {} warnings",
+        path.display(),
+        report.sources,
+        report.error_count(),
+        report.warning_count()
+    );
+
+    Ok(if report.error_count() > 0 { 1 } else { 0 })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::downloader::{Downloader, DEFAULT_SOURCE_SCHEMES};
+
+    #[test]
+    fn test_good_config_passes() {
+        let content = include_str!("../fixtures/lint/good.conf");
+        let report = lint_config(content, DEFAULT_SOURCE_SCHEMES);
+
+        assert!(report.issues.is_empty(), "{:?}", report.issues);
+        assert_eq!(report.sources, Downloader::parse_config(content).len());
+    }
+
+    #[test]
+    fn test_bad_config_reports_each_problem() {
+        let content = include_str!("../fixtures/lint/bad.conf");
+        let report = lint_config(content, DEFAULT_SOURCE_SCHEMES);
+
+        let found: Vec<(usize, Severity)> = report.issues.iter().map(|i| (i.line, i.severity)).collect();
+        assert_eq!(
+            found,
+            vec![
+                (3, Severity::Error),   // not a URL
+                (4, Severity::Error),   // ftp://
+                (5, Severity::Warning), // duplicate
+                (6, Severity::Warning), // plain http
+                (7, Severity::Warning), // unknown option
+            ]
+        );
+        assert_eq!(report.error_count(), 2);
+        assert!(report.issues[2].message.contains("line 2"));
+    }
+
+    #[test]
+    fn test_configured_schemes_are_allowed() {
+        let content = include_str!("../fixtures/lint/ftp.conf");
+        let schemes = ["ftp", "https"];
+        let report = lint_config(content, &schemes);
+
+        assert!(report.issues.is_empty(), "{:?}", report.issues);
+        assert_eq!(report.sources, Downloader::parse_config_with_schemes(content, &schemes).sources.len());
+
+        // The same file fails under the default schemes
+        let report = lint_config(content, DEFAULT_SOURCE_SCHEMES);
+        assert_eq!(report.error_count(), 1);
+        assert!(report.issues[0].message.contains("ftp"));
+    }
+}
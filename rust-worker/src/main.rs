@@ -1,59 +1,144 @@
+mod cache_archive;
+mod cancel;
+mod categorizer;
 mod config;
 mod db;
+mod domain_stats;
 mod downloader;
+mod error;
 mod extractor;
 mod generator;
+mod lint;
+mod output_sink;
 mod processor;
+mod rebuild;
+mod reload;
+mod scheduler;
+mod webhook;
 mod whitelist;
 mod worker;
 
 use anyhow::Result;
 use mongodb::Client;
-use std::path::Path;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
 use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::Arc;
-use tracing::{error, info, Level};
-use tracing_subscriber::FmtSubscriber;
+use std::sync::{Arc, RwLock};
+use tracing::{error, info, warn};
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{fmt, reload as log_reload, EnvFilter, Registry};
 
 use config::Config;
 use worker::Worker;
 
-#[tokio::main]
-async fn main() -> Result<()> {
-    // Initialize logging
-    FmtSubscriber::builder()
-        .with_max_level(Level::INFO)
-        .with_target(false)
-        .with_thread_ids(false)
-        .compact()
-        .init();
+/// Config file to lint, from `--lint <file>` / `--validate-config <file>` or `WORKER_MODE=lint <file>`
+fn lint_target() -> Result<Option<PathBuf>> {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let file = if let Some(pos) = args.iter().position(|a| a == "--lint" || a == "--validate-config") {
+        args.get(pos + 1)
+    } else if std::env::var("WORKER_MODE").is_ok_and(|m| m == "lint") {
+        args.first()
+    } else {
+        return Ok(None);
+    };
 
-    info!("Blocklist Worker starting...");
+    match file {
+        Some(file) => Ok(Some(PathBuf::from(file))),
+        None => anyhow::bail!("Lint mode needs a config file: --lint <file>"),
+    }
+}
+
+/// Cache transfer requested on the command line
+enum CacheTransfer {
+    Export(PathBuf),
+    Import(PathBuf),
+}
+
+/// Cache archive to write or load, from `--export-cache <file>` / `--import-cache <file>`
+fn cache_transfer() -> Result<Option<CacheTransfer>> {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    for (flag, transfer) in [
+        ("--export-cache", CacheTransfer::Export as fn(PathBuf) -> CacheTransfer),
+        ("--import-cache", CacheTransfer::Import),
+    ] {
+        if let Some(pos) = args.iter().position(|a| a == flag) {
+            return match args.get(pos + 1) {
+                Some(file) => Ok(Some(transfer(PathBuf::from(file)))),
+                None => anyhow::bail!("{} needs an archive file: {} <file>", flag, flag),
+            };
+        }
+    }
+    Ok(None)
+}
+
+/// Locations tried for the .env file, first match wins
+const ENV_PATHS: [&str; 3] = [
+    "../.env",    // If running from rust-worker/
+    ".env",       // If running from project root
+    "../../.env", // If running from rust-worker/target/release/
+];
+
+/// Read the first .env file found
+///
+/// The values are only handed to `Config::from_env_with`, never set in the
+/// process environment, so a reload can re-read the file while other threads
+/// run. Variables set in the real environment always win over the file.
+fn read_env_file() -> HashMap<String, String> {
+    for path in ENV_PATHS.map(Path::new) {
+        if !path.exists() {
+            continue;
+        }
+        match dotenvy::from_path_iter(path).and_then(|vars| vars.collect::<Result<HashMap<_, _>, _>>()) {
+            Ok(vars) => {
+                info!("Loaded environment from {:?}", path);
+                return vars;
+            }
+            Err(e) => {
+                error!("Failed to load .env from {:?}: {}", path, e);
+            }
+        }
+    }
+    HashMap::new()
+}
 
-    // Load .env file from project root (parent directory)
-    // Try multiple locations for the .env file
-    let env_paths = [
-        Path::new("../.env"),           // If running from rust-worker/
-        Path::new(".env"),              // If running from project root
-        Path::new("../../.env"),        // If running from rust-worker/target/release/
-    ];
-
-    for path in env_paths {
-        if path.exists() {
-            match dotenvy::from_path(path) {
-                Ok(_) => {
-                    info!("Loaded environment from {:?}", path);
-                    break;
-                }
-                Err(e) => {
-                    error!("Failed to load .env from {:?}: {}", path, e);
-                }
+/// Handle for swapping the log filter at runtime
+type LogFilterHandle = log_reload::Handle<EnvFilter, Registry>;
+
+/// Apply `LOG_LEVEL`, keeping the current filter if it doesn't parse
+fn set_log_level(handle: &LogFilterHandle, level: &str) {
+    match EnvFilter::try_new(level) {
+        Ok(filter) => {
+            if let Err(e) = handle.reload(filter) {
+                error!("Failed to apply LOG_LEVEL {:?}: {}", level, e);
             }
         }
+        Err(e) => warn!("Invalid LOG_LEVEL {:?}, keeping the current level: {}", level, e),
+    }
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    // Lint mode: check a config file and exit, without logging or MongoDB
+    if let Some(path) = lint_target()? {
+        let config = Config::from_env_with(&read_env_file());
+        std::process::exit(lint::run(&path, &config.allowed_source_schemes)?);
     }
 
-    // Load configuration
-    let config = Config::from_env();
+    let transfer = cache_transfer()?;
+
+    // Initialize logging (at info until LOG_LEVEL is loaded)
+    let (log_filter, log_handle) = log_reload::Layer::new(EnvFilter::new("info"));
+    tracing_subscriber::registry()
+        .with(log_filter)
+        .with(fmt::layer().with_target(false).with_thread_ids(false).compact())
+        .init();
+
+    info!("Blocklist Worker starting...");
+
+    // Load configuration, with the .env file from project root (parent directory)
+    let config = Config::from_env_with(&read_env_file());
+    set_log_level(&log_handle, &config.log_level);
     info!("Worker ID: {}", config.worker_id);
     info!("Data directory: {:?}", config.data_dir);
 
@@ -75,6 +160,25 @@ async fn main() -> Result<()> {
     db.run_command(bson::doc! { "ping": 1 }).await?;
     info!("Connected to MongoDB database: {}", config.database_name);
 
+    // Cache transfer mode: export or import the source cache and exit
+    if let Some(transfer) = transfer {
+        let cache = db::cache::CacheRepository::new(&db);
+        match transfer {
+            CacheTransfer::Export(path) => {
+                let exported = cache_archive::export(&cache, &path).await?;
+                info!("Exported {} cached sources to {:?}", exported, path);
+            }
+            CacheTransfer::Import(path) => {
+                let summary = cache_archive::import(&cache, &path).await?;
+                info!(
+                    "Imported {} cached sources from {:?} ({} already cached, skipped)",
+                    summary.imported, path, summary.skipped
+                );
+            }
+        }
+        return Ok(());
+    }
+
     // Clean up stale cache on startup
     info!("Cleaning up stale cache entries...");
     let downloader = downloader::Downloader::new(config.clone(), &db)?;
@@ -89,6 +193,23 @@ async fn main() -> Result<()> {
         }
     }
 
+    // Drop content-addressed output blobs no user links to any more
+    #[cfg(unix)]
+    if config.content_addressed_output {
+        match output_sink::ContentAddressedSink::prune_store(&config.output_store_dir()) {
+            Ok(pruned) if pruned > 0 => info!("Pruned {} unreferenced output blobs", pruned),
+            Ok(_) => {}
+            Err(e) => error!("Output store pruning failed: {}", e),
+        }
+    }
+
+    // Reload concurrency, timeouts and log level on SIGHUP
+    let config = Arc::new(RwLock::new(config));
+    #[cfg(unix)]
+    reload::ConfigReloader::new(Arc::clone(&config), || Config::from_env_with(&read_env_file()))
+    .with_on_reload(move |config| set_log_level(&log_handle, &config.log_level))
+    .spawn_on_sighup()?;
+
     // Create and run worker
     let worker = Worker::new(config, db, shutdown);
 
@@ -0,0 +1,461 @@
+//! Where generated lists are written
+//!
+//! `OutputGenerator` writes through an `OutputSink`, so the same generation
+//! code can target the local directory nginx serves or an object store. A
+//! write is staged and only becomes visible on `commit`; dropping a staged
+//! write without committing discards it and leaves the previous version.
+
+use sha2::{Digest, Sha256};
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::generator::replace_file;
+
+/// Suffix for in-progress output files; renamed into place once complete
+pub const TMP_SUFFIX: &str = ".tmp";
+
+/// Destination for output files
+pub trait OutputSink: Send + Sync {
+    /// Make sure the destination exists before writing
+    fn prepare(&self) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Start writing `name`; readers keep seeing the old version until commit
+    fn create(&self, name: &str) -> io::Result<Box<dyn StagedOutput>>;
+
+    /// Read back a committed file
+    fn open(&self, name: &str) -> io::Result<Box<dyn Read + Send>>;
+
+    /// Names of every file at the destination (empty if it doesn't exist yet)
+    fn list(&self) -> io::Result<Vec<String>>;
+
+    /// Delete a file
+    fn remove(&self, name: &str) -> io::Result<()>;
+}
+
+/// A write in progress
+pub trait StagedOutput: Write + Send {
+    /// Publish the written bytes under the final name, returning their size
+    ///
+    /// Must be all-or-nothing: on the filesystem this is a rename over the old
+    /// file, for object storage a single PUT of the complete body.
+    fn commit(self: Box<Self>) -> io::Result<u64>;
+}
+
+/// Local directory output (what nginx serves)
+pub struct FilesystemSink {
+    dir: PathBuf,
+}
+
+impl FilesystemSink {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self { dir: dir.into() }
+    }
+}
+
+impl OutputSink for FilesystemSink {
+    fn prepare(&self) -> io::Result<()> {
+        fs::create_dir_all(&self.dir)
+    }
+
+    fn create(&self, name: &str) -> io::Result<Box<dyn StagedOutput>> {
+        let tmp_path = self.dir.join(format!("{}{}", name, TMP_SUFFIX));
+        // Filename templates may put files in subdirectories
+        if let Some(parent) = tmp_path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let file = File::create(&tmp_path)?;
+        Ok(Box::new(StagedFile {
+            file: Some(file),
+            tmp_path,
+            path: self.dir.join(name),
+        }))
+    }
+
+    fn open(&self, name: &str) -> io::Result<Box<dyn Read + Send>> {
+        Ok(Box::new(File::open(self.dir.join(name))?))
+    }
+
+    fn list(&self) -> io::Result<Vec<String>> {
+        let mut names = Vec::new();
+        list_files(&self.dir, "", &mut names)?;
+        Ok(names)
+    }
+
+    fn remove(&self, name: &str) -> io::Result<()> {
+        fs::remove_file(self.dir.join(name))
+    }
+}
+
+/// Collect files under `dir` as `/`-separated names relative to the sink root
+fn list_files(dir: &Path, prefix: &str, names: &mut Vec<String>) -> io::Result<()> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+
+    for entry in entries {
+        let entry = entry?;
+        let Some(name) = entry.file_name().to_str().map(|name| format!("{}{}", prefix, name)) else {
+            continue;
+        };
+        if entry.file_type()?.is_dir() {
+            list_files(&entry.path(), &format!("{}/", name), names)?;
+        } else {
+            names.push(name);
+        }
+    }
+    Ok(())
+}
+
+/// `{name}.tmp`, synced and renamed over `name` on commit
+struct StagedFile {
+    /// None once committed
+    file: Option<File>,
+    tmp_path: PathBuf,
+    path: PathBuf,
+}
+
+impl Write for StagedFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self.file.as_mut() {
+            Some(file) => file.write(buf),
+            None => Err(io::Error::other("write after commit")),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self.file.as_mut() {
+            Some(file) => file.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+impl StagedOutput for StagedFile {
+    fn commit(mut self: Box<Self>) -> io::Result<u64> {
+        if let Some(file) = self.file.take() {
+            file.sync_all()?;
+        }
+        replace_file(&self.tmp_path, &self.path)?;
+        Ok(fs::metadata(&self.path)?.len())
+    }
+}
+
+impl Drop for StagedFile {
+    fn drop(&mut self) {
+        // Still holding the file means commit never ran: discard the partial write
+        if self.file.take().is_some() {
+            let _ = fs::remove_file(&self.tmp_path);
+        }
+    }
+}
+
+/// Local directory whose files are hard links into a shared content-addressed store
+///
+/// Committed files are stored once under `{store}/{sha256}{ext}` and linked
+/// into `dir`, so byte-identical lists of different users share storage.
+/// Files only match when every byte does, header included, so builds leave
+/// out the "Generated:" header line when this is on.
+pub struct ContentAddressedSink {
+    dir: FilesystemSink,
+    store: PathBuf,
+}
+
+/// Unreferenced blobs younger than this are kept: a commit may be about to link them
+const PRUNE_GRACE: Duration = Duration::from_secs(3600);
+
+impl ContentAddressedSink {
+    pub fn new(dir: impl Into<PathBuf>, store: impl Into<PathBuf>) -> Self {
+        Self {
+            dir: FilesystemSink::new(dir),
+            store: store.into(),
+        }
+    }
+
+    /// Remove blobs no output directory links to any more, returning how many
+    #[cfg(unix)]
+    pub fn prune_store(store: &Path) -> io::Result<u64> {
+        use std::os::unix::fs::MetadataExt;
+
+        let entries = match fs::read_dir(store) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(0),
+            Err(e) => return Err(e),
+        };
+
+        let now = chrono::Utc::now().timestamp();
+        let mut pruned = 0;
+        for entry in entries {
+            let entry = entry?;
+            let meta = entry.metadata()?;
+            // ctime moves whenever a link is added or removed
+            let idle = now.saturating_sub(meta.ctime()) as u64;
+            if meta.is_file() && meta.nlink() == 1 && idle >= PRUNE_GRACE.as_secs() {
+                fs::remove_file(entry.path())?;
+                pruned += 1;
+            }
+        }
+        Ok(pruned)
+    }
+}
+
+impl OutputSink for ContentAddressedSink {
+    fn prepare(&self) -> io::Result<()> {
+        self.dir.prepare()?;
+        fs::create_dir_all(&self.store)
+    }
+
+    fn create(&self, name: &str) -> io::Result<Box<dyn StagedOutput>> {
+        let tmp_path = self
+            .store
+            .join(format!("{}{}", uuid::Uuid::new_v4(), TMP_SUFFIX));
+        let file = File::create(&tmp_path)?;
+        // Keep the extension (".txt.gz") so blobs are recognisable
+        let base = name.rsplit('/').next().unwrap_or(name);
+        let ext = base.find('.').map_or("", |i| &base[i..]).to_string();
+        let path = self.dir.dir.join(name);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        Ok(Box::new(StagedBlob {
+            file: Some(file),
+            hasher: Sha256::new(),
+            tmp_path,
+            store: self.store.clone(),
+            ext,
+            path,
+        }))
+    }
+
+    fn open(&self, name: &str) -> io::Result<Box<dyn Read + Send>> {
+        self.dir.open(name)
+    }
+
+    fn list(&self) -> io::Result<Vec<String>> {
+        self.dir.list()
+    }
+
+    fn remove(&self, name: &str) -> io::Result<()> {
+        // Only the link; the blob goes once nothing links to it (prune_store)
+        self.dir.remove(name)
+    }
+}
+
+/// Write hashed as it goes into a temp file in the store
+struct StagedBlob {
+    /// None once committed
+    file: Option<File>,
+    hasher: Sha256,
+    tmp_path: PathBuf,
+    store: PathBuf,
+    ext: String,
+    path: PathBuf,
+}
+
+impl Write for StagedBlob {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let file = self
+            .file
+            .as_mut()
+            .ok_or_else(|| io::Error::other("write after commit"))?;
+        let n = file.write(buf)?;
+        self.hasher.update(&buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self.file.as_mut() {
+            Some(file) => file.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+impl StagedOutput for StagedBlob {
+    fn commit(mut self: Box<Self>) -> io::Result<u64> {
+        if let Some(file) = self.file.take() {
+            file.sync_all()?;
+        }
+
+        let hash = format!("{:x}", std::mem::take(&mut self.hasher).finalize());
+        let blob = self.store.join(format!("{}{}", hash, self.ext));
+        if blob.exists() {
+            fs::remove_file(&self.tmp_path)?;
+        } else {
+            replace_file(&self.tmp_path, &blob)?;
+        }
+
+        // Link next to the target, then rename over it so readers never see a gap
+        // Appended to the whole name: files differing only in their last extension stage apart
+        let mut link_tmp = self.path.clone().into_os_string();
+        link_tmp.push(format!(".link{}", TMP_SUFFIX));
+        let link_tmp = PathBuf::from(link_tmp);
+        let _ = fs::remove_file(&link_tmp);
+        fs::hard_link(&blob, &link_tmp)?;
+        replace_file(&link_tmp, &self.path)?;
+        // Renaming onto a link to the same blob is a no-op that leaves link_tmp behind
+        let _ = fs::remove_file(&link_tmp);
+
+        Ok(fs::metadata(&self.path)?.len())
+    }
+}
+
+impl Drop for StagedBlob {
+    fn drop(&mut self) {
+        if self.file.take().is_some() {
+            let _ = fs::remove_file(&self.tmp_path);
+        }
+    }
+}
+
+/// In-memory sink standing in for object storage in tests
+#[cfg(test)]
+pub mod memory {
+    use super::*;
+    use std::collections::HashMap;
+    use std::sync::{Arc, Mutex};
+
+    type Objects = Arc<Mutex<HashMap<String, Vec<u8>>>>;
+
+    /// Objects only appear once committed, like a completed PUT
+    #[derive(Default)]
+    pub struct MemorySink {
+        pub objects: Objects,
+    }
+
+    struct StagedObject {
+        name: String,
+        body: Vec<u8>,
+        objects: Objects,
+    }
+
+    impl Write for StagedObject {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.body.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl StagedOutput for StagedObject {
+        fn commit(self: Box<Self>) -> io::Result<u64> {
+            let size = self.body.len() as u64;
+            self.objects.lock().unwrap().insert(self.name, self.body);
+            Ok(size)
+        }
+    }
+
+    impl OutputSink for MemorySink {
+        fn create(&self, name: &str) -> io::Result<Box<dyn StagedOutput>> {
+            Ok(Box::new(StagedObject {
+                name: name.to_string(),
+                body: Vec::new(),
+                objects: Arc::clone(&self.objects),
+            }))
+        }
+
+        fn open(&self, name: &str) -> io::Result<Box<dyn Read + Send>> {
+            let body = self.objects.lock().unwrap().get(name).cloned();
+            match body {
+                Some(body) => Ok(Box::new(io::Cursor::new(body))),
+                None => Err(io::Error::new(io::ErrorKind::NotFound, name.to_string())),
+            }
+        }
+
+        fn list(&self) -> io::Result<Vec<String>> {
+            Ok(self.objects.lock().unwrap().keys().cloned().collect())
+        }
+
+        fn remove(&self, name: &str) -> io::Result<()> {
+            self.objects.lock().unwrap().remove(name);
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_identical_files_share_one_blob() {
+        let temp_dir = TempDir::new().unwrap();
+        let store = temp_dir.path().join("store");
+        let alice = ContentAddressedSink::new(temp_dir.path().join("alice"), &store);
+        let bob = ContentAddressedSink::new(temp_dir.path().join("bob"), &store);
+
+        let write = |sink: &ContentAddressedSink, name: &str, body: &[u8]| {
+            sink.prepare().unwrap();
+            let mut staged = sink.create(name).unwrap();
+            staged.write_all(body).unwrap();
+            staged.commit().unwrap()
+        };
+        assert_eq!(write(&alice, "ads_plain.txt.gz", b"ads.com\n"), 8);
+        write(&bob, "ads_plain.txt.gz", b"ads.com\n");
+        write(&bob, "malware_plain.txt.gz", b"malware.net\n");
+        // Rewriting unchanged content over an existing link is fine
+        write(&alice, "ads_plain.txt.gz", b"ads.com\n");
+
+        let blobs: Vec<_> = fs::read_dir(&store).unwrap().map(|e| e.unwrap().path()).collect();
+        assert_eq!(blobs.len(), 2, "{:?}", blobs);
+        assert!(blobs.iter().all(|b| b.to_string_lossy().ends_with(".txt.gz")));
+        assert_eq!(alice.list().unwrap(), vec!["ads_plain.txt.gz"]);
+
+        let mut content = String::new();
+        bob.open("ads_plain.txt.gz").unwrap().read_to_string(&mut content).unwrap();
+        assert_eq!(content, "ads.com\n");
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::MetadataExt;
+            let a = fs::metadata(temp_dir.path().join("alice/ads_plain.txt.gz")).unwrap();
+            let b = fs::metadata(temp_dir.path().join("bob/ads_plain.txt.gz")).unwrap();
+            assert_eq!(a.ino(), b.ino());
+            assert_eq!(a.nlink(), 3);
+
+            // A blob that just lost its last link is kept through the grace period
+            bob.remove("malware_plain.txt.gz").unwrap();
+            assert_eq!(ContentAddressedSink::prune_store(&store).unwrap(), 0);
+            assert_eq!(fs::read_dir(&store).unwrap().count(), 2);
+        }
+    }
+
+    #[test]
+    fn test_names_differing_in_last_extension_commit_concurrently() {
+        let temp_dir = TempDir::new().unwrap();
+        let sink = ContentAddressedSink::new(temp_dir.path().join("alice"), temp_dir.path().join("store"));
+        sink.prepare().unwrap();
+
+        // e.g. OUTPUT_FILENAME_TEMPLATE={name}.{format}.gz with uncompressed variants
+        let names = ["ads.hosts", "ads.plain"];
+        std::thread::scope(|scope| {
+            for name in names {
+                let sink = &sink;
+                scope.spawn(move || {
+                    for round in 0..50 {
+                        let mut staged = sink.create(name).unwrap();
+                        writeln!(staged, "{} {}", name, round).unwrap();
+                        staged.commit().unwrap();
+                    }
+                });
+            }
+        });
+
+        for name in names {
+            let mut content = String::new();
+            sink.open(name).unwrap().read_to_string(&mut content).unwrap();
+            assert_eq!(content, format!("{} 49\n", name));
+        }
+        let mut listed = sink.list().unwrap();
+        listed.sort();
+        assert_eq!(listed, names);
+    }
+}
@@ -1,26 +1,36 @@
-use anyhow::Result;
+use anyhow::{Context, Result};
 use bson::DateTime as BsonDateTime;
 use chrono::Utc;
 use mongodb::Database;
+use rayon::prelude::*;
+use regex::Regex;
 use sha2::{Digest, Sha256};
 use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
-use std::time::Instant;
+use std::time::{Duration, Instant};
 use tokio::sync::Mutex;
 use tracing::{debug, info, warn};
 
-use crate::config::Config;
-use crate::db::job::{Job, JobRepository};
+use crate::cancel::CancelToken;
+use crate::categorizer::CategoryMap;
+use crate::config::{Config, FormatShiftPolicy};
+use crate::db::cache::ExtractionSummary;
+use crate::db::job::{Job, JobRepository, JobType};
 use crate::db::progress::{
-    JobProgress, JobResult, JobStage, OutputFile, SourceProgress,
-    SourceStatus, StageSnapshot,
+    ConfigDiff, CopySavings, GenerationProgress, JobProgress, JobResult, JobStage, OutputFile, SkipReason,
+    SourceProgress, SourceStatus, StageSnapshot, WhitelistPatternMatch,
 };
+use crate::db::retry::with_retry;
 use crate::db::user::{ListMetadata, MatchedUser, UserRepository};
-use crate::db::user_config::UserConfigRepository;
-use crate::downloader::{DownloadResult, Downloader, Source};
-use crate::extractor::DomainExtractor;
-use crate::generator::OutputGenerator;
-use crate::whitelist::WhitelistManager;
+use crate::db::user_config::{OperatorLists, UserConfig, UserConfigRepository};
+use crate::error::{ErrorKind, WorkerError};
+use crate::downloader::{DownloadResult, Downloader, ParsedConfig, Source};
+use crate::extractor::{decode_source, DomainExtractor, ExtractionOutput, ExtractionResult, FormatBreakdown, ReservedDomainFilter};
+use crate::generator::{replace_file, FilenameTemplate, OutputFormat, OutputGenerator, COMBINED_LIST_NAME};
+use crate::output_sink::{ContentAddressedSink, FilesystemSink, OutputSink};
+use crate::webhook::{WebhookNotifier, WebhookPayload};
+use crate::whitelist::{scope_category_whitelist, WhitelistManager};
 
 /// Domains organized by category for per-category output generation
 pub struct CategoryDomains {
@@ -30,6 +40,8 @@ pub struct CategoryDomains {
     /// Raw adblock rules keyed by domain (for adblock output passthrough)
     /// Only populated for domains that came from adblock-format sources
     pub adblock_rules: HashMap<String, String>,
+    /// Names of the sources that listed each domain (EMIT_SOURCE_ATTRIBUTION only)
+    pub source_attribution: Option<HashMap<String, Vec<String>>>,
 }
 
 impl CategoryDomains {
@@ -37,6 +49,29 @@ impl CategoryDomains {
         Self {
             by_category: HashMap::new(),
             adblock_rules: HashMap::new(),
+            source_attribution: None,
+        }
+    }
+
+    /// Also record which sources listed each domain
+    pub fn with_source_attribution(mut self) -> Self {
+        self.source_attribution = Some(HashMap::new());
+        self
+    }
+
+    /// Record `source` as a contributor of these domains (if attribution is on)
+    ///
+    /// Sources are added one at a time, so a domain listed twice by the same
+    /// source (e.g. in two sections) only needs checking against the last name.
+    fn attribute(&mut self, source: &str, results: &[ExtractionResult]) {
+        let Some(attribution) = self.source_attribution.as_mut() else {
+            return;
+        };
+        for result in results {
+            let names = attribution.entry(result.domain.clone()).or_default();
+            if names.last().map(String::as_str) != Some(source) {
+                names.push(source.to_string());
+            }
         }
     }
 
@@ -59,6 +94,211 @@ impl CategoryDomains {
     pub fn category_count(&self) -> usize {
         self.by_category.len()
     }
+
+    /// Add extracted domains to `category`, keeping raw adblock rules
+    ///
+    /// With a classifier, domains it knows go to their mapped category
+    /// instead. Returns how many domains were new to `category`.
+    fn add(&mut self, category: Option<String>, results: Vec<ExtractionResult>, classifier: Option<&CategoryMap>) -> usize {
+        let mut classified = Vec::new();
+        let category_set = self.by_category.entry(category).or_default();
+        let count_before = category_set.len();
+
+        for extraction_result in results {
+            match classifier.and_then(|map| map.lookup(&extraction_result.domain)) {
+                Some(mapped) => classified.push((mapped.to_string(), extraction_result.domain.clone())),
+                None => {
+                    category_set.insert(extraction_result.domain.clone());
+                }
+            }
+            // Store raw adblock rule if present (for adblock output passthrough);
+            // when sources disagree the smallest rule wins, whatever order they came in
+            if let Some(raw_rule) = extraction_result.raw_adblock_rule {
+                match self.adblock_rules.entry(extraction_result.domain) {
+                    std::collections::hash_map::Entry::Occupied(mut existing) => {
+                        if raw_rule < *existing.get() {
+                            existing.insert(raw_rule);
+                        }
+                    }
+                    std::collections::hash_map::Entry::Vacant(slot) => {
+                        slot.insert(raw_rule);
+                    }
+                }
+            }
+        }
+        let new_in_category = category_set.len() - count_before;
+
+        for (mapped, domain) in classified {
+            self.by_category.entry(Some(mapped)).or_default().insert(domain);
+        }
+        new_in_category
+    }
+}
+
+/// Directory inside the target's output dir where a copied file set is staged
+const COPY_STAGING_DIR: &str = ".copy-staging";
+
+/// Category that operator-level blocks are written to
+const OPERATOR_CATEGORY: &str = "operator";
+
+/// Share of a source's domains that must vanish, along with a format change, to count as a shift
+const FORMAT_SHIFT_MIN_DROP: f64 = 0.5;
+
+/// Added/removed domains listed by a config preview (the counts cover all of them)
+const PREVIEW_SAMPLE_SIZE: usize = 20;
+
+/// A source's extracted domains, one entry per section (None = the source's own category)
+type Sections = Vec<(Option<String>, ExtractionOutput)>;
+
+/// Operator-level domains applied to every build after the user's whitelist
+#[derive(Debug, Default)]
+pub struct OperatorLayer {
+    /// Always blocked, added to the `operator` category
+    pub block: HashSet<String>,
+    /// Never blocked, removed from every category
+    pub allow: HashSet<String>,
+}
+
+impl OperatorLayer {
+    /// Parse the operator block/allow lists (same format as a forced-block list)
+    pub fn from_lists(lists: &OperatorLists) -> Self {
+        Self {
+            block: JobProcessor::parse_forced_blocks(lists.blocklist.as_deref().unwrap_or_default()),
+            allow: JobProcessor::parse_forced_blocks(lists.allowlist.as_deref().unwrap_or_default()),
+        }
+    }
+
+    /// Sorted, normalized form for config hashes (empty when no operator lists)
+    fn canonical(&self) -> String {
+        if self.block.is_empty() && self.allow.is_empty() {
+            return String::new();
+        }
+        let sorted = |set: &HashSet<String>| {
+            let mut domains: Vec<&str> = set.iter().map(|d| d.as_str()).collect();
+            domains.sort_unstable();
+            domains.join("\n")
+        };
+        format!("block:\n{}\nallow:\n{}", sorted(&self.block), sorted(&self.allow))
+    }
+}
+
+/// Debounces progress writes made from inside a loop
+struct ProgressThrottle {
+    interval: Duration,
+    last: Option<Instant>,
+}
+
+impl ProgressThrottle {
+    fn new(interval: Duration) -> Self {
+        Self { interval, last: None }
+    }
+
+    /// Whether enough time has passed to write again (the first call always is)
+    fn ready(&mut self, now: Instant) -> bool {
+        if self.last.is_some_and(|last| now.duration_since(last) < self.interval) {
+            return false;
+        }
+        self.last = Some(now);
+        true
+    }
+}
+
+/// Records generation progress on the job from whichever thread generation runs on
+///
+/// The combined list may be generated on a rayon thread, outside any tokio
+/// runtime. An uncontended lock needs no runtime; otherwise the update waits
+/// on the job's runtime where that's allowed, and is skipped (logged once)
+/// where it isn't, so a progress write can never panic the build.
+struct GenerationProgressWriter {
+    progress: Arc<Mutex<JobProgress>>,
+    /// Runtime the job runs on, if the writer was created inside one
+    handle: Option<tokio::runtime::Handle>,
+    skip_logged: std::sync::atomic::AtomicBool,
+}
+
+impl GenerationProgressWriter {
+    fn new(progress: Arc<Mutex<JobProgress>>) -> Self {
+        Self {
+            progress,
+            handle: tokio::runtime::Handle::try_current().ok(),
+            skip_logged: std::sync::atomic::AtomicBool::new(false),
+        }
+    }
+
+    /// Set the job's generation progress; returns whether it was written
+    fn update(&self, gen_progress: &GenerationProgress) -> bool {
+        let mut progress = match self.progress.try_lock() {
+            Ok(progress) => progress,
+            Err(_) => match self.lock_blocking() {
+                Some(progress) => progress,
+                None => {
+                    if !self.skip_logged.swap(true, std::sync::atomic::Ordering::Relaxed) {
+                        warn!("Generation progress can't be recorded from this thread, skipping updates");
+                    }
+                    return false;
+                }
+            },
+        };
+        progress.generation = Some(gen_progress.clone());
+        true
+    }
+
+    /// Wait for the contended lock, if this thread may block on a runtime
+    fn lock_blocking(&self) -> Option<tokio::sync::MutexGuard<'_, JobProgress>> {
+        use tokio::runtime::{Handle, RuntimeFlavor};
+
+        match Handle::try_current() {
+            // A multi-threaded worker can hand its tasks off and block
+            Ok(handle) if handle.runtime_flavor() == RuntimeFlavor::MultiThread => {
+                Some(tokio::task::block_in_place(|| handle.block_on(self.progress.lock())))
+            }
+            // Blocking the only thread of a current-thread runtime would deadlock
+            Ok(_) => None,
+            // Off-runtime thread (e.g. rayon): block on the job's runtime
+            Err(_) => self.handle.as_ref().map(|handle| handle.block_on(self.progress.lock())),
+        }
+    }
+}
+
+/// Tracks which source each domain came from, to count per-source unique domains
+///
+/// Keys are domain hashes rather than strings to keep the extra memory small on
+/// multi-million domain builds.
+struct SourceOverlap {
+    hasher: std::collections::hash_map::RandomState,
+    /// Domain hash -> owning source index, or `SHARED` once seen in two sources
+    owners: HashMap<u64, usize>,
+}
+
+impl SourceOverlap {
+    const SHARED: usize = usize::MAX;
+
+    fn new() -> Self {
+        Self {
+            hasher: Default::default(),
+            owners: HashMap::new(),
+        }
+    }
+
+    /// Record that `source` lists `domain`
+    fn record(&mut self, source: usize, domain: &str) {
+        use std::hash::BuildHasher;
+        let owner = self.owners.entry(self.hasher.hash_one(domain)).or_insert(source);
+        if *owner != source {
+            *owner = Self::SHARED;
+        }
+    }
+
+    /// Count of domains only listed by each source, indexed by source
+    fn unique_counts(&self, sources: usize) -> Vec<u64> {
+        let mut counts = vec![0; sources];
+        for &owner in self.owners.values() {
+            if let Some(count) = counts.get_mut(owner) {
+                *count += 1;
+            }
+        }
+        counts
+    }
 }
 
 /// Main job processor that orchestrates the entire pipeline
@@ -69,15 +309,57 @@ pub struct JobProcessor {
     user_repo: UserRepository,
     downloader: Downloader,
     extractor: DomainExtractor,
+    /// Section header comment that switches the category mid-source (SECTION_HEADER_PATTERN)
+    section_header: Option<Regex>,
+    /// Classifier for domains from uncategorized sources (None unless AUTO_CATEGORIZE)
+    category_map: Option<CategoryMap>,
+    /// Drops `.onion` and similar domains (None unless FILTER_RESERVED_DOMAINS)
+    reserved_filter: Option<ReservedDomainFilter>,
+    /// Output file naming (OUTPUT_FILENAME_TEMPLATE)
+    filename_template: FilenameTemplate,
+    /// Fires on worker shutdown; aborts downloads so the job can be released
+    cancel: CancelToken,
+    /// Post-build notifications (None unless ENABLE_WEBHOOKS)
+    webhooks: Option<WebhookNotifier>,
 }
 
 impl JobProcessor {
     /// Create a new job processor
     pub fn new(config: Config, job_repo: JobRepository, db: &Database) -> Result<Self> {
         let downloader = Downloader::new(config.clone(), db)?;
-        let extractor = DomainExtractor::new();
+        let extractor = DomainExtractor::new()
+            .with_domain_prefixes(&config.domain_prefixes)
+            .with_hosts_sink_ips(&config.hosts_sink_ips)
+            .map_err(|e| WorkerError::ConfigInvalid(format!("HOSTS_SINK_IPS: {:#}", e)))?;
         let user_config_repo = UserConfigRepository::new(db);
         let user_repo = UserRepository::new(db);
+        let section_header = config
+            .section_header_pattern
+            .as_deref()
+            .map(Regex::new)
+            .transpose()
+            .map_err(|e| WorkerError::ConfigInvalid(format!("SECTION_HEADER_PATTERN: {}", e)))?;
+
+        let category_map = match (config.auto_categorize, &config.category_map_file) {
+            (false, _) => None,
+            (true, Some(path)) => Some(CategoryMap::load(path)?),
+            (true, None) => Some(CategoryMap::bundled()),
+        };
+        if let Some(map) = &category_map {
+            debug!("Auto-categorizing with {} mapped domains", map.len());
+        }
+
+        let reserved_filter = config
+            .filter_reserved_domains
+            .then(|| ReservedDomainFilter::new(&config.reserved_tlds));
+
+        let filename_template = FilenameTemplate::parse(&config.output_filename_template)
+            .map_err(|e| WorkerError::ConfigInvalid(format!("OUTPUT_FILENAME_TEMPLATE: {}", e)))?;
+
+        let webhooks = config
+            .enable_webhooks
+            .then(WebhookNotifier::new)
+            .transpose()?;
 
         Ok(Self {
             config,
@@ -86,12 +368,50 @@ impl JobProcessor {
             user_repo,
             downloader,
             extractor,
+            section_header,
+            category_map,
+            reserved_filter,
+            filename_template,
+            cancel: CancelToken::default(),
+            webhooks,
         })
     }
 
-    /// Compute config hash (SHA256 of blocklists + whitelist)
-    fn compute_config_hash(blocklists: &str, whitelist: &str) -> String {
-        let combined = format!("{}\n---SEPARATOR---\n{}", blocklists, whitelist);
+    /// Abort the job's downloads when `cancel` fires (worker shutdown)
+    pub fn with_cancel(mut self, cancel: CancelToken) -> Self {
+        self.cancel = cancel;
+        self
+    }
+
+    /// Notify the user's webhook, if they set one and webhooks are enabled
+    ///
+    /// Sent in the background so a slow endpoint never holds up the next job;
+    /// delivery failures are logged only, the job has already completed.
+    fn send_webhook(&self, url: Option<&str>, payload: WebhookPayload) {
+        let (Some(notifier), Some(url)) = (&self.webhooks, url) else {
+            return;
+        };
+        let notifier = notifier.clone();
+        let url = url.to_string();
+        tokio::spawn(async move {
+            if let Err(e) = notifier.notify(&url, &payload).await {
+                warn!("Failed to deliver webhook for job {}: {}", payload.job_id, e);
+            }
+        });
+    }
+
+    /// Compute config hash (SHA256 of blocklists + whitelist + forced blocks + operator layer)
+    ///
+    /// The forced-block list and operator layer are only appended when set, so
+    /// existing hashes stay valid.
+    fn compute_config_hash(blocklists: &str, whitelist: &str, forced_blocks: &str, operator: &str) -> String {
+        let mut combined = format!("{}\n---SEPARATOR---\n{}", blocklists, whitelist);
+        if !forced_blocks.trim().is_empty() {
+            combined.push_str(&format!("\n---FORCED---\n{}", forced_blocks));
+        }
+        if !operator.is_empty() {
+            combined.push_str(&format!("\n---OPERATOR---\n{}", operator));
+        }
         let mut hasher = Sha256::new();
         hasher.update(combined.as_bytes());
         format!("{:x}", hasher.finalize())
@@ -101,115 +421,883 @@ impl JobProcessor {
     ///
     /// Creates a fingerprint from sorted, normalized sources and whitelist patterns.
     /// Two configs with same sources and whitelist (regardless of comments/order) → same fingerprint.
-    fn compute_config_fingerprint(blocklists: &str, whitelist: &str) -> String {
+    /// A restricted format set is included so users never copy a different set of files.
+    /// The operator layer is included so copy-on-match never reuses output built
+    /// under different operator lists.
+    fn compute_config_fingerprint(
+        blocklists: &str,
+        whitelist: &str,
+        forced_blocks: &HashSet<String>,
+        formats: &[OutputFormat],
+        operator: &str,
+        schemes: &[String],
+    ) -> String {
         // Parse and sort sources by URL
-        let mut sources = Downloader::parse_config(blocklists);
+        let mut sources = Downloader::parse_config_with_schemes(blocklists, schemes).sources;
         sources.sort_by(|a, b| a.url.cmp(&b.url));
 
         // Create normalized string representation of sources
         let sources_str: Vec<String> = sources
             .iter()
-            .map(|s| {
-                format!(
-                    "{}|{}|{}",
-                    s.url.to_lowercase().trim_end_matches('/'),
-                    s.name.to_lowercase(),
-                    s.category.as_deref().unwrap_or("").to_lowercase()
-                )
-            })
+            .map(|s| {
+                let mut line = format!(
+                    "{}|{}|{}",
+                    s.url.to_lowercase().trim_end_matches('/'),
+                    s.name.to_lowercase(),
+                    s.category.as_deref().unwrap_or("").to_lowercase()
+                );
+                // Options change what is extracted, so they're part of the fingerprint
+                if let Some(selector) = &s.json_selector {
+                    line.push_str(&format!("|json={}", selector));
+                }
+                if s.pac {
+                    line.push_str("|format=pac");
+                }
+                if s.method != reqwest::Method::GET {
+                    line.push_str(&format!("|method={}", s.method));
+                }
+                if let Some(body) = &s.body {
+                    line.push_str(&format!("|body={}", body));
+                }
+                // Mirrors can serve different content when the primary is down
+                for mirror in &s.mirrors {
+                    line.push_str(&format!("|mirror={}", mirror.url.to_lowercase().trim_end_matches('/')));
+                }
+                line
+            })
+            .collect();
+
+        // Parse and sort whitelist patterns
+        let whitelist_mgr = WhitelistManager::from_content(whitelist);
+        let patterns = whitelist_mgr.patterns_as_strings();
+
+        // Hash combined normalized content
+        let mut combined = format!("{}\n---\n{}", sources_str.join("\n"), patterns.join("\n"));
+        // Forced blocks override the whitelist, so only identical sets can share output
+        if !forced_blocks.is_empty() {
+            let mut forced: Vec<&str> = forced_blocks.iter().map(|d| d.as_str()).collect();
+            forced.sort_unstable();
+            combined.push_str(&format!("\n---forced---\n{}", forced.join("\n")));
+        }
+        // Only restricted format sets change the fingerprint (existing fingerprints stay valid)
+        if formats != OutputFormat::all().as_slice() {
+            let names: Vec<&str> = formats.iter().map(|f| f.as_str()).collect();
+            combined.push_str(&format!("\n---formats---\n{}", names.join(",")));
+        }
+        if !operator.is_empty() {
+            combined.push_str(&format!("\n---operator---\n{}", operator));
+        }
+        let mut hasher = Sha256::new();
+        hasher.update(combined.as_bytes());
+        format!("{:x}", hasher.finalize())
+    }
+
+    /// Copy output files from a matching user to the target user
+    async fn copy_output_files(
+        &self,
+        source: &MatchedUser,
+        target_username: &str,
+    ) -> Result<Vec<OutputFile>> {
+        let source_dir = self.config.output_dir(&source.username);
+        let target_dir = self.config.output_dir(target_username);
+
+        info!(
+            "Copying output files from {} to {}",
+            source_dir.display(),
+            target_dir.display()
+        );
+
+        // Ensure target directory exists
+        std::fs::create_dir_all(&target_dir)?;
+
+        // Copy every output file (.gz and any .zst/uncompressed variants) from source to target
+        let mut names: Vec<(String, &str)> = Vec::new();
+        for filename in FilesystemSink::new(&source_dir).list()? {
+            if !self.filename_template.matches(&filename) {
+                continue;
+            }
+            let compression = if filename.ends_with(".gz") {
+                "gz"
+            } else if filename.ends_with(".zst") {
+                "zst"
+            } else {
+                "none"
+            };
+            names.push((filename, compression));
+        }
+        if names.is_empty() {
+            anyhow::bail!("No output files found in {}", source_dir.display());
+        }
+
+        // All or nothing: a source file vanishing mid-copy leaves the target untouched
+        let filenames: Vec<String> = names.iter().map(|(name, _)| name.clone()).collect();
+        let store = self
+            .config
+            .content_addressed_output
+            .then(|| self.config.output_store_dir());
+        Self::copy_files_staged(&source_dir, &target_dir, &filenames, store.as_deref())?;
+
+        let mut output_files = Vec::new();
+        for (filename_str, compression) in names {
+            let target_path = target_dir.join(&filename_str);
+
+            // List and format from the filename template (e.g. all_domains_hosts.txt.gz)
+            let (list, format) = self
+                .filename_template
+                .parse_name(&filename_str)
+                .unwrap_or_else(|| (String::new(), "unknown".to_string()));
+
+            // Get domain count from source's list metadata if available
+            let domain_count = source
+                .lists
+                .iter()
+                .find(|l| l.name == list)
+                .map(|l| l.domain_count)
+                .unwrap_or(0);
+
+            let size_bytes = std::fs::metadata(&target_path)?.len();
+
+            debug!("Copied {} ({} bytes)", filename_str, size_bytes);
+            output_files.push(OutputFile {
+                name: filename_str,
+                format,
+                size_bytes,
+                domain_count,
+                compression: compression.to_string(),
+            });
+        }
+
+        // Remove the target's old files now that the copied set is in place
+        OutputGenerator::new(&target_dir)
+            .with_filename_template(self.filename_template.clone())
+            .cleanup_old_files(&output_files)?;
+
+        info!(
+            "Copied {} output files ({} bytes total)",
+            output_files.len(),
+            output_files.iter().map(|f| f.size_bytes).sum::<u64>()
+        );
+
+        Ok(output_files)
+    }
+
+    /// Copy `names` from `source_dir` into `target_dir`, all or nothing
+    ///
+    /// Every file is first copied into a staging directory; only once all copies
+    /// succeed are they moved over the target's files. A failed copy leaves the
+    /// previous output intact so the caller can fall back to a normal build.
+    /// With a content-addressed `store`, files are published through it as
+    /// links to shared blobs, as a build would write them.
+    fn copy_files_staged(
+        source_dir: &Path,
+        target_dir: &Path,
+        names: &[String],
+        store: Option<&Path>,
+    ) -> Result<()> {
+        let staging = target_dir.join(COPY_STAGING_DIR);
+        let _ = std::fs::remove_dir_all(&staging);
+        std::fs::create_dir_all(&staging)?;
+
+        let staged = names.iter().try_for_each(|name| {
+            // Names may include subdirectories (OUTPUT_FILENAME_TEMPLATE)
+            if let Some(parent) = staging.join(name).parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            std::fs::copy(source_dir.join(name), staging.join(name))
+                .map(|_| ())
+                .with_context(|| format!("Failed to copy {}", name))
+        });
+        if let Err(e) = staged {
+            let _ = std::fs::remove_dir_all(&staging);
+            return Err(e);
+        }
+
+        if let Some(store) = store {
+            let sink = ContentAddressedSink::new(target_dir, store);
+            sink.prepare()?;
+            for name in names {
+                let mut staged = sink.create(name)?;
+                std::io::copy(&mut std::fs::File::open(staging.join(name))?, &mut staged)?;
+                staged.commit()?;
+            }
+        } else {
+            // Same filesystem, so each move is a rename readers never see half-done
+            for name in names {
+                if let Some(parent) = target_dir.join(name).parent() {
+                    std::fs::create_dir_all(parent)?;
+                }
+                replace_file(&staging.join(name), &target_dir.join(name))?;
+            }
+        }
+        let _ = std::fs::remove_dir_all(&staging);
+        Ok(())
+    }
+
+    /// Bucket for a source's domains: its own category, else the configured default
+    fn resolve_category(category: Option<&str>, default_category: Option<&str>) -> Option<String> {
+        category.or(default_category).map(String::from)
+    }
+
+    /// Deduplicated domains for the combined list, leaving out opt-in categories
+    ///
+    /// Opt-in categories (e.g. nsfw) still get their own files; they just never
+    /// appear in all_domains. A domain also listed in a regular category stays.
+    fn combined_domains<D>(by_category: &HashMap<Option<String>, D>, optin_categories: &HashSet<String>) -> HashSet<String>
+    where
+        for<'a> &'a D: IntoIterator<Item = &'a String>,
+    {
+        by_category
+            .iter()
+            .filter(|(cat, _)| !matches!(cat, Some(c) if optin_categories.contains(&c.to_lowercase())))
+            .flat_map(|(_, domains)| domains.into_iter().cloned())
+            .collect()
+    }
+
+    /// Unique domains in the combined list and across every category
+    ///
+    /// The two differ when opt-in categories hold domains no regular category
+    /// lists, which is why a category can look bigger than the "total".
+    fn unique_domain_counts(domains: &CategoryDomains, optin_categories: &HashSet<String>) -> (u64, u64) {
+        let combined = Self::combined_domains(&domains.by_category, optin_categories).len() as u64;
+        (combined, domains.total_count() as u64)
+    }
+
+    /// Domains per category (sources without one under `uncategorized`), for `JobResult.categories`
+    fn category_counts(domains: &CategoryDomains) -> HashMap<String, u64> {
+        domains
+            .by_category
+            .iter()
+            .map(|(category, domains)| {
+                let name = category.clone().unwrap_or_else(|| "uncategorized".to_string());
+                (name, domains.len() as u64)
+            })
+            .collect()
+    }
+
+    /// Write the per-category files, then the combined `all_domains_*` files
+    ///
+    /// The combined list (deduplicated, opt-in categories excluded) only reads
+    /// `by_category`, so with `concurrent` it is merged, sorted and written
+    /// while the category files are still going. Files come back in the same
+    /// order either way: categories first, then combined.
+    fn generate_outputs(
+        generator: &OutputGenerator,
+        by_category: &HashMap<Option<String>, Vec<String>>,
+        adblock_rules: &HashMap<String, String>,
+        optin_categories: &HashSet<String>,
+        sort_domains: fn(HashSet<String>) -> Vec<String>,
+        concurrent: bool,
+        progress_callback: impl FnMut(&GenerationProgress) + Send,
+    ) -> Result<Vec<OutputFile>> {
+        let categories = || generator.generate_all_categories(by_category, adblock_rules);
+        let combined = || {
+            let all_sorted = sort_domains(Self::combined_domains(by_category, optin_categories));
+            generator.generate_all(&all_sorted, adblock_rules, progress_callback)
+        };
+
+        let (mut output_files, combined_files) = if concurrent {
+            let (category_files, combined_files) = rayon::join(categories, combined);
+            (category_files?, combined_files?)
+        } else {
+            let category_files = categories()?;
+            (category_files, combined()?)
+        };
+
+        output_files.extend(combined_files);
+        Ok(output_files)
+    }
+
+    /// Apply the operator layer to whitelist-filtered domains
+    ///
+    /// Operator blocks are added to the `operator` category even when no user
+    /// source lists them; operator allows are then removed from every category.
+    fn apply_operator_layer(mut domains: CategoryDomains, operator: &OperatorLayer) -> CategoryDomains {
+        if !operator.block.is_empty() {
+            domains
+                .by_category
+                .entry(Some(OPERATOR_CATEGORY.to_string()))
+                .or_default()
+                .extend(operator.block.iter().cloned());
+        }
+
+        if !operator.allow.is_empty() {
+            for category_domains in domains.by_category.values_mut() {
+                category_domains.retain(|d| !operator.allow.contains(d));
+            }
+            domains.by_category.retain(|_, d| !d.is_empty());
+            domains.adblock_rules.retain(|d, _| !operator.allow.contains(d));
+            if let Some(attribution) = domains.source_attribution.as_mut() {
+                attribution.retain(|d, _| !operator.allow.contains(d));
+            }
+        }
+
+        domains
+    }
+
+    /// Parse a forced-block list into normalized domains
+    ///
+    /// One domain per line; `#` comments and blank lines are ignored.
+    fn parse_forced_blocks(content: &str) -> HashSet<String> {
+        content
+            .lines()
+            .map(|line| match line.find('#') {
+                Some(idx) => &line[..idx],
+                None => line,
+            })
+            .map(|line| line.trim().trim_end_matches('.').to_lowercase())
+            .filter(|domain| !domain.is_empty())
+            .collect()
+    }
+
+    /// Apply the whitelist to every category, keeping forced-block domains
+    ///
+    /// Forced-block domains are never removed, even when a whitelist pattern
+    /// matches them. Returns the filtered categories and the pattern matches
+    /// used for progress reporting.
+    fn apply_whitelist(
+        whitelist: &WhitelistManager,
+        category_domains: CategoryDomains,
+        forced_blocks: &HashSet<String>,
+    ) -> (CategoryDomains, Vec<WhitelistPatternMatch>, HashMap<String, u64>) {
+        // Filter ALL domains to get whitelist stats (pattern matches, etc.)
+        let mut all_domains = category_domains.all_unique();
+        all_domains.retain(|d| !forced_blocks.contains(d));
+        let (_, _, mut pattern_matches) = whitelist.filter_domains(all_domains);
+
+        // Filter each category separately (scoped patterns / exempt categories apply here)
+        // A domain listed in several categories counts towards each one it's removed from
+        let mut filtered = CategoryDomains::new();
+        let mut category_removed: HashMap<String, u64> = HashMap::new();
+        for (category, domains) in category_domains.by_category {
+            let (forced, candidates): (HashSet<String>, HashSet<String>) =
+                domains.into_iter().partition(|d| forced_blocks.contains(d));
+
+            let (mut remaining, removed, scoped_matches) =
+                whitelist.filter_category(candidates, category.as_deref());
+            pattern_matches.extend(scoped_matches);
+            if removed > 0 {
+                let name = category.clone().unwrap_or_else(|| "uncategorized".to_string());
+                *category_removed.entry(name).or_insert(0) += removed;
+            }
+            remaining.extend(forced);
+
+            if !remaining.is_empty() {
+                filtered.by_category.insert(category, remaining);
+            }
+        }
+        pattern_matches.sort_by(|a, b| b.match_count.cmp(&a.match_count).then_with(|| a.pattern.cmp(&b.pattern)));
+
+        // Copy over adblock_rules for domains that remain after whitelist filtering
+        let remaining_domains = filtered.all_unique();
+        for (domain, rule) in category_domains.adblock_rules {
+            if remaining_domains.contains(&domain) {
+                filtered.adblock_rules.insert(domain, rule);
+            }
+        }
+        filtered.source_attribution = category_domains.source_attribution.map(|mut attribution| {
+            attribution.retain(|domain, _| remaining_domains.contains(domain));
+            attribution
+        });
+
+        (filtered, pattern_matches, category_removed)
+    }
+
+    /// Resolve a user's enabled formats, falling back to the default formats
+    ///
+    /// Unknown names are ignored; an empty or entirely unknown list means the defaults.
+    fn resolve_formats(names: Option<Vec<String>>) -> Vec<OutputFormat> {
+        let requested: Vec<OutputFormat> = names
+            .unwrap_or_default()
+            .iter()
+            .filter_map(|name| OutputFormat::from_name(name))
+            .collect();
+
+        if requested.is_empty() {
+            return OutputFormat::all();
+        }
+
+        // Keep canonical order (hosts, plain, adblock, umbrella) regardless of config order
+        OutputFormat::supported()
+            .into_iter()
+            .filter(|f| requested.contains(f))
+            .collect()
+    }
+
+    /// Per-format domain counts of a list, kept only when the formats disagree
+    fn differing_format_counts(counts: HashMap<String, u64>) -> HashMap<String, u64> {
+        let mut values = counts.values();
+        let first = values.next();
+        if values.all(|count| Some(count) == first) {
+            HashMap::new()
+        } else {
+            counts
+        }
+    }
+
+    /// Build list metadata for all categories + all_domains from generated files
+    fn build_list_metadata(
+        output_files: &[OutputFile],
+        unique_domains: u64,
+        formats: &[OutputFormat],
+        template: &FilenameTemplate,
+        now: BsonDateTime,
+    ) -> Vec<ListMetadata> {
+        let format_names: Vec<String> = formats.iter().map(|f| f.as_str().to_string()).collect();
+        let mut all_lists: Vec<ListMetadata> = Vec::new();
+
+        // Group output files by category to build ListMetadata for each
+        // The filename template gives each file's list, e.g. advertising_hosts.txt.gz
+        // -> "advertising"; all_domains (the combined list) is handled separately below
+        // Each file's own count, per list and format (variants repeat their .gz count)
+        let mut list_format_counts: HashMap<String, HashMap<String, u64>> = HashMap::new();
+        for file in output_files {
+            let Some((list, format)) = template.parse_name(&file.name) else {
+                continue;
+            };
+            list_format_counts.entry(list).or_default().insert(format, file.domain_count);
+        }
+        let combined_format_counts = list_format_counts.remove(COMBINED_LIST_NAME).unwrap_or_default();
+
+        // Build ListMetadata for each category
+        for (category, format_counts) in list_format_counts {
+            // Collapsed formats (umbrella, adblock) can list fewer domains, so
+            // the largest count is the category's size
+            let domain_count = format_counts.values().copied().max().unwrap_or(0);
+            let list = ListMetadata {
+                name: category,
+                is_public: true,  // All lists are always public
+                formats: format_names.clone(),
+                domain_count,
+                format_counts: Self::differing_format_counts(format_counts),
+                last_updated: now,
+            };
+            all_lists.push(list);
+        }
+
+        // Add all_domains (combined list)
+        let all_domains_list = ListMetadata {
+            name: COMBINED_LIST_NAME.to_string(),
+            is_public: true,  // All lists are always public
+            formats: format_names,
+            domain_count: unique_domains,
+            format_counts: Self::differing_format_counts(combined_format_counts),
+            last_updated: now,
+        };
+        all_lists.push(all_domains_list);
+
+        all_lists
+    }
+
+    /// Fail a build whose unique domains (all categories) exceed `max` (0 = unlimited)
+    fn check_total_domains(total: usize, max: u64) -> Result<()> {
+        if max > 0 && total as u64 > max {
+            anyhow::bail!(
+                "Build has {} unique domains, over the limit of {} - remove some sources or add whitelist patterns",
+                total,
+                max
+            );
+        }
+        Ok(())
+    }
+
+    /// Config for a job: its inline override if set, otherwise the stored config
+    async fn job_config(repo: &UserConfigRepository, job: &Job) -> Result<UserConfig> {
+        match &job.config_override {
+            Some(config) => Ok(config.clone()),
+            None => repo.get_config(&job.username).await,
+        }
+    }
+
+    /// Whether a job may take a matching user's output instead of building
+    ///
+    /// Not for forced rebuilds, test builds (the match is against stored
+    /// configs), or users who opted out to get an independent build.
+    fn copy_on_match_allowed(job: &Job, user_config: &UserConfig) -> bool {
+        !job.force_rebuild && job.config_override.is_none() && user_config.allow_copy_on_match != Some(false)
+    }
+
+    /// Append per-category whitelists to the global one as scoped patterns
+    ///
+    /// Categories are merged in name order so the content (and with it the
+    /// config hash) doesn't depend on map ordering. Returns skipped-line warnings.
+    fn merge_category_whitelists(whitelist_content: &mut String, category_whitelists: HashMap<String, String>) -> Vec<String> {
+        let mut categories: Vec<_> = category_whitelists.into_iter().collect();
+        categories.sort();
+
+        let mut warnings = Vec::new();
+        for (category, content) in categories {
+            let (scoped, skipped) = scope_category_whitelist(&category, &content);
+            warnings.extend(skipped);
+            if scoped.is_empty() {
+                continue;
+            }
+            if !whitelist_content.is_empty() && !whitelist_content.ends_with('\n') {
+                whitelist_content.push('\n');
+            }
+            whitelist_content.push_str(&scoped);
+        }
+        warnings
+    }
+
+    /// Whitelist patterns that failed to parse or are over-broad, as job messages
+    ///
+    /// Err in strict mode (STRICT_WHITELIST), so the job fails rather than
+    /// building without the user's exceptions (or with most of the list
+    /// whitelisted away); otherwise Ok with warnings.
+    fn check_whitelist(whitelist_content: &str, strict: bool) -> Result<Vec<String>, Vec<String>> {
+        let whitelist = WhitelistManager::from_content(whitelist_content);
+        let mut errors = whitelist.parse_errors().to_vec();
+        errors.extend_from_slice(whitelist.overbroad_warnings());
+        if strict && !errors.is_empty() {
+            return Err(errors);
+        }
+        Ok(errors)
+    }
+
+    /// Whether a config with no valid sources builds empty lists instead of failing
+    fn builds_empty_list(user_config: &UserConfig, worker_default: bool) -> bool {
+        user_config.empty_config_produces_empty_list.unwrap_or(worker_default)
+    }
+
+    /// Where a job's lists are written; test builds never replace the published ones
+    fn job_output_dir(&self, job: &Job) -> PathBuf {
+        if job.config_override.is_some() {
+            self.config.test_output_dir(&job.username)
+        } else {
+            self.config.output_dir(&job.username)
+        }
+    }
+
+    /// Process a job, failing it once it runs past `max_job_duration_secs`
+    ///
+    /// On timeout the job's future is dropped, which aborts its downloads.
+    pub async fn process_job_with_deadline(&self, job: &Job) -> Result<()> {
+        Self::with_deadline(self.config.max_job_duration_secs, self.process_job(job)).await
+    }
+
+    /// Run `work`, giving up with `JobTimedOut` after `limit_secs` (0 = no limit)
+    async fn with_deadline(limit_secs: u64, work: impl std::future::Future<Output = Result<()>>) -> Result<()> {
+        if limit_secs == 0 {
+            return work.await;
+        }
+        match tokio::time::timeout(Duration::from_secs(limit_secs), work).await {
+            Ok(result) => result,
+            Err(_) => Err(WorkerError::JobTimedOut { limit_secs }.into()),
+        }
+    }
+
+    /// Preview job: diff the proposed config (`config_override`) against the stored one
+    ///
+    /// Nothing is generated, and the user's config, lists and hash are left alone.
+    async fn process_preview(&self, job: &Job) -> Result<()> {
+        let Some(proposed) = &job.config_override else {
+            self.job_repo
+                .fail(&job.id, vec!["Preview job has no proposed config".to_string()])
+                .await?;
+            return Ok(());
+        };
+        let current = match self.user_config_repo.get_config(&job.username).await {
+            Ok(config) => config,
+            Err(e) => {
+                self.job_repo
+                    .fail(&job.id, vec![format!("Failed to load config: {}", e)])
+                    .await?;
+                return Ok(());
+            }
+        };
+
+        // Shutting down: leave the job claimed so the worker releases it back to the queue
+        let Some(result) = self.preview_config_diff(&current, proposed).await? else {
+            info!("Preview {} interrupted by shutdown", job.job_id);
+            return Ok(());
+        };
+        if let Some(diff) = &result.config_diff {
+            info!(
+                "Preview for {}: +{} / -{} domains ({} unchanged)",
+                job.username, diff.added_count, diff.removed_count, diff.unchanged_count
+            );
+        }
+        self.job_repo.complete(&job.id, result).await
+    }
+
+    /// Compare the combined lists two configs would build, without building either
+    ///
+    /// Sources are downloaded through the cache (each once, even when both
+    /// configs list it), then extracted and whitelisted as in a build. The
+    /// operator layer applies to both sides alike and is left out. The result
+    /// carries the diff in `config_diff`; failed sources are counted and listed
+    /// in `config_warnings`, as their domains are missing from both sides.
+    /// None if the worker shut down before the downloads finished.
+    pub async fn preview_config_diff(&self, current: &UserConfig, proposed: &UserConfig) -> Result<Option<JobResult>> {
+        let parse = |config: &UserConfig| {
+            Downloader::parse_config_with_schemes(
+                config.blocklists.as_deref().unwrap_or_default(),
+                &self.config.allowed_source_schemes,
+            )
+        };
+        let current_sources = parse(current).sources;
+        let ParsedConfig {
+            sources: proposed_sources,
+            warnings,
+        } = parse(proposed);
+
+        let mut seen = HashSet::new();
+        let unique: Vec<Source> = current_sources
+            .iter()
+            .chain(&proposed_sources)
+            .filter(|source| seen.insert(Downloader::cache_key(source)))
+            .cloned()
             .collect();
+        let downloads = self.downloader.download_sources(unique, false, &self.cancel, |_, _| {}).await;
+        if self.cancel.is_cancelled() {
+            return Ok(None);
+        }
 
-        // Parse and sort whitelist patterns
-        let whitelist_mgr = WhitelistManager::from_content(whitelist);
-        let patterns = whitelist_mgr.patterns_as_strings();
-
-        // Hash combined normalized content
-        let combined = format!("{}\n---\n{}", sources_str.join("\n"), patterns.join("\n"));
-        let mut hasher = Sha256::new();
-        hasher.update(combined.as_bytes());
-        format!("{:x}", hasher.finalize())
+        let mut result = self.diff_downloads((current, &current_sources), (proposed, &proposed_sources), downloads);
+        result.config_warnings.splice(0..0, warnings);
+        Ok(Some(result))
     }
 
-    /// Copy output files from a matching user to the target user
-    async fn copy_output_files(
+    /// Diff two configs' combined lists from their sources' downloads
+    fn diff_downloads(
         &self,
-        source: &MatchedUser,
-        target_username: &str,
-    ) -> Result<Vec<OutputFile>> {
-        let source_dir = self.config.output_dir(&source.username);
-        let target_dir = self.config.output_dir(target_username);
+        current: (&UserConfig, &[Source]),
+        proposed: (&UserConfig, &[Source]),
+        downloads: Vec<DownloadResult>,
+    ) -> JobResult {
+        let mut warnings = Vec::new();
+        let mut extracted = HashMap::new();
+        let mut sources_failed = 0;
+        for mut download in downloads {
+            let sections = match &download.error {
+                Some(error) => Err(anyhow::anyhow!("{}", error)),
+                None => {
+                    let streamed = download.extracted.take();
+                    let content = download.content.as_deref().unwrap_or_default();
+                    self.extract_sections(&download.source, content, streamed)
+                }
+            };
+            match sections {
+                Ok(sections) => {
+                    extracted.insert(download.source_id().to_string(), sections);
+                }
+                Err(e) => {
+                    sources_failed += 1;
+                    warnings.push(format!("{} failed, its domains are left out: {:#}", download.source.name, e));
+                }
+            }
+        }
 
-        info!(
-            "Copying output files from {} to {}",
-            source_dir.display(),
-            target_dir.display()
+        let current_domains = self.preview_domains(current.0, current.1, &extracted);
+        let proposed_domains = self.preview_domains(proposed.0, proposed.1, &extracted);
+
+        let mut result = JobResult::preview(
+            ConfigDiff::between(&current_domains, &proposed_domains, PREVIEW_SAMPLE_SIZE),
+            extracted.len() as u64,
+            sources_failed,
         );
+        result.config_warnings = warnings;
+        result
+    }
 
-        // Ensure target directory exists
-        std::fs::create_dir_all(&target_dir)?;
+    /// Combined list one config would build from already-extracted sources
+    fn preview_domains(
+        &self,
+        config: &UserConfig,
+        sources: &[Source],
+        extracted: &HashMap<String, Vec<(Option<String>, ExtractionOutput)>>,
+    ) -> HashSet<String> {
+        let mut category_domains = CategoryDomains::new();
+        for source in sources {
+            let Some(sections) = extracted.get(&Downloader::cache_key(source)) else {
+                continue;
+            };
+            let category = Self::resolve_category(source.category.as_deref(), self.config.default_category.as_deref());
+            for (section, output) in sections {
+                let classifier = self
+                    .category_map
+                    .as_ref()
+                    .filter(|_| section.is_none() && source.category.is_none());
+                let section_category = section.clone().or_else(|| category.clone());
+                category_domains.add(section_category, output.results.clone(), classifier);
+            }
+        }
+
+        let mut whitelist_content = config.whitelist.clone().unwrap_or_default();
+        Self::merge_category_whitelists(&mut whitelist_content, config.category_whitelists.clone().unwrap_or_default());
+        let whitelist = WhitelistManager::from_content(&whitelist_content);
+        let forced_blocks = Self::parse_forced_blocks(config.forced_blocklist.as_deref().unwrap_or_default());
 
-        // Clean up any existing files in target
-        if let Ok(entries) = std::fs::read_dir(&target_dir) {
-            for entry in entries.flatten() {
-                let path = entry.path();
-                if path.extension().map_or(false, |ext| ext == "gz") {
-                    let _ = std::fs::remove_file(&path);
+        let (filtered, _, _) = Self::apply_whitelist(&whitelist, category_domains, &forced_blocks);
+        Self::combined_domains(&filtered.by_category, &self.config.optin_categories)
+    }
+
+    /// Complete `job` with a copy of `matched`'s output (same config fingerprint)
+    ///
+    /// Returns the copied files and unique domain count, or None if the files
+    /// couldn't be copied and the job should be built normally instead.
+    async fn complete_from_match(
+        &self,
+        job: &Job,
+        matched: &MatchedUser,
+        source_count: u64,
+        formats: &[OutputFormat],
+        config_hash: &str,
+        fingerprint: &str,
+    ) -> Result<Option<(Vec<OutputFile>, u64)>> {
+        // Get source user's last job stats first (needed for domain counts and stats)
+        let source_stats = self
+            .job_repo
+            .get_last_completed_result(&matched.username)
+            .await
+            .ok()
+            .flatten();
+
+        // Copy output files from matched user
+        let mut output_files = match self.copy_output_files(matched, &job.username).await {
+            Ok(output_files) => output_files,
+            Err(e) => {
+                // Copy failed, fall through to normal build
+                warn!(
+                    "Failed to copy files from {}: {} - proceeding with normal build",
+                    matched.username, e
+                );
+                return Ok(None);
+            }
+        };
+
+        // Populate domain counts from source_stats.output_files if available
+        // This handles the case where matched.lists is empty (e.g., __default__)
+        if let Some(ref src) = source_stats {
+            for file in &mut output_files {
+                if let Some(src_file) = src.output_files.iter().find(|f| f.name == file.name) {
+                    file.domain_count = src_file.domain_count;
                 }
             }
         }
 
-        // Copy all .gz files from source to target
-        let mut output_files = Vec::new();
-        let entries = std::fs::read_dir(&source_dir)?;
-
-        for entry in entries.flatten() {
-            let filename = entry.file_name();
-            let filename_str = filename.to_string_lossy();
+        let total_output_size: u64 = output_files.iter().map(|f| f.size_bytes).sum();
+        let unique_domains = output_files
+            .iter()
+            .find(|f| self.filename_template.is_combined(&f.name))
+            .map(|f| f.domain_count)
+            .unwrap_or_else(|| {
+                // Fallback to source stats or matched.total_domains
+                source_stats
+                    .as_ref()
+                    .map(|s| s.unique_domains)
+                    .unwrap_or(matched.total_domains)
+            });
 
-            if filename_str.ends_with(".txt.gz") {
-                let source_path = entry.path();
-                let target_path = target_dir.join(&filename);
+        // Build result indicating this was a copy, with stats from source
+        let mut result = if let Some(ref src) = source_stats {
+            JobResult {
+                combined_unique_domains: src.combined_unique_domains,
+                all_categories_unique_domains: src.all_categories_unique_domains,
+                ..JobResult::copied_from_user(
+                    matched.username.clone(),
+                    src.total_domains,
+                    unique_domains,
+                    output_files.clone(),
+                    src.sources_processed,
+                    src.sources_failed,
+                    src.whitelisted_removed,
+                    src.categories.clone(),
+                )
+            }
+        } else {
+            // Fallback if no source job found
+            JobResult::copied_from_user(
+                matched.username.clone(),
+                matched.total_domains,
+                unique_domains,
+                output_files.clone(),
+                0,
+                0,
+                0,
+                std::collections::HashMap::new(),
+            )
+        };
 
-                std::fs::copy(&source_path, &target_path)?;
+        // Copy full progress from source job (includes whitelist breakdown, stage snapshots)
+        let progress = if let Ok(Some(mut source_progress)) = self
+            .job_repo
+            .get_last_completed_progress(&matched.username)
+            .await
+        {
+            // Ensure it shows as completed
+            source_progress.current_step = "completed".to_string();
+            source_progress.stage = JobStage::Completed;
+            source_progress
+        } else {
+            // Fallback to minimal progress if source not found
+            let sources_count = source_stats
+                .as_ref()
+                .map(|s| s.sources_processed + s.sources_failed)
+                .unwrap_or(0);
+            JobProgress {
+                current_step: "completed".to_string(),
+                stage: JobStage::Completed,
+                total_sources: sources_count,
+                processed_sources: sources_count,
+                ..JobProgress::default()
+            }
+        };
+        self.job_repo.update_progress(&job.id, &progress).await?;
 
-                // Extract format and domain count from filename
-                // Format: {name}_{format}.txt.gz (e.g., all_domains_hosts.txt.gz)
-                let base_name = filename_str.trim_end_matches(".txt.gz");
-                let parts: Vec<&str> = base_name.rsplitn(2, '_').collect();
-                let format = if parts.len() == 2 {
-                    parts[0].to_string()
-                } else {
-                    "unknown".to_string()
-                };
+        // Record what the copy saved ("copy-on-match saved X GB this week")
+        result.copy_savings = Some(CopySavings::estimate(source_count, &progress));
 
-                // Get domain count from source's list metadata if available
-                let domain_count = source
-                    .lists
-                    .iter()
-                    .find(|l| base_name.starts_with(&l.name))
-                    .map(|l| l.domain_count)
-                    .unwrap_or(0);
+        // Mark job as completed
+        self.job_repo.complete(&job.id, result).await?;
 
-                let size_bytes = std::fs::metadata(&target_path)?.len();
+        // Build list metadata from output files
+        let now = BsonDateTime::from_millis(Utc::now().timestamp_millis());
+        let mut all_lists: Vec<ListMetadata> = Vec::new();
 
-                output_files.push(OutputFile {
-                    name: filename_str.to_string(),
-                    format,
-                    size_bytes,
-                    domain_count,
+        // If we have lists from matched user, use those
+        if !matched.lists.is_empty() {
+            for list in &matched.lists {
+                all_lists.push(ListMetadata {
+                    name: list.name.clone(),
+                    is_public: true,
+                    formats: list.formats.clone(),
+                    domain_count: list.domain_count,
+                    format_counts: list.format_counts.clone(),
+                    last_updated: now,
                 });
-
-                debug!("Copied {} ({} bytes)", filename_str, size_bytes);
             }
+        } else {
+            // Build list metadata from output files
+            all_lists = Self::build_list_metadata(
+                &output_files,
+                unique_domains,
+                formats,
+                &self.filename_template,
+                now,
+            );
         }
 
-        info!(
-            "Copied {} output files ({} bytes total)",
-            output_files.len(),
-            output_files.iter().map(|f| f.size_bytes).sum::<u64>()
-        );
+        // Update user document
+        if let Err(e) = self
+            .user_repo
+            .update_after_build(
+                &job.username,
+                all_lists,
+                unique_domains,
+                total_output_size,
+                config_hash.to_string(),
+                fingerprint.to_string(),
+            )
+            .await
+        {
+            warn!(
+                "Failed to update user document for {}: {}",
+                job.username, e
+            );
+        }
 
-        Ok(output_files)
+        Ok(Some((output_files, unique_domains)))
     }
 
     /// Process a single job
@@ -220,13 +1308,35 @@ impl JobProcessor {
             job.job_id, job.username
         );
 
+        if job.job_type == JobType::Preview {
+            return self.process_preview(job).await;
+        }
+
         if job.force_rebuild {
             info!("Force rebuild requested - bypassing all caching optimizations");
         }
 
-        // Load config from MongoDB
-        let config_content = match self.user_config_repo.get_blocklists(&job.username).await {
-            Ok(content) => content,
+        // Same logical request as a job that already finished (double-click, API retry)
+        if self.config.idempotency_window_secs > 0 {
+            let window = Duration::from_secs(self.config.idempotency_window_secs);
+            match self.job_repo.find_completed_duplicate(job, window).await {
+                Ok(Some(original)) => {
+                    info!(
+                        "Skipping job {} - idempotency key already built by job {}",
+                        job.job_id, original.job_id
+                    );
+                    self.job_repo.skip(&job.id, SkipReason::Duplicate).await?;
+                    return Ok(());
+                }
+                Ok(None) => {}
+                Err(e) => warn!("Idempotency check failed for job {}, building anyway: {}", job.job_id, e),
+            }
+        }
+
+        // Load config from MongoDB (or the job's inline override)
+        let test_build = job.config_override.is_some();
+        let user_config = match Self::job_config(&self.user_config_repo, job).await {
+            Ok(config) => config,
             Err(e) => {
                 self.job_repo
                     .fail(&job.id, vec![format!("Failed to load config: {}", e)])
@@ -234,31 +1344,101 @@ impl JobProcessor {
                 return Ok(());
             }
         };
+        let copy_allowed = Self::copy_on_match_allowed(job, &user_config);
+        let allow_empty = Self::builds_empty_list(&user_config, self.config.empty_config_produces_empty_list);
+        let Some(config_content) = user_config.blocklists else {
+            self.job_repo
+                .fail(
+                    &job.id,
+                    vec![format!("Failed to load config: No blocklist config found for: {}", job.username)],
+                )
+                .await?;
+            return Ok(());
+        };
+
+        // Whitelist content is needed early for config hash calculation
+        let mut whitelist_content = user_config.whitelist.unwrap_or_default();
+        let category_whitelist_warnings = Self::merge_category_whitelists(
+            &mut whitelist_content,
+            user_config.category_whitelists.unwrap_or_default(),
+        );
+        let webhook_url = user_config.webhook_url;
+        let whitelist_warnings = match Self::check_whitelist(&whitelist_content, self.config.strict_whitelist) {
+            Ok(warnings) => warnings,
+            Err(errors) => {
+                self.job_repo.fail(&job.id, errors).await?;
+                return Ok(());
+            }
+        };
+
+        // Forced blocks survive whitelist filtering
+        let forced_blocks_content = user_config.forced_blocklist.unwrap_or_default();
+        let forced_blocks = Self::parse_forced_blocks(&forced_blocks_content);
+
+        // Output formats to generate (defaults to all three)
+        let formats = Self::resolve_formats(user_config.enabled_formats);
 
-        // Load whitelist content early for config hash calculation
-        let whitelist_content = self.user_config_repo.get_whitelist(&job.username).await
-            .unwrap_or_default();
+        // Operator-level block/allow lists apply to every build
+        let operator = match self.user_config_repo.get_operator_lists().await {
+            Ok(lists) => OperatorLayer::from_lists(&lists),
+            Err(e) => {
+                warn!("Failed to load operator lists: {}", e);
+                OperatorLayer::default()
+            }
+        };
+        let operator_canonical = operator.canonical();
 
         // Compute current config hash
-        let current_config_hash = Self::compute_config_hash(&config_content, &whitelist_content);
+        let current_config_hash = Self::compute_config_hash(
+            &config_content,
+            &whitelist_content,
+            &forced_blocks_content,
+            &operator_canonical,
+        );
 
         // Compute normalized fingerprint for cross-user matching
-        let config_fingerprint = Self::compute_config_fingerprint(&config_content, &whitelist_content);
+        let config_fingerprint = Self::compute_config_fingerprint(
+            &config_content,
+            &whitelist_content,
+            &forced_blocks,
+            &formats,
+            &operator_canonical,
+            &self.config.allowed_source_schemes,
+        );
 
         // Parse sources
-        let sources = Downloader::parse_config(&config_content);
-        if sources.is_empty() {
-            self.job_repo
-                .fail(&job.id, vec!["No valid sources in config".to_string()])
-                .await?;
+        let ParsedConfig {
+            sources,
+            warnings: mut config_warnings,
+        } = Downloader::parse_config_with_schemes(&config_content, &self.config.allowed_source_schemes);
+        for warning in &config_warnings {
+            warn!("Job {}: {}", job.job_id, warning);
+        }
+        // An emptied config can be a deliberate "turn blocking off"
+        let empty_config = sources.is_empty();
+        if empty_config && !allow_empty {
+            let mut errors = vec!["No valid sources in config".to_string()];
+            errors.extend(config_warnings);
+            self.job_repo.fail(&job.id, errors).await?;
             return Ok(());
         }
+        config_warnings.extend(category_whitelist_warnings);
+        config_warnings.extend(whitelist_warnings);
 
-        info!("Found {} sources to process", sources.len());
+        if empty_config {
+            info!("No sources configured for {} - building empty lists", job.username);
+        } else {
+            info!("Found {} sources to process", sources.len());
+        }
+
+        if test_build {
+            info!("Inline config override - test build, stored config and lists are left alone");
+        }
 
         // Check for "no changes" optimization
         // Skip if: config hash unchanged AND all sources would be cache hits
-        if !job.force_rebuild {
+        // (both shortcuts compare against the stored config, so test builds skip them)
+        if !job.force_rebuild && !test_build {
             if let Ok(Some(stored_hash)) = self.user_repo.get_config_hash(&job.username).await {
                 if stored_hash == current_config_hash {
                     // Config unchanged, check if all sources are cached
@@ -269,10 +1449,7 @@ impl JobProcessor {
                             job.job_id
                         );
                         self.job_repo
-                            .skip(
-                                &job.id,
-                                "No changes detected since last build. All sources are cached and configuration unchanged.".to_string(),
-                            )
+                            .skip(&job.id, SkipReason::NoChanges)
                             .await?;
                         return Ok(());
                     }
@@ -281,157 +1458,32 @@ impl JobProcessor {
         }
 
         // Check for matching config fingerprint in other users (copy-on-match optimization)
-        if !job.force_rebuild {
-        if let Ok(Some(matched)) = self
-            .user_repo
-            .find_user_by_fingerprint(&config_fingerprint, &job.username)
-            .await
-        {
-            info!(
-                "Config matches user '{}' - copying output files instead of rebuilding",
-                matched.username
-            );
-
-            // Get source user's last job stats first (needed for domain counts and stats)
-            let source_stats = self
-                .job_repo
-                .get_last_completed_result(&matched.username)
+        if copy_allowed {
+            if let Ok(Some(matched)) = self
+                .user_repo
+                .find_user_by_fingerprint(&config_fingerprint, &job.username)
                 .await
-                .ok()
-                .flatten();
-
-            // Copy output files from matched user
-            match self.copy_output_files(&matched, &job.username).await {
-                Ok(mut output_files) => {
-                    // Populate domain counts from source_stats.output_files if available
-                    // This handles the case where matched.lists is empty (e.g., __default__)
-                    if let Some(ref src) = source_stats {
-                        for file in &mut output_files {
-                            if let Some(src_file) = src.output_files.iter().find(|f| f.name == file.name) {
-                                file.domain_count = src_file.domain_count;
-                            }
-                        }
-                    }
-
-                    let total_output_size: u64 = output_files.iter().map(|f| f.size_bytes).sum();
-                    let unique_domains = output_files
-                        .iter()
-                        .find(|f| f.name.starts_with("all_domains"))
-                        .map(|f| f.domain_count)
-                        .unwrap_or_else(|| {
-                            // Fallback to source stats or matched.total_domains
-                            source_stats
-                                .as_ref()
-                                .map(|s| s.unique_domains)
-                                .unwrap_or(matched.total_domains)
-                        });
-
-                    // Build result indicating this was a copy, with stats from source
-                    let result = if let Some(ref src) = source_stats {
-                        JobResult::copied_from_user(
-                            matched.username.clone(),
-                            src.total_domains,
-                            unique_domains,
-                            output_files.clone(),
-                            src.sources_processed,
-                            src.sources_failed,
-                            src.whitelisted_removed,
-                            src.categories.clone(),
-                        )
-                    } else {
-                        // Fallback if no source job found
-                        JobResult::copied_from_user(
-                            matched.username.clone(),
-                            matched.total_domains,
-                            unique_domains,
-                            output_files.clone(),
-                            0,
-                            0,
-                            0,
-                            std::collections::HashMap::new(),
-                        )
-                    };
-
-                    // Copy full progress from source job (includes whitelist breakdown, stage snapshots)
-                    let progress = if let Ok(Some(mut source_progress)) = self
-                        .job_repo
-                        .get_last_completed_progress(&matched.username)
-                        .await
-                    {
-                        // Ensure it shows as completed
-                        source_progress.current_step = "completed".to_string();
-                        source_progress.stage = JobStage::Completed;
-                        source_progress
-                    } else {
-                        // Fallback to minimal progress if source not found
-                        let sources_count = source_stats
-                            .as_ref()
-                            .map(|s| s.sources_processed + s.sources_failed)
-                            .unwrap_or(0);
-                        let mut p = JobProgress::default();
-                        p.current_step = "completed".to_string();
-                        p.stage = JobStage::Completed;
-                        p.total_sources = sources_count;
-                        p.processed_sources = sources_count;
-                        p
-                    };
-                    self.job_repo.update_progress(&job.id, &progress).await?;
-
-                    // Mark job as completed
-                    self.job_repo.complete(&job.id, result).await?;
-
-                    // Build list metadata from output files
-                    let now = BsonDateTime::from_millis(Utc::now().timestamp_millis());
-                    let mut all_lists: Vec<ListMetadata> = Vec::new();
-
-                    // If we have lists from matched user, use those
-                    if !matched.lists.is_empty() {
-                        for list in &matched.lists {
-                            all_lists.push(ListMetadata {
-                                name: list.name.clone(),
-                                is_public: true,
-                                formats: list.formats.clone(),
-                                domain_count: list.domain_count,
-                                last_updated: now,
-                            });
-                        }
-                    } else {
-                        // Build list metadata from output files
-                        let mut seen_names: HashSet<String> = HashSet::new();
-                        for file in &output_files {
-                            let base_name = file.name.trim_end_matches(".txt.gz");
-                            let name = base_name.rsplitn(2, '_').last().unwrap_or(base_name);
-                            if !seen_names.contains(name) {
-                                seen_names.insert(name.to_string());
-                                all_lists.push(ListMetadata {
-                                    name: name.to_string(),
-                                    is_public: true,
-                                    formats: vec!["hosts".to_string(), "plain".to_string(), "adblock".to_string()],
-                                    domain_count: file.domain_count,
-                                    last_updated: now,
-                                });
-                            }
-                        }
-                    }
-
-                    // Update user document
-                    if let Err(e) = self
-                        .user_repo
-                        .update_after_build(
-                            &job.username,
-                            all_lists,
-                            unique_domains,
-                            total_output_size,
-                            current_config_hash.clone(),
-                            config_fingerprint.clone(),
-                        )
-                        .await
-                    {
-                        warn!(
-                            "Failed to update user document for {}: {}",
-                            job.username, e
-                        );
-                    }
+            {
+                info!(
+                    "Config matches user '{}' - copying output files instead of rebuilding",
+                    matched.username
+                );
+
+                let copied = self
+                    .complete_from_match(
+                        job,
+                        &matched,
+                        sources.len() as u64,
+                        &formats,
+                        &current_config_hash,
+                        &config_fingerprint,
+                    )
+                    .await?;
+                if let Some((output_files, unique_domains)) = copied {
+                    let mut payload =
+                        WebhookPayload::completed(&job.job_id, &job.username, unique_domains, &output_files);
+                    payload.copied_from = Some(matched.username.clone());
+                    self.send_webhook(webhook_url.as_deref(), payload);
 
                     let duration = start_time.elapsed();
                     info!(
@@ -444,16 +1496,8 @@ impl JobProcessor {
 
                     return Ok(());
                 }
-                Err(e) => {
-                    // Copy failed, fall through to normal build
-                    warn!(
-                        "Failed to copy files from {}: {} - proceeding with normal build",
-                        matched.username, e
-                    );
-                }
             }
         }
-        }
 
         // Initialize progress tracking
         let progress = Arc::new(Mutex::new(JobProgress::downloading(sources.len() as u64)));
@@ -464,7 +1508,7 @@ impl JobProcessor {
             p.sources = sources
                 .iter()
                 .map(|s| SourceProgress {
-                    id: Downloader::hash_url(&s.url),
+                    id: Downloader::cache_key(s),
                     name: s.name.clone(),
                     url: s.url.clone(),
                     status: SourceStatus::Pending,
@@ -475,6 +1519,8 @@ impl JobProcessor {
                     download_time_ms: None,
                     domain_count: None,
                     domain_change: None,
+                    domain_history: Vec::new(),
+                    unique_domain_count: None,
                     format_breakdown: None,
                     detected_formats: Vec::new(),
                     error: None,
@@ -483,35 +1529,64 @@ impl JobProcessor {
                     completed_at: None,
                 })
                 .collect();
+
+            // Reclaimed after a worker crash: keep what the last attempt finished
+            let resumed = p.resume_from(&job.progress);
+            if resumed > 0 {
+                info!(
+                    "Resuming job {} with {} of {} sources already downloaded",
+                    job.job_id,
+                    resumed,
+                    p.sources.len()
+                );
+            }
         }
 
         // Update progress in DB
         self.update_progress(&job.id, &progress).await?;
 
         // Stage 1: Download sources
-        let download_results = self
+        let mut download_results = self
             .download_stage(&job.id, sources, job.force_rebuild, Arc::clone(&progress))
             .await?;
 
+        // Shutting down: leave the job claimed so the worker releases it back to the queue
+        if self.cancel.is_cancelled() {
+            info!(
+                "Job {} interrupted by shutdown after {} of {} downloads",
+                job.job_id,
+                download_results.len(),
+                progress.lock().await.sources.len()
+            );
+            return Ok(());
+        }
+
         // Check for complete failure
         let successful_downloads: Vec<&DownloadResult> = download_results
             .iter()
             .filter(|r| r.error.is_none())
             .collect();
 
-        if successful_downloads.is_empty() {
+        if successful_downloads.is_empty() && !empty_config {
+            // A cache outage fails every source; say so rather than blaming the sources
+            let cache_down = !download_results.is_empty()
+                && download_results
+                    .iter()
+                    .all(|r| r.error_kind == Some(ErrorKind::CacheUnavailable));
+            let message = if cache_down {
+                "All source downloads failed: cache unavailable"
+            } else {
+                "All source downloads failed"
+            };
             self.job_repo
-                .fail(
-                    &job.id,
-                    vec!["All source downloads failed".to_string()],
-                )
+                .fail(&job.id, vec![message.to_string()])
                 .await?;
             return Ok(());
         }
 
         // Stage 2: Extract domains (organized by category)
         let category_domains = self
-            .extraction_stage(&job.id, &download_results, Arc::clone(&progress))
+            .extraction_stage(&job.id, &mut download_results, Arc::clone(&progress))
             .await?;
 
         info!(
@@ -520,7 +1595,7 @@ impl JobProcessor {
             category_domains.category_count()
         );
 
-        if category_domains.is_empty() {
+        if category_domains.is_empty() && !empty_config {
             self.job_repo
                 .fail(&job.id, vec!["No domains extracted".to_string()])
                 .await?;
@@ -529,7 +1604,13 @@ impl JobProcessor {
 
         // Stage 3: Whitelist filtering
         let (filtered_domains, whitelist_removed, _whitelist_progress) = self
-            .whitelist_stage(&job.id, &job.username, category_domains, Arc::clone(&progress))
+            .whitelist_stage(
+                &job.id,
+                &whitelist_content,
+                category_domains,
+                &forced_blocks,
+                Arc::clone(&progress),
+            )
             .await?;
 
         info!(
@@ -538,9 +1619,23 @@ impl JobProcessor {
             whitelist_removed
         );
 
+        let filtered_domains = Self::apply_operator_layer(filtered_domains, &operator);
+
+        // Refuse over-limit builds before spending CPU on generation
+        if self.config.max_total_domains > 0 {
+            if let Err(e) = Self::check_total_domains(filtered_domains.total_count(), self.config.max_total_domains) {
+                self.job_repo.fail(&job.id, vec![e.to_string()]).await?;
+                return Ok(());
+            }
+        }
+
+        let (combined_unique_domains, all_categories_unique_domains) =
+            Self::unique_domain_counts(&filtered_domains, &self.config.optin_categories);
+        let category_counts = Self::category_counts(&filtered_domains);
+
         // Stage 4: Generate output files (per-category + combined)
         let output_files = self
-            .generation_stage(&job.id, &job.username, filtered_domains, Arc::clone(&progress))
+            .generation_stage(&job.id, self.job_output_dir(job), filtered_domains, &formats, Arc::clone(&progress))
             .await?;
 
         // Calculate final stats
@@ -553,7 +1648,7 @@ impl JobProcessor {
         // Get unique domains from the combined "all_domains" file
         let unique_domains = output_files
             .iter()
-            .find(|f| f.name.starts_with("all_domains"))
+            .find(|f| self.filename_template.is_combined(&f.name))
             .map(|f| f.domain_count)
             .unwrap_or(0);
 
@@ -561,7 +1656,7 @@ impl JobProcessor {
         let total_output_size: u64 = output_files.iter().map(|f| f.size_bytes).sum();
 
         // Build result
-        let result = JobResult::success(
+        let mut result = JobResult::success(
             sources_processed,
             sources_failed,
             total_domains,
@@ -569,56 +1664,30 @@ impl JobProcessor {
             whitelist_removed,
             output_files.clone(),
         );
+        result.combined_unique_domains = combined_unique_domains;
+        result.all_categories_unique_domains = all_categories_unique_domains;
+        // Same per-category breakdown a copy-on-match build takes from its source job
+        result.categories = category_counts;
+        result.config_warnings = config_warnings;
 
         // Mark job as completed
         self.job_repo.complete(&job.id, result).await?;
 
+        if test_build {
+            info!(
+                "Test build {} completed in {:.2}s - {} domains",
+                job.job_id,
+                start_time.elapsed().as_secs_f64(),
+                unique_domains
+            );
+            return Ok(());
+        }
+
         // Update user document with lists and stats
         // Build list metadata for all categories + all_domains
         let now = BsonDateTime::from_millis(Utc::now().timestamp_millis());
-        let mut all_lists: Vec<ListMetadata> = Vec::new();
-
-        // Group output files by category to build ListMetadata for each
-        // Files are named: {category}_{format}.txt.gz (e.g., advertising_hosts.txt.gz)
-        // or all_domains_{format}.txt.gz for the combined list
-        let mut category_domain_counts: HashMap<String, u64> = HashMap::new();
-        for file in &output_files {
-            // Skip all_domains files - handled separately below
-            if file.name.starts_with("all_domains") {
-                continue;
-            }
-
-            // Extract category name: e.g., "advertising_hosts.txt.gz" -> "advertising"
-            // or "uncategorized_hosts.txt.gz" -> "uncategorized"
-            if let Some(category) = file.name.split('_').next() {
-                // Only count hosts format files to get domain count (avoid triple-counting)
-                if file.format == "hosts" {
-                    category_domain_counts.insert(category.to_string(), file.domain_count);
-                }
-            }
-        }
-
-        // Build ListMetadata for each category
-        for (category, domain_count) in &category_domain_counts {
-            let list = ListMetadata {
-                name: category.clone(),
-                is_public: true,  // All lists are always public
-                formats: vec!["hosts".to_string(), "plain".to_string(), "adblock".to_string()],
-                domain_count: *domain_count,
-                last_updated: now,
-            };
-            all_lists.push(list);
-        }
-
-        // Add all_domains (combined list)
-        let all_domains_list = ListMetadata {
-            name: "all_domains".to_string(),
-            is_public: true,  // All lists are always public
-            formats: vec!["hosts".to_string(), "plain".to_string(), "adblock".to_string()],
-            domain_count: unique_domains,
-            last_updated: now,
-        };
-        all_lists.push(all_domains_list);
+        let all_lists =
+            Self::build_list_metadata(&output_files, unique_domains, &formats, &self.filename_template, now);
 
         info!(
             "Saving {} lists for user {}: {:?}",
@@ -627,19 +1696,28 @@ impl JobProcessor {
             all_lists.iter().map(|l| &l.name).collect::<Vec<_>>()
         );
 
-        // Update user document
-        if let Err(e) = self.user_repo.update_after_build(
-            &job.username,
-            all_lists,
-            unique_domains,
-            total_output_size,
-            current_config_hash,
-            config_fingerprint,
-        ).await {
+        // Update user document (retried on transient connection errors)
+        if let Err(e) = with_retry("user document update", || {
+            self.user_repo.update_after_build(
+                &job.username,
+                all_lists.clone(),
+                unique_domains,
+                total_output_size,
+                current_config_hash.clone(),
+                config_fingerprint.clone(),
+            )
+        })
+        .await
+        {
             warn!("Failed to update user document for {}: {}", job.username, e);
             // Don't fail the job for this - it's not critical
         }
 
+        self.send_webhook(
+            webhook_url.as_deref(),
+            WebhookPayload::completed(&job.job_id, &job.username, unique_domains, &output_files),
+        );
+
         let duration = start_time.elapsed();
         info!(
             "Job {} completed in {:.2}s - {} domains",
@@ -659,14 +1737,44 @@ impl JobProcessor {
         force: bool,
         progress: Arc<Mutex<JobProgress>>,
     ) -> Result<Vec<DownloadResult>> {
-        // Download sources - the callback just logs progress, we'll update DB after
-        let results = self
+        // Download sources; live byte counts come back over a channel and are
+        // written at most once per progress interval across all downloads
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel::<(usize, SourceProgress)>();
+        let downloads = self
             .downloader
-            .download_sources(sources, force, |_idx, _source_progress| {
-                // Progress updates are handled after all downloads complete
-                // to avoid frequent DB writes during parallel downloads
-            })
-            .await;
+            .download_sources(sources, force, &self.cancel, move |idx, source_progress| {
+                let _ = tx.send((idx, source_progress.clone()));
+            });
+        tokio::pin!(downloads);
+
+        let mut throttle = ProgressThrottle::new(Duration::from_millis(self.config.progress_update_interval_ms));
+        let results = loop {
+            tokio::select! {
+                results = &mut downloads => break results,
+                Some((idx, live)) = rx.recv() => {
+                    {
+                        let mut p = progress.lock().await;
+                        if let Some(source) = p.sources.get_mut(idx) {
+                            Self::apply_live_progress(source, &live);
+                        }
+                        // Checkpoint for a worker that reclaims this job after a crash
+                        p.processed_sources = p
+                            .sources
+                            .iter()
+                            .filter(|s| matches!(s.status, SourceStatus::Completed | SourceStatus::Failed))
+                            .count() as u64;
+                    }
+                    if throttle.ready(Instant::now()) {
+                        self.update_progress(job_id, &progress).await?;
+                    }
+                }
+            }
+        };
+
+        // Partial results after a cancel don't line up with the progress entries
+        if self.cancel.is_cancelled() {
+            return Ok(results);
+        }
 
         // Final progress update
         {
@@ -688,114 +1796,320 @@ impl JobProcessor {
             p.processed_sources = p.sources.len() as u64;
         }
 
-        self.update_progress(&job_id, &progress).await?;
+        self.update_progress(job_id, &progress).await?;
 
         Ok(results)
     }
 
+    /// Copy a download's live state onto the job's entry for that source
+    ///
+    /// A finished download replaces the entry outright, so completed sources
+    /// are checkpointed as they land; while it runs only the byte counts move.
+    fn apply_live_progress(source: &mut SourceProgress, live: &SourceProgress) {
+        if matches!(live.status, SourceStatus::Completed | SourceStatus::Failed) {
+            *source = live.clone();
+            return;
+        }
+        if live.status != SourceStatus::Downloading {
+            return;
+        }
+        source.status = SourceStatus::Downloading;
+        source.bytes_downloaded = live.bytes_downloaded;
+        source.bytes_total = live.bytes_total;
+        source.download_percent = live.download_percent;
+        if source.started_at.is_none() {
+            source.started_at = live.started_at.clone();
+        }
+    }
+
+    /// Extract one source's domains, one entry per section (None = the source's own category)
+    ///
+    /// `streamed` is the output already extracted while downloading, if any.
+    /// Only JSON-array sources can fail (the body isn't the expected JSON).
+    fn extract_sections(
+        &self,
+        source: &Source,
+        content: &[u8],
+        streamed: Option<ExtractionOutput>,
+    ) -> Result<Sections> {
+        let mut sections: Sections = if let Some(output) = streamed {
+            vec![(None, output)]
+        } else if let Some(selector) = &source.json_selector {
+            let content = decode_source(content, self.config.transcode_sources);
+            vec![(None, self.extractor.extract_from_json(content.as_bytes(), selector)?)]
+        } else if source.pac {
+            let content = decode_source(content, self.config.transcode_sources);
+            vec![(None, self.extractor.extract_from_pac(&content))]
+        } else {
+            // Convert bytes to string for extraction (BOM dropped, optionally transcoded)
+            let content_str = decode_source(content, self.config.transcode_sources);
+            match &self.section_header {
+                Some(header) => self.extractor.extract_sections(&content_str, header),
+                None => vec![(None, self.extractor.extract_from_content_with_breakdown(&content_str))],
+            }
+        };
+        if let Some(filter) = &self.reserved_filter {
+            let dropped: usize = sections.iter_mut().map(|(_, output)| filter.retain(output)).sum();
+            if dropped > 0 {
+                debug!("Dropped {} reserved-TLD domains from {}", dropped, source.name);
+            }
+        }
+        Ok(sections)
+    }
+
+    /// Extract the given downloaded sources, in parallel across sources
+    ///
+    /// Each source's lines are already split over the rayon pool; running the
+    /// sources side by side keeps the cores busy between many small ones.
+    /// Every source's sections are held until the call returns, so callers
+    /// pass a bounded batch. Results keep the order of `download_results`,
+    /// with None for sources that failed or have no content. Streamed
+    /// extractions are taken out.
+    fn extract_all(&self, download_results: &mut [DownloadResult]) -> Vec<Option<Result<Sections>>> {
+        // Extracted while downloading (STREAMING_EXTRACTION)
+        let streamed: Vec<Option<ExtractionOutput>> = download_results.iter_mut().map(|r| r.extracted.take()).collect();
+
+        download_results
+            .par_iter()
+            .zip(streamed)
+            .map(|(result, streamed)| {
+                if result.error.is_some() || (streamed.is_none() && result.content.is_none()) {
+                    return None;
+                }
+                let content = result.content.as_deref().unwrap_or_default();
+                Some(self.extract_sections(&result.source, content, streamed))
+            })
+            .collect()
+    }
+
+    /// Add one source's sections to the category buckets and overlap counts
+    ///
+    /// A section header overrides the source category; uncategorized sources
+    /// use the configured default. Called in source order, so the buckets and
+    /// adblock rules come out the same however the extraction was scheduled.
+    fn add_sections(
+        &self,
+        category_domains: &mut CategoryDomains,
+        overlap: &mut SourceOverlap,
+        source_idx: usize,
+        source: &Source,
+        sections: Sections,
+    ) {
+        let category = Self::resolve_category(source.category.as_deref(), self.config.default_category.as_deref());
+
+        for (section, output) in sections {
+            // Only domains with no category of their own are auto-categorized
+            let classifier = self
+                .category_map
+                .as_ref()
+                .filter(|_| section.is_none() && source.category.is_none());
+            let section_category = section.or_else(|| category.clone());
+
+            for extraction_result in &output.results {
+                overlap.record(source_idx, &extraction_result.domain);
+            }
+            category_domains.attribute(&source.name, &output.results);
+            let new_in_category = category_domains.add(section_category.clone(), output.results, classifier);
+            debug!(
+                "{} added {} new domains to category {:?}",
+                source.name, new_in_category, section_category
+            );
+        }
+    }
+
+    /// Describe a drastic change since the source's last extraction, if any
+    ///
+    /// Feeds switch formats harmlessly and counts move, so only both at once
+    /// counts: the primary format changed (or nothing was recognized) and over
+    /// `FORMAT_SHIFT_MIN_DROP` of the domains are gone.
+    fn format_shift(previous: &ExtractionSummary, domain_count: u64, breakdown: &FormatBreakdown) -> Option<String> {
+        let before = previous.format_breakdown.primary_format()?;
+        let after = breakdown.primary_format();
+        if after == Some(before) || domain_count as f64 >= previous.domain_count as f64 * (1.0 - FORMAT_SHIFT_MIN_DROP) {
+            return None;
+        }
+        Some(format!(
+            "Format changed from {} ({} domains) to {} ({} domains) since the last build; the feed may be broken",
+            before,
+            previous.domain_count,
+            after.unwrap_or("nothing recognizable"),
+            domain_count
+        ))
+    }
+
     /// Extraction stage: extract domains and organize by category
     async fn extraction_stage(
         &self,
-        _job_id: &bson::oid::ObjectId,
-        download_results: &[DownloadResult],
+        job_id: &bson::oid::ObjectId,
+        download_results: &mut [DownloadResult],
         progress: Arc<Mutex<JobProgress>>,
     ) -> Result<CategoryDomains> {
         let mut category_domains = CategoryDomains::new();
+        if self.config.emit_source_attribution {
+            category_domains = category_domains.with_source_attribution();
+        }
+        let mut overlap = SourceOverlap::new();
+        let mut throttle = ProgressThrottle::new(Duration::from_millis(self.config.progress_update_interval_ms));
 
-        for result in download_results {
-            if result.error.is_some() {
-                continue;
-            }
+        // Extract a batch of sources side by side, then merge them in source
+        // order; batches keep only a few sources' domains in memory at once
+        let batch_size = rayon::current_num_threads().max(1);
+        for batch_start in (0..download_results.len()).step_by(batch_size) {
+            let batch = batch_start..(batch_start + batch_size).min(download_results.len());
 
-            let content = match &result.content {
-                Some(bytes) => bytes,
-                None => {
-                    warn!("No content for {}", result.source.name);
-                    continue;
+            // Show the batch's sources as processing while their content is extracted
+            {
+                let mut p = progress.lock().await;
+                let extractable = |r: &&DownloadResult| r.error.is_none() && (r.content.is_some() || r.extracted.is_some());
+                for result in download_results[batch.clone()].iter().filter(extractable) {
+                    p.set_source_status(result.source_id(), SourceStatus::Processing);
                 }
-            };
+            }
+            self.update_progress(job_id, &progress).await?;
 
-            // Convert bytes to string for extraction
-            let content_str = match String::from_utf8_lossy(content) {
-                std::borrow::Cow::Borrowed(s) => s.to_string(),
-                std::borrow::Cow::Owned(s) => s,
-            };
+            let extracted = self.extract_all(&mut download_results[batch.clone()]);
 
-            // Extract domains from content with format breakdown
-            let extraction_output = self.extractor.extract_from_content_with_breakdown(&content_str);
-            let extraction_results = extraction_output.results;
-            let format_breakdown = extraction_output.format_breakdown;
+            for (offset, (result, sections)) in download_results[batch.clone()].iter().zip(extracted).enumerate() {
+                let source_idx = batch.start + offset;
+                let Some(sections) = sections else {
+                    if result.error.is_none() {
+                        warn!("No content for {}", result.source.name);
+                    }
+                    continue;
+                };
 
-            // domain_count = total domains from this source
-            let source_domain_count = extraction_results.len() as u64;
+                let sections = match sections {
+                    Ok(sections) => sections,
+                    Err(e) => {
+                        // JSON-array source: a parse failure is reported as a source error
+                        warn!("Failed to extract from {}: {:#}", result.source.name, e);
+                        let mut p = progress.lock().await;
+                        p.set_source_status(result.source_id(), SourceStatus::Failed);
+                        if let Some(source) = p.sources.iter_mut().find(|s| s.id == result.source_id()) {
+                            source.error = Some(format!("{:#}", e));
+                        }
+                        continue;
+                    }
+                };
 
-            // Calculate domain_change = current - previous
-            let domain_change = result.previous_domain_count
-                .map(|prev| source_domain_count as i64 - prev as i64);
+                let mut format_breakdown = FormatBreakdown::default();
+                for (_, output) in &sections {
+                    format_breakdown.merge(&output.format_breakdown);
+                }
 
-            // Get detected format names
-            let detected_formats = format_breakdown.detected_formats();
+                // domain_count = total domains from this source
+                let source_domain_count = sections.iter().map(|(_, o)| o.results.len() as u64).sum::<u64>();
 
-            // Get category from source
-            let category = result.source.category.clone();
+                // Calculate domain_change = current - previous
+                let domain_change = result.previous_domain_count
+                    .map(|prev| source_domain_count as i64 - prev as i64);
 
-            // Add domains to category bucket and store raw adblock rules
-            let category_set = category_domains.by_category
-                .entry(category.clone())
-                .or_insert_with(HashSet::new);
-            let count_before = category_set.len();
+                // Get detected format names
+                let detected_formats = format_breakdown.detected_formats();
 
-            for extraction_result in extraction_results {
-                category_set.insert(extraction_result.domain.clone());
-                // Store raw adblock rule if present (for adblock output passthrough)
-                if let Some(raw_rule) = extraction_result.raw_adblock_rule {
-                    category_domains.adblock_rules.insert(extraction_result.domain, raw_rule);
+                // A feed that switched format and lost most of its domains likely broke
+                // (a cache hit is the content the last run already checked)
+                if self.config.format_shift_policy != FormatShiftPolicy::Off && !result.cache_hit {
+                    let previous = match self.downloader.last_extraction(&result.url_hash).await {
+                        Ok(previous) => previous,
+                        Err(e) => {
+                            warn!("Failed to read last extraction of {}: {}", result.source.name, e);
+                            None
+                        }
+                    };
+                    if let Some(shift) = previous.and_then(|prev| Self::format_shift(&prev, source_domain_count, &format_breakdown)) {
+                        warn!("{}: {}", result.source.name, shift);
+                        let fail = self.config.format_shift_policy == FormatShiftPolicy::Fail;
+                        let mut p = progress.lock().await;
+                        if let Some(source) = p.sources.iter_mut().find(|s| s.id == result.source_id()) {
+                            if fail {
+                                source.error = Some(shift);
+                            } else {
+                                source.warnings.push(shift);
+                            }
+                        }
+                        if fail {
+                            p.set_source_status(result.source_id(), SourceStatus::Failed);
+                            continue;
+                        }
+                    }
                 }
-            }
 
-            let new_in_category = category_set.len() - count_before;
-
-            debug!(
-                "Extracted {} domains from {} [category: {:?}] ({} new in category, change: {:?}, formats: {:?})",
-                source_domain_count,
-                result.source.name,
-                category,
-                new_in_category,
-                domain_change,
-                detected_formats
-            );
+                debug!(
+                    "Extracted {} domains from {} (change: {:?}, formats: {:?})",
+                    source_domain_count,
+                    result.source.name,
+                    domain_change,
+                    detected_formats
+                );
+                self.add_sections(&mut category_domains, &mut overlap, source_idx, &result.source, sections);
+
+                // Update source progress with domain_count, domain_change, and format info
+                {
+                    let mut p = progress.lock().await;
+                    if let Some(source) = p.sources.iter_mut().find(|s| s.id == result.source_id()) {
+                        source.domain_count = Some(source_domain_count);
+                        source.domain_change = domain_change;
+                        source.format_breakdown = Some(format_breakdown.clone());
+                        source.detected_formats = detected_formats;
+                    }
+                    p.set_source_status(result.source_id(), SourceStatus::Completed);
+                }
+                if throttle.ready(Instant::now()) {
+                    self.update_progress(job_id, &progress).await?;
+                }
 
-            // Update source progress with domain_count, domain_change, and format info
-            {
-                let mut p = progress.lock().await;
-                if let Some(source) = p.sources.iter_mut().find(|s| s.id == result.url_hash) {
-                    source.domain_count = Some(source_domain_count);
-                    source.domain_change = domain_change;
-                    source.format_breakdown = Some(format_breakdown);
-                    source.detected_formats = detected_formats;
+                // Save domain_count to cache for next run, then show the
+                // source's trend including this build
+                if let Err(e) = self
+                    .downloader
+                    .update_domain_count(&result.url_hash, source_domain_count, &format_breakdown)
+                    .await
+                {
+                    warn!("Failed to update domain count in cache for {}: {}", result.source.name, e);
+                } else {
+                    match self.downloader.domain_history(&result.url_hash).await {
+                        Ok(history) => {
+                            let mut p = progress.lock().await;
+                            if let Some(source) = p.sources.iter_mut().find(|s| s.id == result.source_id()) {
+                                source.domain_history = history;
+                            }
+                        }
+                        Err(e) => warn!("Failed to read domain history of {}: {}", result.source.name, e),
+                    }
                 }
             }
+        }
 
-            // Save domain_count to cache for next run
-            if let Err(e) = self.downloader.update_domain_count(&result.url_hash, source_domain_count).await {
-                warn!("Failed to update domain count in cache for {}: {}", result.source.name, e);
+        // Domains no other source contributed ("why did this source only add 12?")
+        let unique_counts = overlap.unique_counts(download_results.len());
+        {
+            let mut p = progress.lock().await;
+            for (result, unique) in download_results.iter().zip(unique_counts) {
+                if let Some(source) = p.sources.iter_mut().find(|s| s.id == result.source_id()) {
+                    if source.domain_count.is_some() {
+                        source.unique_domain_count = Some(unique);
+                    }
+                }
             }
         }
+        self.update_progress(job_id, &progress).await?;
 
         Ok(category_domains)
     }
 
     /// Whitelist stage: filter out whitelisted domains from all categories
+    ///
+    /// Domains in `forced_blocks` are kept regardless of whitelist matches.
     async fn whitelist_stage(
         &self,
         job_id: &bson::oid::ObjectId,
-        username: &str,
+        whitelist_content: &str,
         category_domains: CategoryDomains,
+        forced_blocks: &HashSet<String>,
         progress: Arc<Mutex<JobProgress>>,
     ) -> Result<(CategoryDomains, u64, crate::db::progress::WhitelistProgress)> {
-        // Get all unique domains for global stats
-        let all_domains = category_domains.all_unique();
-        let domains_before = all_domains.len() as u64;
+        let domains_before = category_domains.total_count() as u64;
 
         // Capture downloading stage snapshot before transitioning
         {
@@ -818,34 +2132,18 @@ impl JobProcessor {
         }
         self.update_progress(job_id, &progress).await?;
 
-        // Load whitelist from MongoDB
-        let whitelist_content = self.user_config_repo.get_whitelist(username).await?;
-        let whitelist = WhitelistManager::from_content(&whitelist_content);
-
-        // Filter ALL domains to get whitelist stats (pattern matches, etc.)
-        let (_, total_removed, pattern_matches) = whitelist.filter_domains(all_domains);
-
-        // Filter each category separately
-        let mut filtered = CategoryDomains::new();
-        for (category, domains) in category_domains.by_category {
-            let (remaining, _, _) = whitelist.filter_domains(domains);
-            if !remaining.is_empty() {
-                filtered.by_category.insert(category, remaining);
-            }
-        }
+        let whitelist = WhitelistManager::from_content(whitelist_content).with_sample_count(self.config.whitelist_sample_count);
 
-        // Copy over adblock_rules for domains that remain after whitelist filtering
-        let remaining_domains = filtered.all_unique();
-        for (domain, rule) in category_domains.adblock_rules {
-            if remaining_domains.contains(&domain) {
-                filtered.adblock_rules.insert(domain, rule);
-            }
-        }
+        let (filtered, pattern_matches, category_removed) =
+            Self::apply_whitelist(&whitelist, category_domains, forced_blocks);
 
         let domains_after = filtered.total_count() as u64;
+        // A domain kept by an exempt category or forced block isn't removed from the combined set
+        let total_removed = domains_before.saturating_sub(domains_after);
 
         // Create whitelist progress
-        let whitelist_progress = whitelist.create_progress(domains_before, domains_after, pattern_matches);
+        let whitelist_progress =
+            whitelist.create_progress(domains_before, domains_after, pattern_matches, category_removed);
 
         // Update progress
         {
@@ -861,8 +2159,9 @@ impl JobProcessor {
     async fn generation_stage(
         &self,
         job_id: &bson::oid::ObjectId,
-        username: &str,
+        output_dir: PathBuf,
         category_domains: CategoryDomains,
+        formats: &[OutputFormat],
         progress: Arc<Mutex<JobProgress>>,
     ) -> Result<Vec<OutputFile>> {
         let total_domains = category_domains.total_count() as u64;
@@ -882,55 +2181,83 @@ impl JobProcessor {
         // Update progress to generation stage
         {
             let mut p = progress.lock().await;
-            p.to_generation(total_domains);
+            let format_names: Vec<&str> = formats.iter().map(|f| f.as_str()).collect();
+            p.to_generation(total_domains, &format_names);
         }
         self.update_progress(job_id, &progress).await?;
 
         // Create output generator
-        let output_dir = self.config.output_dir(username);
-        let generator = OutputGenerator::new(output_dir);
-
-        // Clean up old files
-        generator.cleanup_old_files()?;
-
-        // Extract adblock_rules before consuming category_domains
+        let mut generator = OutputGenerator::new(&output_dir);
+        if self.config.content_addressed_output {
+            generator = generator.with_output_sink(Arc::new(ContentAddressedSink::new(
+                output_dir,
+                self.config.output_store_dir(),
+            )));
+        }
+        // A build timestamp would make every rebuild a new blob in the shared store
+        let mut generator = generator
+            .with_filename_template(self.filename_template.clone())
+            .with_generated_line(!self.config.content_addressed_output || self.config.source_date_epoch.is_some());
+        if let Some(epoch) = self.config.source_date_epoch {
+            match chrono::DateTime::from_timestamp(epoch, 0) {
+                Some(generated_at) => generator = generator.with_generated_at(generated_at),
+                None => warn!("SOURCE_DATE_EPOCH {} is out of range, using the build time", epoch),
+            }
+        }
+        let generator = generator
+            .with_zstd(self.config.emit_zstd)
+            .with_uncompressed(self.config.emit_uncompressed)
+            .with_collapse_covered_subdomains(self.config.collapse_covered_subdomains)
+            .with_max_concurrent_writes(self.config.max_concurrent_file_writes)
+            .with_formats(formats.to_vec());
+
+        // Extract adblock_rules (and source attribution) before consuming category_domains
         let adblock_rules = category_domains.adblock_rules;
+        let generator = generator.with_source_attribution(category_domains.source_attribution);
+
+        // Sort order: alphabetical, or grouped by suffix for better compression
+        let sort_domains = if self.config.compression_optimized_sort {
+            DomainExtractor::sort_domains_by_suffix
+        } else {
+            DomainExtractor::sort_domains
+        };
 
         // Convert HashSets to sorted Vecs per category
         let sorted_by_category: HashMap<Option<String>, Vec<String>> = category_domains
             .by_category
             .into_iter()
-            .map(|(cat, domains)| (cat, DomainExtractor::sort_domains(domains)))
+            .map(|(cat, domains)| (cat, sort_domains(domains)))
             .collect();
 
-        // Generate all category files in parallel (with adblock passthrough)
-        let mut output_files = generator.generate_all_categories(&sorted_by_category, &adblock_rules)?;
-
-        // Create combined "all domains" list (deduplicated across categories)
-        // Note: nsfw category is excluded from the combined list
-        let all_domains: HashSet<String> = sorted_by_category
-            .iter()
-            .filter(|(cat, _)| {
-                // Exclude nsfw category from all_domains
-                !matches!(cat, Some(c) if c == "nsfw")
-            })
-            .flat_map(|(_, domains)| domains.iter().cloned())
-            .collect();
-        let all_sorted = DomainExtractor::sort_domains(all_domains);
-
-        // Generate combined files (all_domains_*.txt.gz) for backward compatibility
-        let progress_clone = Arc::clone(&progress);
-        let combined_files = generator.generate_all(&all_sorted, &adblock_rules, |gen_progress| {
-            let progress = Arc::clone(&progress_clone);
-            let gen_progress = gen_progress.clone();
-            tokio::task::block_in_place(|| {
-                tokio::runtime::Handle::current().block_on(async {
-                    let mut p = progress.lock().await;
-                    p.generation = Some(gen_progress);
-                });
-            });
-        })?;
-        output_files.extend(combined_files);
+        // Domains in categories with a sink IP are written as rewrites
+        let generator = generator.with_sinks(OutputGenerator::domain_sinks(
+            &sorted_by_category,
+            &self.config.category_sinks,
+        ));
+
+        // Generate category and combined files (with adblock passthrough); the
+        // combined progress callback may run on a rayon thread
+        let progress_writer = GenerationProgressWriter::new(Arc::clone(&progress));
+        let output_files = Self::generate_outputs(
+            &generator,
+            &sorted_by_category,
+            &adblock_rules,
+            &self.config.optin_categories,
+            sort_domains,
+            self.config.concurrent_combined_generation,
+            |gen_progress| {
+                progress_writer.update(gen_progress);
+            },
+        )?;
+        let attribution_files = generator.generate_source_attribution(&sorted_by_category)?;
+
+        // Remove old files only now that every new file is in place
+        generator.cleanup_old_files(&[output_files.as_slice(), attribution_files.as_slice()].concat())?;
+
+        // Optionally read every file back and check its recorded domain count
+        if self.config.verify_output {
+            generator.verify_output_files(&output_files)?;
+        }
 
         // Capture generation stage snapshot before completing
         {
@@ -961,7 +2288,953 @@ impl JobProcessor {
         progress: &Arc<Mutex<JobProgress>>,
     ) -> Result<()> {
         let p = progress.lock().await;
-        self.job_repo.update_progress(job_id, &*p).await?;
+        self.job_repo.update_progress(job_id, &p).await?;
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    /// Processor on a MongoDB client that never connects: any database read fails
+    async fn offline_processor() -> JobProcessor {
+        let client = mongodb::Client::with_uri_str("mongodb://127.0.0.1:1/?serverSelectionTimeoutMS=200")
+            .await
+            .unwrap();
+        let db = client.database("test");
+        JobProcessor::new(Config::from_env(), JobRepository::new(&db, "test".to_string()), &db).unwrap()
+    }
+
+    #[test]
+    fn test_only_hosts_format_generated() {
+        let formats = JobProcessor::resolve_formats(Some(vec!["hosts".to_string()]));
+        assert_eq!(formats, vec![OutputFormat::Hosts]);
+
+        let temp_dir = TempDir::new().unwrap();
+        let generator = OutputGenerator::new(temp_dir.path()).with_formats(formats.clone());
+
+        let mut by_category = HashMap::new();
+        by_category.insert(Some("ads".to_string()), vec!["ads.example.com".to_string()]);
+        let adblock_rules = HashMap::new();
+
+        let mut output_files = generator.generate_all_categories(&by_category, &adblock_rules).unwrap();
+        output_files.extend(
+            generator
+                .generate_all(&["ads.example.com".to_string()], &adblock_rules, |_| {})
+                .unwrap(),
+        );
+
+        let mut names: Vec<&str> = output_files.iter().map(|f| f.name.as_str()).collect();
+        names.sort();
+        assert_eq!(names, vec!["ads_hosts.txt.gz", "all_domains_hosts.txt.gz"]);
+
+        let now = BsonDateTime::from_millis(0);
+        let lists = JobProcessor::build_list_metadata(&output_files, 1, &formats, &FilenameTemplate::default(), now);
+        assert_eq!(lists.len(), 2);
+        for list in &lists {
+            assert_eq!(list.formats, vec!["hosts".to_string()]);
+            assert_eq!(list.domain_count, 1);
+        }
+    }
+
+    #[test]
+    fn test_forced_block_survives_whitelist() {
+        let whitelist = WhitelistManager::from_content("*.example.com");
+        let forced = JobProcessor::parse_forced_blocks("# keep this one\nAds.Example.com.\n");
+
+        let mut category_domains = CategoryDomains::new();
+        category_domains.by_category.insert(
+            Some("ads".to_string()),
+            ["ads.example.com", "cdn.example.com", "tracker.net"]
+                .iter()
+                .map(|d| d.to_string())
+                .collect(),
+        );
+
+        let (filtered, _, _) = JobProcessor::apply_whitelist(&whitelist, category_domains, &forced);
+        let ads = &filtered.by_category[&Some("ads".to_string())];
+
+        assert!(ads.contains("ads.example.com"));
+        assert!(!ads.contains("cdn.example.com"));
+        assert!(ads.contains("tracker.net"));
+    }
+
+    #[test]
+    fn test_operator_blocks_added_without_user_sources() {
+        let operator = OperatorLayer::from_lists(&OperatorLists {
+            blocklist: Some("# known bad infra\nC2.Evil.net\nads.example.com\n".to_string()),
+            allowlist: Some("allowed.example.com\n".to_string()),
+        });
+
+        let mut category_domains = CategoryDomains::new();
+        category_domains.by_category.insert(
+            Some("ads".to_string()),
+            ["ads.example.com", "allowed.example.com"].iter().map(|d| d.to_string()).collect(),
+        );
+        category_domains.by_category.insert(None, HashSet::from(["allowed.example.com".to_string()]));
+        category_domains
+            .adblock_rules
+            .insert("allowed.example.com".to_string(), "||allowed.example.com^".to_string());
+
+        let result = JobProcessor::apply_operator_layer(category_domains, &operator);
+
+        let operator_domains = &result.by_category[&Some(OPERATOR_CATEGORY.to_string())];
+        assert!(operator_domains.contains("c2.evil.net"));
+        assert!(result.all_unique().contains("c2.evil.net"));
+
+        // Allows win everywhere, and emptied categories are dropped
+        assert!(!result.all_unique().contains("allowed.example.com"));
+        assert!(!result.by_category.contains_key(&None));
+        assert!(result.adblock_rules.is_empty());
+
+        // The operator layer changes the fingerprint, so copy-on-match can't reuse stale output
+        let formats = OutputFormat::all();
+        let schemes = vec!["https".to_string()];
+        let without = JobProcessor::compute_config_fingerprint(
+            "https://a.com/l.txt",
+            "",
+            &HashSet::new(),
+            &formats,
+            "",
+            &schemes,
+        );
+        let with = JobProcessor::compute_config_fingerprint(
+            "https://a.com/l.txt",
+            "",
+            &HashSet::new(),
+            &formats,
+            &operator.canonical(),
+            &schemes,
+        );
+        assert_ne!(without, with);
+    }
+
+    #[test]
+    fn test_failed_copy_keeps_previous_output() {
+        let source = TempDir::new().unwrap();
+        let target = TempDir::new().unwrap();
+        std::fs::write(source.path().join("ads_hosts.txt.gz"), b"new ads").unwrap();
+        std::fs::write(source.path().join("all_domains_hosts.txt.gz"), b"new all").unwrap();
+        std::fs::write(target.path().join("ads_hosts.txt.gz"), b"old ads").unwrap();
+
+        // The source user's files disappear part-way through the listed set
+        let names = vec![
+            "ads_hosts.txt.gz".to_string(),
+            "malware_hosts.txt.gz".to_string(),
+            "all_domains_hosts.txt.gz".to_string(),
+        ];
+        let err = JobProcessor::copy_files_staged(source.path(), target.path(), &names, None).unwrap_err();
+        assert!(err.to_string().contains("malware_hosts.txt.gz"));
+
+        // Nothing from the partial copy reached the target, and staging is gone
+        assert_eq!(std::fs::read(target.path().join("ads_hosts.txt.gz")).unwrap(), b"old ads");
+        assert!(!target.path().join("all_domains_hosts.txt.gz").exists());
+        assert!(!target.path().join(COPY_STAGING_DIR).exists());
+
+        // A complete set is swapped in
+        let names = vec!["ads_hosts.txt.gz".to_string(), "all_domains_hosts.txt.gz".to_string()];
+        JobProcessor::copy_files_staged(source.path(), target.path(), &names, None).unwrap();
+        assert_eq!(std::fs::read(target.path().join("ads_hosts.txt.gz")).unwrap(), b"new ads");
+        assert_eq!(std::fs::read(target.path().join("all_domains_hosts.txt.gz")).unwrap(), b"new all");
+        assert!(!target.path().join(COPY_STAGING_DIR).exists());
+
+        // With content-addressed output the copies go into the shared store
+        let store = TempDir::new().unwrap();
+        let linked = TempDir::new().unwrap();
+        JobProcessor::copy_files_staged(source.path(), linked.path(), &names, Some(store.path())).unwrap();
+        JobProcessor::copy_files_staged(source.path(), target.path(), &names, Some(store.path())).unwrap();
+        assert_eq!(std::fs::read(linked.path().join("ads_hosts.txt.gz")).unwrap(), b"new ads");
+        assert_eq!(std::fs::read_dir(store.path()).unwrap().count(), 2);
+        assert!(!linked.path().join(COPY_STAGING_DIR).exists());
+    }
+
+    #[test]
+    fn test_unique_domains_per_source() {
+        let mut overlap = SourceOverlap::new();
+        for domain in ["a.com", "b.com", "shared.com", "a.com"] {
+            overlap.record(0, domain);
+        }
+        for domain in ["shared.com", "c.com"] {
+            overlap.record(1, domain);
+        }
+
+        // Source 0 repeats a.com but it still counts once; shared.com counts for neither
+        assert_eq!(overlap.unique_counts(3), vec![2, 1, 0]);
+    }
+
+    #[test]
+    fn test_whitelist_removals_counted_per_category() {
+        let whitelist = WhitelistManager::from_content("@@example.com\nads:tracker.net");
+
+        let mut category_domains = CategoryDomains::new();
+        let mut insert = |category: Option<&str>, domains: &[&str]| {
+            category_domains
+                .by_category
+                .insert(category.map(String::from), domains.iter().map(|d| d.to_string()).collect());
+        };
+        insert(Some("ads"), &["a.example.com", "b.example.com", "tracker.net", "ads.org"]);
+        insert(Some("malware"), &["example.com", "evil.org"]);
+        insert(None, &["c.example.com", "misc.org"]);
+
+        let domains_before = category_domains.total_count() as u64;
+        let (filtered, _, category_removed) =
+            JobProcessor::apply_whitelist(&whitelist, category_domains, &HashSet::new());
+        let total_removed = domains_before - filtered.total_count() as u64;
+
+        assert_eq!(category_removed["ads"], 3);
+        assert_eq!(category_removed["malware"], 1);
+        assert_eq!(category_removed["uncategorized"], 1);
+        assert_eq!(category_removed.values().sum::<u64>(), total_removed);
+    }
+
+    #[test]
+    fn test_uncategorized_source_uses_default_category() {
+        let sources = Downloader::parse_config(
+            "https://example.com/ads.txt|Ads|advertising\nhttps://example.com/misc.txt|Misc",
+        );
+        let categories: Vec<Option<String>> = sources
+            .iter()
+            .map(|s| JobProcessor::resolve_category(s.category.as_deref(), Some("general")))
+            .collect();
+        assert_eq!(
+            categories,
+            vec![Some("advertising".to_string()), Some("general".to_string())]
+        );
+
+        // No default keeps the uncategorized bucket
+        assert_eq!(JobProcessor::resolve_category(None, None), None);
+    }
+
+    #[test]
+    fn test_optin_categories_excluded_from_combined() {
+        let optin: HashSet<String> = ["nsfw", "gambling"].iter().map(|c| c.to_string()).collect();
+
+        let mut by_category = HashMap::new();
+        by_category.insert(Some("ads".to_string()), vec!["ads.com".to_string(), "shared.com".to_string()]);
+        by_category.insert(Some("nsfw".to_string()), vec!["adult.com".to_string(), "shared.com".to_string()]);
+        by_category.insert(Some("Gambling".to_string()), vec!["bet.com".to_string()]);
+        by_category.insert(None, vec!["misc.com".to_string()]);
+
+        let mut combined: Vec<String> = JobProcessor::combined_domains(&by_category, &optin).into_iter().collect();
+        combined.sort();
+        assert_eq!(combined, vec!["ads.com", "misc.com", "shared.com"]);
+
+        // Opt-in categories still produce their own files
+        let temp_dir = TempDir::new().unwrap();
+        let generator = OutputGenerator::new(temp_dir.path()).with_formats(vec![OutputFormat::Plain]);
+        let files = generator.generate_all_categories(&by_category, &HashMap::new()).unwrap();
+        let mut names: Vec<&str> = files.iter().map(|f| f.name.as_str()).collect();
+        names.sort();
+        assert_eq!(
+            names,
+            vec!["Gambling_plain.txt.gz", "ads_plain.txt.gz", "nsfw_plain.txt.gz", "uncategorized_plain.txt.gz"]
+        );
+    }
+
+    #[test]
+    fn test_resolve_formats_defaults_to_all() {
+        assert_eq!(JobProcessor::resolve_formats(None), OutputFormat::all());
+        assert_eq!(JobProcessor::resolve_formats(Some(Vec::new())), OutputFormat::all());
+        assert_eq!(
+            JobProcessor::resolve_formats(Some(vec!["adblock".to_string(), "HOSTS".to_string()])),
+            vec![OutputFormat::Hosts, OutputFormat::Adblock]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_config_override_used_without_touching_stored_config() {
+        // Nothing listens here: any read of the stored config would fail the test
+        let processor = offline_processor().await;
+        let config = processor.config.clone();
+
+        let mut job = Job::queued("alice", None, crate::db::job::JobType::Manual);
+        job.config_override = Some(UserConfig {
+            blocklists: Some("https://example.com/new-source.txt|New".to_string()),
+            enabled_formats: Some(vec!["plain".to_string()]),
+            ..Default::default()
+        });
+
+        let loaded = JobProcessor::job_config(&processor.user_config_repo, &job).await.unwrap();
+        assert_eq!(loaded.blocklists.as_deref(), Some("https://example.com/new-source.txt|New"));
+        assert_eq!(processor.job_output_dir(&job), config.test_output_dir("alice"));
+
+        // Without the override the stored config and published directory are used
+        job.config_override = None;
+        assert!(JobProcessor::job_config(&processor.user_config_repo, &job).await.is_err());
+        assert_eq!(processor.job_output_dir(&job), config.output_dir("alice"));
+
+        // The field is optional on existing job documents
+        let doc = bson::to_document(&job).unwrap();
+        assert!(!doc.contains_key("config_override"));
+        let round_trip: Job = bson::from_document(doc).unwrap();
+        assert!(round_trip.config_override.is_none());
+    }
+
+    #[test]
+    fn test_extraction_status_transitions() {
+        let mut progress = JobProgress::downloading(2);
+        progress.sources = ["a", "b"]
+            .iter()
+            .map(|id| SourceProgress {
+                id: id.to_string(),
+                name: format!("Source {}", id),
+                status: SourceStatus::Completed,
+                ..Default::default()
+            })
+            .collect();
+
+        assert!(progress.set_source_status("a", SourceStatus::Processing));
+        assert_eq!(progress.sources[0].status, SourceStatus::Processing);
+        assert_eq!(progress.current_source.as_deref(), Some("Source a"));
+        assert_eq!(progress.sources[1].status, SourceStatus::Completed);
+
+        assert!(progress.set_source_status("a", SourceStatus::Completed));
+        assert_eq!(progress.sources[0].status, SourceStatus::Completed);
+        assert!(progress.current_source.is_none());
+        assert!(!progress.set_source_status("missing", SourceStatus::Processing));
+
+        // Writes in between are debounced to the configured interval
+        let mut throttle = ProgressThrottle::new(Duration::from_millis(500));
+        let start = Instant::now();
+        assert!(throttle.ready(start));
+        assert!(!throttle.ready(start + Duration::from_millis(100)));
+        assert!(throttle.ready(start + Duration::from_millis(600)));
+    }
+
+    #[test]
+    fn test_over_limit_total_fails_before_generation() {
+        let mut domains = CategoryDomains::new();
+        domains.by_category.insert(
+            Some("ads".to_string()),
+            ["a.com", "b.com", "c.com"].iter().map(|d| d.to_string()).collect(),
+        );
+        // Shared domains count once across categories
+        domains.by_category.insert(
+            Some("tracking".to_string()),
+            ["c.com", "d.com"].iter().map(|d| d.to_string()).collect(),
+        );
+        assert_eq!(domains.total_count(), 4);
+
+        let err = JobProcessor::check_total_domains(domains.total_count(), 3).unwrap_err();
+        assert!(err.to_string().contains("4 unique domains, over the limit of 3"));
+        assert!(JobProcessor::check_total_domains(domains.total_count(), 4).is_ok());
+        assert!(JobProcessor::check_total_domains(domains.total_count(), 0).is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_slow_job_aborted_at_deadline() {
+        let start = Instant::now();
+        let slow_job = async {
+            tokio::time::sleep(Duration::from_secs(30)).await;
+            Ok(())
+        };
+
+        let err = JobProcessor::with_deadline(1, slow_job).await.unwrap_err();
+        assert_eq!(ErrorKind::of(&err), ErrorKind::JobTimedOut);
+        assert!(err.to_string().contains("exceeded time limit of 1s"));
+        assert!(start.elapsed() < Duration::from_secs(5));
+
+        // No limit: the job runs to completion
+        assert!(JobProcessor::with_deadline(0, async { Ok(()) }).await.is_ok());
+    }
+
+    #[test]
+    fn test_copy_disabled_user_always_builds() {
+        let mut config = UserConfig {
+            blocklists: Some("https://example.com/list.txt|Example".to_string()),
+            ..Default::default()
+        };
+        let mut job = Job::queued("alice", None, crate::db::job::JobType::Manual);
+        assert!(JobProcessor::copy_on_match_allowed(&job, &config));
+
+        config.allow_copy_on_match = Some(false);
+        assert!(!JobProcessor::copy_on_match_allowed(&job, &config));
+
+        // Forced rebuilds and test builds never copy, whatever the flag says
+        config.allow_copy_on_match = Some(true);
+        job.force_rebuild = true;
+        assert!(!JobProcessor::copy_on_match_allowed(&job, &config));
+        job.force_rebuild = false;
+        job.config_override = Some(config.clone());
+        assert!(!JobProcessor::copy_on_match_allowed(&job, &config));
+
+        // Unset flags stay off stored documents
+        let doc = bson::to_document(&UserConfig { allow_copy_on_match: None, ..config }).unwrap();
+        assert!(!doc.contains_key("allow_copy_on_match"));
+        assert!(!doc.contains_key("allow_copy_source"));
+    }
+
+    #[test]
+    fn test_empty_config_fails_or_builds_empty_list() {
+        let mut config = UserConfig {
+            blocklists: Some("# blocking turned off\n".to_string()),
+            ..Default::default()
+        };
+        assert!(Downloader::parse_config(config.blocklists.as_deref().unwrap()).is_empty());
+
+        // Default: an empty config fails the job and the old lists stay
+        assert!(!JobProcessor::builds_empty_list(&config, false));
+        // Worker-wide opt-in
+        assert!(JobProcessor::builds_empty_list(&config, true));
+
+        // The user's own setting wins either way
+        config.empty_config_produces_empty_list = Some(true);
+        assert!(JobProcessor::builds_empty_list(&config, false));
+        config.empty_config_produces_empty_list = Some(false);
+        assert!(!JobProcessor::builds_empty_list(&config, true));
+
+        // Generation copes with nothing to write: the combined list comes out empty
+        let temp_dir = tempfile::tempdir().unwrap();
+        let generator = OutputGenerator::new(temp_dir.path()).with_formats(vec![OutputFormat::Hosts]);
+        let files = generator.generate_all(&[], &HashMap::new(), |_| {}).unwrap();
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].name, "all_domains_hosts.txt.gz");
+        assert_eq!(files[0].domain_count, 0);
+    }
+
+    #[test]
+    fn test_uncategorized_domain_lands_in_mapped_category() {
+        let map = CategoryMap::bundled();
+        assert_eq!(map.lookup("doubleclick.net"), Some("ads"));
+        assert_eq!(map.lookup("net"), None);
+
+        let results = DomainExtractor::new()
+            .extract_from_content_with_breakdown("stats.doubleclick.net\nexample.org\n||www.google-analytics.com^")
+            .results;
+
+        let mut category_domains = CategoryDomains::new();
+        let new_in_category = category_domains.add(None, results.clone(), Some(&map));
+        assert_eq!(new_in_category, 1);
+        assert!(category_domains.by_category[&Some("ads".to_string())].contains("stats.doubleclick.net"));
+        assert!(category_domains.by_category[&Some("tracking".to_string())].contains("www.google-analytics.com"));
+        assert_eq!(category_domains.by_category[&None].len(), 1);
+        assert!(category_domains.by_category[&None].contains("example.org"));
+        // Adblock passthrough follows the domain into its new category
+        assert!(category_domains.adblock_rules.contains_key("www.google-analytics.com"));
+
+        // Without a classifier everything stays in the source's bucket
+        let mut category_domains = CategoryDomains::new();
+        category_domains.add(None, results, None);
+        assert_eq!(category_domains.category_count(), 1);
+        assert_eq!(category_domains.by_category[&None].len(), 3);
+
+        // Custom maps use the same format
+        let custom = CategoryMap::parse("# comment\nExample.ORG. social\nmalformed-line\n");
+        assert_eq!(custom.len(), 1);
+        assert_eq!(custom.lookup("cdn.example.org"), Some("social"));
+    }
+
+    #[test]
+    fn test_bad_whitelist_regex_fails_strict_build() {
+        let whitelist = "/^ads[0-9+\\.example\\.com$/\n/^cdn\\.example\\.com$/\nmalware:/(unclosed/";
+
+        let errors = JobProcessor::check_whitelist(whitelist, true).unwrap_err();
+        assert_eq!(errors.len(), 2);
+        assert!(errors[0].starts_with("Invalid whitelist regex /^ads[0-9+\\.example\\.com$/"));
+        assert!(errors[1].contains("/(unclosed/") && errors[1].ends_with("(category malware)"));
+
+        // Lenient mode builds anyway and reports the same problems
+        assert_eq!(JobProcessor::check_whitelist(whitelist, false).unwrap(), errors);
+        assert_eq!(JobProcessor::check_whitelist("@@example.com", true).unwrap(), Vec::<String>::new());
+
+        // The valid regex still applies; before, one bad pattern dropped them all
+        let manager = WhitelistManager::from_content(whitelist);
+        assert!(manager.is_whitelisted("cdn.example.com"));
+    }
+
+    /// Categories of synthetic domains for the generation tests
+    fn generation_fixture(per_category: usize) -> HashMap<Option<String>, Vec<String>> {
+        ["ads", "tracking", "malware", "social"]
+            .iter()
+            .map(|cat| {
+                // Every fourth domain is shared with the next category, so the combined set dedups
+                let domains: HashSet<String> = (0..per_category)
+                    .map(|i| match i % 4 {
+                        0 => format!("shared{}.example.com", i),
+                        _ => format!("d{}.{}.example.net", i, cat),
+                    })
+                    .collect();
+                (Some(cat.to_string()), DomainExtractor::sort_domains(domains))
+            })
+            .chain(std::iter::once((None, vec!["plain.example.org".to_string()])))
+            .collect()
+    }
+
+    /// Generate into a fresh directory; returns the file list and every gzip file's entries
+    fn generate_fixture(
+        by_category: &HashMap<Option<String>, Vec<String>>,
+        concurrent: bool,
+    ) -> (Vec<OutputFile>, Vec<(String, String)>) {
+        let temp_dir = TempDir::new().unwrap();
+        let generator = OutputGenerator::new(temp_dir.path()).with_zstd(true);
+        let mut optin = HashSet::new();
+        optin.insert("social".to_string());
+
+        let files = JobProcessor::generate_outputs(
+            &generator,
+            by_category,
+            &HashMap::new(),
+            &optin,
+            DomainExtractor::sort_domains,
+            concurrent,
+            |_| {},
+        )
+        .unwrap();
+
+        // Headers carry a timestamp; compare the decompressed entries instead
+        let mut contents: Vec<(String, String)> = files
+            .iter()
+            .filter(|f| f.compression == "gz")
+            .map(|f| {
+                let mut body = String::new();
+                std::io::Read::read_to_string(
+                    &mut flate2::read::GzDecoder::new(std::fs::File::open(temp_dir.path().join(&f.name)).unwrap()),
+                    &mut body,
+                )
+                .unwrap();
+                let entries: Vec<&str> = body.lines().filter(|l| !l.starts_with('#') && !l.starts_with('!')).collect();
+                (f.name.clone(), entries.join("\n"))
+            })
+            .collect();
+        contents.sort();
+        (files, contents)
+    }
+
+    #[test]
+    fn test_concurrent_combined_generation_matches_sequential() {
+        let by_category = generation_fixture(200);
+
+        let (seq_files, seq_contents) = generate_fixture(&by_category, false);
+        let (con_files, con_contents) = generate_fixture(&by_category, true);
+
+        // Same files in the same order, with the same counts and entries
+        let summary = |files: &[OutputFile]| -> Vec<(String, u64)> {
+            files.iter().map(|f| (f.name.clone(), f.domain_count)).collect()
+        };
+        assert_eq!(summary(&seq_files), summary(&con_files));
+        assert_eq!(seq_contents, con_contents);
+
+        // Combined list comes last and leaves the opt-in category out
+        let combined = seq_files.iter().find(|f| f.name == "all_domains_hosts.txt.gz").unwrap();
+        let expected = JobProcessor::combined_domains(&by_category, &["social".to_string()].into_iter().collect());
+        assert_eq!(combined.domain_count, expected.len() as u64);
+        assert!(seq_files.last().unwrap().name.starts_with("all_domains"));
+    }
+
+    #[test]
+    fn test_same_inputs_produce_identical_output() {
+        // Two sources that overlap and disagree on an adblock rule
+        let source = |lines: &[(&str, Option<&str>)]| -> Vec<ExtractionResult> {
+            lines
+                .iter()
+                .map(|(domain, rule)| ExtractionResult {
+                    domain: domain.to_string(),
+                    raw_adblock_rule: rule.map(str::to_string),
+                })
+                .collect()
+        };
+        let easylist = source(&[
+            ("ads.example.com", Some("||ads.example.com^$third-party")),
+            ("banner.example.net", None),
+            ("keep.example.org", None),
+        ]);
+        let peter_lowe = source(&[
+            ("ads.example.com", Some("||ads.example.com^")),
+            ("pixel.example.net", None),
+            ("b.allowed.net", None),
+            ("a.allowed.com", None),
+        ]);
+        // Both patterns remove one domain, so only tie-breaking orders them
+        let whitelist = WhitelistManager::from_content("b.allowed.net\na.allowed.com");
+
+        let build = |order: &[(&str, &Vec<ExtractionResult>)], dir: &Path| {
+            let mut domains = CategoryDomains::new();
+            for (category, results) in order {
+                domains.add(Some(category.to_string()), (*results).clone(), None);
+                domains.add(None, (*results).clone(), None);
+            }
+            let (filtered, matches, _) = JobProcessor::apply_whitelist(&whitelist, domains, &HashSet::new());
+            let by_category: HashMap<Option<String>, Vec<String>> = filtered
+                .by_category
+                .into_iter()
+                .map(|(cat, domains)| (cat, DomainExtractor::sort_domains_by_suffix(domains)))
+                .collect();
+            let generator = OutputGenerator::new(dir)
+                .with_zstd(true)
+                .with_generated_at(chrono::DateTime::from_timestamp(1_700_000_000, 0).unwrap());
+            let files = JobProcessor::generate_outputs(
+                &generator,
+                &by_category,
+                &filtered.adblock_rules,
+                &HashSet::new(),
+                DomainExtractor::sort_domains_by_suffix,
+                true,
+                |_| {},
+            )
+            .unwrap();
+            let patterns: Vec<String> = matches.into_iter().map(|m| m.pattern).collect();
+            (files, patterns)
+        };
+
+        let first_dir = TempDir::new().unwrap();
+        let second_dir = TempDir::new().unwrap();
+        let (first, first_patterns) = build(&[("ads", &easylist), ("tracking", &peter_lowe)], first_dir.path());
+        let (second, second_patterns) = build(&[("tracking", &peter_lowe), ("ads", &easylist)], second_dir.path());
+
+        assert_eq!(first_patterns, vec!["a.allowed.com", "b.allowed.net"]);
+        assert_eq!(first_patterns, second_patterns);
+
+        // Same file list in the same order, and every file byte-identical
+        let names: Vec<&str> = first.iter().map(|f| f.name.as_str()).collect();
+        assert_eq!(names, second.iter().map(|f| f.name.as_str()).collect::<Vec<_>>());
+        for name in names {
+            let a = std::fs::read(first_dir.path().join(name)).unwrap();
+            let b = std::fs::read(second_dir.path().join(name)).unwrap();
+            assert!(a == b, "{} differs between builds", name);
+        }
+
+        // The conflicting rule resolves the same way regardless of source order
+        let mut adblock = String::new();
+        std::io::Read::read_to_string(
+            &mut flate2::read::GzDecoder::new(std::fs::File::open(first_dir.path().join("all_domains_adblock.txt.gz")).unwrap()),
+            &mut adblock,
+        )
+        .unwrap();
+        assert!(adblock.contains("\n||ads.example.com^\n"));
+    }
+
+    #[test]
+    fn test_category_whitelist_applies_only_to_its_category() {
+        let mut whitelist_content = "ok.example.com".to_string();
+        let category_whitelists = HashMap::from([
+            ("ads".to_string(), "# ads only\nshared.net\nmalware:other.net\n".to_string()),
+            ("bad category!".to_string(), "everywhere.net".to_string()),
+        ]);
+        let warnings = JobProcessor::merge_category_whitelists(&mut whitelist_content, category_whitelists);
+        assert_eq!(warnings.len(), 2);
+        assert_eq!(whitelist_content, "ok.example.com\nads:shared.net\n");
+
+        let whitelist = WhitelistManager::from_content(&whitelist_content);
+        let mut category_domains = CategoryDomains::new();
+        for category in ["ads", "malware"] {
+            category_domains.by_category.insert(
+                Some(category.to_string()),
+                ["ok.example.com", "shared.net", "other.net", "everywhere.net"]
+                    .iter()
+                    .map(|d| d.to_string())
+                    .collect(),
+            );
+        }
+
+        let (filtered, _, _) = JobProcessor::apply_whitelist(&whitelist, category_domains, &HashSet::new());
+        let ads = &filtered.by_category[&Some("ads".to_string())];
+        let malware = &filtered.by_category[&Some("malware".to_string())];
+
+        // Global pattern applies to both, the category pattern only to ads
+        assert!(!ads.contains("ok.example.com") && !malware.contains("ok.example.com"));
+        assert!(!ads.contains("shared.net"));
+        assert!(malware.contains("shared.net"));
+        assert!(malware.contains("other.net") && ads.contains("everywhere.net"));
+    }
+
+    #[tokio::test]
+    async fn test_preview_diff_between_configs_differing_by_one_source() {
+        let processor = offline_processor().await;
+
+        let config = |blocklists: &str| UserConfig {
+            blocklists: Some(blocklists.to_string()),
+            whitelist: Some("allowed.example".to_string()),
+            ..Default::default()
+        };
+        let current = config("https://a.example/ads.txt|Ads|ads\nhttps://b.example/malware.txt|Malware|malware");
+        let proposed = config(&format!("{}\nhttps://c.example/extra.txt|Extra|ads", current.blocklists.as_deref().unwrap()));
+        let parse = |config: &UserConfig| Downloader::parse_config(config.blocklists.as_deref().unwrap());
+        let (current_sources, proposed_sources) = (parse(&current), parse(&proposed));
+
+        // Each source downloaded once, as preview_config_diff does
+        let downloads = proposed_sources
+            .iter()
+            .zip(["ads.com\nshared.com\n", "evil.com\n", "shared.com\nnew1.com\nnew2.com\nallowed.example\n"])
+            .map(|(source, body)| DownloadResult {
+                source: source.clone(),
+                url_hash: Downloader::cache_key(source),
+                primary_hash: None,
+                content: Some(body.as_bytes().to_vec()),
+                cache_hit: true,
+                bytes_downloaded: 0,
+                download_time_ms: 0,
+                error: None,
+                error_kind: None,
+                warnings: Vec::new(),
+                previous_domain_count: None,
+                extracted: None,
+            })
+            .collect();
+
+        let result = processor.diff_downloads((&current, &current_sources), (&proposed, &proposed_sources), downloads);
+        let diff = result.config_diff.unwrap();
+
+        assert_eq!(diff.added_count, 2);
+        assert_eq!(diff.added_sample, vec!["new1.com", "new2.com"]);
+        assert_eq!(diff.removed_count, 0);
+        assert!(diff.removed_sample.is_empty());
+        assert_eq!(diff.unchanged_count, 3);
+        assert_eq!((result.sources_processed, result.sources_failed), (3, 0));
+        assert!(result.output_files.is_empty());
+
+        // A shutdown mid-preview is no result rather than an error, so the job is released
+        let cancel = CancelToken::default();
+        cancel.cancel();
+        let processor = processor.with_cancel(cancel);
+        assert!(processor.preview_config_diff(&current, &proposed).await.unwrap().is_none());
+    }
+
+    #[test]
+    fn test_generation_progress_written_from_non_tokio_thread() {
+        let generation = GenerationProgress::default();
+        let progress = Arc::new(Mutex::new(JobProgress::default()));
+
+        // Created outside any runtime: no handle to fall back on
+        let writer = GenerationProgressWriter::new(Arc::clone(&progress));
+        assert!(writer.handle.is_none());
+
+        std::thread::scope(|scope| {
+            // Uncontended: written without a runtime
+            assert!(scope.spawn(|| writer.update(&generation)).join().unwrap());
+            assert!(progress.try_lock().unwrap().generation.is_some());
+
+            // Contended with nothing to block on: skipped instead of panicking
+            let held = progress.try_lock().unwrap();
+            assert!(!scope.spawn(|| writer.update(&generation)).join().unwrap());
+            assert!(!scope.spawn(|| writer.update(&generation)).join().unwrap());
+            drop(held);
+        });
+        assert!(writer.skip_logged.load(std::sync::atomic::Ordering::Relaxed));
+
+        // Through the real callback, with combined generation on a rayon thread
+        progress.try_lock().unwrap().generation = None;
+        let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+        let writer = runtime.block_on(async { GenerationProgressWriter::new(Arc::clone(&progress)) });
+        let temp_dir = TempDir::new().unwrap();
+        let files = JobProcessor::generate_outputs(
+            &OutputGenerator::new(temp_dir.path()),
+            &generation_fixture(5),
+            &HashMap::new(),
+            &HashSet::new(),
+            DomainExtractor::sort_domains,
+            true,
+            |gen_progress| {
+                writer.update(gen_progress);
+            },
+        )
+        .unwrap();
+        assert!(!files.is_empty());
+        assert!(progress.try_lock().unwrap().generation.is_some());
+    }
+
+    #[test]
+    fn test_format_and_count_shift_flagged() {
+        let previous = ExtractionSummary {
+            domain_count: 100_000,
+            format_breakdown: FormatBreakdown {
+                hosts: 100_000,
+                ..Default::default()
+            },
+        };
+        let adblock = |count: u64| FormatBreakdown {
+            adblock: count,
+            ..Default::default()
+        };
+
+        // Hosts feed now serves a bare adblock header: flagged
+        let shift = JobProcessor::format_shift(&previous, 2, &adblock(2)).unwrap();
+        assert!(shift.contains("from hosts (100000 domains) to adblock (2 domains)"), "{}", shift);
+        assert!(JobProcessor::format_shift(&previous, 0, &FormatBreakdown::default())
+            .unwrap()
+            .contains("to nothing recognizable"));
+
+        // A format switch alone, or a drop alone, is fine
+        assert!(JobProcessor::format_shift(&previous, 90_000, &adblock(90_000)).is_none());
+        let hosts = FormatBreakdown {
+            hosts: 10,
+            ..Default::default()
+        };
+        assert!(JobProcessor::format_shift(&previous, 10, &hosts).is_none());
+    }
+
+    #[test]
+    fn test_unique_counts_split_by_optin_exclusion() {
+        let optin: HashSet<String> = ["nsfw"].iter().map(|c| c.to_string()).collect();
+        let mut domains = CategoryDomains::new();
+        domains.by_category.insert(
+            Some("advertising".to_string()),
+            ["ads.com", "shared.com"].iter().map(|d| d.to_string()).collect(),
+        );
+        domains.by_category.insert(
+            Some("nsfw".to_string()),
+            ["a.xxx", "b.xxx", "c.xxx", "shared.com"].iter().map(|d| d.to_string()).collect(),
+        );
+
+        // The nsfw list is bigger than the combined list, but not bigger than everything
+        let (combined, all_categories) = JobProcessor::unique_domain_counts(&domains, &optin);
+        assert_eq!((combined, all_categories), (2, 5));
+
+        let mut result = JobResult::success(2, 0, 6, combined, 0, Vec::new());
+        result.combined_unique_domains = combined;
+        result.all_categories_unique_domains = all_categories;
+        let doc = bson::to_document(&result).unwrap();
+        assert_eq!(doc.get_i64("combined_unique_domains").unwrap(), 2);
+        assert_eq!(doc.get_i64("all_categories_unique_domains").unwrap(), 5);
+
+        // Results stored before the split read back as zero
+        let mut old = doc.clone();
+        old.remove("combined_unique_domains");
+        old.remove("all_categories_unique_domains");
+        let old: JobResult = bson::from_document(old).unwrap();
+        assert_eq!((old.combined_unique_domains, old.all_categories_unique_domains), (0, 0));
+    }
+
+    #[test]
+    fn test_list_metadata_keeps_differing_format_counts() {
+        let temp_dir = TempDir::new().unwrap();
+        let formats = vec![OutputFormat::Hosts, OutputFormat::Adblock];
+        let generator = OutputGenerator::new(temp_dir.path())
+            .with_formats(formats.clone())
+            .with_collapse_covered_subdomains(true);
+
+        // ||ads.com^ already covers www.ads.com, so adblock lists one domain fewer
+        let ads = vec!["ads.com".to_string(), "www.ads.com".to_string()];
+        let mut by_category = HashMap::new();
+        by_category.insert(Some("ads".to_string()), ads.clone());
+        by_category.insert(Some("malware".to_string()), vec!["evil.net".to_string()]);
+        let mut output_files = generator.generate_all_categories(&by_category, &HashMap::new()).unwrap();
+        output_files.extend(generator.generate_all(&[ads, vec!["evil.net".to_string()]].concat(), &HashMap::new(), |_| {}).unwrap());
+
+        let count = |name: &str, format: &str| {
+            output_files.iter().find(|f| f.name == format!("{}_{}.txt.gz", name, format)).unwrap().domain_count
+        };
+        assert_eq!((count("ads", "hosts"), count("ads", "adblock")), (2, 1));
+
+        let now = BsonDateTime::from_millis(0);
+        let lists = JobProcessor::build_list_metadata(&output_files, 3, &formats, &FilenameTemplate::default(), now);
+        let list = |name: &str| lists.iter().find(|l| l.name == name).unwrap();
+
+        let ads = list("ads");
+        assert_eq!(ads.domain_count, 2);
+        assert_eq!(ads.format_counts, HashMap::from([("hosts".to_string(), 2), ("adblock".to_string(), 1)]));
+        assert_eq!(list(COMBINED_LIST_NAME).format_counts["adblock"], 2);
+
+        // Formats that agree don't repeat the count
+        assert_eq!(list("malware").domain_count, 1);
+        assert!(list("malware").format_counts.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_parallel_extraction_matches_sequential() {
+        let processor = offline_processor().await;
+
+        let sources = Downloader::parse_config(
+            "https://a.example/ads.txt|Ads|ads\n\
+             https://b.example/more-ads.txt|More ads|ads\n\
+             https://c.example/malware.txt|Malware|malware\n\
+             https://d.example/misc.txt|Misc\n\
+             https://e.example/down.txt|Down|ads\n\
+             https://f.example/streamed.txt|Streamed|malware",
+        );
+        let bodies = [
+            Some("||ads.com^\n||shared.com^$important\n0.0.0.0 tracker.net\n"),
+            Some("shared.com\nads.com\nonly-here.org\n"),
+            Some("evil.com\nshared.com\n"),
+            Some("misc.io\n"),
+            None,
+            None,
+        ];
+        let downloads = || -> Vec<DownloadResult> {
+            sources
+                .iter()
+                .zip(bodies)
+                .map(|(source, body)| DownloadResult {
+                    source: source.clone(),
+                    url_hash: Downloader::cache_key(source),
+                    primary_hash: None,
+                    content: body.map(|b| b.as_bytes().to_vec()),
+                    cache_hit: true,
+                    bytes_downloaded: 0,
+                    download_time_ms: 0,
+                    error: (source.name == "Down").then(|| "HTTP 503".to_string()),
+                    error_kind: None,
+                    warnings: Vec::new(),
+                    previous_domain_count: None,
+                    extracted: (source.name == "Streamed")
+                        .then(|| processor.extractor.extract_from_content_with_breakdown("streamed.net\nevil.com\n")),
+                })
+                .collect()
+        };
+
+        let mut parallel = CategoryDomains::new();
+        let mut parallel_overlap = SourceOverlap::new();
+        let mut results = downloads();
+        let extracted = processor.extract_all(&mut results);
+        assert!(extracted[4].is_none());
+        for (idx, (result, sections)) in results.iter().zip(extracted).enumerate() {
+            if let Some(sections) = sections {
+                processor.add_sections(&mut parallel, &mut parallel_overlap, idx, &result.source, sections.unwrap());
+            }
+        }
+
+        let mut sequential = CategoryDomains::new();
+        let mut sequential_overlap = SourceOverlap::new();
+        for (idx, mut result) in downloads().into_iter().enumerate() {
+            if result.error.is_some() {
+                continue;
+            }
+            let streamed = result.extracted.take();
+            let content = result.content.as_deref().unwrap_or_default();
+            let sections = processor.extract_sections(&result.source, content, streamed).unwrap();
+            processor.add_sections(&mut sequential, &mut sequential_overlap, idx, &result.source, sections);
+        }
+
+        assert_eq!(parallel.by_category, sequential.by_category);
+        assert_eq!(parallel.adblock_rules, sequential.adblock_rules);
+        assert_eq!(parallel_overlap.unique_counts(6), sequential_overlap.unique_counts(6));
+
+        // Shared domains are deduplicated within a category and kept in each category listing them
+        let ads = &parallel.by_category[&Some("ads".to_string())];
+        assert_eq!(ads.len(), 4);
+        assert!(parallel.by_category[&Some("malware".to_string())].contains("shared.com"));
+        assert!(parallel.by_category[&Some("malware".to_string())].contains("streamed.net"));
+        assert_eq!(parallel.adblock_rules["shared.com"], "||shared.com^$important");
+    }
+
+    #[test]
+    fn test_built_result_reports_category_counts() {
+        let mut domains = CategoryDomains::new();
+        domains.by_category.insert(
+            Some("ads".to_string()),
+            ["ads.com", "shared.com"].iter().map(|d| d.to_string()).collect(),
+        );
+        domains.by_category.insert(
+            Some("malware".to_string()),
+            ["evil.com", "shared.com", "worse.net"].iter().map(|d| d.to_string()).collect(),
+        );
+        domains.by_category.insert(None, ["misc.io"].iter().map(|d| d.to_string()).collect());
+
+        let counts = JobProcessor::category_counts(&domains);
+        assert_eq!(
+            counts,
+            HashMap::from([
+                ("ads".to_string(), 2),
+                ("malware".to_string(), 3),
+                ("uncategorized".to_string(), 1)
+            ])
+        );
+
+        // Stored like a copied job's breakdown, so copies of this build carry it on
+        let mut result = JobResult::success(3, 0, 6, 5, 0, Vec::new());
+        result.categories = counts;
+        let doc = bson::to_document(&result).unwrap();
+        assert_eq!(doc.get_document("categories").unwrap().get_i64("malware").unwrap(), 3);
+    }
+}
@@ -0,0 +1,105 @@
+use anyhow::Result;
+use bson::oid::ObjectId;
+use mongodb::Database;
+use std::collections::HashSet;
+use tracing::{debug, info};
+
+use crate::db::job::{Job, JobRepository, JobType};
+use crate::db::progress::JobResult;
+use crate::db::user::UserRepository;
+
+/// Username of the default lists (rebuilt alongside users)
+const DEFAULT_USERNAME: &str = "__default__";
+
+/// Build one admin job per user, skipping users with a job already queued/processing
+///
+/// Child jobs inherit the bulk job's `force_rebuild` flag.
+pub fn plan_rebuild(
+    users: &[(Option<ObjectId>, String)],
+    active: &HashSet<String>,
+    force_rebuild: bool,
+) -> Vec<Job> {
+    users
+        .iter()
+        .filter(|(_, username)| {
+            let skip = active.contains(username);
+            if skip {
+                debug!("Skipping {} - job already pending", username);
+            }
+            !skip
+        })
+        .map(|(user_id, username)| {
+            let mut job = Job::queued(username, *user_id, JobType::Admin);
+            job.force_rebuild = force_rebuild;
+            job
+        })
+        .collect()
+}
+
+/// Fans a `rebuild_all` job out into per-user jobs
+pub struct BulkRebuild {
+    job_repo: JobRepository,
+    user_repo: UserRepository,
+}
+
+impl BulkRebuild {
+    /// Create a new bulk rebuild handler
+    pub fn new(db: &Database, worker_id: String) -> Self {
+        Self {
+            job_repo: JobRepository::new(db, worker_id),
+            user_repo: UserRepository::new(db),
+        }
+    }
+
+    /// Enqueue a job for the default lists and every enabled user, then complete `job`
+    pub async fn run(&self, job: &Job) -> Result<()> {
+        let mut users = vec![(None, DEFAULT_USERNAME.to_string())];
+        users.extend(self.user_repo.list_buildable().await?);
+
+        // This job is itself processing; its user still gets a rebuild
+        let active = self.job_repo.active_usernames(&job.id).await?;
+        let jobs = plan_rebuild(&users, &active, job.force_rebuild);
+
+        for child in &jobs {
+            self.job_repo.enqueue(child).await?;
+        }
+
+        info!(
+            "Bulk rebuild {} enqueued {} jobs ({} users already pending)",
+            job.job_id,
+            jobs.len(),
+            users.len() - jobs.len()
+        );
+
+        self.job_repo
+            .complete(&job.id, JobResult::bulk_rebuild(jobs.len() as u64))
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_enqueues_one_job_per_user_skipping_pending() {
+        let users = vec![
+            (None, DEFAULT_USERNAME.to_string()),
+            (Some(ObjectId::new()), "alice".to_string()),
+            (Some(ObjectId::new()), "bob".to_string()),
+            (Some(ObjectId::new()), "carol".to_string()),
+        ];
+        let active: HashSet<String> = ["bob".to_string()].into_iter().collect();
+
+        let jobs = plan_rebuild(&users, &active, true);
+
+        let usernames: Vec<&str> = jobs.iter().map(|j| j.username.as_str()).collect();
+        assert_eq!(usernames, vec![DEFAULT_USERNAME, "alice", "carol"]);
+        assert!(jobs.iter().all(|j| j.force_rebuild && j.job_type == JobType::Admin));
+        assert_eq!(jobs[1].user_id, users[1].0);
+
+        // Each job gets its own id
+        let ids: HashSet<&str> = jobs.iter().map(|j| j.job_id.as_str()).collect();
+        assert_eq!(ids.len(), jobs.len());
+    }
+}
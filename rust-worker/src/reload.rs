@@ -0,0 +1,180 @@
+//! Config reload on SIGHUP
+//!
+//! The worker keeps its config behind a lock. Each job takes a snapshot when
+//! it starts, so a reload applies from the next job on and never changes the
+//! one in flight. Only the settings `Config::apply_reload` takes are reloaded.
+
+use anyhow::Result;
+use std::sync::{Arc, PoisonError, RwLock};
+use tracing::{info, warn};
+
+use crate::config::Config;
+
+/// Config shared between the worker loop and the reload task
+pub type SharedConfig = Arc<RwLock<Config>>;
+
+/// Snapshot of the current config
+pub fn snapshot(config: &SharedConfig) -> Config {
+    config.read().unwrap_or_else(PoisonError::into_inner).clone()
+}
+
+type LoadConfig = Box<dyn Fn() -> Config + Send + Sync>;
+type ReloadHook = Box<dyn Fn(&Config) + Send + Sync>;
+
+/// Re-reads the config and applies its hot-reloadable settings
+pub struct ConfigReloader {
+    config: SharedConfig,
+    load: LoadConfig,
+    on_reload: Option<ReloadHook>,
+}
+
+impl ConfigReloader {
+    /// Reloader for `config`, reading new values with `load` (e.g. `Config::from_env_with` over a fresh .env)
+    pub fn new(config: SharedConfig, load: impl Fn() -> Config + Send + Sync + 'static) -> Self {
+        Self {
+            config,
+            load: Box::new(load),
+            on_reload: None,
+        }
+    }
+
+    /// Run `hook` with the updated config after each reload (e.g. to swap the log filter)
+    pub fn with_on_reload(mut self, hook: impl Fn(&Config) + Send + Sync + 'static) -> Self {
+        self.on_reload = Some(Box::new(hook));
+        self
+    }
+
+    /// Load the config again and apply what can change live
+    pub fn reload(&self) {
+        let fresh = (self.load)();
+        let (updated, ignored) = {
+            let mut config = self.config.write().unwrap_or_else(PoisonError::into_inner);
+            let ignored = config.apply_reload(fresh);
+            (config.clone(), ignored)
+        };
+
+        for name in ignored {
+            warn!("{} changed, but only takes effect after a restart; ignored", name);
+        }
+        if let Some(hook) = &self.on_reload {
+            hook(&updated);
+        }
+        info!(
+            "Config reloaded: max_concurrent_downloads={}, http_timeout_secs={}, connect_timeout_secs={}, read_idle_timeout_secs={}, log_level={}",
+            updated.max_concurrent_downloads,
+            updated.http_timeout_secs,
+            updated.connect_timeout_secs,
+            updated.read_idle_timeout_secs,
+            updated.log_level
+        );
+    }
+
+    /// Reload every time the process receives SIGHUP
+    #[cfg(unix)]
+    pub fn spawn_on_sighup(self) -> Result<tokio::task::JoinHandle<()>> {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        // Registered before returning, so a SIGHUP from here on can't kill the worker
+        let mut hangups = signal(SignalKind::hangup())?;
+        Ok(tokio::spawn(async move {
+            while hangups.recv().await.is_some() {
+                info!("Received SIGHUP, reloading config");
+                self.reload();
+            }
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use std::sync::Mutex;
+
+    #[test]
+    fn test_reload_applies_tunable_settings() {
+        let config: SharedConfig = Arc::new(RwLock::new(Config::from_env()));
+        let original = snapshot(&config);
+
+        let load = || {
+            let mut fresh = Config::from_env();
+            fresh.max_concurrent_downloads = 3;
+            fresh.log_level = "debug".to_string();
+            fresh.mongo_uri = "mongodb://elsewhere:27017".to_string();
+            fresh
+        };
+        let hooked = Arc::new(Mutex::new(None));
+        let seen = Arc::clone(&hooked);
+        ConfigReloader::new(Arc::clone(&config), load)
+            .with_on_reload(move |config| *seen.lock().unwrap() = Some(config.log_level.clone()))
+            .reload();
+
+        let reloaded = snapshot(&config);
+        assert_eq!(reloaded.max_concurrent_downloads, 3);
+        assert_eq!(reloaded.log_level, "debug");
+        assert_eq!(hooked.lock().unwrap().as_deref(), Some("debug"));
+
+        // Restart-only settings and the worker identity stay as they were
+        assert_eq!(reloaded.mongo_uri, original.mongo_uri);
+        assert_eq!(reloaded.worker_id, original.worker_id);
+    }
+
+    #[test]
+    fn test_reload_rereads_env_file_values() {
+        let env_file = Arc::new(Mutex::new(HashMap::from([(
+            "MAX_CONCURRENT_DOWNLOADS".to_string(),
+            "4".to_string(),
+        )])));
+        let config: SharedConfig = Arc::new(RwLock::new(Config::from_env_with(
+            &env_file.lock().unwrap(),
+        )));
+        assert_eq!(snapshot(&config).max_concurrent_downloads, 4);
+
+        let file = Arc::clone(&env_file);
+        let reloader = ConfigReloader::new(Arc::clone(&config), move || {
+            Config::from_env_with(&file.lock().unwrap())
+        });
+        env_file
+            .lock()
+            .unwrap()
+            .insert("MAX_CONCURRENT_DOWNLOADS".to_string(), "9".to_string());
+        reloader.reload();
+        assert_eq!(snapshot(&config).max_concurrent_downloads, 9);
+
+        // A value dropped from the file falls back to the default
+        env_file.lock().unwrap().clear();
+        reloader.reload();
+        assert_eq!(
+            snapshot(&config).max_concurrent_downloads,
+            Config::from_env().max_concurrent_downloads
+        );
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_sighup_triggers_reload() {
+        use std::time::{Duration, Instant};
+
+        let config: SharedConfig = Arc::new(RwLock::new(Config::from_env()));
+        let task = ConfigReloader::new(Arc::clone(&config), || {
+            let mut fresh = Config::from_env();
+            fresh.max_concurrent_downloads = 17;
+            fresh
+        })
+        .spawn_on_sighup()
+        .unwrap();
+
+        let status = std::process::Command::new("kill")
+            .args(["-HUP", &std::process::id().to_string()])
+            .status()
+            .unwrap();
+        assert!(status.success());
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while snapshot(&config).max_concurrent_downloads != 17 {
+            assert!(Instant::now() < deadline, "config not reloaded after SIGHUP");
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        task.abort();
+    }
+}
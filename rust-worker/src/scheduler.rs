@@ -0,0 +1,364 @@
+use anyhow::{Context, Result};
+use bson::{oid::ObjectId, DateTime as BsonDateTime};
+use chrono::{DateTime, Utc};
+use mongodb::Database;
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::Mutex;
+use tracing::{debug, info, warn};
+
+use crate::db::job::{Job, JobRepository, JobType};
+use crate::db::schedule::{Schedule, ScheduleRepository};
+
+/// How often the scheduler checks for due schedules
+pub const TICK_INTERVAL_SECS: u64 = 30;
+
+/// What to do with a schedule at a given point in time
+#[derive(Debug, Clone, PartialEq)]
+pub enum ScheduleAction {
+    /// Schedule has never run - compute its first run without enqueueing
+    Initialize { next: DateTime<Utc> },
+    /// Schedule is due - enqueue a job and advance to the next run
+    Enqueue { next: DateTime<Utc> },
+    /// Schedule is due but the user's previous job is still queued/processing
+    SkipActive { next: DateTime<Utc> },
+    /// Nothing to do yet
+    NotDue,
+}
+
+/// Compute the next run of a cron expression strictly after `after`
+///
+/// Accepts standard 5-field expressions (minute precision) as well as the
+/// 6/7-field form with seconds understood by the `cron` crate.
+pub fn next_run_after(expression: &str, after: DateTime<Utc>) -> Result<DateTime<Utc>> {
+    let expression = expression.trim();
+    let normalized = if expression.split_whitespace().count() == 5 {
+        format!("0 {}", expression)
+    } else {
+        expression.to_string()
+    };
+
+    let schedule = cron::Schedule::from_str(&normalized)
+        .with_context(|| format!("Invalid cron expression '{}'", expression))?;
+
+    schedule
+        .after(&after)
+        .next()
+        .with_context(|| format!("Cron expression '{}' has no future runs", expression))
+}
+
+/// Decide what to do with a schedule at `now`
+pub fn evaluate(schedule: &Schedule, now: DateTime<Utc>, has_active_job: bool) -> Result<ScheduleAction> {
+    let next = next_run_after(&schedule.cron, now)?;
+
+    match schedule.next_run_at {
+        None => Ok(ScheduleAction::Initialize { next }),
+        Some(due) if due.timestamp_millis() > now.timestamp_millis() => Ok(ScheduleAction::NotDue),
+        Some(_) if has_active_job => Ok(ScheduleAction::SkipActive { next }),
+        Some(_) => Ok(ScheduleAction::Enqueue { next }),
+    }
+}
+
+/// Where the scheduler reads schedules and puts jobs (MongoDB outside tests)
+pub trait ScheduleStore {
+    /// Enabled schedules that are due or not yet initialized
+    async fn find_due(&self, now: BsonDateTime) -> Result<Vec<Schedule>>;
+
+    /// Move a schedule from `previous` to `next`; false if another worker got there first
+    async fn advance(
+        &self,
+        id: &ObjectId,
+        previous: Option<BsonDateTime>,
+        next: BsonDateTime,
+        ran_at: Option<BsonDateTime>,
+    ) -> Result<bool>;
+
+    /// Whether the user already has a queued or processing job
+    async fn has_active_job(&self, username: &str) -> Result<bool>;
+
+    /// Add a job to the queue
+    async fn enqueue(&self, job: &Job) -> Result<()>;
+}
+
+/// The `schedules` and `jobs` collections
+pub struct MongoScheduleStore {
+    schedule_repo: ScheduleRepository,
+    job_repo: JobRepository,
+}
+
+impl ScheduleStore for MongoScheduleStore {
+    async fn find_due(&self, now: BsonDateTime) -> Result<Vec<Schedule>> {
+        self.schedule_repo.find_due(now).await
+    }
+
+    async fn advance(
+        &self,
+        id: &ObjectId,
+        previous: Option<BsonDateTime>,
+        next: BsonDateTime,
+        ran_at: Option<BsonDateTime>,
+    ) -> Result<bool> {
+        self.schedule_repo.advance(id, previous, next, ran_at).await
+    }
+
+    async fn has_active_job(&self, username: &str) -> Result<bool> {
+        self.job_repo.has_active_job(username).await
+    }
+
+    async fn enqueue(&self, job: &Job) -> Result<()> {
+        self.job_repo.enqueue(job).await
+    }
+}
+
+/// In-process scheduler that enqueues jobs from the `schedules` collection
+pub struct Scheduler<S = MongoScheduleStore> {
+    store: S,
+    /// Schedules whose cron failed to parse, with that cron; skipped quietly
+    /// until the expression changes, so the warning isn't repeated every tick
+    invalid: Mutex<HashMap<ObjectId, String>>,
+}
+
+impl Scheduler {
+    /// Create a new scheduler
+    pub fn new(db: &Database, worker_id: String) -> Self {
+        Self::with_store(MongoScheduleStore {
+            schedule_repo: ScheduleRepository::new(db),
+            job_repo: JobRepository::new(db, worker_id),
+        })
+    }
+}
+
+impl<S: ScheduleStore> Scheduler<S> {
+    /// Scheduler over any schedule store
+    pub fn with_store(store: S) -> Self {
+        Self {
+            store,
+            invalid: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Evaluate all due schedules once, returning the number of jobs enqueued
+    pub async fn tick(&self) -> Result<u64> {
+        self.tick_at(Utc::now()).await
+    }
+
+    /// Whether `schedule`'s cron is usable, warning once per invalid expression
+    fn cron_valid(&self, schedule: &Schedule) -> bool {
+        let mut invalid = self.invalid.lock().unwrap_or_else(std::sync::PoisonError::into_inner);
+        if invalid.get(&schedule.id) == Some(&schedule.cron) {
+            return false;
+        }
+        match next_run_after(&schedule.cron, Utc::now()) {
+            Ok(_) => {
+                invalid.remove(&schedule.id);
+                true
+            }
+            Err(e) => {
+                warn!("Skipping schedule for {} until its cron is fixed: {:#}", schedule.username, e);
+                invalid.insert(schedule.id, schedule.cron.clone());
+                false
+            }
+        }
+    }
+
+    async fn tick_at(&self, now: DateTime<Utc>) -> Result<u64> {
+        let now_bson = BsonDateTime::from_millis(now.timestamp_millis());
+        let mut enqueued = 0;
+
+        for schedule in self.store.find_due(now_bson).await? {
+            if !self.cron_valid(&schedule) {
+                continue;
+            }
+            let has_active_job = self.store.has_active_job(&schedule.username).await?;
+
+            let action = match evaluate(&schedule, now, has_active_job) {
+                Ok(action) => action,
+                Err(e) => {
+                    warn!("Skipping schedule for {}: {}", schedule.username, e);
+                    continue;
+                }
+            };
+
+            let (next, ran_at) = match &action {
+                ScheduleAction::NotDue => continue,
+                ScheduleAction::Initialize { next } => (*next, None),
+                ScheduleAction::Enqueue { next } | ScheduleAction::SkipActive { next } => {
+                    (*next, Some(now_bson))
+                }
+            };
+
+            // Claim this tick; if another worker advanced the schedule first, it owns the run
+            let next_bson = BsonDateTime::from_millis(next.timestamp_millis());
+            if !self
+                .store
+                .advance(&schedule.id, schedule.next_run_at, next_bson, ran_at)
+                .await?
+            {
+                debug!("Schedule for {} already advanced by another worker", schedule.username);
+                continue;
+            }
+
+            match action {
+                ScheduleAction::Enqueue { .. } => {
+                    let job = Job::queued(&schedule.username, schedule.user_id, JobType::Scheduled);
+                    self.store.enqueue(&job).await?;
+                    info!(
+                        "Enqueued scheduled job {} for {} (next run {})",
+                        job.job_id, schedule.username, next
+                    );
+                    enqueued += 1;
+                }
+                ScheduleAction::SkipActive { .. } => {
+                    info!(
+                        "Skipping scheduled run for {} - previous job still active (next run {})",
+                        schedule.username, next
+                    );
+                }
+                _ => debug!("Initialized schedule for {} (next run {})", schedule.username, next),
+            }
+        }
+
+        Ok(enqueued)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn schedule(cron: &str, next_run_at: Option<DateTime<Utc>>) -> Schedule {
+        Schedule {
+            id: ObjectId::new(),
+            user_id: None,
+            username: "alice".to_string(),
+            cron: cron.to_string(),
+            enabled: true,
+            next_run_at: next_run_at.map(|t| BsonDateTime::from_millis(t.timestamp_millis())),
+            last_run_at: None,
+        }
+    }
+
+    #[test]
+    fn test_next_run_after_five_field() {
+        let now = Utc.with_ymd_and_hms(2024, 1, 1, 10, 30, 0).unwrap();
+        let next = next_run_after("0 * * * *", now).unwrap();
+        assert_eq!(next, Utc.with_ymd_and_hms(2024, 1, 1, 11, 0, 0).unwrap());
+
+        assert!(next_run_after("not a cron", now).is_err());
+    }
+
+    #[test]
+    fn test_due_schedule_enqueues_exactly_one_job() {
+        let now = Utc.with_ymd_and_hms(2024, 1, 1, 10, 30, 0).unwrap();
+        let due = Utc.with_ymd_and_hms(2024, 1, 1, 10, 0, 0).unwrap();
+        let mut s = schedule("0 * * * *", Some(due));
+
+        // Simulate consecutive ticks at the same instant
+        let mut enqueued = 0;
+        for _ in 0..3 {
+            if let ScheduleAction::Enqueue { next } = evaluate(&s, now, false).unwrap() {
+                enqueued += 1;
+                s.next_run_at = Some(BsonDateTime::from_millis(next.timestamp_millis()));
+            }
+        }
+        assert_eq!(enqueued, 1);
+        assert_eq!(evaluate(&s, now, false).unwrap(), ScheduleAction::NotDue);
+    }
+
+    #[test]
+    fn test_due_schedule_with_active_job_is_skipped() {
+        let now = Utc.with_ymd_and_hms(2024, 1, 1, 10, 30, 0).unwrap();
+        let due = Utc.with_ymd_and_hms(2024, 1, 1, 10, 0, 0).unwrap();
+        let s = schedule("0 * * * *", Some(due));
+
+        assert_eq!(
+            evaluate(&s, now, true).unwrap(),
+            ScheduleAction::SkipActive {
+                next: Utc.with_ymd_and_hms(2024, 1, 1, 11, 0, 0).unwrap()
+            }
+        );
+    }
+
+    #[test]
+    fn test_new_schedule_is_initialized_not_enqueued() {
+        let now = Utc.with_ymd_and_hms(2024, 1, 1, 10, 30, 0).unwrap();
+        let s = schedule("0 * * * *", None);
+        assert!(matches!(
+            evaluate(&s, now, false).unwrap(),
+            ScheduleAction::Initialize { .. }
+        ));
+    }
+
+    /// Schedules and queued jobs in memory
+    #[derive(Default)]
+    struct MemoryStore {
+        schedules: Mutex<Vec<Schedule>>,
+        jobs: Mutex<Vec<Job>>,
+    }
+
+    impl ScheduleStore for MemoryStore {
+        async fn find_due(&self, now: BsonDateTime) -> Result<Vec<Schedule>> {
+            let schedules = self.schedules.lock().unwrap();
+            Ok(schedules
+                .iter()
+                .filter(|s| s.enabled && s.next_run_at.is_none_or(|next| next <= now))
+                .cloned()
+                .collect())
+        }
+
+        async fn advance(
+            &self,
+            id: &ObjectId,
+            previous: Option<BsonDateTime>,
+            next: BsonDateTime,
+            ran_at: Option<BsonDateTime>,
+        ) -> Result<bool> {
+            let mut schedules = self.schedules.lock().unwrap();
+            let Some(schedule) = schedules.iter_mut().find(|s| s.id == *id && s.next_run_at == previous) else {
+                return Ok(false);
+            };
+            schedule.next_run_at = Some(next);
+            schedule.last_run_at = ran_at.or(schedule.last_run_at);
+            Ok(true)
+        }
+
+        async fn has_active_job(&self, _username: &str) -> Result<bool> {
+            Ok(false)
+        }
+
+        async fn enqueue(&self, job: &Job) -> Result<()> {
+            self.jobs.lock().unwrap().push(job.clone());
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_tick_enqueues_one_job_per_due_window() {
+        let store = MemoryStore::default();
+        store.schedules.lock().unwrap().push(schedule("0 * * * *", None));
+        store.schedules.lock().unwrap().push(schedule("every hour", None));
+        let scheduler = Scheduler::with_store(store);
+        let at = |h, m, s| Utc.with_ymd_and_hms(2024, 1, 1, h, m, s).unwrap();
+
+        // First sight only initializes; nothing is due until 11:00
+        assert_eq!(scheduler.tick_at(at(10, 30, 0)).await.unwrap(), 0);
+        assert_eq!(scheduler.tick_at(at(10, 59, 30)).await.unwrap(), 0);
+
+        // Ticks every 30s through the 11:00 window enqueue once
+        assert_eq!(scheduler.tick_at(at(11, 0, 10)).await.unwrap(), 1);
+        assert_eq!(scheduler.tick_at(at(11, 0, 40)).await.unwrap(), 0);
+        assert_eq!(scheduler.tick_at(at(11, 30, 0)).await.unwrap(), 0);
+
+        // And once again in the next window
+        assert_eq!(scheduler.tick_at(at(12, 0, 20)).await.unwrap(), 1);
+
+        let jobs = scheduler.store.jobs.lock().unwrap();
+        assert_eq!(jobs.len(), 2);
+        assert!(jobs.iter().all(|j| j.username == "alice" && j.job_type == JobType::Scheduled));
+
+        // The invalid cron was set aside on the first tick and stays there
+        let invalid = scheduler.invalid.lock().unwrap();
+        assert_eq!(invalid.values().collect::<Vec<_>>(), vec!["every hour"]);
+    }
+}
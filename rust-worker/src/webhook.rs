@@ -0,0 +1,329 @@
+//! Post-build webhook notifications
+//!
+//! Users can set `config.webhook_url` to have the worker POST a small JSON
+//! summary once their build completes (e.g. to reload their resolver).
+//! Delivery is best effort: failures are retried, then logged by the caller,
+//! and never fail the job.
+//!
+//! The URL is user input, so only http(s) URLs that resolve to public
+//! addresses are called; the worker must not reach into its own network.
+
+use anyhow::{bail, Result};
+use reqwest::dns::{Addrs, Name, Resolve, Resolving};
+use reqwest::header::CONTENT_TYPE;
+use reqwest::{Client, Url};
+use serde::Serialize;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::warn;
+
+use crate::db::job::JobStatus;
+use crate::db::progress::OutputFile;
+
+/// Retries after the first attempt before giving up
+const MAX_RETRIES: u32 = 2;
+
+/// Backoff before the first retry (doubled for each further retry)
+const BASE_DELAY_MS: u64 = 500;
+
+/// Per-attempt timeout; a slow endpoint shouldn't hold up the next job
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Body POSTed to the user's webhook
+#[derive(Debug, Serialize)]
+pub struct WebhookPayload {
+    pub job_id: String,
+    pub username: String,
+    pub status: &'static str,
+    /// Unique domains in the combined list
+    pub domain_count: u64,
+    /// Names of the generated files
+    pub output_files: Vec<String>,
+    /// Username whose output was copied (copy-on-match builds)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub copied_from: Option<String>,
+}
+
+impl WebhookPayload {
+    /// Payload for a successfully completed build
+    pub fn completed(job_id: &str, username: &str, domain_count: u64, output_files: &[OutputFile]) -> Self {
+        Self {
+            job_id: job_id.to_string(),
+            username: username.to_string(),
+            status: JobStatus::Completed.as_str(),
+            domain_count,
+            output_files: output_files.iter().map(|f| f.name.clone()).collect(),
+            copied_from: None,
+        }
+    }
+}
+
+/// Whether `ip` is on the public internet (not loopback, private, link-local, ...)
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, c, _] = ip.octets();
+            !(ip.is_loopback()
+                || ip.is_private()
+                || ip.is_link_local()
+                || ip.is_broadcast()
+                || ip.is_multicast()
+                // "This network" (0.0.0.0/8), including the unspecified address
+                || a == 0
+                // Carrier-grade NAT (100.64.0.0/10)
+                || (a == 100 && (64..128).contains(&b))
+                // IETF protocol assignments (192.0.0.0/24)
+                || (a == 192 && b == 0 && c == 0)
+                // Benchmarking (198.18.0.0/15)
+                || (a == 198 && (b & 0xfe) == 18))
+        }
+        IpAddr::V6(ip) => {
+            let segments = ip.segments();
+            // NAT64 (64:ff9b::/96) reaches the IPv4 address in the last 32 bits
+            if segments[..6] == [0x64, 0xff9b, 0, 0, 0, 0] {
+                let [.., a, b, c, d] = ip.octets();
+                return is_public(IpAddr::V4(Ipv4Addr::new(a, b, c, d)));
+            }
+            // IPv4-mapped (::ffff:a.b.c.d) and IPv4-compatible (::a.b.c.d);
+            // :: and ::1 land in 0.0.0.0/8 here
+            match ip.to_ipv4() {
+                Some(ip) => is_public(IpAddr::V4(ip)),
+                None => {
+                    let first = segments[0];
+                    !(ip.is_multicast()
+                        // Unique local (fc00::/7) and link-local (fe80::/10)
+                        || (first & 0xfe00) == 0xfc00
+                        || (first & 0xffc0) == 0xfe80)
+                }
+            }
+        }
+    }
+}
+
+/// Resolves hostnames to their public addresses only
+///
+/// Checked at connect time, so a name can't pass validation and then
+/// resolve to an internal address for the actual request.
+struct PublicResolver;
+
+impl Resolve for PublicResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        Box::pin(async move {
+            let host = name.as_str();
+            let addrs: Vec<SocketAddr> = tokio::net::lookup_host((host, 0))
+                .await?
+                .filter(|addr| is_public(addr.ip()))
+                .collect();
+            if addrs.is_empty() {
+                return Err(format!("{} has no public address", host).into());
+            }
+            Ok(Box::new(addrs.into_iter()) as Addrs)
+        })
+    }
+}
+
+/// Delivers webhook payloads over its own HTTP client (cheap to clone)
+///
+/// Separate from the download client: source settings such as
+/// HTTP2_PRIOR_KNOWLEDGE would break ordinary HTTP/1.1 endpoints.
+#[derive(Clone)]
+pub struct WebhookNotifier {
+    client: Client,
+    /// Allow loopback and private targets (tests only)
+    allow_private: bool,
+}
+
+impl WebhookNotifier {
+    pub fn new() -> Result<Self> {
+        let client = Client::builder()
+            // A redirect could point anywhere, including back inside the network
+            .redirect(reqwest::redirect::Policy::none())
+            .dns_resolver(Arc::new(PublicResolver))
+            .user_agent("BlocklistWorker/1.0 (lists.zachlagden.uk)")
+            .build()?;
+        Ok(Self {
+            client,
+            allow_private: false,
+        })
+    }
+
+    /// Reject anything but http(s) URLs, and literal addresses that aren't public
+    ///
+    /// Hostnames are checked again when they resolve (`PublicResolver`).
+    fn validate_url(&self, url: &str) -> Result<Url> {
+        let url = Url::parse(url)?;
+        if !matches!(url.scheme(), "http" | "https") {
+            bail!("Webhook URL scheme {:?} is not allowed", url.scheme());
+        }
+        let ip = match url.host() {
+            Some(url::Host::Ipv4(ip)) => Some(IpAddr::V4(ip)),
+            Some(url::Host::Ipv6(ip)) => Some(IpAddr::V6(ip)),
+            Some(url::Host::Domain(_)) => None,
+            None => bail!("Webhook URL has no host"),
+        };
+        if let Some(ip) = ip {
+            if !self.allow_private && !is_public(ip) {
+                bail!("Webhook URL points to non-public address {}", ip);
+            }
+        }
+        Ok(url)
+    }
+
+    /// POST `payload` to `url`, retrying network errors and non-2xx responses
+    pub async fn notify(&self, url: &str, payload: &WebhookPayload) -> Result<()> {
+        let url = self.validate_url(url)?;
+        let body = serde_json::to_vec(payload)?;
+
+        let mut attempt = 0;
+        loop {
+            let result = self
+                .client
+                .post(url.clone())
+                .timeout(REQUEST_TIMEOUT)
+                .header(CONTENT_TYPE, "application/json")
+                .body(body.clone())
+                .send()
+                .await
+                .and_then(|response| response.error_for_status());
+
+            match result {
+                Ok(_) => return Ok(()),
+                Err(e) if attempt < MAX_RETRIES => {
+                    let delay = Duration::from_millis(BASE_DELAY_MS << attempt);
+                    attempt += 1;
+                    warn!(
+                        "Webhook delivery for job {} failed (attempt {}/{}), retrying in {:?}: {}",
+                        payload.job_id,
+                        attempt,
+                        MAX_RETRIES + 1,
+                        delay,
+                        e
+                    );
+                    tokio::time::sleep(delay).await;
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn test_completed_job_posts_payload() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+
+        // Mock endpoint: fail the first request, record and accept the second
+        let server = tokio::spawn(async move {
+            let mut requests = Vec::new();
+            for status in ["500 Internal Server Error", "204 No Content"] {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                let mut request = Vec::new();
+                let mut buf = [0u8; 4096];
+                // Read until the headers and the whole body have arrived
+                loop {
+                    let n = socket.read(&mut buf).await.unwrap();
+                    request.extend_from_slice(&buf[..n]);
+                    let text = String::from_utf8_lossy(&request);
+                    if let Some((head, body)) = text.split_once("\r\n\r\n") {
+                        let length = head
+                            .lines()
+                            .find_map(|l| l.to_lowercase().strip_prefix("content-length: ").map(str::to_string))
+                            .and_then(|v| v.trim().parse::<usize>().ok())
+                            .unwrap_or(0);
+                        if body.len() >= length {
+                            break;
+                        }
+                    }
+                    if n == 0 {
+                        break;
+                    }
+                }
+                let response = format!("HTTP/1.1 {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n", status);
+                socket.write_all(response.as_bytes()).await.unwrap();
+                requests.push(String::from_utf8(request).unwrap());
+            }
+            requests
+        });
+
+        let files = vec![
+            OutputFile {
+                name: "all_domains_hosts.txt.gz".to_string(),
+                format: "hosts".to_string(),
+                size_bytes: 100,
+                domain_count: 2,
+                compression: "gz".to_string(),
+            },
+            OutputFile {
+                name: "ads_hosts.txt.gz".to_string(),
+                format: "hosts".to_string(),
+                size_bytes: 60,
+                domain_count: 1,
+                compression: "gz".to_string(),
+            },
+        ];
+        let payload = WebhookPayload::completed("job-1", "alice", 2, &files);
+        let notifier = WebhookNotifier {
+            client: Client::new(),
+            allow_private: true,
+        };
+        notifier.notify(&url, &payload).await.unwrap();
+
+        let requests = server.await.unwrap();
+        assert_eq!(requests.len(), 2);
+        let request = &requests[1];
+        assert!(request.starts_with("POST /hook "));
+        assert!(request.to_lowercase().contains("content-type: application/json"));
+
+        let body: serde_json::Value = serde_json::from_str(request.split_once("\r\n\r\n").unwrap().1).unwrap();
+        assert_eq!(
+            body,
+            serde_json::json!({
+                "job_id": "job-1",
+                "username": "alice",
+                "status": "completed",
+                "domain_count": 2,
+                "output_files": ["all_domains_hosts.txt.gz", "ads_hosts.txt.gz"],
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_internal_targets_are_rejected() {
+        let notifier = WebhookNotifier::new().unwrap();
+        let payload = WebhookPayload::completed("job-1", "alice", 0, &[]);
+        for url in [
+            "ftp://example.com/hook",
+            "file:///etc/passwd",
+            "http://127.0.0.1:8080/hook",
+            "http://10.1.2.3/hook",
+            "http://169.254.169.254/latest/meta-data",
+            "http://[::1]/hook",
+            "http://[fd00::1]/hook",
+            "http://[::ffff:192.168.1.1]/hook",
+            "http://0.1.2.3/hook",
+            "http://224.0.0.1/hook",
+            "http://192.0.0.8/hook",
+            "http://198.19.0.1/hook",
+            "http://[ff02::1]/hook",
+            "http://[::10.0.0.1]/hook",
+            "http://[64:ff9b::7f00:1]/hook",
+            // Hostnames are checked when they resolve
+            "http://localhost:1/hook",
+        ] {
+            assert!(notifier.notify(url, &payload).await.is_err(), "{}", url);
+        }
+
+        assert!(is_public("93.184.216.34".parse().unwrap()));
+        assert!(is_public("2606:2800:220:1::".parse().unwrap()));
+        assert!(!is_public("100.64.0.1".parse().unwrap()));
+        assert!(is_public("64:ff9b::5db8:d822".parse().unwrap()));
+        assert!(is_public("198.20.0.1".parse().unwrap()));
+    }
+}
@@ -1,6 +1,7 @@
 use rayon::prelude::*;
-use regex::RegexSet;
-use std::collections::HashSet;
+use regex::{Regex, RegexSet};
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
 use tracing::{debug, info, warn};
 
 use crate::db::progress::{WhitelistPatternMatch, WhitelistProgress};
@@ -30,6 +31,147 @@ impl std::fmt::Display for PatternType {
 pub struct PatternInfo {
     pub original: String,
     pub pattern_type: PatternType,
+    /// Normalized form used for matching (lowercase, `@@` stripped)
+    pub normalized: String,
+    /// Compiled matcher for wildcard and regex patterns
+    pub matcher: Option<Regex>,
+}
+
+/// Lowercase a domain or pattern, converting Unicode labels to punycode (`xn--`)
+///
+/// Works label by label so wildcard patterns (`*.пример.рф`) convert too; a
+/// label IDNA rejects is just lowercased.
+fn to_ascii_idn(domain: &str) -> String {
+    if domain.is_ascii() {
+        return domain.to_ascii_lowercase();
+    }
+
+    domain
+        .split('.')
+        .map(|label| {
+            if label.is_ascii() {
+                return label.to_ascii_lowercase();
+            }
+            match url::Host::parse(label) {
+                Ok(url::Host::Domain(ascii)) => ascii,
+                _ => label.to_lowercase(),
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(".")
+}
+
+/// Trim, lowercase and punycode a domain, borrowing when it's already normalized
+fn normalize_domain(domain: &str) -> Cow<'_, str> {
+    let domain = domain.trim();
+    if !domain.is_ascii() {
+        Cow::Owned(to_ascii_idn(domain))
+    } else if domain.bytes().any(|b| b.is_ascii_uppercase()) {
+        Cow::Owned(domain.to_ascii_lowercase())
+    } else {
+        Cow::Borrowed(domain)
+    }
+}
+
+/// Scope keyword that exempts categories from whitelisting: `exempt:malware,phishing`
+const EXEMPT_SCOPE: &str = "exempt";
+
+/// Scope name used for sources without a category
+const UNCATEGORIZED_SCOPE: &str = "uncategorized";
+
+/// Split a `category:pattern` scope prefix off a whitelist line
+///
+/// Only a plain category name counts as a scope, so regexes like `/(?:ads)/`
+/// are never mistaken for one.
+fn split_scope(pattern: &str) -> Option<(String, &str)> {
+    let (scope, rest) = pattern.split_once(':')?;
+    let is_category = !scope.is_empty()
+        && scope.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+    if is_category && !rest.trim().is_empty() {
+        Some((scope.to_lowercase(), rest.trim()))
+    } else {
+        None
+    }
+}
+
+/// Rewrite a category's own whitelist as `category:pattern` lines for `from_content`
+///
+/// Appended to the global whitelist, the lines build that category's scoped
+/// manager, so the category gets global + its own patterns. Lines that carry a
+/// scope of their own are dropped (a warning each), as is the whole list when
+/// the category isn't a plain name (its lines would otherwise apply everywhere).
+pub fn scope_category_whitelist(category: &str, content: &str) -> (String, Vec<String>) {
+    let mut scoped = String::new();
+    let mut warnings = Vec::new();
+    if split_scope(&format!("{}:x", category)).is_none_or(|(scope, _)| scope == EXEMPT_SCOPE) {
+        warnings.push(format!("Whitelist for category '{}' ignored: not a plain category name", category));
+        return (scoped, warnings);
+    }
+
+    for line in content.lines() {
+        let pattern = match line.find('#') {
+            Some(idx) => line[..idx].trim(),
+            None => line.trim(),
+        };
+        if pattern.is_empty() {
+            continue;
+        }
+        if split_scope(pattern).is_some() {
+            warnings.push(format!(
+                "Skipped '{}' in the whitelist for category '{}': scopes only work in the main whitelist",
+                pattern, category
+            ));
+            continue;
+        }
+        scoped.push_str(&format!("{}:{}\n", category, pattern));
+    }
+    (scoped, warnings)
+}
+
+/// Varied domains a sane pattern shouldn't mostly match, for spotting over-broad ones
+const BREADTH_SAMPLE: &[&str] = &[
+    "ads.doubleclick.net",
+    "tracker.example.com",
+    "malware.example.org",
+    "telemetry.vendor.io",
+    "pixel.social.com",
+    "ad.news.co.uk",
+    "metrics.shop.de",
+    "cdn.track.example.fr",
+    "a.b.c.example.ru",
+    "x1.example.xyz",
+    "phish.login.example.info",
+    "stats.app.dev",
+    "banner.example.jp",
+    "analytics.example.com.br",
+    "beacon.example.net",
+    "counter.example.nl",
+];
+
+/// Share of `BREADTH_SAMPLE` a pattern may match before it counts as over-broad
+const MAX_BREADTH_SHARE: f64 = 0.5;
+
+/// Why a pattern would whitelist far more than intended, if it would
+///
+/// Structurally broad patterns (`*`, `*.com`, `@@com`: a whole TLD or
+/// everything) are caught directly; anything else that matches most of a
+/// varied sample of domains (e.g. `/.*/`) is caught by trying it.
+fn overbroad_reason(pattern: &PatternInfo) -> Option<String> {
+    let literal = pattern.normalized.replace('*', "");
+    let literal = literal.trim_matches('.');
+    match pattern.pattern_type {
+        PatternType::Wildcard if literal.is_empty() => return Some("matches every domain".to_string()),
+        PatternType::Wildcard | PatternType::Subdomain if !literal.contains('.') => {
+            return Some(format!("covers every domain under .{}", literal));
+        }
+        PatternType::Exact | PatternType::Subdomain => return None,
+        _ => {}
+    }
+
+    let matcher = pattern.matcher.as_ref()?;
+    let matched = BREADTH_SAMPLE.iter().filter(|domain| matcher.is_match(domain)).count();
+    (matched as f64 >= BREADTH_SAMPLE.len() as f64 * MAX_BREADTH_SHARE)
+        .then(|| format!("matches {} of {} unrelated test domains", matched, BREADTH_SAMPLE.len()))
 }
 
 /// Optimized whitelist manager with O(1) exact lookups and batch regex matching
@@ -42,6 +184,16 @@ pub struct WhitelistManager {
     regex_set: Option<RegexSet>,
     /// Original patterns for progress reporting
     all_patterns: Vec<PatternInfo>,
+    /// Patterns that only apply to one category (`category:pattern`)
+    scoped: HashMap<String, WhitelistManager>,
+    /// Categories no whitelist pattern applies to (`exempt:category`)
+    exempt_categories: HashSet<String>,
+    /// Patterns that couldn't be compiled (and so are never applied)
+    parse_errors: Vec<String>,
+    /// Patterns that would whitelist almost everything (still applied)
+    overbroad: Vec<String>,
+    /// Example domains reported per matched pattern (0 = none)
+    sample_count: usize,
 }
 
 impl WhitelistManager {
@@ -52,6 +204,11 @@ impl WhitelistManager {
             subdomain_patterns: Vec::new(),
             regex_set: None,
             all_patterns: Vec::new(),
+            scoped: HashMap::new(),
+            exempt_categories: HashSet::new(),
+            parse_errors: Vec::new(),
+            overbroad: Vec::new(),
+            sample_count: 0,
         }
     }
 
@@ -61,6 +218,9 @@ impl WhitelistManager {
         let mut subdomain_patterns = Vec::new();
         let mut regex_strings = Vec::new();
         let mut all_patterns = Vec::new();
+        let mut scoped_lines: HashMap<String, Vec<&str>> = HashMap::new();
+        let mut exempt_categories = HashSet::new();
+        let mut parse_errors = Vec::new();
 
         for line in content.lines() {
             let line = line.trim();
@@ -73,43 +233,77 @@ impl WhitelistManager {
                 continue;
             }
 
+            // Category scoping: "exempt:malware" or "malware:pattern"
+            if let Some((scope, rest)) = split_scope(pattern) {
+                if scope == EXEMPT_SCOPE {
+                    exempt_categories.extend(
+                        rest.split(',')
+                            .map(|c| c.trim().to_lowercase())
+                            .filter(|c| !c.is_empty()),
+                    );
+                } else {
+                    scoped_lines.entry(scope).or_default().push(rest);
+                }
+                continue;
+            }
+
             // Regex pattern: /pattern/
             if pattern.starts_with('/') && pattern.ends_with('/') && pattern.len() > 2 {
                 let regex_str = &pattern[1..pattern.len() - 1];
+                // A bad regex would also sink the RegexSet, so leave it out of both
+                let matcher = match Regex::new(regex_str) {
+                    Ok(matcher) => matcher,
+                    Err(e) => {
+                        warn!("Skipping invalid whitelist regex {}: {}", pattern, e);
+                        parse_errors.push(format!("Invalid whitelist regex {}: {}", pattern, e));
+                        continue;
+                    }
+                };
                 regex_strings.push(regex_str.to_string());
                 all_patterns.push(PatternInfo {
                     original: pattern.to_string(),
                     pattern_type: PatternType::Regex,
+                    normalized: regex_str.to_string(),
+                    matcher: Some(matcher),
                 });
             }
             // Subdomain pattern: @@domain.com
             else if pattern.starts_with("@@") {
-                let domain = pattern.trim_start_matches("@@").to_lowercase();
+                let domain = to_ascii_idn(pattern.trim_start_matches("@@"));
                 let dotted = format!(".{}", domain);
-                subdomain_patterns.push((domain, dotted));
+                subdomain_patterns.push((domain.clone(), dotted));
                 all_patterns.push(PatternInfo {
                     original: pattern.to_string(),
                     pattern_type: PatternType::Subdomain,
+                    normalized: domain,
+                    matcher: None,
                 });
             }
             // Wildcard pattern: *.domain.com
             else if pattern.contains('*') {
+                let normalized = to_ascii_idn(pattern);
                 let regex_str = format!(
                     "^{}$",
-                    regex::escape(pattern).replace(r"\*", ".*")
+                    regex::escape(&normalized).replace(r"\*", ".*")
                 );
+                let matcher = Regex::new(&regex_str).ok();
                 regex_strings.push(regex_str);
                 all_patterns.push(PatternInfo {
                     original: pattern.to_string(),
                     pattern_type: PatternType::Wildcard,
+                    normalized,
+                    matcher,
                 });
             }
             // Exact match
             else {
-                exact_patterns.insert(pattern.to_lowercase());
+                let normalized = to_ascii_idn(pattern);
+                exact_patterns.insert(normalized.clone());
                 all_patterns.push(PatternInfo {
                     original: pattern.to_string(),
                     pattern_type: PatternType::Exact,
+                    normalized,
+                    matcher: None,
                 });
             }
         }
@@ -135,17 +329,75 @@ impl WhitelistManager {
             regex_strings.len()
         );
 
+        let mut overbroad: Vec<String> = all_patterns
+            .iter()
+            .filter_map(|pattern| {
+                let reason = overbroad_reason(pattern)?;
+                warn!("Whitelist pattern {} is over-broad: {}", pattern.original, reason);
+                Some(format!(
+                    "Whitelist pattern {} is over-broad ({}) and would gut the blocklist",
+                    pattern.original, reason
+                ))
+            })
+            .collect();
+
+        // Build a nested manager per scoped category
+        let scoped: HashMap<String, WhitelistManager> = scoped_lines
+            .into_iter()
+            .map(|(scope, lines)| {
+                info!("Loading whitelist patterns scoped to category '{}'", scope);
+                let manager = WhitelistManager::from_content(&lines.join("\n"));
+                parse_errors.extend(manager.parse_errors.iter().map(|e| format!("{} (category {})", e, scope)));
+                overbroad.extend(manager.overbroad.iter().map(|w| format!("{} (category {})", w, scope)));
+                (scope, manager)
+            })
+            .collect();
+
+        if !exempt_categories.is_empty() {
+            info!("Categories exempt from whitelisting: {:?}", exempt_categories);
+        }
+
         Self {
             exact_patterns,
             subdomain_patterns,
             regex_set,
             all_patterns,
+            scoped,
+            exempt_categories,
+            parse_errors,
+            overbroad,
+            sample_count: 0,
+        }
+    }
+
+    /// Report up to `count` example domains per matched pattern (WHITELIST_SAMPLE_COUNT)
+    ///
+    /// Samples are the alphabetically first matches, so reruns show the same ones.
+    pub fn with_sample_count(mut self, count: usize) -> Self {
+        self.sample_count = count;
+        for scoped in self.scoped.values_mut() {
+            scoped.sample_count = count;
         }
+        self
+    }
+
+    /// Patterns that failed to parse and are not applied
+    pub fn parse_errors(&self) -> &[String] {
+        &self.parse_errors
+    }
+
+    /// Patterns broad enough to whitelist almost everything (they are applied)
+    pub fn overbroad_warnings(&self) -> &[String] {
+        &self.overbroad
     }
 
     /// Check if a domain is whitelisted (optimized: O(1) for exact, then linear for subdomain/regex)
     #[inline]
     pub fn is_whitelisted(&self, domain: &str) -> bool {
+        // Extracted domains are already normalized, so this rarely allocates
+        let domain = normalize_domain(domain);
+        let domain = domain.as_ref();
+
         // O(1) exact match check
         if self.exact_patterns.contains(domain) {
             return true;
@@ -168,38 +420,23 @@ impl WhitelistManager {
         false
     }
 
-    /// Check if domain matches a specific pattern
+    /// Check if domain matches a specific pattern (uses the pattern's stored normalized form)
     fn matches_pattern(&self, domain: &str, pattern: &PatternInfo) -> bool {
+        let domain = normalize_domain(domain);
+        let domain = domain.as_ref();
+
         match pattern.pattern_type {
-            PatternType::Exact => {
-                pattern.original.to_lowercase() == domain
-            }
+            PatternType::Exact => pattern.normalized == domain,
             PatternType::Subdomain => {
-                let suffix = pattern.original.trim_start_matches("@@").to_lowercase();
-                let dotted = format!(".{}", suffix);
-                domain == suffix || domain.ends_with(&dotted)
-            }
-            PatternType::Wildcard => {
-                // Convert wildcard to regex and match
-                let regex_str = format!(
-                    "^{}$",
-                    regex::escape(&pattern.original).replace(r"\*", ".*")
-                );
-                if let Ok(re) = regex::Regex::new(&regex_str) {
-                    re.is_match(domain)
-                } else {
-                    false
-                }
-            }
-            PatternType::Regex => {
-                // Compile and match the specific regex
-                let regex_str = &pattern.original[1..pattern.original.len() - 1];
-                if let Ok(re) = regex::Regex::new(regex_str) {
-                    re.is_match(domain)
-                } else {
-                    false
-                }
+                let suffix = pattern.normalized.as_str();
+                domain == suffix
+                    || (domain.ends_with(suffix)
+                        && domain.as_bytes()[domain.len() - suffix.len() - 1] == b'.')
             }
+            PatternType::Wildcard | PatternType::Regex => pattern
+                .matcher
+                .as_ref()
+                .is_some_and(|re| re.is_match(domain)),
         }
     }
 
@@ -216,7 +453,7 @@ impl WhitelistManager {
         let total = domains.len();
 
         // Partition into remaining and removed
-        let (remaining, removed_domains): (HashSet<String>, Vec<String>) = domains
+        let (remaining, mut removed_domains): (HashSet<String>, Vec<String>) = domains
             .into_par_iter()
             .partition_map(|domain| {
                 if self.is_whitelisted(&domain) {
@@ -235,35 +472,44 @@ impl WhitelistManager {
             total
         );
 
+        // Sorted so each pattern's samples are its first matches
+        if self.sample_count > 0 {
+            removed_domains.par_sort_unstable();
+        }
+
         // Count matches per pattern, deduplicating by pattern string
         use std::collections::HashMap;
-        let mut pattern_counts: HashMap<String, (String, u64)> = HashMap::new();
+        let mut pattern_counts: HashMap<String, (String, u64, Vec<String>)> = HashMap::new();
 
         for p in &self.all_patterns {
-            let count = removed_domains
-                .iter()
-                .filter(|d| self.matches_pattern(d, p))
-                .count() as u64;
+            let mut count = 0;
+            let mut samples = Vec::new();
+            for domain in removed_domains.iter().filter(|d| self.matches_pattern(d, p)) {
+                count += 1;
+                if samples.len() < self.sample_count {
+                    samples.push(domain.clone());
+                }
+            }
 
             if count > 0 {
                 pattern_counts
                     .entry(p.original.clone())
-                    .or_insert((p.pattern_type.to_string(), count));
+                    .or_insert((p.pattern_type.to_string(), count, samples));
             }
         }
 
         let mut pattern_matches: Vec<WhitelistPatternMatch> = pattern_counts
             .into_iter()
-            .map(|(pattern, (pattern_type, match_count))| WhitelistPatternMatch {
+            .map(|(pattern, (pattern_type, match_count, samples))| WhitelistPatternMatch {
                 pattern,
                 pattern_type,
                 match_count,
-                samples: Vec::new(),
+                samples,
             })
             .collect();
 
-        // Sort by match count descending
-        pattern_matches.sort_by(|a, b| b.match_count.cmp(&a.match_count));
+        // Sort by match count descending; ties by pattern so the top 20 never depend on hash order
+        pattern_matches.sort_by(|a, b| b.match_count.cmp(&a.match_count).then_with(|| a.pattern.cmp(&b.pattern)));
 
         // Limit to top 20
         pattern_matches.truncate(20);
@@ -271,12 +517,43 @@ impl WhitelistManager {
         (remaining, removed, pattern_matches)
     }
 
+    /// Filter one category's domains, honoring scoped patterns and exemptions
+    ///
+    /// Applies the global patterns plus any patterns scoped to `category`
+    /// (None = uncategorized). Exempt categories are returned untouched.
+    /// Returns (remaining_domains, removed_count, scoped_pattern_matches); the
+    /// matches cover only the scoped patterns, reported as `category:pattern`.
+    pub fn filter_category(
+        &self,
+        domains: HashSet<String>,
+        category: Option<&str>,
+    ) -> (HashSet<String>, u64, Vec<WhitelistPatternMatch>) {
+        let scope = category.unwrap_or(UNCATEGORIZED_SCOPE).to_lowercase();
+        if self.exempt_categories.contains(&scope) {
+            return (domains, 0, Vec::new());
+        }
+
+        let (remaining, removed, _) = self.filter_domains(domains);
+
+        match self.scoped.get(&scope) {
+            Some(scoped) => {
+                let (remaining, scoped_removed, mut matches) = scoped.filter_domains(remaining);
+                for m in &mut matches {
+                    m.pattern = format!("{}:{}", scope, m.pattern);
+                }
+                (remaining, removed + scoped_removed, matches)
+            }
+            None => (remaining, removed, Vec::new()),
+        }
+    }
+
     /// Create progress report for whitelist stage
     pub fn create_progress(
         &self,
         domains_before: u64,
         domains_after: u64,
         pattern_matches: Vec<WhitelistPatternMatch>,
+        category_removed: HashMap<String, u64>,
     ) -> WhitelistProgress {
         WhitelistProgress {
             domains_before,
@@ -284,6 +561,7 @@ impl WhitelistManager {
             total_removed: domains_before.saturating_sub(domains_after),
             processing: false,
             patterns: pattern_matches,
+            category_removed,
         }
     }
 
@@ -298,14 +576,22 @@ impl WhitelistManager {
                 p.original.to_lowercase()
             })
             .collect();
+        for (scope, scoped) in &self.scoped {
+            patterns.extend(
+                scoped
+                    .patterns_as_strings()
+                    .into_iter()
+                    .map(|p| format!("{}:{}", scope, p)),
+            );
+        }
+        patterns.extend(
+            self.exempt_categories
+                .iter()
+                .map(|c| format!("{}:{}", EXEMPT_SCOPE, c)),
+        );
         patterns.sort();
         patterns
     }
-
-    /// Check if whitelist is empty
-    pub fn is_empty(&self) -> bool {
-        self.all_patterns.is_empty()
-    }
 }
 
 impl Default for WhitelistManager {
@@ -378,4 +664,127 @@ mod tests {
         // Not matched
         assert!(!manager.is_whitelisted("other.com"));
     }
+
+    #[test]
+    fn test_mixed_case_patterns_and_domains() {
+        let content = "Example.COM\n@@Google.Com\n*.Ads.Com";
+        let manager = WhitelistManager::from_content(content);
+
+        for domain in ["example.com", "EXAMPLE.com", " example.com\t", "mail.google.com", "x.ADS.com"] {
+            assert!(manager.is_whitelisted(domain), "{} should be whitelisted", domain);
+            assert!(
+                manager.all_patterns.iter().any(|p| manager.matches_pattern(domain, p)),
+                "{} should match a pattern",
+                domain
+            );
+        }
+        assert!(!manager.is_whitelisted("notgoogle.com"));
+
+        // Sample counting attributes removals to the original pattern text
+        let domains: HashSet<String> = ["example.com", "a.ads.com", "b.ads.com", "keep.com"]
+            .iter()
+            .map(|d| d.to_string())
+            .collect();
+        let (remaining, removed, matches) = manager.filter_domains(domains);
+        assert_eq!(remaining.len(), 1);
+        assert_eq!(removed, 3);
+        assert_eq!(matches[0].pattern, "*.Ads.Com");
+        assert_eq!(matches[0].match_count, 2);
+        assert_eq!(matches[1].pattern, "Example.COM");
+    }
+
+    #[test]
+    fn test_category_scoped_patterns() {
+        let content = "exempt:malware\nads.com\nadvertising:track.com\n/(?:cdn)\\.example\\.com/";
+        let manager = WhitelistManager::from_content(content);
+
+        let domains = || -> HashSet<String> {
+            ["ads.com", "track.com", "cdn.example.com", "evil.com"]
+                .iter()
+                .map(|d| d.to_string())
+                .collect()
+        };
+
+        // Protected category keeps everything, even globally whitelisted domains
+        let (remaining, removed, _) = manager.filter_category(domains(), Some("malware"));
+        assert_eq!(remaining.len(), 4);
+        assert_eq!(removed, 0);
+
+        // Scoped pattern applies only to its own category
+        let (remaining, removed, matches) = manager.filter_category(domains(), Some("advertising"));
+        assert!(!remaining.contains("ads.com"));
+        assert!(!remaining.contains("track.com"));
+        assert!(!remaining.contains("cdn.example.com"));
+        assert!(remaining.contains("evil.com"));
+        assert_eq!(removed, 3);
+        assert_eq!(matches[0].pattern, "advertising:track.com");
+
+        let (remaining, _, _) = manager.filter_category(domains(), Some("tracking"));
+        assert!(!remaining.contains("ads.com"));
+        assert!(remaining.contains("track.com"));
+    }
+
+    #[test]
+    fn test_unicode_patterns_match_punycode_domains() {
+        let manager = WhitelistManager::from_content("@@пример.рф\nbücher.de\n*.münchen.de");
+
+        // Subdomain, exact and wildcard patterns all compare in punycode
+        assert!(manager.is_whitelisted("xn--e1afmkfd.xn--p1ai"));
+        assert!(manager.is_whitelisted("cdn.xn--e1afmkfd.xn--p1ai"));
+        assert!(manager.is_whitelisted("xn--bcher-kva.de"));
+        assert!(manager.is_whitelisted("www.xn--mnchen-3ya.de"));
+        assert!(!manager.is_whitelisted("xn--mnchen-3ya.de"));
+
+        // Unicode domains are normalized the same way
+        assert!(manager.is_whitelisted("Bücher.de"));
+        assert!(!manager.is_whitelisted("buecher.de"));
+    }
+
+    #[test]
+    fn test_overbroad_patterns_flagged() {
+        let manager = WhitelistManager::from_content("*.com\n*.example.com\n@@good.org\ncom");
+        assert_eq!(
+            manager.overbroad_warnings(),
+            ["Whitelist pattern *.com is over-broad (covers every domain under .com) and would gut the blocklist"]
+        );
+        // Still applied: the warning is all it does
+        assert!(manager.is_whitelisted("tracker.evil.com"));
+
+        let manager = WhitelistManager::from_content("*\n/.*/\n/^ads\\./\n@@net\nads:*.*");
+        let warnings = manager.overbroad_warnings();
+        assert_eq!(warnings.len(), 4, "{:?}", warnings);
+        assert!(warnings[0].contains("Whitelist pattern * is over-broad (matches every domain)"));
+        assert!(warnings[1].contains("/.*/ is over-broad (matches 16 of 16 unrelated test domains)"));
+        assert!(warnings[2].contains("@@net is over-broad (covers every domain under .net)"));
+        assert!(warnings[3].starts_with("Whitelist pattern *.* is over-broad") && warnings[3].ends_with("(category ads)"));
+    }
+
+    #[test]
+    fn test_sample_count_limits_samples() {
+        let domains: HashSet<String> = ["e.ads.com", "a.ads.com", "d.ads.com", "b.ads.com", "c.ads.com", "keep.net"]
+            .iter()
+            .map(|d| d.to_string())
+            .collect();
+        let whitelist = "*.ads.com\nb.ads.com\nads:@@x.net";
+
+        let samples = |count: usize| {
+            let manager = WhitelistManager::from_content(whitelist).with_sample_count(count);
+            let (_, removed, matches) = manager.filter_domains(domains.clone());
+            assert_eq!(removed, 5);
+            let wildcard = matches.iter().find(|m| m.pattern == "*.ads.com").unwrap();
+            assert_eq!(wildcard.match_count, 5);
+            (wildcard.samples.clone(), manager.scoped["ads"].sample_count)
+        };
+
+        // The first matches alphabetically, never more than configured
+        let (three, scoped) = samples(3);
+        assert_eq!(three, vec!["a.ads.com", "b.ads.com", "c.ads.com"]);
+        assert_eq!(scoped, 3);
+        assert_eq!(samples(10).0.len(), 5);
+
+        // 0 turns samples off (and is the default)
+        assert!(samples(0).0.is_empty());
+        let (_, _, matches) = WhitelistManager::from_content(whitelist).filter_domains(domains);
+        assert!(matches.iter().all(|m| m.samples.is_empty()));
+    }
 }
@@ -7,13 +7,25 @@ use tokio::sync::Mutex;
 use tokio::time::{interval, sleep};
 use tracing::{debug, error, info, warn};
 
+use crate::cancel::CancelToken;
 use crate::config::Config;
-use crate::db::job::JobRepository;
+use crate::db::job::{JobRepository, JobType};
+use crate::db::stats::StatsRepository;
+use crate::db::worker::{WorkerRecord, WorkerRepository};
+use crate::domain_stats;
+use crate::generator::FilenameTemplate;
 use crate::processor::JobProcessor;
+use crate::rebuild::BulkRebuild;
+use crate::reload::{self, SharedConfig};
+use crate::scheduler::{self, Scheduler};
+
+/// Seconds between finished-job retention sweeps
+const JOB_RETENTION_INTERVAL_SECS: u64 = 3600;
 
 /// Worker that processes jobs from the queue
 pub struct Worker {
-    config: Config,
+    /// Reloaded on SIGHUP; each job runs with a snapshot taken when it starts
+    config: SharedConfig,
     db: Database,
     shutdown: Arc<AtomicBool>,
     current_job: Arc<Mutex<Option<String>>>,
@@ -21,7 +33,7 @@ pub struct Worker {
 
 impl Worker {
     /// Create a new worker
-    pub fn new(config: Config, db: Database, shutdown: Arc<AtomicBool>) -> Self {
+    pub fn new(config: SharedConfig, db: Database, shutdown: Arc<AtomicBool>) -> Self {
         Self {
             config,
             db,
@@ -30,15 +42,54 @@ impl Worker {
         }
     }
 
+    /// Current config (settings reloaded on SIGHUP included)
+    fn config(&self) -> Config {
+        reload::snapshot(&self.config)
+    }
+
     /// Start the worker main loop
     pub async fn run(&self) -> Result<()> {
-        info!("Worker {} starting", self.config.worker_id);
+        // Background tasks are set up once, from the config at startup
+        let config = self.config();
+        info!("Worker {} starting", config.worker_id);
+
+        let job_repo = JobRepository::new(&self.db, config.worker_id.clone());
+        let worker_repo = WorkerRepository::new(&self.db);
+
+        // Register in the workers collection for operator dashboards
+        if let Err(e) = worker_repo.register(&WorkerRecord::current(&config.worker_id)).await {
+            warn!("Failed to register worker: {}", e);
+        }
 
-        let job_repo = JobRepository::new(&self.db, self.config.worker_id.clone());
+        if let Err(e) = job_repo.ensure_indexes().await {
+            warn!("Failed to create job indexes: {}", e);
+        }
 
         // Start heartbeat task
         let heartbeat_handle = self.spawn_heartbeat_task();
 
+        // Start scheduler task (only when the worker owns scheduling)
+        let scheduler_handle = if config.internal_scheduler {
+            info!("Internal scheduler enabled");
+            Some(self.spawn_scheduler_task())
+        } else {
+            None
+        };
+
+        // Start finished-job retention sweep (0 days = keep forever)
+        let retention_handle = if config.job_retention_days > 0 {
+            Some(self.spawn_job_retention_task())
+        } else {
+            None
+        };
+
+        // Start top domains aggregation task (0 interval = disabled)
+        let stats_handle = if config.top_domains_interval_secs > 0 {
+            Some(self.spawn_top_domains_task())
+        } else {
+            None
+        };
+
         // Main job processing loop
         loop {
             if self.shutdown.load(Ordering::Relaxed) {
@@ -57,21 +108,30 @@ impl Worker {
                         *current = Some(job.job_id.clone());
                     }
 
-                    // Create processor for this job
-                    let processor = match JobProcessor::new(
-                        self.config.clone(),
-                        JobRepository::new(&self.db, self.config.worker_id.clone()),
-                        &self.db,
-                    ) {
-                        Ok(p) => p,
-                        Err(e) => {
-                            error!("Failed to create processor: {}", e);
-                            continue;
-                        }
+                    let outcome = if job.job_type == JobType::RebuildAll {
+                        // Bulk rebuild only fans out per-user jobs
+                        BulkRebuild::new(&self.db, config.worker_id.clone())
+                            .run(&job)
+                            .await
+                    } else {
+                        // Create processor for this job
+                        let processor = match JobProcessor::new(
+                            self.config(),
+                            JobRepository::new(&self.db, config.worker_id.clone()),
+                            &self.db,
+                        ) {
+                            Ok(p) => p.with_cancel(CancelToken::from_flag(Arc::clone(&self.shutdown))),
+                            Err(e) => {
+                                error!("Failed to create processor: {}", e);
+                                continue;
+                            }
+                        };
+
+                        // Process the job
+                        processor.process_job_with_deadline(&job).await
                     };
 
-                    // Process the job
-                    if let Err(e) = processor.process_job(&job).await {
+                    if let Err(e) = outcome {
                         error!("Job {} failed with error: {}", job.job_id, e);
 
                         // Mark as failed
@@ -103,22 +163,36 @@ impl Worker {
 
         // Cleanup
         heartbeat_handle.abort();
+        if let Some(handle) = scheduler_handle {
+            handle.abort();
+        }
+        if let Some(handle) = stats_handle {
+            handle.abort();
+        }
+        if let Some(handle) = retention_handle {
+            handle.abort();
+        }
         self.release_jobs(&job_repo).await?;
+        if let Err(e) = worker_repo.mark_stopped(&config.worker_id).await {
+            warn!("Failed to mark worker as stopped: {}", e);
+        }
 
-        info!("Worker {} stopped", self.config.worker_id);
+        info!("Worker {} stopped", config.worker_id);
         Ok(())
     }
 
     /// Spawn heartbeat background task
     fn spawn_heartbeat_task(&self) -> tokio::task::JoinHandle<()> {
+        let config = self.config();
         let db = self.db.clone();
-        let worker_id = self.config.worker_id.clone();
+        let worker_id = config.worker_id.clone();
         let current_job = Arc::clone(&self.current_job);
-        let heartbeat_interval = self.config.heartbeat_interval_secs;
+        let heartbeat_interval = config.heartbeat_interval_secs;
         let shutdown = Arc::clone(&self.shutdown);
 
         tokio::spawn(async move {
-            let job_repo = JobRepository::new(&db, worker_id);
+            let job_repo = JobRepository::new(&db, worker_id.clone());
+            let worker_repo = WorkerRepository::new(&db);
             let mut ticker = interval(Duration::from_secs(heartbeat_interval));
 
             loop {
@@ -128,6 +202,11 @@ impl Worker {
                     break;
                 }
 
+                // Worker liveness (independent of whether a job is running)
+                if let Err(e) = worker_repo.heartbeat(&worker_id).await {
+                    warn!("Worker heartbeat error: {}", e);
+                }
+
                 // Send heartbeat for current job
                 let job_id = {
                     let current = current_job.lock().await;
@@ -145,6 +224,107 @@ impl Worker {
         })
     }
 
+    /// Spawn scheduler background task
+    fn spawn_scheduler_task(&self) -> tokio::task::JoinHandle<()> {
+        let config = self.config();
+        let scheduler = Scheduler::new(&self.db, config.worker_id.clone());
+        let shutdown = Arc::clone(&self.shutdown);
+
+        tokio::spawn(async move {
+            let mut ticker = interval(Duration::from_secs(scheduler::TICK_INTERVAL_SECS));
+
+            loop {
+                ticker.tick().await;
+
+                if shutdown.load(Ordering::Relaxed) {
+                    break;
+                }
+
+                match scheduler.tick().await {
+                    Ok(0) => debug!("Scheduler tick: nothing due"),
+                    Ok(n) => info!("Scheduler enqueued {} jobs", n),
+                    Err(e) => error!("Scheduler error: {}", e),
+                }
+            }
+        })
+    }
+
+    /// Spawn the finished-job retention sweep (runs at startup, then hourly)
+    fn spawn_job_retention_task(&self) -> tokio::task::JoinHandle<()> {
+        let config = self.config();
+        let job_repo = JobRepository::new(&self.db, config.worker_id.clone());
+        let retention_days = config.job_retention_days;
+        let min_per_user = config.job_retention_min_per_user;
+        let shutdown = Arc::clone(&self.shutdown);
+
+        tokio::spawn(async move {
+            let mut ticker = interval(Duration::from_secs(JOB_RETENTION_INTERVAL_SECS));
+
+            loop {
+                ticker.tick().await;
+
+                if shutdown.load(Ordering::Relaxed) {
+                    break;
+                }
+
+                match job_repo.cleanup_old_jobs(retention_days, min_per_user).await {
+                    Ok(0) => debug!("Job retention: nothing to delete"),
+                    Ok(n) => info!("Deleted {} jobs older than {} days", n, retention_days),
+                    Err(e) => error!("Job retention cleanup failed: {}", e),
+                }
+            }
+        })
+    }
+
+    /// Spawn periodic cross-user top domains aggregation task
+    fn spawn_top_domains_task(&self) -> tokio::task::JoinHandle<()> {
+        let config = self.config();
+        let stats_repo = StatsRepository::new(&self.db);
+        let data_dir = config.data_dir.clone();
+        let template = FilenameTemplate::parse(&config.output_filename_template).unwrap_or_else(|e| {
+            warn!("OUTPUT_FILENAME_TEMPLATE: {}; looking for default filenames", e);
+            FilenameTemplate::default()
+        });
+        let limit = config.top_domains_limit;
+        let report_interval = config.top_domains_interval_secs;
+        let shutdown = Arc::clone(&self.shutdown);
+
+        tokio::spawn(async move {
+            let mut ticker = interval(Duration::from_secs(report_interval));
+
+            loop {
+                ticker.tick().await;
+
+                if shutdown.load(Ordering::Relaxed) {
+                    break;
+                }
+
+                // CPU/IO heavy - keep it off the async runtime threads
+                let data_dir = data_dir.clone();
+                let template = template.clone();
+                let top = tokio::task::spawn_blocking(move || {
+                    domain_stats::aggregate_top_domains(&data_dir, &template, limit)
+                })
+                .await;
+
+                match top {
+                    Ok(Ok(top)) => {
+                        info!(
+                            "Aggregated top {} domains across {} users",
+                            top.domains.len(),
+                            top.users_scanned
+                        );
+                        if let Err(e) = stats_repo.save_top_domains(&top).await {
+                            error!("Failed to save top domains: {}", e);
+                        }
+                    }
+                    Ok(Err(e)) => error!("Top domains aggregation failed: {}", e),
+                    Err(e) => error!("Top domains aggregation task panicked: {}", e),
+                }
+            }
+        })
+    }
+
     /// Release jobs back to queue (on shutdown)
     async fn release_jobs(&self, job_repo: &JobRepository) -> Result<()> {
         let job_id = {